@@ -1,9 +1,15 @@
 #include "daemon.hpp"
 
+#include <fcntl.h>
 #include <linux/un.h>
 #include <sys/socket.h>
+#include <time.h>
 #include <unistd.h>
 
+#include <algorithm>
+#include <cstdarg>
+#include <cstring>
+
 #include "logging.hpp"
 #include "socket_utils.hpp"
 
@@ -13,19 +19,47 @@ void Init(const char *path) { TMP_PATH = path; }
 
 std::string GetTmpPath() { return TMP_PATH; }
 
+// Resolved once and inherited by forked processes, looked up again when the daemon is unreachable
+static std::string socket_path;
+static std::string log_socket_path;
+
+static std::string ResolvePath(const char *entry, const char *legacy) {
+    char name[64];
+    int fd = open((TMP_PATH + entry).c_str(), O_RDONLY | O_CLOEXEC);
+    if (fd >= 0) {
+        ssize_t len = read(fd, name, sizeof(name) - 1);
+        close(fd);
+        if (len > 0 && !memchr(name, '/', len)) {
+            name[len] = '\0';
+            return TMP_PATH + kCPSocketDir + name;
+        }
+    }
+    return TMP_PATH + legacy;
+}
+
+static std::string ResolveSocketPath() { return ResolvePath(kCPSocketEntry, kCPSocketName); }
+
 int Connect(uint8_t retry) {
     int fd = socket(PF_UNIX, SOCK_STREAM | SOCK_CLOEXEC, 0);
     struct sockaddr_un addr{
         .sun_family = AF_UNIX,
         .sun_path = {0},
     };
-    auto socket_path = TMP_PATH + kCPSocketName;
-    strcpy(addr.sun_path, socket_path.c_str());
     socklen_t socklen = sizeof(addr);
 
     while (retry--) {
+        if (socket_path.empty()) socket_path = ResolveSocketPath();
+        strlcpy(addr.sun_path, socket_path.c_str(), sizeof(addr.sun_path));
         int r = connect(fd, reinterpret_cast<struct sockaddr *>(&addr), socklen);
         if (r == 0) return fd;
+        // Once renamed, the socket is reached again right away at its new name
+        auto resolved = ResolveSocketPath();
+        if (resolved != socket_path) {
+            socket_path = std::move(resolved);
+            retry++;
+            continue;
+        }
+        socket_path.clear();
         if (retry) {
             PLOGE("Retrying to connect to zygiskd, sleep 1s");
             sleep(1);
@@ -46,7 +80,7 @@ bool PingHeartbeat() {
     return true;
 }
 
-uint32_t GetProcessFlags(uid_t uid) {
+uint32_t GetProcessFlags(uid_t uid, SpawnPath path) {
     UniqueFd fd = Connect(1);
     if (fd == -1) {
         PLOGE("GetProcessFlags");
@@ -54,6 +88,7 @@ uint32_t GetProcessFlags(uid_t uid) {
     }
     socket_utils::write_u8(fd, (uint8_t) SocketAction::GetProcessFlags);
     socket_utils::write_u32(fd, uid);
+    socket_utils::write_u8(fd, (uint8_t) path);
     return socket_utils::read_u32(fd);
 }
 
@@ -66,18 +101,37 @@ void CacheMountNamespace(pid_t pid) {
     socket_utils::write_u32(fd, (uint32_t) pid);
 }
 
-std::string UpdateMountNamespace(MountNamespace type) {
-    UniqueFd fd = Connect(1);
-    if (fd == -1) {
-        PLOGE("UpdateMountNamespace");
-        return "";
+// Namespaces replaced meanwhile may have their fd number reused, the daemon
+// confirms the generation echoed back once the namespace is open
+static constexpr int kMountNamespaceAttempts = 3;
+
+int UpdateMountNamespace(MountNamespace type, int uid, bool inherits_clean) {
+    for (int attempt = 0; attempt < kMountNamespaceAttempts; attempt++) {
+        UniqueFd fd = Connect(1);
+        if (fd == -1) {
+            PLOGE("UpdateMountNamespace");
+            return -1;
+        }
+        socket_utils::write_u8(fd, (uint8_t) SocketAction::UpdateMountNamespace);
+        socket_utils::write_u8(fd, (uint8_t) type);
+        socket_utils::write_u32(fd, (uint32_t) uid);
+        socket_utils::write_u8(fd, inherits_clean ? 1 : 0);
+        uint32_t target_pid = socket_utils::read_u32(fd);
+        int target_fd = (int) socket_utils::read_u32(fd);
+        if (target_fd == 0) return kKeepMountNamespace;
+        size_t generation = socket_utils::read_usize(fd);
+        auto ns_path = "/proc/" + std::to_string(target_pid) + "/fd/" + std::to_string(target_fd);
+        int ns_fd = open(ns_path.data(), O_RDONLY | O_CLOEXEC);
+        if (ns_fd < 0) {
+            PLOGE("open mount namespace [%s]", ns_path.data());
+            return -1;
+        }
+        socket_utils::write_usize(fd, generation);
+        if (socket_utils::read_u8(fd) == 1) return ns_fd;
+        LOGD("mount namespace [%s] replaced while opening it", ns_path.data());
+        close(ns_fd);
     }
-    socket_utils::write_u8(fd, (uint8_t) SocketAction::UpdateMountNamespace);
-    socket_utils::write_u8(fd, (uint8_t) type);
-    uint32_t target_pid = socket_utils::read_u32(fd);
-    int target_fd = (int) socket_utils::read_u32(fd);
-    if (target_fd == 0) return "";
-    return "/proc/" + std::to_string(target_pid) + "/fd/" + std::to_string(target_fd);
+    return -1;
 }
 
 std::vector<Module> ReadModules() {
@@ -124,6 +178,25 @@ int GetModuleDir(size_t index) {
     return socket_utils::recv_fd(fd);
 }
 
+int CreateSocketPair(size_t index, int type) {
+    UniqueFd fd = Connect(1);
+    if (fd == -1) {
+        PLOGE("CreateSocketPair");
+        return -1;
+    }
+    socket_utils::write_u8(fd, (uint8_t) SocketAction::CreateSocketPair);
+    socket_utils::write_usize(fd, index);
+    socket_utils::write_u8(fd, (uint8_t) type);
+    if (socket_utils::read_u8(fd) != 1) return -1;
+    int pair = socket_utils::recv_fd(fd);
+    // The companion acknowledges on its end, as for ConnectCompanion
+    if (pair >= 0 && socket_utils::read_u8(pair) != 1) {
+        close(pair);
+        return -1;
+    }
+    return pair;
+}
+
 void ZygoteRestart() {
     UniqueFd fd = Connect(1);
     if (fd == -1) {
@@ -149,4 +222,127 @@ void SystemServerStarted() {
         }
     }
 }
+
+std::vector<std::pair<std::string, std::string>> GetPropertyOverlay(std::string_view process) {
+    std::vector<std::pair<std::string, std::string>> overlay;
+    UniqueFd fd = Connect(1);
+    if (fd == -1) {
+        PLOGE("GetPropertyOverlay");
+        return overlay;
+    }
+    socket_utils::write_u8(fd, (uint8_t) SocketAction::GetPropertyOverlay);
+    socket_utils::write_string(fd, process);
+    size_t len = socket_utils::read_usize(fd);
+    for (size_t i = 0; i < len; i++) {
+        std::string name = socket_utils::read_string(fd);
+        std::string value = socket_utils::read_string(fd);
+        overlay.emplace_back(std::move(name), std::move(value));
+    }
+    return overlay;
+}
+
+// Connect with a socket labeled `context` instead of the context of the calling thread
+static int ConnectAs(const char *context) {
+    int attr = open("/proc/thread-self/attr/sockcreate", O_WRONLY | O_CLOEXEC);
+    if (attr < 0) return -1;
+    int fd = -1;
+    if (write(attr, context, strlen(context)) >= 0) {
+        fd = Connect(1);
+        int saved_errno = errno;
+        // An empty context goes back to labeling sockets like the thread
+        write(attr, "", 1);
+        errno = saved_errno;
+    }
+    close(attr);
+    return fd;
+}
+
+int ReportInjection(uid_t uid, std::string_view process, const std::vector<size_t> &modules,
+                    UnmountResult unmount, uint32_t duration_us) {
+    // Kept by the app to acknowledge its modules, the only socket of zygote it may use
+    UniqueFd fd = ConnectAs(kAppSocketContext);
+    if (fd == -1) {
+        PLOGE("ReportInjection");
+        return -1;
+    }
+    socket_utils::write_u8(fd, (uint8_t) SocketAction::ReportInjection);
+    socket_utils::write_u32(fd, uid);
+    socket_utils::write_string(fd, process);
+    socket_utils::write_usize(fd, modules.size());
+    for (size_t index : modules) {
+        socket_utils::write_usize(fd, index);
+    }
+    socket_utils::write_u8(fd, (uint8_t) unmount);
+    socket_utils::write_u32(fd, duration_us);
+    // Created while still zygote, the connection stays usable after specialization
+    return fd.release();
+}
+
+void AckInjection(int fd, size_t index) {
+    if (!socket_utils::write_usize(fd, index)) {
+        PLOGE("AckInjection");
+    }
+}
+
+// Asked again at most this often while the daemon has none or is unreachable
+constexpr int64_t kLogSocketRetryInterval = 5;
+
+// The log socket is not published anywhere, only the daemon tells where it is
+static std::string RequestLogSocketPath() {
+    static int64_t last_request = -kLogSocketRetryInterval;
+    struct timespec now{};
+    clock_gettime(CLOCK_MONOTONIC, &now);
+    if (now.tv_sec - last_request < kLogSocketRetryInterval) return {};
+    last_request = now.tv_sec;
+    UniqueFd fd = Connect(1);
+    if (fd == -1) return {};
+    socket_utils::write_u8(fd, (uint8_t) SocketAction::GetLogSocket);
+    // Empty while log forwarding is stopped
+    return socket_utils::read_string(fd);
+}
+
+// Records lost because the log channel was full, reported with the next record
+static uint32_t forward_log_dropped = 0;
+
+void ForwardLog(int prio, const char *tag, const char *fmt, ...) {
+    char record[1024];
+    auto pid = (uint32_t) getpid();
+    record[0] = (char) prio;
+    memcpy(record + 1, &pid, sizeof(pid));
+    memcpy(record + 5, &forward_log_dropped, sizeof(forward_log_dropped));
+    size_t len = 9;
+    len += strlcpy(record + len, tag, sizeof(record) - len) + 1;
+    if (len < sizeof(record)) {
+        va_list args;
+        va_start(args, fmt);
+        int n = vsnprintf(record + len, sizeof(record) - len, fmt, args);
+        va_end(args);
+        if (n > 0) len += std::min((size_t) n, sizeof(record) - len - 1);
+    } else {
+        len = sizeof(record);
+    }
+
+    UniqueFd fd = socket(PF_UNIX, SOCK_DGRAM | SOCK_CLOEXEC | SOCK_NONBLOCK, 0);
+    struct sockaddr_un addr{
+        .sun_family = AF_UNIX,
+        .sun_path = {0},
+    };
+    auto send = [&]() {
+        strlcpy(addr.sun_path, log_socket_path.c_str(), sizeof(addr.sun_path));
+        return sendto(fd, record, len, MSG_DONTWAIT, reinterpret_cast<struct sockaddr *>(&addr),
+                      sizeof(addr)) != -1;
+    };
+    if (log_socket_path.empty()) log_socket_path = RequestLogSocketPath();
+    bool sent = fd != -1 && !log_socket_path.empty() && send();
+    // Renamed by `zygiskd rotate-secrets` since it was handed out
+    if (fd != -1 && !sent && errno != EAGAIN) {
+        log_socket_path = RequestLogSocketPath();
+        sent = !log_socket_path.empty() && send();
+    }
+    if (sent) {
+        forward_log_dropped = 0;
+    } else {
+        forward_log_dropped++;
+    }
+}
 }  // namespace zygiskd
@@ -2,6 +2,7 @@
 
 #include <android/dlext.h>
 #include <dlfcn.h>
+#include <fcntl.h>
 #include <libgen.h>
 
 #include "logging.hpp"
@@ -43,6 +44,15 @@ void* DlopenExt(const char* path, int flags) {
 }
 
 void* DlopenMem(int fd, int flags) {
+    // Libraries are mapped from memfds shared by all injected processes, which
+    // only stay shareable as long as no process can alter their content
+    constexpr int kRequiredSeals = F_SEAL_SHRINK | F_SEAL_GROW | F_SEAL_WRITE;
+    if (int seals = fcntl(fd, F_GET_SEALS);
+        seals < 0 || (seals & kRequiredSeals) != kRequiredSeals) {
+        LOGE("dlopen fd %d: not a sealed memfd", fd);
+        return nullptr;
+    }
+
     auto info = android_dlextinfo{.flags = ANDROID_DLEXT_USE_LIBRARY_FD,
                                   .reserved_addr = nullptr,
                                   .reserved_size = 0,
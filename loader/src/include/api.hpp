@@ -308,6 +308,44 @@ private:
 #define REGISTER_ZYGISK_COMPANION(func)                                                            \
     void zygisk_companion_entry(int client) { func(client); }
 
+// Package metadata resolved by the daemon, see zygisk_companion_get_package_info.
+struct PackageInfo {
+    int64_t version_code;
+    // Hex encoded SHA-256 of the current signing certificate, empty if unknown
+    char cert_digest[65];
+    // Package name of the installer, empty if unknown or installed by adb
+    char installer[256];
+};
+
+// Adjustments of an app process the daemon applies for the root companion, see
+// zygisk_companion_adjust_process. Values are passed as strings and validated.
+enum ProcessAdjustment : int {
+    // Nice value of every thread, from 0 to 19
+    ADJUST_NICE = 0,
+    // /proc/<pid>/oom_score_adj, from 0 to 1000
+    ADJUST_OOM_SCORE = 1,
+    // cpuset: top-app, foreground, background, system-background or restricted
+    ADJUST_CGROUP = 2,
+};
+
+// Operations the daemon runs as root for the companion, see zygisk_companion_run_privileged.
+enum PrivilegedOperation : int {
+    // Copy the regular file arg0 to arg1, both in the module or its scratch directory and
+    // given as canonical absolute paths; symbolic links leading out of them are refused
+    PRIV_COPY_FILE = 0,
+    // Current SELinux mode, "Enforcing" or "Permissive", as output; takes no argument
+    PRIV_GET_ENFORCE = 1,
+    // Set property arg0 to arg1; only debug.* and log.tag.* properties are accepted
+    PRIV_SET_PROPERTY = 2,
+};
+
+// Types of the socket pairs the daemon creates for the module, see zygisk_create_socket_pair.
+enum SocketType : int {
+    SOCKET_STREAM = 0,
+    SOCKET_DGRAM = 1,
+    SOCKET_SEQPACKET = 2,
+};
+
 /*********************************************************
  * The following is internal ABI implementation detail.
  * You do not have to understand what it is doing.
@@ -389,4 +427,52 @@ void zygisk_module_entry(zygisk::internal::api_table *, JNIEnv *);
 [[gnu::visibility("default"), maybe_unused]]
 void zygisk_companion_entry(int);
 
+// Define this function in your module to run work in the root companion once per boot,
+// without waiting for an app to connect to it. Modules exporting it get their companion
+// started with the daemon; zygisk_companion_entry must be defined as well. The function is
+// called on its own thread of the companion, once sys.boot_completed is set and
+// zygisk_companion_boot_delay more seconds have passed. A companion spawned again after
+// crashing does not run it again during the same boot.
+[[gnu::visibility("default"), maybe_unused]]
+void zygisk_companion_boot_entry();
+
+// Define this variable in your module to delay zygisk_companion_boot_entry by this many seconds
+// after boot completes, e.g. to let the system settle first. Defaults to 0.
+[[gnu::visibility("default"), maybe_unused]]
+extern uint32_t zygisk_companion_boot_delay;
+
+// Define this pointer (initialized to nullptr) in your module to look up packages from the
+// root companion, e.g. to verify which app is talking to it without running pm yourself.
+// It is set before zygisk_companion_entry is first called, and returns false if the package
+// is not installed.
+[[gnu::visibility("default"), maybe_unused]]
+extern bool (*zygisk_companion_get_package_info)(const char *package, zygisk::PackageInfo *info);
+
+// Define this pointer (initialized to nullptr) in your module to adjust the scheduling of app
+// processes from the root companion, which raw writes often fail to do under SELinux. Only
+// app processes the module was injected into can be adjusted; every request is audited.
+// Returns false if it was refused.
+[[gnu::visibility("default"), maybe_unused]]
+extern bool (*zygisk_companion_adjust_process)(pid_t pid, int adjustment, const char *value);
+
+// Define this pointer (initialized to nullptr) in your module to have the daemon run one of the
+// vetted zygisk::PrivilegedOperation for the root companion, instead of exec-ing su and prompting
+// the user again. Unused arguments may be nullptr. The output, if any, is copied to `output`,
+// truncated to `output_size` and nul-terminated. Only requests from the companion process
+// itself are accepted, not from its children. Every request is audited. Returns false if it
+// was refused or failed.
+[[gnu::visibility("default"), maybe_unused]]
+extern bool (*zygisk_companion_run_privileged)(int operation, const char *arg0, const char *arg1,
+                                               char *output, size_t output_size);
+
+// Define this pointer (initialized to nullptr) in your module to get sockets from the daemon
+// that keep working after specialization: sockets the module creates itself carry the context
+// of the app and hit avc denials when used towards root processes. Pass the Api received in
+// onLoad and a zygisk::SocketType. One end of the pair is returned, already exempted from
+// being closed by zygote; the other end is passed to your companion request handler.
+// Like Api::connectCompanion(), this only works in the pre[XXX]Specialize methods.
+// Returns -1 if errors occurred.
+[[gnu::visibility("default"), maybe_unused]]
+extern int (*zygisk_create_socket_pair)(zygisk::Api *api, int type);
+
 }  // extern "C"
@@ -12,7 +12,14 @@
 #define LP_SELECT(lp32, lp64) lp32
 #endif
 
+// The daemon socket has a random name per boot, published in this file and
+// changed by `zygiskd rotate-secrets`
+constexpr auto kCPSocketEntry = "/sockets/" LP_SELECT("cp32", "cp64");
+constexpr auto kCPSocketDir = "/sockets/";
+// Where daemons of older versions listen
 constexpr auto kCPSocketName = "/" LP_SELECT("cp32", "cp64") ".sock";
+// Label of the sockets apps keep past specialization, see sepolicy.rule
+constexpr auto kAppSocketContext = "u:object_r:zygisk_app_socket:s0";
 
 class UniqueFd {
     using Fd = int;
@@ -65,10 +72,30 @@ enum class SocketAction {
     GetModuleDir,
     ZygoteRestart,
     SystemServerStarted,
+    GetPropertyOverlay,
+    DisableAll,
+    Handover,
+    GetPackageInfo,
+    ReportInjection,
+    AdjustProcess,
+    CreateSocketPair,
+    RunPrivileged,
+    DumpProfile,
+    RotateSecrets,
+    RunScript,
+    ControlSubsystem,
+    GetLogSocket,
+    GetHistory,
 };
 
 enum class MountNamespace { Clean, Root, Module };
 
+// How the process being specialized came out of zygote
+enum class SpawnPath { Fork, Usap, SystemServer };
+
+// Outcome of the mount namespace switch of an app, kept in its injection history
+enum class UnmountResult { Skipped, Unmounted, Failed, Root, Module };
+
 void Init(const char* path);
 
 std::string GetTmpPath();
@@ -77,17 +104,44 @@ bool PingHeartbeat();
 
 std::vector<Module> ReadModules();
 
-uint32_t GetProcessFlags(uid_t uid);
+uint32_t GetProcessFlags(uid_t uid, SpawnPath path);
 
 void CacheMountNamespace(pid_t pid);
 
-std::string UpdateMountNamespace(MountNamespace type);
+// Returned by UpdateMountNamespace when the process keeps the namespace it has
+constexpr int kKeepMountNamespace = -2;
+
+// `uid` selects the hide strategy of the app, -1 stands for zygote itself. `inherits_clean`
+// tells that the process was forked after zygote switched to its Clean namespace, which
+// the process can then keep. Returns an open mount namespace fd, kKeepMountNamespace,
+// or -1 on failure.
+int UpdateMountNamespace(MountNamespace type, int uid, bool inherits_clean);
 
 int ConnectCompanion(size_t index);
 
 int GetModuleDir(size_t index);
 
+// One end of a socket pair labeled by the daemon, the other end going to the companion.
+// `type` is a zygisk::SocketType.
+int CreateSocketPair(size_t index, int type);
+
 void ZygoteRestart();
 
 void SystemServerStarted();
+
+std::vector<std::pair<std::string, std::string>> GetPropertyOverlay(std::string_view process);
+
+// `modules` are the indexes of the modules loaded into the process, at least one. Returns the
+// connection, kept open to acknowledge each module with AckInjection once past its
+// post-specialize phase, or -1 on failure.
+int ReportInjection(uid_t uid, std::string_view process, const std::vector<size_t>& modules,
+                    UnmountResult unmount, uint32_t duration_us);
+
+// Acknowledge the module at `index`, or all are done with `INJECTION_ACK_END`.
+void AckInjection(int fd, size_t index);
+constexpr size_t INJECTION_ACK_END = SIZE_MAX;
+
+// Send a log record to zygiskd on its low-priority log channel, never blocking.
+void ForwardLog(int prio, const char *tag, const char *fmt, ...)
+    __attribute__((format(printf, 3, 4)));
 }  // namespace zygiskd
@@ -104,14 +104,33 @@ DCL_HOOK_FUNC(int, fork) { return (g_ctx && g_ctx->pid >= 0) ? g_ctx->pid : old_
 DCL_HOOK_FUNC(static int, unshare, int flags) {
     int res = old_unshare(flags);
     if (g_ctx && (flags & CLONE_NEWNS) != 0 && res == 0 &&
-        // Skip system server and the first app process since we don't need to hide traces for them
-        !(g_ctx->flags & SERVER_FORK_AND_SPECIALIZE) && !(g_ctx->info_flags & IS_FIRST_PROCESS)) {
-        if (g_ctx->info_flags & (PROCESS_IS_MANAGER | PROCESS_GRANTED_ROOT)) {
-            ZygiskContext::update_mount_namespace(zygiskd::MountNamespace::Root);
-        } else if (!(g_ctx->flags & DO_REVERT_UNMOUNT)) {
-            ZygiskContext::update_mount_namespace(zygiskd::MountNamespace::Module);
+        !(g_ctx->flags & SERVER_FORK_AND_SPECIALIZE)) {
+        int uid = g_ctx->args.app->uid;
+        auto unmount = zygiskd::UnmountResult::Skipped;
+        if (g_ctx->info_flags & IS_FIRST_PROCESS) {
+            // No need to hide traces for the first app process
+        } else if (g_ctx->info_flags & (PROCESS_IS_MANAGER | PROCESS_GRANTED_ROOT)) {
+            unmount = ZygiskContext::update_mount_namespace(zygiskd::MountNamespace::Root, uid)
+                          ? zygiskd::UnmountResult::Root
+                          : zygiskd::UnmountResult::Failed;
+            old_unshare(CLONE_NEWNS);
+        } else {
+            // USAP processes may be forked before zygote itself got unmounted, and the app may
+            // use another hide strategy than zygote, the daemon tells if the inherited namespace
+            // can be kept
+            bool clean = g_ctx->flags & DO_REVERT_UNMOUNT;
+            auto type = clean ? zygiskd::MountNamespace::Clean : zygiskd::MountNamespace::Module;
+            if (!ZygiskContext::update_mount_namespace(type, uid)) {
+                unmount = zygiskd::UnmountResult::Failed;
+            } else if (clean) {
+                unmount = zygiskd::UnmountResult::Unmounted;
+            } else {
+                unmount = zygiskd::UnmountResult::Module;
+            }
+            old_unshare(CLONE_NEWNS);
         }
-        old_unshare(CLONE_NEWNS);
+        // Still privileged here, unlike after specialization
+        g_ctx->report_injection(unmount);
     }
     // Restore errno back to 0
     errno = 0;
@@ -131,7 +150,7 @@ DCL_HOOK_FUNC(static int, pthread_attr_setstacksize, void *target, size_t size)
 
     if (g_hook->should_unmap) {
         g_hook->restore_plt_hook();
-        if (g_hook->should_unmap) {
+        if (g_hook->should_unmap && !g_hook->keep_mapped) {
             void *start_addr = g_hook->start_addr;
             size_t block_size = g_hook->block_size;
             delete g_hook;
@@ -165,6 +184,7 @@ ZygiskContext::ZygiskContext(JNIEnv *env, void *args)
       flags(0),
       info_flags(0),
       allowed_fds(get_fd_max()),
+      injection_ack_fd(-1),
       hook_info_lock(PTHREAD_MUTEX_INITIALIZER) {
     g_ctx = this;
 }
@@ -8,6 +8,7 @@
 #include <sys/types.h>
 #include <unistd.h>
 
+#include <algorithm>
 #include <lsplt.hpp>
 
 #include "daemon.hpp"
@@ -88,6 +89,21 @@ int ZygiskModule::connectCompanion() const { return zygiskd::ConnectCompanion(id
 /* Zygisksu changed: Use own zygiskd */
 int ZygiskModule::getModuleDir() const { return zygiskd::GetModuleDir(id); }
 
+// Set as zygisk_create_socket_pair of the modules defining it
+static int CreateSocketPair(zygisk::Api *api, int type) {
+    if (api == nullptr || g_ctx == nullptr) return -1;
+    // zygisk::Api only holds the table the module registered with
+    auto *table = *reinterpret_cast<ApiTable **>(api);
+    if (table == nullptr || table->base.impl == nullptr) return -1;
+    int fd = zygiskd::CreateSocketPair(table->base.impl->getId(), type);
+    // Zygote would close it during specialization otherwise
+    if (fd >= 0 && !g_ctx->exempt_fd(fd)) {
+        close(fd);
+        return -1;
+    }
+    return fd;
+}
+
 void ZygiskModule::setOption(zygisk::Option opt) {
     if (g_ctx == nullptr) return;
     switch (opt) {
@@ -290,6 +306,10 @@ void ZygiskContext::run_modules_pre() {
         auto &m = ms[i];
         if (void *handle = DlopenMem(m.memfd, RTLD_NOW);
             void *entry = handle ? dlsym(handle, "zygisk_module_entry") : nullptr) {
+            if (auto create = reinterpret_cast<decltype(&CreateSocketPair) *>(
+                    dlsym(handle, "zygisk_create_socket_pair"))) {
+                *create = &CreateSocketPair;
+            }
             modules.emplace_back(i, handle, entry);
         }
     }
@@ -314,8 +334,14 @@ void ZygiskContext::run_modules_post() {
         } else if (flags & SERVER_FORK_AND_SPECIALIZE) {
             m.postServerSpecialize(args.server);
         }
+        if (injection_ack_fd >= 0) zygiskd::AckInjection(injection_ack_fd, m.getId());
         if (m.tryUnload()) modules_unloaded++;
     }
+    if (injection_ack_fd >= 0) {
+        zygiskd::AckInjection(injection_ack_fd, zygiskd::INJECTION_ACK_END);
+        close(injection_ack_fd);
+        injection_ack_fd = -1;
+    }
 
     if (modules.size() > 0) {
         LOGD("modules unloaded: %zu/%zu", modules_unloaded, modules.size());
@@ -324,9 +350,10 @@ void ZygiskContext::run_modules_post() {
 }
 
 void ZygiskContext::app_specialize_pre() {
+    clock_gettime(CLOCK_MONOTONIC, &specialize_start);
     if (!(flags & APP_FORK_AND_SPECIALIZE)) {
         // Avoid fetching process flags twice
-        info_flags = zygiskd::GetProcessFlags(args.app->uid);
+        info_flags = zygiskd::GetProcessFlags(args.app->uid, zygiskd::SpawnPath::Usap);
     }
 
     if ((info_flags & IS_FIRST_PROCESS) && !g_hook->zygote_unmounted) {
@@ -334,11 +361,16 @@ void ZygiskContext::app_specialize_pre() {
     }
 
     if ((info_flags & UNMOUNT_MASK) == UNMOUNT_MASK) {
-        LOGI("[%s] is on the denylist\n", process);
+        zygiskd::ForwardLog(ANDROID_LOG_INFO, LOG_TAG, "[%s] is on the denylist", process);
         flags |= DO_REVERT_UNMOUNT;
     }
 
     flags |= APP_SPECIALIZE;
+    // An overlay is only ever sent for properties a user or an opted-in module asked
+    // for: its hooks point into us, leaving libzygisk.so in /proc/self/maps for good
+    if (apply_property_overlay()) {
+        g_hook->keep_mapped = true;
+    }
     run_modules_pre();
 }
 
@@ -401,7 +433,7 @@ void ZygiskContext::nativeForkAndSpecialize_pre() {
     LOGV("pre forkAndSpecialize [%s]\n", process);
     flags |= APP_FORK_AND_SPECIALIZE;
 
-    info_flags = zygiskd::GetProcessFlags(args.app->uid);
+    info_flags = zygiskd::GetProcessFlags(args.app->uid, zygiskd::SpawnPath::Fork);
 
     if (!g_hook->zygote_unmounted) {
         // Cache mount profiles if not done
@@ -410,7 +442,7 @@ void ZygiskContext::nativeForkAndSpecialize_pre() {
         }
 
         // Unmount the root implementation for Zygote
-        update_mount_namespace(zygiskd::MountNamespace::Clean);
+        update_mount_namespace(zygiskd::MountNamespace::Clean, -1);
         g_hook->zygote_unmounted = true;
         LOGV("zygote process mounting points cleared");
     }
@@ -432,21 +464,37 @@ void ZygiskContext::nativeForkAndSpecialize_post() {
 
 // -----------------------------------------------------------------
 
-bool ZygiskContext::update_mount_namespace(zygiskd::MountNamespace namespace_type) {
-    std::string ns_path = zygiskd::UpdateMountNamespace(namespace_type);
-    if (!ns_path.starts_with("/proc/")) {
-        PLOGE("update mount namespace [%s]", ns_path.data());
-        return false;
+void ZygiskContext::report_injection(zygiskd::UnmountResult unmount) {
+    struct timespec now;
+    clock_gettime(CLOCK_MONOTONIC, &now);
+    int64_t duration_us = (now.tv_sec - specialize_start.tv_sec) * 1000000 +
+                          (now.tv_nsec - specialize_start.tv_nsec) / 1000;
+    std::vector<size_t> loaded;
+    for (const auto &m : modules) {
+        loaded.push_back(m.getId());
     }
+    // Processes without modules have no acknowledgments to send, and are not worth a connection
+    // of their own for their history
+    if (loaded.empty()) return;
+    injection_ack_fd =
+        zygiskd::ReportInjection(args.app->uid, process ? process : "", loaded, unmount,
+                                 (uint32_t) std::max<int64_t>(duration_us, 0));
+}
 
-    auto updated_ns = open(ns_path.data(), O_RDONLY);
-    if (updated_ns >= 0) {
-        LOGD("set mount namespace to [%s] fd=[%d]\n", ns_path.data(), updated_ns);
-        setns(updated_ns, CLONE_NEWNS);
-    } else {
-        PLOGE("open mount namespace [%s]", ns_path.data());
+bool ZygiskContext::update_mount_namespace(zygiskd::MountNamespace namespace_type, int uid) {
+    // Apps forked once zygote switched to its Clean namespace inherit a copy of it
+    bool inherits_clean = uid != -1 && g_hook->zygote_unmounted;
+    int updated_ns = zygiskd::UpdateMountNamespace(namespace_type, uid, inherits_clean);
+    if (updated_ns == zygiskd::kKeepMountNamespace) {
+        LOGD("mount namespace [%d] kept\n", (int) namespace_type);
+        return true;
+    }
+    if (updated_ns < 0) {
+        LOGD("mount namespace [%d] not updated\n", (int) namespace_type);
         return false;
     }
+    LOGD("set mount namespace [%d] fd=[%d]\n", (int) namespace_type, updated_ns);
+    setns(updated_ns, CLONE_NEWNS);
     close(updated_ns);
     return true;
 }
@@ -1,6 +1,7 @@
 #pragma once
 
 #include <regex.h>
+#include <time.h>
 
 #include <bitset>
 #include <list>
@@ -267,6 +268,10 @@ struct ZygiskContext {
     uint32_t info_flags;
     std::vector<bool> allowed_fds;
     std::vector<int> exempted_fds;
+    // When app specialization started, to report how long injection took
+    struct timespec specialize_start;
+    // Connection of the injection report, acknowledging modules past post-specialize
+    int injection_ack_fd;
 
     struct RegisterInfo {
         regex_t regex;
@@ -308,7 +313,10 @@ struct ZygiskContext {
 
     bool plt_hook_commit();
 
-    static bool update_mount_namespace(zygiskd::MountNamespace namespace_type);
+    bool apply_property_overlay();
+    void report_injection(zygiskd::UnmountResult unmount);
+
+    static bool update_mount_namespace(zygiskd::MountNamespace namespace_type, int uid);
 };
 
 #undef DCL_PRE_POST
@@ -323,6 +331,8 @@ struct HookContext {
     size_t block_size = 0;
     bool should_unmap = false;
     bool zygote_unmounted = false;
+    // Hooks installed for the specialized process need our code to stay mapped
+    bool keep_mapped = false;
     jint MODIFIER_NATIVE = 0;
     jmethodID member_getModifiers = nullptr;
     std::vector<lsplt::MapInfo> cached_map_infos = {};
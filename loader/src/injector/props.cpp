@@ -0,0 +1,72 @@
+#include <sys/mman.h>
+#include <sys/system_properties.h>
+
+#include <lsplt.hpp>
+#include <string>
+#include <vector>
+
+#include "daemon.hpp"
+#include "logging.hpp"
+#include "module.hpp"
+
+// Virtual values of read-only properties for the current app process, resolved by zygiskd.
+// Since only ro.* properties are virtualized, values never change once specialized.
+static std::vector<std::pair<std::string, std::string>> overlay;
+
+static const char *find_overlay(const char *name) {
+    for (auto &[key, value] : overlay) {
+        if (key == name) return value.data();
+    }
+    return nullptr;
+}
+
+using PropCallback = void (*)(void *cookie, const char *name, const char *value, uint32_t serial);
+
+static int (*old_system_property_get)(const char *, char *);
+static void (*old_system_property_read_callback)(const prop_info *, PropCallback, void *);
+
+static int new_system_property_get(const char *name, char *value) {
+    if (auto v = find_overlay(name)) {
+        strlcpy(value, v, PROP_VALUE_MAX);
+        return static_cast<int>(strlen(value));
+    }
+    return old_system_property_get(name, value);
+}
+
+static void new_system_property_read_callback(const prop_info *pi, PropCallback callback,
+                                              void *cookie) {
+    struct Forward {
+        PropCallback callback;
+        void *cookie;
+    } forward{callback, cookie};
+    old_system_property_read_callback(
+        pi,
+        [](void *cookie, const char *name, const char *value, uint32_t serial) {
+            auto forward = reinterpret_cast<Forward *>(cookie);
+            if (auto v = find_overlay(name)) value = v;
+            forward->callback(forward->cookie, name, value, serial);
+        },
+        &forward);
+}
+
+bool ZygiskContext::apply_property_overlay() {
+    overlay = zygiskd::GetPropertyOverlay(process);
+    if (overlay.empty()) return false;
+
+    for (auto &map : g_hook->cached_map_infos) {
+        if (map.offset != 0 || !map.is_private || !(map.perms & PROT_READ)) continue;
+        if (map.path.ends_with("/libc.so")) continue;
+        lsplt::RegisterHook(map.dev, map.inode, "__system_property_get",
+                            reinterpret_cast<void *>(new_system_property_get),
+                            reinterpret_cast<void **>(&old_system_property_get));
+        lsplt::RegisterHook(map.dev, map.inode, "__system_property_read_callback",
+                            reinterpret_cast<void *>(new_system_property_read_callback),
+                            reinterpret_cast<void **>(&old_system_property_read_callback));
+    }
+    // Even a partial commit leaves hooks pointing into our code, so report success regardless
+    if (!lsplt::CommitHook(g_hook->cached_map_infos)) {
+        LOGW("some property hooks failed for [%s]", process);
+    }
+    LOGD("[%s] %zu properties virtualized", process, overlay.size());
+    return true;
+}
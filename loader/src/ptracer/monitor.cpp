@@ -59,7 +59,7 @@ public:
 
     void Loop() {
         running = true;
-        constexpr auto MAX_EVENTS = 2;
+        constexpr auto MAX_EVENTS = 4;
         struct epoll_event events[MAX_EVENTS];
         while (running) {
             int nfds = epoll_wait(epoll_fd_, events, MAX_EVENTS, -1);
@@ -114,6 +114,69 @@ struct Status {
 static Status status64;
 static Status status32;
 
+#ifndef __NR_pidfd_open
+#define __NR_pidfd_open 434
+#endif
+
+// Zygotes are children of init, not ours: a pidfd tells us when an injected one exits,
+// without mistaking a process which reused its pid for it.
+struct ZygoteHandler : public EventHandler {
+private:
+    Status &status_;
+    const char *abi_;
+    pid_t pid_ = -1;
+    int pidfd_ = -1;
+
+    void Forget(EventLoop &loop) {
+        if (pidfd_ < 0) return;
+        loop.UnregisterHandler(*this);
+        close(pidfd_);
+        pidfd_ = -1;
+    }
+
+    void Exited() {
+        LOGI("zygote%s pid %d exited", abi_, pid_);
+        status_.zygote_injected = false;
+        updateStatus();
+    }
+
+public:
+    ZygoteHandler(Status &status, const char *abi) : status_(status), abi_(abi) {}
+
+    void Watch(EventLoop &loop, pid_t pid) {
+        Forget(loop);
+        pid_ = pid;
+        pidfd_ = static_cast<int>(syscall(__NR_pidfd_open, pid, 0));
+        if (pidfd_ == -1) {
+            // Kernels older than 5.3 leave the status to the next zygote start
+            if (errno == ESRCH) {
+                Exited();
+            } else if (errno != ENOSYS) {
+                PLOGE("pidfd_open %d", pid);
+            }
+            return;
+        }
+        if (!loop.RegisterHandler(*this, EPOLLIN)) {
+            close(pidfd_);
+            pidfd_ = -1;
+        }
+    }
+
+    int GetFd() override { return pidfd_; }
+
+    void HandleEvent(EventLoop &loop, uint32_t) override {
+        Forget(loop);
+        Exited();
+    }
+
+    ~ZygoteHandler() {
+        if (pidfd_ >= 0) close(pidfd_);
+    }
+};
+
+static ZygoteHandler zygote_watch64(status64, "64");
+static ZygoteHandler zygote_watch32(status32, "32");
+
 struct SocketHandler : public EventHandler {
     struct [[gnu::packed]] MsgHead {
         Command cmd;
@@ -330,7 +393,7 @@ public:
 
     int GetFd() override { return signal_fd_; }
 
-    void HandleEvent(EventLoop &, uint32_t) override {
+    void HandleEvent(EventLoop &loop, uint32_t) override {
         for (;;) {
             ssize_t s = read(signal_fd_, &fdsi, sizeof(fdsi));
             if (s == -1) {
@@ -406,6 +469,7 @@ public:
                         auto program = get_program(pid);
                         LOGV("%d program %s", pid, program.c_str());
                         const char *tracer = nullptr;
+                        ZygoteHandler *zygote_watch = nullptr;
                         do {
                             if (tracing_state != TRACING) {
                                 LOGW("stop injecting %d because not tracing", pid);
@@ -414,6 +478,7 @@ public:
 #define PRE_INJECT(abi, is_64)                                                                     \
     if (program == "/system/bin/app_process" #abi) {                                               \
         tracer = "./bin/zygisk-ptrace" #abi;                                                       \
+        zygote_watch = &zygote_watch##abi;                                                         \
         if (should_stop_inject##abi()) {                                                           \
             LOGW("zygote" #abi " restart too much times, stop injecting");                         \
             tracing_state = STOPPING;                                                              \
@@ -450,6 +515,8 @@ public:
                                     } else if (p == -1) {
                                         PLOGE("failed to fork, kill");
                                         kill(pid, SIGKILL);
+                                    } else {
+                                        zygote_watch->Watch(loop, pid);
                                     }
                                 }
                             }
@@ -112,12 +112,16 @@ extract "$ZIPFILE" 'post-fs-data.sh' "$MODPATH"
 extract "$ZIPFILE" 'service.sh'      "$MODPATH"
 extract "$ZIPFILE" 'uninstall.sh'      "$MODPATH"
 extract "$ZIPFILE" 'zygisk-ctl.sh'   "$MODPATH"
+extract "$ZIPFILE" 'zygisk-script.sh' "$MODPATH"
+extract "$ZIPFILE" 'zygisk-protocheck.sh' "$MODPATH"
 mv "$TMPDIR/sepolicy.rule" "$MODPATH"
 
 mkdir "$MODPATH/bin"
 mkdir "$MODPATH/lib"
 mkdir "$MODPATH/lib64"
 mv "$MODPATH/zygisk-ctl.sh" "$MODPATH/bin/zygisk-ctl"
+mv "$MODPATH/zygisk-script.sh" "$MODPATH/bin/zygisk-script"
+mv "$MODPATH/zygisk-protocheck.sh" "$MODPATH/bin/zygisk-protocheck"
 
 if [ "$ARCH" = "x86" ] || [ "$ARCH" = "x64" ]; then
   ui_print "- Extracting x86 libraries"
@@ -3,3 +3,4 @@
 export TMP_PATH=@WORK_DIRECTORY@
 
 rm -rf $TMP_PATH
+rm -rf /data/adb/zygisksu
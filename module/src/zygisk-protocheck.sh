@@ -0,0 +1,20 @@
+MODDIR=${0%/*}/..
+
+export TMP_PATH=@WORK_DIRECTORY@
+
+# Standing in for a daemon is done with the primary ABI, run zygiskd32 directly for 32-bit injectors
+if [ "$1" = "serve" ]; then
+  if [ -x $MODDIR/bin/zygiskd64 ]; then
+    exec $MODDIR/bin/zygiskd64 protocheck "$@"
+  fi
+  exec $MODDIR/bin/zygiskd32 protocheck "$@"
+fi
+
+# Each daemon is checked by the binary of its ABI, which shares its word size
+status=0
+for daemon in zygiskd64 zygiskd32; do
+  if [ -x $MODDIR/bin/$daemon ]; then
+    $MODDIR/bin/$daemon protocheck || status=1
+  fi
+done
+exit $status
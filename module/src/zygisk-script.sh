@@ -0,0 +1,9 @@
+MODDIR=${0%/*}/..
+
+export TMP_PATH=@WORK_DIRECTORY@
+
+# Scripts of modules reach the daemon of the primary ABI
+if [ -x $MODDIR/bin/zygiskd64 ]; then
+  exec $MODDIR/bin/zygiskd64 script "$@"
+fi
+exec $MODDIR/bin/zygiskd32 script "$@"
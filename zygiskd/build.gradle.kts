@@ -5,6 +5,7 @@ plugins {
 
 val minAPatchVersion: Int by rootProject.extra
 val minKsuVersion: Int by rootProject.extra
+val minKsudVersion: Int by rootProject.extra
 val maxKsuVersion: Int by rootProject.extra
 val minMagiskVersion: Int by rootProject.extra
 val verCode: Int by rootProject.extra
@@ -24,10 +25,15 @@ cargo {
     targetDirectory = "build/intermediates/rust"
     val isDebug = gradle.startParameter.taskNames.any { it.toLowerCase().contains("debug") }
     profile = if (isDebug) "debug" else "release"
+    // A single root implementation backend, see Cargo.toml
+    (project.findProperty("rootImpl") as String?)?.let {
+        features { noDefaultBut(arrayOf(it)) }
+    }
     exec = { spec, _ ->
         spec.environment("ANDROID_NDK_HOME", android.ndkDirectory.path)
         spec.environment("MIN_APATCH_VERSION", minAPatchVersion)
         spec.environment("MIN_KSU_VERSION", minKsuVersion)
+        spec.environment("MIN_KSUD_VERSION", minKsudVersion)
         spec.environment("MAX_KSU_VERSION", maxKsuVersion)
         spec.environment("MIN_MAGISK_VERSION", minMagiskVersion)
         spec.environment("ZKSU_VERSION", "$verName-$verCode-$commitHash-$profile")
@@ -0,0 +1,162 @@
+use crate::{audit, dryrun, pidfd, users};
+use anyhow::{Result, bail};
+use num_enum::TryFromPrimitive;
+use std::fs;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Process adjustments modules may request from their companion, mirroring
+// `zygisk::ProcessAdjustment` of api.hpp
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Adjustment {
+    Nice,
+    OomScoreAdj,
+    Cgroup,
+}
+
+// Apps may be deprioritized freely, but never raised above the default priority
+const NICE_RANGE: std::ops::RangeInclusive<i32> = 0..=19;
+// Negative values are reserved for system and persistent processes
+const OOM_SCORE_ADJ_RANGE: std::ops::RangeInclusive<i32> = 0..=1000;
+const CPUSETS: &[&str] = &[
+    "top-app",
+    "foreground",
+    "background",
+    "system-background",
+    "restricted",
+];
+
+// First and last app ids, including isolated processes
+const FIRST_APP_ID: u32 = 10000;
+const LAST_APP_ID: u32 = 99999;
+
+// Recorded processes kept before the exited ones are pruned
+const TARGETS_PRUNED_AT: usize = 256;
+
+/// Processes a module was injected into, the only ones its companion may adjust.
+/// Each is known by its start time as well, so that a reused pid does not match.
+#[derive(Default)]
+pub struct Targets(Mutex<Vec<(i32, u64)>>);
+
+impl Targets {
+    pub fn add(&self, pid: i32) {
+        let Ok(start) = start_time(pid) else {
+            return;
+        };
+        let mut targets = self.0.lock().unwrap();
+        if targets.len() >= TARGETS_PRUNED_AT {
+            targets.retain(|&(pid, start)| start_time(pid).is_ok_and(|s| s == start));
+        }
+        targets.push((pid, start));
+    }
+
+    fn contains(&self, pid: i32, start: u64) -> bool {
+        self.0.lock().unwrap().contains(&(pid, start))
+    }
+}
+
+fn start_time(pid: i32) -> Result<u64> {
+    Ok(procfs::process::Process::new(pid)?.stat()?.starttime)
+}
+
+/// Apply `adjustment` to the app process `pid` on behalf of `module`, which
+/// must have been injected into it.
+pub fn apply(
+    module: &str,
+    targets: &Targets,
+    pid: i32,
+    adjustment: Adjustment,
+    value: &str,
+) -> Result<()> {
+    let result = pidfd::Process::open(pid).and_then(|target| {
+        check_target(&target, targets, pid)?;
+        adjust(pid, adjustment, value)?;
+        // Not undone, but reported: `pid` may have been reused by another process
+        // between the check and the write
+        check_target(&target, targets, pid)
+    });
+    audit::record(&format!(
+        "adjust module={} pid={} {:?}={} result={}",
+        module,
+        pid,
+        adjustment,
+        value,
+        match &result {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        }
+    ));
+    result
+}
+
+fn adjust(pid: i32, adjustment: Adjustment, value: &str) -> Result<()> {
+    match adjustment {
+        Adjustment::Nice => renice(pid, parse_in(value, NICE_RANGE)?),
+        Adjustment::OomScoreAdj => {
+            let value = parse_in(value, OOM_SCORE_ADJ_RANGE)?;
+            write(format!("/proc/{}/oom_score_adj", pid), value.to_string())
+        }
+        Adjustment::Cgroup => {
+            if !CPUSETS.contains(&value) {
+                bail!("unknown cpuset `{}`", value);
+            }
+            write(
+                format!("/dev/cpuset/{}/cgroup.procs", value),
+                pid.to_string(),
+            )
+        }
+    }
+}
+
+// Only app processes the module was injected into may be adjusted, never zygote,
+// system services or other apps. `pid` is pinned by `target`, so that
+// /proc/<pid> is the process checked as long as it is still running.
+fn check_target(target: &pidfd::Process, targets: &Targets, pid: i32) -> Result<()> {
+    let uid = rustix::fs::stat(format!("/proc/{}", pid).as_str())?.st_uid;
+    if target.wait_exit(Duration::ZERO)? {
+        bail!("pid {} exited", pid);
+    }
+    if !(FIRST_APP_ID..=LAST_APP_ID).contains(&users::app_id(uid)) {
+        bail!("pid {} of uid {} is not an app process", pid, uid);
+    }
+    if !targets.contains(pid, start_time(pid)?) {
+        bail!("pid {} was not injected with the module", pid);
+    }
+    Ok(())
+}
+
+fn parse_in(value: &str, range: std::ops::RangeInclusive<i32>) -> Result<i32> {
+    match value.parse::<i32>() {
+        Ok(v) if range.contains(&v) => Ok(v),
+        _ => bail!("`{}` is not within {:?}", value, range),
+    }
+}
+
+fn write(path: String, value: String) -> Result<()> {
+    if dryrun::skip(|| format!("write {} to {}", value, path)) {
+        return Ok(());
+    }
+    fs::write(path, value)?;
+    Ok(())
+}
+
+// The nice value is per thread on Linux
+fn renice(pid: i32, nice: i32) -> Result<()> {
+    if dryrun::skip(|| format!("renice pid {} to {}", pid, nice)) {
+        return Ok(());
+    }
+    for task in fs::read_dir(format!("/proc/{}/task", pid))? {
+        let Some(tid) = task?
+            .file_name()
+            .to_str()
+            .and_then(|t| t.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, nice) } == -1 {
+            bail!(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use crate::constants::ProcessFlags;
+use crate::utils::LateInit;
+use crate::writer::{self, AsyncWriter};
+use crate::zygote::SpawnPath;
+
+// Trail of the decisions taken for every specialized process.
+static AUDIT: LateInit<Option<AsyncWriter>> = LateInit::new();
+
+const FILE: &str = "audit.log";
+
+pub fn setup() {
+    AUDIT.init(writer::open_data_file(FILE));
+}
+
+// `None` as well for CLI commands, which never set it up
+fn writer() -> Option<&'static AsyncWriter> {
+    if AUDIT.initiated() {
+        AUDIT.as_ref()
+    } else {
+        None
+    }
+}
+
+pub fn record(event: &str) {
+    let record = format!("{} {}\n", writer::timestamp(), event).into_bytes();
+    match writer() {
+        Some(writer) => writer.write(record),
+        // Commands such as `quarantine keep` are rare, and exit before a writer thread would
+        None if !AUDIT.initiated() => writer::append_data_file(FILE, &record),
+        None => {}
+    }
+}
+
+pub fn record_process(uid: i32, path: SpawnPath, flags: ProcessFlags) {
+    if writer().is_some() {
+        record(&format!(
+            "uid={} path={:?} flags={:#x}",
+            uid,
+            path,
+            flags.bits()
+        ));
+    }
+}
+
+pub fn dropped() -> u64 {
+    writer().map_or(0, |writer| writer.dropped())
+}
@@ -0,0 +1,54 @@
+use crate::constants::PATH_DATA_DIR;
+use crate::ring::{self, Layout, Ring};
+use crate::{profile, writer};
+use anyhow::{Result, bail};
+use log::warn;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+// A tiny ring of the last lifecycle events, written atomically and synced
+// immediately so that a trail survives even when the device bootloops before
+// logs are persisted.
+const LAYOUT: Layout = Layout {
+    magic: b"NZBB",
+    slots: 64,
+    slot_size: 128,
+};
+
+static RECORDER: Mutex<Option<Ring>> = Mutex::new(None);
+
+fn path() -> std::path::PathBuf {
+    Path::new(PATH_DATA_DIR).join("blackbox")
+}
+
+pub fn setup() {
+    let open = || -> Result<Ring> {
+        fs::create_dir_all(PATH_DATA_DIR)?;
+        Ring::open(&path(), &LAYOUT)
+    };
+    match open() {
+        Ok(recorder) => *RECORDER.lock().unwrap() = Some(recorder),
+        Err(e) => warn!("Black box recorder unavailable: {}", e),
+    }
+}
+
+/// Append a lifecycle event and flush it to storage before returning.
+pub fn record(event: &str) {
+    let mut recorder = profile::lock(&profile::BLACKBOX, &RECORDER);
+    let Some(recorder) = recorder.as_mut() else {
+        return;
+    };
+    let line = format!("{} {}", writer::timestamp(), event);
+    if let Err(e) = recorder.push(&line) {
+        warn!("Failed to record `{}` in black box: {}", event, e);
+    }
+}
+
+/// Events of the black box, oldest first.
+pub fn dump() -> Result<Vec<String>> {
+    if !path().exists() {
+        bail!("no black box recorded yet");
+    }
+    ring::read(&path(), &LAYOUT)
+}
@@ -0,0 +1,343 @@
+use anyhow::{Result, bail};
+
+pub struct Arg {
+    pub name: &'static str,
+    /// Fixed set of accepted values, offered by shell completions
+    pub values: &'static [&'static str],
+}
+
+pub struct Flag {
+    pub name: &'static str,
+    pub help: &'static str,
+}
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub args: &'static [Arg],
+    pub flags: &'static [Flag],
+}
+
+const DRY_RUN: &[Flag] = &[Flag {
+    name: "--dry-run",
+    help: "Print what would be done without doing it",
+}];
+
+// Every user-facing subcommand of the daemon binary; keep in sync with `start` in main.rs.
+// `companion` and `namespace` are internal and deliberately not listed.
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "version",
+        help: "Print the daemon version",
+        args: &[],
+        flags: &[],
+    },
+    CommandSpec {
+        name: "root",
+        help: "Print the detected root implementation",
+        args: &[],
+        flags: &[],
+    },
+    CommandSpec {
+        name: "explain",
+        help: "Show every policy decision taken for a uid, or a package of a user",
+        args: &[
+            Arg {
+                name: "uid|package",
+                values: &[],
+            },
+            Arg {
+                name: "user",
+                values: &[],
+            },
+        ],
+        flags: &[],
+    },
+    CommandSpec {
+        name: "probe",
+        help: "Check which root detection paths apps of a uid can still reach",
+        args: &[Arg {
+            name: "uid",
+            values: &[],
+        }],
+        flags: &[],
+    },
+    CommandSpec {
+        name: "blackbox",
+        help: "Print the last lifecycle events recorded across reboots",
+        args: &[],
+        flags: &[],
+    },
+    CommandSpec {
+        name: "history",
+        help: "Print the last injections of a package, or list the packages recorded",
+        args: &[Arg {
+            name: "package",
+            values: &[],
+        }],
+        flags: &[],
+    },
+    CommandSpec {
+        name: "messages",
+        help: "Print every message code with its text, in the format of a locale file",
+        args: &[],
+        flags: &[],
+    },
+    CommandSpec {
+        name: "standby",
+        help: "Validate this daemon binary and take over from the running daemon",
+        args: &[],
+        flags: DRY_RUN,
+    },
+    CommandSpec {
+        name: "disable-all",
+        help: "Turn NeoZygisk off until the next reboot",
+        args: &[],
+        flags: DRY_RUN,
+    },
+    CommandSpec {
+        name: "rotate-secrets",
+        help: "Regenerate the script tokens and rename the sockets of the running daemons",
+        args: &[],
+        flags: DRY_RUN,
+    },
+    CommandSpec {
+        name: "profile",
+        help: "Print how long the running daemons waited for and held their locks and queues",
+        args: &[],
+        flags: &[],
+    },
+    CommandSpec {
+        name: "dump-companion",
+        help: "Capture a core dump of the companions of a module",
+        args: &[Arg {
+            name: "module",
+            values: &[],
+        }],
+        flags: DRY_RUN,
+    },
+    CommandSpec {
+        name: "script",
+        help: "Query or ask the daemon on behalf of the scripts of a module",
+        args: &[
+            Arg {
+                name: "module",
+                values: &[],
+            },
+            Arg {
+                name: "request",
+                values: &["deny", "setprop", "modules", "history"],
+            },
+            Arg {
+                name: "arg",
+                values: &[],
+            },
+            Arg {
+                name: "value",
+                values: &[],
+            },
+        ],
+        flags: &[],
+    },
+    CommandSpec {
+        name: "protocheck",
+        help: "Check that the running daemon conforms to the protocol, or stand in for one",
+        args: &[
+            Arg {
+                name: "serve",
+                values: &["serve"],
+            },
+            Arg {
+                name: "socket",
+                values: &[],
+            },
+        ],
+        flags: &[],
+    },
+    CommandSpec {
+        name: "subsystem",
+        help: "List the subsystems of the running daemons, or start, stop or reload one",
+        args: &[
+            Arg {
+                name: "control",
+                values: &["list", "start", "stop", "reload"],
+            },
+            Arg {
+                name: "name",
+                values: &["metrics", "props", "nscheck", "logfwd"],
+            },
+        ],
+        flags: &[],
+    },
+    CommandSpec {
+        name: "quarantine",
+        help: "List modules proposed for or put in quarantine, or keep one proposed",
+        args: &[
+            Arg {
+                name: "keep",
+                values: &["keep"],
+            },
+            Arg {
+                name: "module",
+                values: &[],
+            },
+        ],
+        flags: DRY_RUN,
+    },
+    CommandSpec {
+        name: "completions",
+        help: "Print a shell completion script",
+        args: &[Arg {
+            name: "shell",
+            values: &["bash", "zsh"],
+        }],
+        flags: &[],
+    },
+];
+
+pub const GLOBAL_FLAGS: &[Flag] = &[
+    Flag {
+        name: "--describe-commands",
+        help: "Print all commands and flags as JSON",
+    },
+    Flag {
+        name: "--dry-run",
+        help: "Print what would be done without doing it",
+    },
+];
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_flags(flags: &[Flag]) -> String {
+    let flags: Vec<String> = flags
+        .iter()
+        .map(|f| {
+            format!(
+                "{{\"name\":{},\"help\":{}}}",
+                json_string(f.name),
+                json_string(f.help)
+            )
+        })
+        .collect();
+    format!("[{}]", flags.join(","))
+}
+
+pub fn describe_commands() -> String {
+    let commands: Vec<String> = COMMANDS
+        .iter()
+        .map(|c| {
+            let args: Vec<String> = c
+                .args
+                .iter()
+                .map(|a| {
+                    let values: Vec<String> = a.values.iter().map(|v| json_string(v)).collect();
+                    format!(
+                        "{{\"name\":{},\"values\":[{}]}}",
+                        json_string(a.name),
+                        values.join(",")
+                    )
+                })
+                .collect();
+            format!(
+                "{{\"name\":{},\"help\":{},\"args\":[{}],\"flags\":{}}}",
+                json_string(c.name),
+                json_string(c.help),
+                args.join(","),
+                json_flags(c.flags)
+            )
+        })
+        .collect();
+    format!(
+        "{{\"commands\":[{}],\"flags\":{}}}",
+        commands.join(","),
+        json_flags(GLOBAL_FLAGS)
+    )
+}
+
+/// One line per command, printed when the command line is not understood.
+pub fn usage() -> String {
+    let mut usage = String::from("usage: zygiskd <command>, run without one by the monitor only\n");
+    for command in COMMANDS {
+        let args: Vec<String> = command
+            .args
+            .iter()
+            .map(|a| format!(" [{}]", a.name))
+            .collect();
+        usage += &format!("  {}{}: {}\n", command.name, args.concat(), command.help);
+    }
+    usage
+}
+
+pub fn completions(shell: &str) -> Result<String> {
+    let names: Vec<&str> = COMMANDS.iter().map(|c| c.name).collect();
+    let global: Vec<&str> = GLOBAL_FLAGS.iter().map(|f| f.name).collect();
+    let mut cases = String::new();
+    for command in COMMANDS {
+        let mut words: Vec<&str> = command.flags.iter().map(|f| f.name).collect();
+        if let Some(arg) = command.args.first() {
+            words.extend_from_slice(arg.values);
+        }
+        if !words.is_empty() {
+            cases += &format!(
+                "        {}) words=\"{}\" ;;\n",
+                command.name,
+                words.join(" ")
+            );
+        }
+    }
+    match shell {
+        "bash" => Ok(format!(
+            r#"_zygiskd() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" words=""
+    if [ "$COMP_CWORD" -eq 1 ]; then
+        words="{} {}"
+    else
+        case "${{COMP_WORDS[1]}}" in
+{}        esac
+    fi
+    COMPREPLY=($(compgen -W "$words" -- "$cur"))
+}}
+complete -F _zygiskd zygiskd zygiskd64 zygiskd32
+"#,
+            names.join(" "),
+            global.join(" "),
+            cases
+        )),
+        "zsh" => Ok(format!(
+            r#"#compdef zygiskd zygiskd64 zygiskd32
+_zygiskd() {{
+    local candidates=""
+    if (( CURRENT == 2 )); then
+        candidates="{} {}"
+    else
+        # Read the subcommand before shadowing the special `words` array
+        local command="${{words[2]}}"
+        local words=""
+        case "$command" in
+{}        esac
+        candidates="$words"
+    fi
+    compadd -- ${{=candidates}}
+}}
+compdef _zygiskd zygiskd zygiskd64 zygiskd32
+"#,
+            names.join(" "),
+            global.join(" "),
+            cases
+        )),
+        _ => bail!("unsupported shell `{}`, expected bash or zsh", shell),
+    }
+}
@@ -1,28 +1,60 @@
-use crate::dl;
-use crate::utils::{UnixStreamExt, check_unix_socket};
+use crate::constants::CompanionAction;
+use crate::utils::{LateInit, UnixStreamExt, check_unix_socket};
+use crate::{adjust, dl, privop, zygiskd};
 use anyhow::Result;
 use passfd::FdPassingExt;
 use rustix::fs::fstat;
-use std::ffi::c_void;
+use std::ffi::{CStr, c_char, c_void};
 use std::os::fd::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::UnixStream;
 use std::thread;
 
 type ZygiskCompanionEntryFn = unsafe extern "C" fn(i32);
+type ZygiskCompanionBootEntryFn = unsafe extern "C" fn();
+type ZygiskGetPackageInfoFn = unsafe extern "C" fn(*const c_char, *mut ZygiskPackageInfo) -> bool;
+type ZygiskAdjustProcessFn = unsafe extern "C" fn(libc::pid_t, i32, *const c_char) -> bool;
+type ZygiskRunPrivilegedFn =
+    unsafe extern "C" fn(i32, *const c_char, *const c_char, *mut c_char, usize) -> bool;
+
+// Module served by this companion process
+static MODULE_NAME: LateInit<String> = LateInit::new();
+
+// Mirrors `zygisk::PackageInfo` of api.hpp
+#[allow(dead_code)]
+#[repr(C)]
+pub struct ZygiskPackageInfo {
+    version_code: i64,
+    cert_digest: [c_char; 65],
+    installer: [c_char; 256],
+}
+
+struct CompanionEntries {
+    entry: ZygiskCompanionEntryFn,
+    // Optional work scheduled by the daemon once the device finished booting
+    boot_entry: Option<ZygiskCompanionBootEntryFn>,
+    boot_delay: u32,
+}
 
 pub fn entry(fd: i32) {
     log::info!("companion entry fd={}", fd);
     let mut stream = unsafe { UnixStream::from_raw_fd(fd) };
     let name = stream.read_string().expect("read name");
+    MODULE_NAME.init(name.clone());
     let library = stream.recv_fd().expect("receive library fd");
-    let entry = load_module(library).expect("load module");
+    let entries = load_module(library).expect("load module");
     unsafe { libc::close(library) };
 
-    let entry = match entry {
-        Some(entry) => {
+    let entries = match entries {
+        Some(entries) => {
             log::debug!("Companion process created for `{name}`");
             stream.write_u8(1).expect("reply 1");
-            entry
+            stream
+                .write_u8(entries.boot_entry.is_some() as u8)
+                .expect("reply boot entry");
+            stream
+                .write_u32(entries.boot_delay)
+                .expect("reply boot delay");
+            entries
         }
         None => {
             log::debug!("No companion entry for `{name}`");
@@ -30,12 +62,28 @@ pub fn entry(fd: i32) {
             std::process::exit(0);
         }
     };
+    let entry = entries.entry;
 
     loop {
         if !check_unix_socket(&stream, true) {
             log::info!("Something bad happened in zygiskd, terminate companion");
             std::process::exit(0);
         }
+        let action = stream.read_u8().expect("read action");
+        match CompanionAction::try_from(action).expect("invalid companion action") {
+            CompanionAction::HandleRequest => {}
+            CompanionAction::RunDelayedWork => {
+                if let Some(boot_entry) = entries.boot_entry {
+                    log::debug!("Running delayed work of module `{name}`");
+                    thread::spawn(move || unsafe { boot_entry() });
+                }
+                continue;
+            }
+            CompanionAction::SecretsRotated => {
+                zygiskd::forget_daemon_socket();
+                continue;
+            }
+        }
         let fd = stream.recv_fd().expect("recv fd");
         log::trace!("New companion request from module `{name}` fd=`{fd}`");
         let mut stream = unsafe { UnixStream::from_raw_fd(fd) };
@@ -60,7 +108,7 @@ pub fn entry(fd: i32) {
     }
 }
 
-fn load_module(fd: RawFd) -> Result<Option<ZygiskCompanionEntryFn>> {
+fn load_module(fd: RawFd) -> Result<Option<CompanionEntries>> {
     unsafe {
         let path = format!("/proc/self/fd/{fd}");
         let handle = dl::dlopen(&path, libc::RTLD_NOW)?;
@@ -69,7 +117,148 @@ fn load_module(fd: RawFd) -> Result<Option<ZygiskCompanionEntryFn>> {
         if entry.is_null() {
             return Ok(None);
         }
-        let fnptr = std::mem::transmute::<*mut c_void, ZygiskCompanionEntryFn>(entry);
-        Ok(Some(fnptr))
+        let entry = std::mem::transmute::<*mut c_void, ZygiskCompanionEntryFn>(entry);
+
+        // Modules opt into delayed work by exporting `zygisk_companion_boot_entry`,
+        // and optionally `uint32_t zygisk_companion_boot_delay` in seconds.
+        let symbol = std::ffi::CString::new("zygisk_companion_boot_entry")?;
+        let boot_entry = libc::dlsym(handle, symbol.as_ptr());
+        let boot_entry = (!boot_entry.is_null())
+            .then(|| std::mem::transmute::<*mut c_void, ZygiskCompanionBootEntryFn>(boot_entry));
+        let symbol = std::ffi::CString::new("zygisk_companion_boot_delay")?;
+        let boot_delay = libc::dlsym(handle, symbol.as_ptr()) as *const u32;
+        let boot_delay = if boot_delay.is_null() { 0 } else { *boot_delay };
+
+        // Modules declaring the `zygisk_companion_get_package_info` pointer get it filled in
+        let symbol = std::ffi::CString::new("zygisk_companion_get_package_info")?;
+        let get_package_info_ptr =
+            libc::dlsym(handle, symbol.as_ptr()) as *mut Option<ZygiskGetPackageInfoFn>;
+        if !get_package_info_ptr.is_null() {
+            *get_package_info_ptr = Some(get_package_info);
+        }
+        let symbol = std::ffi::CString::new("zygisk_companion_adjust_process")?;
+        let adjust_process_ptr =
+            libc::dlsym(handle, symbol.as_ptr()) as *mut Option<ZygiskAdjustProcessFn>;
+        if !adjust_process_ptr.is_null() {
+            *adjust_process_ptr = Some(adjust_process);
+        }
+        let symbol = std::ffi::CString::new("zygisk_companion_run_privileged")?;
+        let run_privileged_ptr =
+            libc::dlsym(handle, symbol.as_ptr()) as *mut Option<ZygiskRunPrivilegedFn>;
+        if !run_privileged_ptr.is_null() {
+            *run_privileged_ptr = Some(run_privileged);
+        }
+
+        Ok(Some(CompanionEntries {
+            entry,
+            boot_entry,
+            boot_delay,
+        }))
+    }
+}
+
+// Copy `value` into `buf`, truncated and always nul-terminated
+fn copy_c_string(buf: &mut [c_char], value: &str) {
+    let len = value.len().min(buf.len() - 1);
+    for (dst, src) in buf.iter_mut().zip(&value.as_bytes()[..len]) {
+        *dst = *src as c_char;
+    }
+    buf[len] = 0;
+}
+
+unsafe extern "C" fn get_package_info(
+    package: *const c_char,
+    info: *mut ZygiskPackageInfo,
+) -> bool {
+    if package.is_null() || info.is_null() {
+        return false;
+    }
+    let Ok(package) = unsafe { CStr::from_ptr(package) }.to_str() else {
+        return false;
+    };
+    match zygiskd::request_package_info(package) {
+        Ok(Some(result)) => {
+            let info = unsafe { &mut *info };
+            info.version_code = result.version_code;
+            copy_c_string(
+                &mut info.cert_digest,
+                result.cert_digest.as_deref().unwrap_or(""),
+            );
+            copy_c_string(
+                &mut info.installer,
+                result.installer.as_deref().unwrap_or(""),
+            );
+            true
+        }
+        Ok(None) => false,
+        Err(e) => {
+            log::warn!("Failed to get package info of `{package}`: {e}");
+            false
+        }
+    }
+}
+
+unsafe extern "C" fn adjust_process(
+    pid: libc::pid_t,
+    adjustment: i32,
+    value: *const c_char,
+) -> bool {
+    if value.is_null() {
+        return false;
+    }
+    let Ok(value) = unsafe { CStr::from_ptr(value) }.to_str() else {
+        return false;
+    };
+    let Some(adjustment) = u8::try_from(adjustment)
+        .ok()
+        .and_then(|a| adjust::Adjustment::try_from(a).ok())
+    else {
+        return false;
+    };
+    match zygiskd::request_adjust_process(&MODULE_NAME, pid, adjustment, value) {
+        Ok(_) => true,
+        Err(e) => {
+            log::warn!("Failed to apply {adjustment:?}={value} to {pid}: {e}");
+            false
+        }
+    }
+}
+
+unsafe extern "C" fn run_privileged(
+    operation: i32,
+    arg0: *const c_char,
+    arg1: *const c_char,
+    output: *mut c_char,
+    output_size: usize,
+) -> bool {
+    let Some(operation) = u8::try_from(operation)
+        .ok()
+        .and_then(|o| privop::Operation::try_from(o).ok())
+    else {
+        return false;
+    };
+    // Missing arguments are empty, for operations taking fewer than two
+    let arg = |arg: *const c_char| {
+        if arg.is_null() {
+            Some("")
+        } else {
+            unsafe { CStr::from_ptr(arg) }.to_str().ok()
+        }
+    };
+    let (Some(arg0), Some(arg1)) = (arg(arg0), arg(arg1)) else {
+        return false;
+    };
+    match zygiskd::request_privileged(&MODULE_NAME, operation, [arg0, arg1]) {
+        Ok(result) => {
+            if !output.is_null() && output_size > 0 {
+                let output = unsafe { std::slice::from_raw_parts_mut(output, output_size) };
+                copy_c_string(output, &result);
+            }
+            true
+        }
+        Err(e) => {
+            log::warn!("Privileged {operation:?} failed: {e}");
+            false
+        }
     }
 }
@@ -0,0 +1,349 @@
+use crate::constants::{PATH_CONFIG_FILE, PATH_DATA_DIR};
+use crate::quarantine::{self, Mode as QuarantineMode};
+use crate::utils::{self, LateInit};
+use crate::writer::FsyncPolicy;
+use crate::{hide, manifest, store};
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const CONFIG_KEYS: &[&str] = &[
+    "fsync",
+    "writerCapacity",
+    "tmpRoot",
+    "hideStrategy",
+    "hideStrategy.",
+    "unmountBatch",
+    "measureUnmount",
+    "locale",
+    "dryRun",
+    "store",
+    "legacySocketPath",
+    "quarantine",
+    "quarantineCrashes",
+    "quarantineGraceCrashes",
+    "quarantineTimeout",
+    "probePaths",
+    "runtimeProfile",
+    "namespaceCheckInterval",
+    "privateSpace",
+    "privateSpace.",
+    "startupDeadline",
+];
+
+/// Trade-off between features and memory, set by the `runtimeProfile` config key.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RuntimeProfile {
+    /// `minimal` on devices declaring themselves low on RAM, `standard` otherwise
+    Auto,
+    Standard,
+    /// No metrics nor injection history, smaller queues and a single loader thread,
+    /// to keep each daemon within the memory budget of the README
+    Minimal,
+}
+
+/// Policies applied to the apps of an Android 15 Private Space, set by the
+/// `privateSpace` config key.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PrivateSpace {
+    /// Decided as their copy in the parent user
+    Parent,
+    /// Decided on their own, as root implementations see them
+    Own,
+}
+
+impl PrivateSpace {
+    fn parse(value: &str) -> Option<PrivateSpace> {
+        match value {
+            "parent" => Some(PrivateSpace::Parent),
+            "own" => Some(PrivateSpace::Own),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Config {
+    /// When audit and metrics records are flushed to storage
+    pub fsync: FsyncPolicy,
+    /// Records kept in memory per file before the oldest are dropped
+    pub writer_capacity: usize,
+    /// Where per-boot scratch directories are created, TMP_PATH if unset
+    pub tmp_root: Option<PathBuf>,
+    /// How root traces are hidden from apps on the denylist
+    pub hide_strategy: String,
+    /// Packages using another hide strategy, set by `hideStrategy.<package>`
+    pub hide_strategy_overrides: Vec<(String, String)>,
+    /// Detach mounts stacked inside another hidden mount along with it in a single call,
+    /// instead of one by one as before
+    pub unmount_batch: bool,
+    /// Log how long hiding mount points takes and record it in the metrics
+    pub measure_unmount: bool,
+    /// Language of user facing messages such as `zh-CN`, the one of the device if unset
+    pub locale: Option<String>,
+    /// Only log unmounts, property writes and other changes instead of doing them
+    pub dry_run: bool,
+    /// Where injection history is kept, `file` in the data directory or `memory` until the
+    /// daemon exits, out of reach of the `history` command
+    pub store: String,
+    /// Also reach the daemon socket at `TMP_PATH/cp64.sock` as before, for modules and tools
+    /// which hard-code it, at the cost of a predictable path again
+    pub legacy_socket_path: bool,
+    /// What happens to modules crashing apps: `off`, disabled at once with `immediate`, or
+    /// proposed in the status first with `grace`
+    pub quarantine: QuarantineMode,
+    /// Crashes in a boot before a module is quarantined or proposed for quarantine
+    pub quarantine_crashes: u32,
+    /// Further crashes after which a proposed quarantine is done without answer
+    pub quarantine_grace_crashes: u32,
+    /// How long a proposed quarantine waits for an answer before being done
+    pub quarantine_timeout: Duration,
+    /// Absolute paths checked by `zygiskd probe` besides the built-in ones, separated by `:`
+    pub probe_paths: Vec<String>,
+    /// Resolved from `Auto` once loaded
+    pub runtime_profile: RuntimeProfile,
+    /// How often the mount table is compared with the one the cached mount namespaces were
+    /// copied from, `None` to never rebuild them
+    pub namespace_check_interval: Option<Duration>,
+    /// Deny and umount policies of the apps of a Private Space
+    pub private_space: PrivateSpace,
+    /// Packages whose copy in a Private Space is decided otherwise, set by
+    /// `privateSpace.<package>`
+    pub private_space_overrides: Vec<(String, PrivateSpace)>,
+    /// How long after the daemon starts zygote may wait for subsystems, those not started
+    /// by then being started in the background, `None` to wait for every subsystem
+    pub startup_deadline: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            fsync: FsyncPolicy::Periodic(Duration::from_secs(5)),
+            writer_capacity: 256,
+            tmp_root: None,
+            hide_strategy: hide::STRATEGIES[0].name().to_string(),
+            hide_strategy_overrides: Vec::new(),
+            unmount_batch: true,
+            measure_unmount: false,
+            locale: None,
+            dry_run: false,
+            store: store::BACKENDS[0].to_string(),
+            legacy_socket_path: false,
+            quarantine: QuarantineMode::Off,
+            quarantine_crashes: 3,
+            quarantine_grace_crashes: 3,
+            quarantine_timeout: Duration::from_secs(3600),
+            probe_paths: Vec::new(),
+            runtime_profile: RuntimeProfile::Auto,
+            namespace_check_interval: Some(Duration::from_secs(300)),
+            private_space: PrivateSpace::Parent,
+            private_space_overrides: Vec::new(),
+            startup_deadline: Some(Duration::from_secs(5)),
+        }
+    }
+}
+
+static CONFIG: LateInit<Config> = LateInit::new();
+
+// Copy of the last config.prop read without issues, taken instead of a
+// config.prop that cannot be read, like one left half written by a power loss
+fn snapshot_path() -> PathBuf {
+    Path::new(PATH_DATA_DIR).join("config.snapshot")
+}
+
+pub fn setup() {
+    let mut config = load();
+    if config.runtime_profile == RuntimeProfile::Auto {
+        let low_ram = utils::get_property("ro.config.low_ram").is_ok_and(|v| v == "true");
+        config.runtime_profile = if low_ram {
+            RuntimeProfile::Minimal
+        } else {
+            RuntimeProfile::Standard
+        };
+    }
+    CONFIG.init(config);
+    info!("Config: {:?}", *CONFIG);
+}
+
+pub fn get() -> &'static Config {
+    &CONFIG
+}
+
+pub fn minimal() -> bool {
+    get().runtime_profile == RuntimeProfile::Minimal
+}
+
+fn load() -> Config {
+    let mut config = Config::default();
+    let (content, snapshot) = match manifest::read_bounded(Path::new(PATH_CONFIG_FILE)) {
+        Ok(Some(content)) => (
+            content,
+            manifest::read_bounded(&snapshot_path()).ok().flatten(),
+        ),
+        Ok(None) => return config,
+        Err(e) => match manifest::read_bounded(&snapshot_path()) {
+            Ok(Some(content)) => {
+                warn!(
+                    "Using the last good config, {} unreadable: {}",
+                    PATH_CONFIG_FILE, e
+                );
+                (content.clone(), Some(content))
+            }
+            _ => {
+                warn!("Ignoring {}: {}", PATH_CONFIG_FILE, e);
+                return config;
+            }
+        },
+    };
+    let mut issues = Vec::new();
+    for (key, value) in manifest::parse_entries(&content, "config.prop", CONFIG_KEYS, &mut issues) {
+        match key {
+            "fsync" => match value {
+                "never" => config.fsync = FsyncPolicy::Never,
+                "always" => config.fsync = FsyncPolicy::Always,
+                _ => match value.parse::<u64>() {
+                    Ok(secs) if secs > 0 => {
+                        config.fsync = FsyncPolicy::Periodic(Duration::from_secs(secs))
+                    }
+                    _ => issues.push(format!("config.prop: invalid fsync `{}`", value)),
+                },
+            },
+            "writerCapacity" => match value.parse::<usize>() {
+                Ok(capacity) if capacity > 0 => config.writer_capacity = capacity,
+                _ => issues.push(format!("config.prop: invalid writerCapacity `{}`", value)),
+            },
+            "tmpRoot" => {
+                if value.starts_with('/') {
+                    config.tmp_root = Some(PathBuf::from(value));
+                } else {
+                    issues.push(format!("config.prop: tmpRoot `{}` is not absolute", value));
+                }
+            }
+            "hideStrategy" => match hide::find(value) {
+                Some(strategy) => config.hide_strategy = strategy.name().to_string(),
+                None => issues.push(format!("config.prop: unknown hideStrategy `{}`", value)),
+            },
+            "unmountBatch" => match value {
+                "true" | "1" => config.unmount_batch = true,
+                "false" | "0" => config.unmount_batch = false,
+                _ => issues.push(format!("config.prop: invalid unmountBatch `{}`", value)),
+            },
+            "measureUnmount" => match value {
+                "true" | "1" => config.measure_unmount = true,
+                "false" | "0" => config.measure_unmount = false,
+                _ => issues.push(format!("config.prop: invalid measureUnmount `{}`", value)),
+            },
+            "dryRun" => match value {
+                "true" | "1" => config.dry_run = true,
+                "false" | "0" => config.dry_run = false,
+                _ => issues.push(format!("config.prop: invalid dryRun `{}`", value)),
+            },
+            "store" => {
+                if store::BACKENDS.contains(&value) {
+                    config.store = value.to_string();
+                } else {
+                    issues.push(format!("config.prop: unknown store `{}`", value));
+                }
+            }
+            "legacySocketPath" => match value {
+                "true" | "1" => config.legacy_socket_path = true,
+                "false" | "0" => config.legacy_socket_path = false,
+                _ => issues.push(format!("config.prop: invalid legacySocketPath `{}`", value)),
+            },
+            "quarantine" => match quarantine::MODES.iter().find(|(name, _)| *name == value) {
+                Some((_, mode)) => config.quarantine = *mode,
+                None => issues.push(format!("config.prop: unknown quarantine `{}`", value)),
+            },
+            "quarantineCrashes" => match value.parse::<u32>() {
+                Ok(crashes) if crashes > 0 => config.quarantine_crashes = crashes,
+                _ => issues.push(format!(
+                    "config.prop: invalid quarantineCrashes `{}`",
+                    value
+                )),
+            },
+            "quarantineGraceCrashes" => match value.parse::<u32>() {
+                Ok(crashes) if crashes > 0 => config.quarantine_grace_crashes = crashes,
+                _ => issues.push(format!(
+                    "config.prop: invalid quarantineGraceCrashes `{}`",
+                    value
+                )),
+            },
+            "quarantineTimeout" => match value.parse::<u64>() {
+                Ok(secs) if secs > 0 => config.quarantine_timeout = Duration::from_secs(secs),
+                _ => issues.push(format!(
+                    "config.prop: invalid quarantineTimeout `{}`",
+                    value
+                )),
+            },
+            "probePaths" => {
+                for path in value.split(':').filter(|path| !path.is_empty()) {
+                    if path.starts_with('/') {
+                        config.probe_paths.push(path.to_string());
+                    } else {
+                        issues.push(format!(
+                            "config.prop: probe path `{}` is not absolute",
+                            path
+                        ));
+                    }
+                }
+            }
+            "runtimeProfile" => match value {
+                "auto" => config.runtime_profile = RuntimeProfile::Auto,
+                "standard" => config.runtime_profile = RuntimeProfile::Standard,
+                "minimal" => config.runtime_profile = RuntimeProfile::Minimal,
+                _ => issues.push(format!("config.prop: unknown runtimeProfile `{}`", value)),
+            },
+            "namespaceCheckInterval" => match value.parse::<u64>() {
+                Ok(0) => config.namespace_check_interval = None,
+                Ok(secs) => config.namespace_check_interval = Some(Duration::from_secs(secs)),
+                _ => issues.push(format!(
+                    "config.prop: invalid namespaceCheckInterval `{}`",
+                    value
+                )),
+            },
+            "privateSpace" => match PrivateSpace::parse(value) {
+                Some(policy) => config.private_space = policy,
+                None => issues.push(format!("config.prop: unknown privateSpace `{}`", value)),
+            },
+            "startupDeadline" => match value.parse::<u64>() {
+                Ok(0) => config.startup_deadline = None,
+                Ok(secs) => config.startup_deadline = Some(Duration::from_secs(secs)),
+                _ => issues.push(format!("config.prop: invalid startupDeadline `{}`", value)),
+            },
+            "locale" => config.locale = Some(value.to_string()).filter(|v| !v.is_empty()),
+            _ => {
+                if let Some(package) = key.strip_prefix("hideStrategy.") {
+                    match hide::find(value) {
+                        Some(strategy) => config
+                            .hide_strategy_overrides
+                            .push((package.to_string(), strategy.name().to_string())),
+                        None => issues.push(format!(
+                            "config.prop: unknown hideStrategy `{}` for {}",
+                            value, package
+                        )),
+                    }
+                } else if let Some(package) = key.strip_prefix("privateSpace.") {
+                    match PrivateSpace::parse(value) {
+                        Some(policy) => config
+                            .private_space_overrides
+                            .push((package.to_string(), policy)),
+                        None => issues.push(format!(
+                            "config.prop: unknown privateSpace `{}` for {}",
+                            value, package
+                        )),
+                    }
+                }
+            }
+        }
+    }
+    if issues.is_empty() && snapshot.as_ref() != Some(&content) && !config.dry_run {
+        let saved = utils::write_atomic(&snapshot_path(), content.as_bytes());
+        if let Err(e) = saved {
+            warn!("Failed to snapshot the config: {}", e);
+        }
+    }
+    for issue in issues {
+        warn!("{}", issue);
+    }
+    config
+}
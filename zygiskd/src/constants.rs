@@ -1,28 +1,144 @@
 use crate::lp_select;
+use crate::messages::Message;
+use crate::root_impl::RootImpl;
 use bitflags::bitflags;
 use konst::primitive::parse_i32;
 use konst::unwrap_ctx;
 use log::LevelFilter;
 use num_enum::TryFromPrimitive;
 
+#[cfg(feature = "apatch")]
 pub const MIN_APATCH_VERSION: i32 = unwrap_ctx!(parse_i32(env!("MIN_APATCH_VERSION")));
+#[cfg(feature = "kernelsu")]
 pub const MIN_KSU_VERSION: i32 = unwrap_ctx!(parse_i32(env!("MIN_KSU_VERSION")));
+#[cfg(feature = "kernelsu")]
+pub const MIN_KSUD_VERSION: i32 = unwrap_ctx!(parse_i32(env!("MIN_KSUD_VERSION")));
+#[cfg(feature = "kernelsu")]
 pub const MAX_KSU_VERSION: i32 = unwrap_ctx!(parse_i32(env!("MAX_KSU_VERSION")));
+#[cfg(feature = "magisk")]
 pub const MIN_MAGISK_VERSION: i32 = unwrap_ctx!(parse_i32(env!("MIN_MAGISK_VERSION")));
 pub const ZKSU_VERSION: &str = env!("ZKSU_VERSION");
 
+/// Part of a root implementation whose version an advisory is about.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RootPart {
+    /// The version the root implementation is detected with
+    Core,
+    /// ksud, updated with the KernelSU manager apart from the kernel
+    Ksud,
+}
+
+/// Known issue of a root implementation, reported while the version of `part` is below
+/// `fixed_in` on Android SDK levels within `min_sdk..=max_sdk`.
+pub struct RootAdvisory {
+    pub root: RootImpl,
+    pub part: RootPart,
+    pub fixed_in: i32,
+    pub min_sdk: u32,
+    pub max_sdk: u32,
+    pub issue: Message,
+    /// Upstream issue, changelog entry or requirement confirming it
+    pub source: &'static str,
+}
+
+// Supported versions which still deserve an upgrade, beyond what detection refuses.
+// Only confirmed issues belong here, each with `source` pointing to the confirmation.
+pub const ROOT_ADVISORIES: &[RootAdvisory] = &[
+    // Only checked by the installer: the manager, and ksud with it, may be
+    // downgraded or restored from a backup once the module is installed
+    #[cfg(feature = "kernelsu")]
+    RootAdvisory {
+        root: RootImpl::KernelSU,
+        part: RootPart::Ksud,
+        fixed_in: MIN_KSUD_VERSION,
+        min_sdk: 0,
+        max_sdk: u32::MAX,
+        issue: Message {
+            code: "NZ-A001",
+            text: "ksud is older than the installer accepts, update the KernelSU manager",
+        },
+        source: "module/src/customize.sh, MIN_KSUD_VERSION",
+    },
+];
+
+/// Functionality given up on environments known to break it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Reduction {
+    /// Zygote is still served, but no module is loaded
+    NoModules,
+}
+
+/// Environment known not to work, where the daemon runs with `reduction` instead of
+/// failing in obscure ways. Every condition set must match: SDK levels within
+/// `min_sdk..=max_sdk`, the ROM family recognized by `fingerprint`, the root
+/// implementation and its version below `root_fixed_in`.
+pub struct CompatGuard {
+    pub min_sdk: u32,
+    pub max_sdk: u32,
+    pub rom: Option<&'static str>,
+    pub root: Option<RootImpl>,
+    pub root_fixed_in: Option<i32>,
+    pub reduction: Reduction,
+    pub issue: Message,
+}
+
+// Combinations worse than an advisory, kept to what is documented as unsupported
+pub const COMPAT_GUARDS: &[CompatGuard] = &[CompatGuard {
+    // Level 0 is an unreadable SDK, not a guard reason
+    min_sdk: 1,
+    max_sdk: 25,
+    rom: None,
+    root: None,
+    root_fixed_in: None,
+    reduction: Reduction::NoModules,
+    issue: Message {
+        code: "NZ-C001",
+        text: "Android older than 8.0 is not supported, modules are not loaded",
+    },
+}];
+
+// Paths root detection commonly checks for, probed by `zygiskd probe`
+pub const PROBE_PATHS: &[&str] = &[
+    "/system/bin/su",
+    "/system/xbin/su",
+    "/system/sbin/su",
+    "/vendor/bin/su",
+    "/sbin/su",
+    "/sbin/magisk",
+    "/sbin/.magisk",
+    "/system/bin/magisk",
+    "/system/bin/ksud",
+    "/system/bin/apd",
+    "/debug_ramdisk/su",
+    "/debug_ramdisk/magisk",
+    "/debug_ramdisk/.magisk",
+    "/system/app/Superuser.apk",
+    "/system/etc/init/magisk.rc",
+];
+
 #[cfg(debug_assertions)]
 pub const MAX_LOG_LEVEL: LevelFilter = LevelFilter::Trace;
 #[cfg(not(debug_assertions))]
 pub const MAX_LOG_LEVEL: LevelFilter = LevelFilter::Info;
 
 pub const PATH_MODULES_DIR: &str = "..";
+// Where root managers stage module updates until the next boot
+pub const PATH_MODULES_UPDATE_DIR: &str = "../../modules_update";
+pub const ZYGISK_API_VERSION: u32 = 5;
+pub const MANIFEST_SCHEMA_VERSION: u32 = 1;
+pub const MAX_METADATA_SIZE: u64 = 4096;
+pub const PATH_DATA_DIR: &str = "/data/adb/zygisksu";
+pub const PATH_CONFIG_FILE: &str = "/data/adb/zygisksu/config.prop";
+pub const PATH_BUGREPORT_DIR: &str = "/data/adb/zygisksu/bugreport";
+pub const MONITOR_STOP: i32 = 2;
+/// Resident memory targeted by daemons running the minimal runtime profile, see the README
+pub const MINIMAL_MEMORY_BUDGET_KIB: u64 = 8 * 1024;
 pub const ZYGOTE_INJECTED: i32 = lp_select!(5, 4);
 pub const DAEMON_SET_INFO: i32 = lp_select!(7, 6);
 pub const DAEMON_SET_ERROR_INFO: i32 = lp_select!(9, 8);
 pub const SYSTEM_SERVER_STARTED: i32 = 10;
 
-#[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
 #[repr(u8)]
 pub enum DaemonSocketAction {
     PingHeartbeat,
@@ -34,10 +150,43 @@ pub enum DaemonSocketAction {
     GetModuleDir,
     ZygoteRestart,
     SystemServerStarted,
+    GetPropertyOverlay,
+    DisableAll,
+    Handover,
+    GetPackageInfo,
+    ReportInjection,
+    AdjustProcess,
+    CreateSocketPair,
+    RunPrivileged,
+    DumpProfile,
+    RotateSecrets,
+    RunScript,
+    ControlSubsystem,
+    GetLogSocket,
+    GetHistory,
 }
 
+// Types of the socket pairs brokered for modules, mirroring `zygisk::SocketType` of api.hpp
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum SocketPairType {
+    Stream,
+    Datagram,
+    SeqPacket,
+}
+
+// Messages sent by the daemon over its stream to a companion process
 #[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
 #[repr(u8)]
+pub enum CompanionAction {
+    HandleRequest,
+    RunDelayedWork,
+    /// The daemon socket was renamed, its path must be looked up again
+    SecretsRotated,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, TryFromPrimitive)]
+#[repr(u8)]
 pub enum MountNamespace {
     Clean,
     Root,
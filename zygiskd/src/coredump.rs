@@ -0,0 +1,131 @@
+use crate::constants::PATH_BUGREPORT_DIR;
+use crate::{dryrun, pidfd, uring, utils, writer};
+use anyhow::{Result, bail};
+use procfs::process::all_processes;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const CORE_PATTERN: &str = "/proc/sys/kernel/core_pattern";
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Capture a core dump of every companion process of `module`, e.g. to diagnose
+// a deadlocked companion. The companion is killed in the process; the daemon
+// spawns a new one on the next request.
+pub fn main(module: &str) -> Result<()> {
+    let pids = find_companions(module);
+    if pids.is_empty() {
+        bail!("no companion running for module `{}`", module);
+    }
+    for pid in pids {
+        let dir = Path::new(PATH_BUGREPORT_DIR).join(format!(
+            "{}-{}-{}",
+            module,
+            pid,
+            writer::timestamp()
+        ));
+        // Covers the state saved, the core_pattern switch and the abort alike
+        let action = || {
+            format!(
+                "set {} and kill companion {} to dump it to {}",
+                CORE_PATTERN,
+                pid,
+                dir.display()
+            )
+        };
+        if dryrun::skip(action) {
+            continue;
+        }
+        fs::create_dir_all(&dir)?;
+        save_process_state(pid, &dir);
+        match dump_core(pid, &dir) {
+            Ok(core) => println!("Companion {} dumped to {}", pid, core.display()),
+            Err(e) => println!(
+                "Companion {}: no core dump ({}), process state saved in {}",
+                pid,
+                e,
+                dir.display()
+            ),
+        }
+    }
+    Ok(())
+}
+
+// Companions are spawned as `<daemon>-<module> companion <fd>`.
+fn find_companions(module: &str) -> Vec<i32> {
+    let suffix = format!("-{}", module);
+    let Ok(processes) = all_processes() else {
+        return Vec::new();
+    };
+    processes
+        .flatten()
+        .filter(|process| {
+            process.cmdline().is_ok_and(|cmdline| {
+                cmdline.len() == 3 && cmdline[0].ends_with(&suffix) && cmdline[1] == "companion"
+            })
+        })
+        .map(|process| process.pid)
+        .collect()
+}
+
+// Stacks and wait channels are what matters most for a deadlock, and remain
+// available even if the kernel refuses to write the core.
+fn save_process_state(pid: i32, dir: &Path) {
+    for file in ["status", "maps", "wchan", "stack"] {
+        let _ = fs::copy(format!("/proc/{}/{}", pid, file), dir.join(file));
+    }
+    let Ok(tasks) = fs::read_dir(format!("/proc/{}/task", pid)) else {
+        return;
+    };
+    const FILES: [&str; 3] = ["comm", "wchan", "stack"];
+    let tasks: Vec<_> = tasks.flatten().collect();
+    let paths: Vec<PathBuf> = tasks
+        .iter()
+        .flat_map(|task| FILES.map(|name| task.path().join(name)))
+        .collect();
+    // Hung processes may have hundreds of threads, read all at once
+    let contents: Vec<String> = uring::read_files(&paths)
+        .into_iter()
+        .map(|content| String::from_utf8_lossy(&content.unwrap_or_default()).into_owned())
+        .collect();
+    let mut threads = String::new();
+    for (task, files) in tasks.iter().zip(contents.chunks(FILES.len())) {
+        threads.push_str(&format!(
+            "tid {} ({}) wchan {}\n{}\n",
+            task.file_name().to_string_lossy(),
+            files[0].trim(),
+            files[1].trim(),
+            files[2]
+        ));
+    }
+    let _ = utils::write_atomic(&dir.join("threads"), threads.as_bytes());
+}
+
+fn dump_core(pid: i32, dir: &Path) -> Result<PathBuf> {
+    let core = dir.join("core");
+    // Pinned first, so that the abort can only reach the companion itself
+    let process = pidfd::Process::open(pid)?;
+    let limit = libc::rlimit {
+        rlim_cur: libc::RLIM_INFINITY,
+        rlim_max: libc::RLIM_INFINITY,
+    };
+    if unsafe { libc::prlimit(pid, libc::RLIMIT_CORE, &limit, std::ptr::null_mut()) } != 0 {
+        bail!("prlimit: {}", std::io::Error::last_os_error());
+    }
+
+    // core_pattern is global, restore it as soon as the companion is gone
+    let old_pattern = fs::read_to_string(CORE_PATTERN)?;
+    fs::write(CORE_PATTERN, core.to_string_lossy().as_bytes())?;
+    let aborted = process
+        .signal(libc::SIGABRT)
+        .and_then(|_| process.wait_exit(EXIT_TIMEOUT));
+    fs::write(CORE_PATTERN, old_pattern.trim_end().as_bytes())?;
+
+    if !aborted? {
+        bail!("still running {:?} after SIGABRT", EXIT_TIMEOUT);
+    }
+    if !core.exists() {
+        bail!("the kernel did not write {}", core.display());
+    }
+    Ok(core)
+}
@@ -0,0 +1,135 @@
+use crate::constants::{DaemonSocketAction, PATH_DATA_DIR, ZKSU_VERSION};
+use crate::{dryrun, sockdir, utils, writer};
+use log::{error, warn};
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::fs;
+use std::panic::PanicHookInfo;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// The daemon aborts on panic, which init and the monitor only see as a
+// silent restart. The panic hook leaves a marker describing the crash, and the
+// next daemon moves it aside and mentions it in the status.
+
+// No request being served
+const NO_OP: u8 = u8::MAX;
+
+static LAST_OP: AtomicU8 = AtomicU8::new(NO_OP);
+static PREVIOUS: OnceLock<Option<String>> = OnceLock::new();
+
+thread_local! {
+    static CURRENT_OP: Cell<u8> = const { Cell::new(NO_OP) };
+}
+
+fn marker_path() -> PathBuf {
+    Path::new(PATH_DATA_DIR).join(format!("crash.{}", sockdir::ARCH))
+}
+
+// Where the marker is moved once reported, kept until the next crash
+fn reported_path() -> PathBuf {
+    Path::new(PATH_DATA_DIR).join(format!("crash.{}.last", sockdir::ARCH))
+}
+
+fn op_name(op: u8) -> String {
+    match DaemonSocketAction::try_from(op) {
+        Ok(action) => format!("{:?}", action),
+        Err(_) => "none".to_string(),
+    }
+}
+
+/// Marks the thread as serving no request again once dropped.
+pub struct OpGuard;
+
+impl Drop for OpGuard {
+    fn drop(&mut self) {
+        CURRENT_OP.with(|op| op.set(NO_OP));
+    }
+}
+
+/// Remember `action` as the request served by this thread, until the guard
+/// is dropped.
+#[must_use]
+pub fn set_op(action: DaemonSocketAction) -> OpGuard {
+    CURRENT_OP.with(|op| op.set(action as u8));
+    LAST_OP.store(action as u8, Ordering::Relaxed);
+    OpGuard
+}
+
+fn hook(info: &PanicHookInfo) {
+    let message = match info.payload().downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match info.payload().downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "unknown panic".to_string(),
+        },
+    };
+    let location = info
+        .location()
+        .map_or("unknown".to_string(), |l| l.to_string());
+    let thread = std::thread::current();
+    let record = format!(
+        "time={}\nversion={}\narch={}\nthread={}\nop={}\nlast_op={}\nmessage={}\n\
+         location={}\nbacktrace:\n{}\n",
+        writer::timestamp(),
+        ZKSU_VERSION,
+        sockdir::ARCH,
+        thread.name().unwrap_or("unnamed"),
+        op_name(CURRENT_OP.with(Cell::get)),
+        op_name(LAST_OP.load(Ordering::Relaxed)),
+        message.replace('\n', " "),
+        location,
+        Backtrace::force_capture()
+    );
+    error!("Daemon panicked, aborting:\n{}", record);
+    if dryrun::skip(|| format!("write the crash marker {}", marker_path().display())) {
+        return;
+    }
+    if let Err(e) = utils::write_atomic(&marker_path(), record.as_bytes()) {
+        error!("Failed to write the crash marker: {}", e);
+    }
+}
+
+// One line out of a crash record, as shown in the status
+fn summary(record: &str) -> String {
+    let field = |name: &str| {
+        record
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+            .unwrap_or("unknown")
+    };
+    let serving = match field("op") {
+        "none" => String::new(),
+        op => format!(" serving {}", op),
+    };
+    format!(
+        "{} in thread {}{} at {}",
+        field("message"),
+        field("thread"),
+        serving,
+        field("location")
+    )
+}
+
+/// Install the panic hook, after taking the marker a previous daemon left.
+pub fn setup() {
+    let path = marker_path();
+    let previous = match fs::read_to_string(&path) {
+        Ok(record) => {
+            warn!("Previous daemon crashed:\n{}", record);
+            if let Err(e) = fs::rename(&path, reported_path()) {
+                warn!("Failed to move the crash marker aside: {}", e);
+            }
+            Some(summary(&record))
+        }
+        Err(_) => None,
+    };
+    let _ = PREVIOUS.set(previous);
+    std::panic::set_hook(Box::new(hook));
+}
+
+/// Summary of the crash of the previous daemon, if it panicked.
+pub fn previous() -> Option<&'static str> {
+    PREVIOUS.get()?.as_deref()
+}
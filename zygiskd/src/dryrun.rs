@@ -0,0 +1,31 @@
+use log::info;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// In dry-run mode, property writes, module disables, quarantine state and
+// other changes outliving the daemon are only reported as what would have
+// been done, to preview the effect of a config change or a command. Hiding
+// stays active, as it only changes the mount namespaces of apps.
+const OFF: u8 = 0;
+const LOG: u8 = 1;
+// Also print to stdout, for commands run from a shell
+const PRINT: u8 = 2;
+
+static MODE: AtomicU8 = AtomicU8::new(OFF);
+
+pub fn enable(print: bool) {
+    MODE.store(if print { PRINT } else { LOG }, Ordering::Relaxed);
+}
+
+/// Whether the operation described by `action` must be skipped, reporting it if so.
+pub fn skip(action: impl FnOnce() -> String) -> bool {
+    let mode = MODE.load(Ordering::Relaxed);
+    if mode == OFF {
+        return false;
+    }
+    let action = action();
+    info!("Dry run: would {}", action);
+    if mode == PRINT {
+        println!("would {}", action);
+    }
+    true
+}
@@ -0,0 +1,114 @@
+use crate::constants::ProcessFlags;
+use crate::{fingerprint, history, messages, packages, policy, root_impl, users, zygiskd};
+use anyhow::{Result, anyhow, bail};
+
+// Run the decision pipeline of GetProcessFlags for a uid or package without forking anything.
+// A package is looked up in `user`, the owner by default.
+pub fn main(target: &str, user: Option<&str>) -> Result<()> {
+    let (uid, package) = resolve_target(target, user)?;
+    match root_impl::get_impl() {
+        root_impl::RootImpl::APatch
+        | root_impl::RootImpl::KernelSU
+        | root_impl::RootImpl::Magisk => {}
+        _ => bail!("Invalid root implementation: {:?}", root_impl::get_impl()),
+    }
+
+    println!(
+        "Target: uid {} ({})",
+        uid,
+        package.as_deref().unwrap_or("unknown package")
+    );
+    match root_impl::version() {
+        Some(version) => println!(
+            "Root implementation: {:?} {}",
+            root_impl::get_impl(),
+            version
+        ),
+        None => println!("Root implementation: {:?}", root_impl::get_impl()),
+    }
+    for advisory in root_impl::advisories() {
+        println!(
+            "\tadvisory [{}]: {} ({})",
+            advisory.issue.code,
+            messages::text(&advisory.issue),
+            advisory.source
+        );
+    }
+    println!("Environment: {}", fingerprint::setup());
+    for guard in fingerprint::guards() {
+        println!(
+            "\treduced [{}]: {}",
+            guard.issue.code,
+            messages::text(&guard.issue)
+        );
+    }
+
+    let mut rules = Vec::new();
+    let flags = policy::process_flags(uid, false, Some(&mut rules));
+    println!("Rules fired:");
+    for rule in &rules {
+        println!("\t{}", rule);
+    }
+    println!("Process flags: {:?}", flags);
+    if flags.contains(ProcessFlags::PROCESS_ON_DENYLIST) {
+        println!("\tmount points of the root solution will be reverted");
+    }
+
+    match policy::mount_namespace(flags) {
+        Some(namespace) => println!("Mount namespace: {:?}", namespace),
+        None => println!("Mount namespace: inherited from zygote"),
+    }
+
+    let arch = zygiskd::get_arch()?;
+    let modules = zygiskd::load_modules(arch)?;
+    println!("Modules to load({}):", modules.len());
+    for module in &modules {
+        println!("\t{}", module.name);
+    }
+    if !modules.is_empty() {
+        println!("\tmodules may still unload themselves after specialization");
+    }
+    for (name, marker) in zygiskd::flagged_modules(arch) {
+        println!("\tskipped: {}", marker.describe(&name));
+    }
+
+    if let Some(injections) = package.as_deref().and_then(|p| history::query(p).ok()) {
+        println!("Recent injections:");
+        for injection in &injections {
+            println!("\t{}", injection);
+        }
+    }
+    Ok(())
+}
+
+fn resolve_target(target: &str, user: Option<&str>) -> Result<(i32, Option<String>)> {
+    if let Ok(uid) = target.parse::<i32>() {
+        if user.is_some() {
+            bail!("a user only applies to a package");
+        }
+        return Ok((uid, package_of_uid(uid)));
+    }
+    packages::check_name(target)?;
+    let user = match user {
+        Some(user) => user
+            .parse::<u32>()
+            .map_err(|_| anyhow!("invalid user `{}`", user))?,
+        None => 0,
+    };
+    let Some(app_id) = packages::app_id(target)? else {
+        bail!("no package `{}` is installed", target);
+    };
+    let uid = user * users::PER_USER_RANGE + app_id;
+    Ok((uid as i32, Some(target.to_string())))
+}
+
+// Packages sharing a uid are reported by the first name found
+fn package_of_uid(uid: i32) -> Option<String> {
+    let app_id = users::app_id(u32::try_from(uid).ok()?);
+    packages::app_ids()
+        .ok()?
+        .into_iter()
+        .filter(|(_, id)| *id == app_id)
+        .map(|(package, _)| package)
+        .min()
+}
@@ -0,0 +1,145 @@
+use crate::constants::{COMPAT_GUARDS, CompatGuard, Reduction};
+use crate::root_impl::{self, RootImpl};
+use crate::utils::{self, LateInit};
+use crate::version::Version;
+use log::{info, warn};
+use std::fmt;
+use std::fs;
+
+// Properties only set by a given ROM family, checked in order
+const ROM_MARKERS: &[(&str, &str)] = &[
+    ("ro.miui.ui.version.name", "MIUI"),
+    ("ro.build.version.emui", "EMUI"),
+    ("ro.build.version.oplusrom", "ColorOS"),
+    ("ro.vivo.os.version", "OriginOS"),
+    ("ro.build.version.oneui", "One UI"),
+    ("ro.lineage.version", "LineageOS"),
+];
+
+/// What the daemon runs on, as far as compatibility is concerned.
+pub struct Fingerprint {
+    pub sdk: u32,
+    pub release: String,
+    pub vendor: String,
+    pub kernel: String,
+    pub root: RootImpl,
+    pub root_version: Option<Version>,
+    /// ROM families recognized from their properties
+    pub roms: Vec<&'static str>,
+}
+
+impl Fingerprint {
+    pub fn collect() -> Fingerprint {
+        let property = |name: &str| utils::get_property(name).unwrap_or_default();
+        Fingerprint {
+            sdk: property("ro.build.version.sdk").parse().unwrap_or(0),
+            release: property("ro.build.version.release"),
+            vendor: property("ro.product.manufacturer"),
+            kernel: fs::read_to_string("/proc/sys/kernel/osrelease")
+                .map(|release| release.trim().to_string())
+                .unwrap_or_default(),
+            root: *root_impl::get_impl(),
+            root_version: root_impl::version(),
+            roms: ROM_MARKERS
+                .iter()
+                .filter(|(name, _)| !property(name).is_empty())
+                .map(|(_, rom)| *rom)
+                .collect(),
+        }
+    }
+
+    /// Entries of the compatibility table matching this environment.
+    pub fn guards(&self) -> Vec<&'static CompatGuard> {
+        COMPAT_GUARDS
+            .iter()
+            .filter(|g| (g.min_sdk..=g.max_sdk).contains(&self.sdk))
+            .filter(|g| g.rom.is_none_or(|rom| self.roms.contains(&rom)))
+            .filter(|g| g.root.is_none_or(|root| root == self.root))
+            .filter(|g| {
+                g.root_fixed_in.is_none_or(|fixed| {
+                    self.root_version
+                        .as_ref()
+                        .is_some_and(|version| !version.at_least(&fixed.into()))
+                })
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Android {} (sdk {}), vendor {}, kernel {}, root {:?}",
+            self.release, self.sdk, self.vendor, self.kernel, self.root
+        )?;
+        if let Some(version) = &self.root_version {
+            write!(f, " {}", version)?;
+        }
+        if !self.roms.is_empty() {
+            write!(f, ", ROM {}", self.roms.join("/"))?;
+        }
+        Ok(())
+    }
+}
+
+static GUARDS: LateInit<Vec<&'static CompatGuard>> = LateInit::new();
+
+/// Fingerprint the environment once the root implementation is known, and
+/// enter the reduced modes of the known-bad combinations it matches.
+pub fn setup() -> Fingerprint {
+    let fingerprint = Fingerprint::collect();
+    info!("Environment: {}", fingerprint);
+    let guards = fingerprint.guards();
+    for guard in &guards {
+        warn!(
+            "Known incompatibility [{}] {}, running with {:?}",
+            guard.issue.code, guard.issue.text, guard.reduction
+        );
+    }
+    GUARDS.init(guards);
+    fingerprint
+}
+
+/// Compatibility table entries in effect, empty before `setup`.
+pub fn guards() -> &'static [&'static CompatGuard] {
+    if GUARDS.initiated() { &GUARDS } else { &[] }
+}
+
+pub fn reduced(reduction: Reduction) -> bool {
+    guards().iter().any(|guard| guard.reduction == reduction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn on_sdk(sdk: u32) -> Fingerprint {
+        Fingerprint {
+            sdk,
+            release: String::new(),
+            vendor: String::new(),
+            kernel: String::new(),
+            root: RootImpl::KernelSU,
+            root_version: None,
+            roms: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn guards_match_unsupported_sdks() {
+        let codes = |sdk| {
+            on_sdk(sdk)
+                .guards()
+                .iter()
+                .map(|g| g.issue.code)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(codes(25), ["NZ-C001"]);
+        assert!(codes(26).is_empty());
+        // Unreadable, rather than old
+        assert!(codes(0).is_empty());
+        let reductions: Vec<_> = on_sdk(25).guards().iter().map(|g| g.reduction).collect();
+        assert_eq!(reductions, [Reduction::NoModules]);
+    }
+}
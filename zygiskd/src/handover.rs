@@ -0,0 +1,236 @@
+use crate::constants::{DaemonSocketAction, PATH_MODULES_UPDATE_DIR, ZKSU_VERSION};
+use crate::utils::UnixStreamExt;
+use crate::{dryrun, lp_select, root_impl, sockdir};
+use anyhow::{Result, anyhow, bail};
+use log::info;
+use rustix::fs::{FdFlags, fcntl_getfd, fcntl_setfd};
+use std::fs;
+use std::io::{Read, Seek, Write};
+use std::os::fd::{BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// A daemon update is applied by the active daemon exec-ing the new binary in
+// place: the pid tracked by the monitor stays the same, and the listener and
+// state are inherited as descriptors described by the state file, whose fd is
+// named by this variable.
+const HANDOVER_ENV: &str = "ZYGISKD_HANDOVER";
+
+#[derive(Debug, PartialEq)]
+pub struct ModuleState {
+    pub name: String,
+    pub lib_fd: RawFd,
+    pub companion_fd: Option<RawFd>,
+    pub companion_pid: Option<i32>,
+}
+
+/// Everything a new daemon needs to keep serving the zygote of the old one.
+#[derive(Debug, Default, PartialEq)]
+pub struct State {
+    pub version: String,
+    pub listener_fd: RawFd,
+    pub tmpdir: Option<PathBuf>,
+    pub first_process_seen: bool,
+    pub disabled: bool,
+    pub modules: Vec<ModuleState>,
+    // (namespace type, hide strategy, fd)
+    pub namespaces: Vec<(u8, String, RawFd)>,
+}
+
+impl State {
+    fn fds(&self) -> Vec<RawFd> {
+        let mut fds = vec![self.listener_fd];
+        for module in &self.modules {
+            fds.push(module.lib_fd);
+            fds.extend(module.companion_fd);
+        }
+        fds.extend(self.namespaces.iter().map(|(_, _, fd)| *fd));
+        fds
+    }
+
+    pub fn encode(&self) -> String {
+        let mut out = format!(
+            "version\t{}\nlistener\t{}\nfirst_process_seen\t{}\ndisabled\t{}\n",
+            self.version, self.listener_fd, self.first_process_seen as u8, self.disabled as u8
+        );
+        if let Some(dir) = &self.tmpdir {
+            out.push_str(&format!("tmpdir\t{}\n", dir.display()));
+        }
+        for module in &self.modules {
+            out.push_str(&format!(
+                "module\t{}\t{}\t{}\t{}\n",
+                module.name,
+                module.lib_fd,
+                module.companion_fd.unwrap_or(-1),
+                module.companion_pid.unwrap_or(-1)
+            ));
+        }
+        for (namespace_type, strategy, fd) in &self.namespaces {
+            out.push_str(&format!(
+                "namespace\t{}\t{}\t{}\n",
+                namespace_type, strategy, fd
+            ));
+        }
+        out
+    }
+
+    pub fn decode(content: &str) -> Result<State> {
+        let mut state = State::default();
+        let fd = |s: &str| {
+            s.parse::<RawFd>()
+                .map_err(|_| anyhow!("invalid fd `{}`", s))
+        };
+        for line in content.lines() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields.as_slice() {
+                ["version", version] => state.version = version.to_string(),
+                ["listener", listener] => state.listener_fd = fd(listener)?,
+                ["first_process_seen", seen] => state.first_process_seen = *seen == "1",
+                ["disabled", disabled] => state.disabled = *disabled == "1",
+                ["tmpdir", dir] => state.tmpdir = Some(PathBuf::from(dir)),
+                // Daemons before companion pids were handed over leave them out
+                ["module", name, lib_fd, companion_fd, companion_pid @ ..]
+                    if companion_pid.len() <= 1 =>
+                {
+                    state.modules.push(ModuleState {
+                        name: name.to_string(),
+                        lib_fd: fd(lib_fd)?,
+                        companion_fd: Some(fd(companion_fd)?).filter(|fd| *fd >= 0),
+                        companion_pid: match companion_pid {
+                            [pid] => Some(pid.parse::<i32>()?).filter(|pid| *pid > 0),
+                            _ => None,
+                        },
+                    })
+                }
+                ["namespace", namespace_type, strategy, ns_fd] => state.namespaces.push((
+                    namespace_type.parse()?,
+                    strategy.to_string(),
+                    fd(ns_fd)?,
+                )),
+                _ => bail!("invalid handover record `{}`", line),
+            }
+        }
+        Ok(state)
+    }
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> Result<()> {
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    let mut flags = fcntl_getfd(fd)?;
+    flags.set(FdFlags::CLOEXEC, cloexec);
+    fcntl_setfd(fd, flags)?;
+    Ok(())
+}
+
+const DAEMON_BINARY: &str = lp_select!("zygiskd32", "zygiskd64");
+
+/// Check that `exe` is the daemon binary of this module, either the installed
+/// one or the one of a pending update, returning its canonical path and the
+/// version it reports.
+pub fn verify(exe: &str) -> Result<(PathBuf, String)> {
+    let exe = fs::canonicalize(exe).map_err(|e| anyhow!("{}: {}", exe, e))?;
+    // The working directory of the daemon is the module directory
+    let module_dir = std::env::current_dir()?;
+    let id = module_dir
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is no module directory", module_dir.display()))?;
+    let allowed = [
+        module_dir.join("bin").join(DAEMON_BINARY),
+        Path::new(PATH_MODULES_UPDATE_DIR)
+            .join(id)
+            .join("bin")
+            .join(DAEMON_BINARY),
+    ];
+    if !allowed
+        .iter()
+        .any(|path| fs::canonicalize(path).is_ok_and(|path| path == exe))
+    {
+        bail!("{} is not a daemon binary of this module", exe.display());
+    }
+    let output = Command::new(&exe).arg("version").output()?;
+    let version = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .strip_prefix("NeoZygisk daemon ")
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("{} reports no version", exe.display()))?;
+    Ok((exe, version))
+}
+
+/// Replace the current daemon with the binary at `exe`, handing `state` over.
+///
+/// Only returns if the new binary could not be executed, the daemon then keeps running.
+pub fn exec(exe: &Path, state: &State) -> Result<()> {
+    let memfd = memfd::MemfdOptions::default().create("zygiskd-handover")?;
+    memfd.as_file().write_all(state.encode().as_bytes())?;
+    memfd.as_file().rewind()?;
+    let mut inherited = state.fds();
+    inherited.push(std::os::fd::AsRawFd::as_raw_fd(memfd.as_file()));
+    for fd in &inherited {
+        set_cloexec(*fd, false)?;
+    }
+
+    info!("Handing over to {}", exe.display());
+    let process = std::env::args().next().unwrap_or_default();
+    let error = Command::new(exe)
+        .arg0(process)
+        .env(HANDOVER_ENV, inherited.last().unwrap().to_string())
+        .exec();
+
+    for fd in &inherited {
+        let _ = set_cloexec(*fd, true);
+    }
+    bail!("failed to execute {}: {}", exe.display(), error)
+}
+
+/// State handed over by the previous daemon, if this daemon was started by `exec`.
+pub fn take() -> Result<Option<State>> {
+    let Ok(fd) = std::env::var(HANDOVER_ENV) else {
+        return Ok(None);
+    };
+    unsafe { std::env::remove_var(HANDOVER_ENV) };
+    let fd: RawFd = fd.parse()?;
+    let mut file = unsafe { fs::File::from_raw_fd(fd) };
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    let state = State::decode(&content)?;
+    for fd in state.fds() {
+        set_cloexec(fd, true)?;
+    }
+    Ok(Some(state))
+}
+
+/// Take ownership of an inherited descriptor.
+pub fn own(fd: RawFd) -> OwnedFd {
+    unsafe { OwnedFd::from_raw_fd(fd) }
+}
+
+// Run by the new binary in standby: check it can work on this device before
+// asking the active daemon of the same architecture to hand over.
+pub fn standby() -> Result<()> {
+    root_impl::setup();
+    match root_impl::get_impl() {
+        root_impl::RootImpl::APatch
+        | root_impl::RootImpl::KernelSU
+        | root_impl::RootImpl::Magisk => {}
+        _ => bail!("Invalid root implementation: {:?}", root_impl::get_impl()),
+    }
+    let exe = fs::canonicalize("/proc/self/exe")?;
+    let tmp_path = std::env::var("TMP_PATH")
+        .map_err(|_| anyhow!("TMP_PATH is not set, run through zygisk-ctl"))?;
+    let socket = sockdir::lookup(Path::new(&tmp_path), sockdir::ARCH)?;
+    if dryrun::skip(|| format!("ask the daemon at {} to hand over", socket.display())) {
+        return Ok(());
+    }
+    let mut stream = std::os::unix::net::UnixStream::connect(&socket)
+        .map_err(|e| anyhow!("no active daemon at {}: {}", socket.display(), e))?;
+    stream.write_u8(DaemonSocketAction::Handover as u8)?;
+    stream.write_string(&exe.to_string_lossy())?;
+    match stream.read_u8()? {
+        1 => {
+            println!("Active daemon is handing over to {}", ZKSU_VERSION);
+            Ok(())
+        }
+        _ => bail!("active daemon refused to hand over"),
+    }
+}
@@ -0,0 +1,252 @@
+use crate::{config, packages, users};
+use anyhow::{Result, bail};
+use log::{debug, error, warn};
+use procfs::process::MountInfos;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr};
+use std::io::Error;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+/// Hides a set of mount points of the root solution in the current mount namespace.
+///
+/// Which mount points must be hidden is decided elsewhere; `targets` returns them
+/// innermost first and may be called again to observe the effect of a pass.
+pub trait HideStrategy: Sync {
+    /// Name selecting this strategy in config.prop
+    fn name(&self) -> &'static str;
+
+    fn hide(&self, targets: &dyn Fn() -> Result<Vec<CString>>) -> Result<()>;
+}
+
+// Lazily detach every target once, the historical behavior
+struct DetachPerPath;
+
+// Detach targets repeatedly until the mount table is clean, for mounts stacked
+// on the same path or revealed by propagation once their parent is gone
+struct RebuildNamespace;
+
+// Cover targets with what lies beneath them instead of detaching them, for
+// ROMs where detaching busy mount points breaks the namespace
+struct OverlayShadow;
+
+const MAX_REBUILD_PASSES: usize = 8;
+
+// Syscall numbers shared by every architecture since Linux 5.2, older kernels
+// have no detached mounts and targets are then detached
+const SYS_OPEN_TREE: libc::c_long = 428;
+const SYS_MOVE_MOUNT: libc::c_long = 429;
+const OPEN_TREE_CLONE: libc::c_uint = 1;
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 4;
+
+// umount2 calls issued by this process, for the measurement mode
+static DETACH_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+static OVERRIDE_APP_IDS: Mutex<Option<(Option<SystemTime>, HashMap<String, u32>)>> =
+    Mutex::new(None);
+
+pub const STRATEGIES: &[&dyn HideStrategy] = &[&DetachPerPath, &RebuildNamespace, &OverlayShadow];
+
+fn detach(path: &CStr) -> bool {
+    DETACH_CALLS.fetch_add(1, Ordering::Relaxed);
+    if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } == -1 {
+        error!(
+            "failed to to unmount {:?}: {}",
+            path,
+            Error::last_os_error()
+        );
+        false
+    } else {
+        debug!("Unmounted {:?}", path);
+        true
+    }
+}
+
+impl HideStrategy for DetachPerPath {
+    fn name(&self) -> &'static str {
+        "detach-per-path"
+    }
+
+    fn hide(&self, targets: &dyn Fn() -> Result<Vec<CString>>) -> Result<()> {
+        for path in targets()? {
+            detach(&path);
+        }
+        Ok(())
+    }
+}
+
+impl HideStrategy for RebuildNamespace {
+    fn name(&self) -> &'static str {
+        "rebuild-namespace"
+    }
+
+    fn hide(&self, targets: &dyn Fn() -> Result<Vec<CString>>) -> Result<()> {
+        for pass in 1..=MAX_REBUILD_PASSES {
+            let paths = targets()?;
+            if paths.is_empty() {
+                debug!("Mount namespace clean after {} passes", pass - 1);
+                return Ok(());
+            }
+            // Stop once a pass cannot make any progress
+            if !paths
+                .iter()
+                .fold(false, |detached, path| detach(path) | detached)
+            {
+                break;
+            }
+        }
+        warn!("Mount namespace still has {} traces", targets()?.len());
+        Ok(())
+    }
+}
+
+impl HideStrategy for OverlayShadow {
+    fn name(&self) -> &'static str {
+        "overlay-shadow"
+    }
+
+    fn hide(&self, targets: &dyn Fn() -> Result<Vec<CString>>) -> Result<()> {
+        let paths = targets()?;
+        let content = std::fs::read("/proc/self/mountinfo")?;
+        let mounts = MountInfos::from_buf_read(content.as_slice())?;
+        for path in paths {
+            let mount_point = Path::new(OsStr::from_bytes(path.to_bytes()));
+            // The topmost mount at a path comes last
+            let parent = mounts
+                .iter()
+                .rfind(|info| info.mount_point == mount_point)
+                .and_then(|info| mounts.iter().find(|parent| parent.mnt_id == info.pid))
+                .map(|parent| parent.mount_point.as_path());
+            match parent {
+                // Mounts stacked on the same path cover their parent entirely
+                Some(parent) if parent != mount_point => match shadow(&path, parent) {
+                    Ok(()) => debug!("Shadowed {:?}", path),
+                    Err(e) => {
+                        debug!("Cannot shadow {:?}, detaching it: {}", path, e);
+                        detach(&path);
+                    }
+                },
+                _ => {
+                    detach(&path);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Detached copy of the mount at `path`, without the mounts stacked inside it
+fn clone_tree(dirfd: RawFd, path: &CStr) -> Result<OwnedFd> {
+    let flags = OPEN_TREE_CLONE | libc::O_CLOEXEC as libc::c_uint;
+    let fd = unsafe { libc::syscall(SYS_OPEN_TREE, dirfd, path.as_ptr(), flags) };
+    if fd < 0 {
+        bail!("open_tree {:?}: {}", path, Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+// Cover `path` with the content its parent mount has at that path, keeping
+// what the root solution mounted there out of sight without detaching it
+fn shadow(path: &CStr, parent: &Path) -> Result<()> {
+    let relative = Path::new(OsStr::from_bytes(path.to_bytes())).strip_prefix(parent)?;
+    let relative = match relative.as_os_str().as_bytes() {
+        b"" => c".".to_owned(),
+        bytes => CString::new(bytes)?,
+    };
+    let tree = clone_tree(
+        libc::AT_FDCWD,
+        &CString::new(parent.as_os_str().as_bytes())?,
+    )?;
+    let beneath = clone_tree(tree.as_raw_fd(), &relative)?;
+    let moved = unsafe {
+        libc::syscall(
+            SYS_MOVE_MOUNT,
+            beneath.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    };
+    if moved == -1 {
+        bail!("move_mount: {}", Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Number of umount2 calls issued so far.
+pub fn detach_calls() -> usize {
+    DETACH_CALLS.load(Ordering::Relaxed)
+}
+
+pub fn find(name: &str) -> Option<&'static dyn HideStrategy> {
+    STRATEGIES.iter().copied().find(|s| s.name() == name)
+}
+
+fn configured(name: &str) -> &'static dyn HideStrategy {
+    // Names are validated when loading the config
+    find(name).unwrap_or(STRATEGIES[0])
+}
+
+/// Strategy for the app process of `uid`, `None` meaning zygote itself.
+pub fn for_uid(uid: Option<u32>) -> &'static dyn HideStrategy {
+    let config = config::get();
+    // Same app in any user
+    let overridden = uid.and_then(|uid| {
+        let app_ids = override_app_ids();
+        config
+            .hide_strategy_overrides
+            .iter()
+            .find(|(package, _)| app_ids.get(package) == Some(&users::app_id(uid)))
+    });
+    match overridden {
+        Some((_, name)) => configured(name),
+        None => configured(&config.hide_strategy),
+    }
+}
+
+// App ids of the packages with an override, resolved again only once the
+// package manager database changed rather than at every fork
+fn override_app_ids() -> HashMap<String, u32> {
+    let overrides = &config::get().hide_strategy_overrides;
+    if overrides.is_empty() {
+        return HashMap::new();
+    }
+    let modified = packages::modified();
+    let mut cache = OVERRIDE_APP_IDS.lock().unwrap();
+    match &*cache {
+        Some((at, app_ids)) if modified.is_some() && *at == modified => app_ids.clone(),
+        _ => {
+            let mut app_ids = packages::app_ids().unwrap_or_else(|e| {
+                debug!(
+                    "Cannot resolve packages with a hide strategy override: {}",
+                    e
+                );
+                HashMap::new()
+            });
+            app_ids.retain(|package, _| overrides.iter().any(|(p, _)| p == package));
+            *cache = Some((modified, app_ids.clone()));
+            app_ids
+        }
+    }
+}
+
+/// Every strategy selected by the config, whose namespaces must be prepared.
+pub fn in_use() -> Vec<&'static dyn HideStrategy> {
+    let config = config::get();
+    STRATEGIES
+        .iter()
+        .copied()
+        .filter(|s| {
+            s.name() == config.hide_strategy
+                || config
+                    .hide_strategy_overrides
+                    .iter()
+                    .any(|(_, n)| n == s.name())
+        })
+        .collect()
+}
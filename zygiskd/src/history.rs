@@ -0,0 +1,106 @@
+use crate::ring::Layout;
+use crate::store::{self, Table};
+use crate::writer;
+use crate::{config, packages, zygiskd};
+use anyhow::{Result, bail};
+use log::warn;
+use num_enum::TryFromPrimitive;
+use std::time::Duration;
+
+// The last injections of every package, recorded per package so that a
+// chatty app cannot push the others out.
+static TABLE: Table = Table {
+    name: "history",
+    layout: Layout {
+        magic: b"NZIH",
+        slots: 16,
+        slot_size: 256,
+    },
+};
+
+/// Outcome of the mount namespace switch of an app, as reported by the injector.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum UnmountResult {
+    /// The first app process, which keeps the namespace of zygote
+    Skipped,
+    /// Switched to the Clean namespace
+    Unmounted,
+    /// No namespace could be switched to
+    Failed,
+    /// Switched to the Root namespace, as an app granted root or the manager
+    Root,
+    /// Switched to the Module namespace, keeping the mounts of modules only
+    Module,
+}
+
+pub struct Injection<'a> {
+    pub process: &'a str,
+    pub uid: u32,
+    pub modules: Vec<&'a str>,
+    pub unmount: UnmountResult,
+    pub duration: Duration,
+}
+
+// Processes like `com.example:remote` are recorded under their package
+fn package_of(process: &str) -> &str {
+    process.split(':').next().unwrap_or(process)
+}
+
+pub fn record(injection: &Injection) {
+    if config::minimal() {
+        return;
+    }
+    let record = || -> Result<()> {
+        let package = package_of(injection.process);
+        packages::check_name(package)?;
+        let line = format!(
+            "{} {} uid={} modules={} unmount={:?} us={}",
+            writer::timestamp(),
+            injection.process,
+            injection.uid,
+            if injection.modules.is_empty() {
+                "none".to_string()
+            } else {
+                injection.modules.join(",")
+            },
+            injection.unmount,
+            injection.duration.as_micros()
+        );
+        store::get().push(&TABLE, package, &line)
+    };
+    if let Err(e) = record() {
+        warn!("Failed to record injection of {}: {}", injection.process, e);
+    }
+}
+
+/// Injections of `package`, oldest first.
+pub fn get(package: &str) -> Result<Vec<String>> {
+    packages::check_name(package)?;
+    match store::get().records(&TABLE, package)? {
+        Some(injections) => Ok(injections),
+        None => bail!("no injection of {} recorded", package),
+    }
+}
+
+/// Packages with a recorded history.
+pub fn packages() -> Result<Vec<String>> {
+    store::get().keys(&TABLE)
+}
+
+/// Injections of `package`, or the packages with a history if empty.
+pub fn read(package: &str) -> Result<Vec<String>> {
+    match package {
+        "" => packages(),
+        package => get(package),
+    }
+}
+
+/// Like `read`, from a command run from a shell: records kept in memory
+/// are only known to the daemons, which are asked for them then.
+pub fn query(package: &str) -> Result<Vec<String>> {
+    if store::get().name() == "memory" {
+        return zygiskd::request_history(package);
+    }
+    read(package)
+}
@@ -0,0 +1,211 @@
+use crate::subsystem::Subsystem;
+use crate::{config, sockdir, utils};
+use anyhow::Result;
+use log::{Level, info, log, warn};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Logs forwarded from injected processes travel on their own datagram socket,
+// so a flood of diagnostics can never delay requests on the control socket.
+// Senders never block: they drop records when the socket buffer is full and
+// report how many were lost with their next record.
+
+const QUOTA_WINDOW: Duration = Duration::from_secs(10);
+const QUOTA_RECORDS: u32 = 64;
+const MAX_RECORD_SIZE: usize = 1024;
+const HEADER_SIZE: usize = 9;
+// Senders tracked before expired quotas are pruned
+const MAX_QUOTAS: usize = 256;
+const MINIMAL_MAX_QUOTAS: usize = 32;
+// How long a stopped receiver may keep its socket
+const STOP_LATENCY: Duration = Duration::from_secs(1);
+
+struct Quota {
+    window_start: Instant,
+    accepted: u32,
+    dropped: u64,
+}
+
+// Bumped on every stop, ending the receiver of the previous start
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static SOCKET_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+pub struct LogForwarding;
+
+pub static SUBSYSTEM: LogForwarding = LogForwarding;
+
+impl Subsystem for LogForwarding {
+    fn name(&self) -> &'static str {
+        "logfwd"
+    }
+
+    fn start(&self) -> Result<()> {
+        let tmp_path = std::env::var("TMP_PATH")?;
+        let path = sockdir::unpublished(Path::new(&tmp_path))?;
+        spawn(bind(&path)?)?;
+        *SOCKET_PATH.lock().unwrap() = Some(path);
+        RUNNING.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Senders lose their records until the socket is bound again
+    fn stop(&self) {
+        GENERATION.fetch_add(1, Ordering::SeqCst);
+        RUNNING.store(false, Ordering::SeqCst);
+        if let Some(path) = SOCKET_PATH.lock().unwrap().take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    fn running(&self) -> bool {
+        RUNNING.load(Ordering::SeqCst)
+    }
+}
+
+fn bind(path: &Path) -> Result<UnixDatagram> {
+    let _ = std::fs::remove_file(path);
+    // Zygote and apps send to it, like to the daemon socket
+    let socket = {
+        let _context = utils::ScopedSockCreateContext::new(utils::ZYGOTE_CONTEXT)?;
+        UnixDatagram::bind(path)?
+    };
+    utils::chcon(&path.to_string_lossy(), "u:object_r:zygisk_file:s0")?;
+    socket.set_read_timeout(Some(STOP_LATENCY))?;
+    Ok(socket)
+}
+
+fn spawn(socket: UnixDatagram) -> Result<()> {
+    let generation = GENERATION.load(Ordering::SeqCst);
+    thread::Builder::new()
+        .name("logfwd".to_string())
+        .spawn(move || {
+            // Diagnostics are the least important work of the daemon
+            unsafe {
+                libc::setpriority(libc::PRIO_PROCESS, 0, 19);
+            }
+            receive_loop(socket, generation)
+        })?;
+    Ok(())
+}
+
+/// Path of the socket, asked for by senders since it is published nowhere.
+pub fn socket_path() -> Option<PathBuf> {
+    SOCKET_PATH.lock().unwrap().clone()
+}
+
+/// Move the socket to a new random name, along with the daemon socket.
+pub fn rotate(tmp_path: &Path) -> Result<()> {
+    let mut current = SOCKET_PATH.lock().unwrap();
+    let Some(old) = current.as_ref() else {
+        return Ok(());
+    };
+    let path = sockdir::unpublished(tmp_path)?;
+    let socket = bind(&path)?;
+    // Ends the receiver of the old socket
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    let _ = std::fs::remove_file(old);
+    *current = Some(path);
+    spawn(socket)
+}
+
+fn receive_loop(socket: UnixDatagram, generation: u64) {
+    let mut quotas: HashMap<u32, Quota> = HashMap::new();
+    let mut buf = [0u8; MAX_RECORD_SIZE];
+    let max_quotas = if config::minimal() {
+        MINIMAL_MAX_QUOTAS
+    } else {
+        MAX_QUOTAS
+    };
+    while GENERATION.load(Ordering::SeqCst) == generation {
+        let size = match socket.recv(&mut buf) {
+            Ok(size) => size,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                warn!("Failed to receive forwarded log: {}", e);
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+        };
+        let Some(record) = parse_record(&buf[..size]) else {
+            continue;
+        };
+        let pid = record.pid;
+        let now = Instant::now();
+        if quotas.len() > max_quotas {
+            quotas.retain(|_, q| now.duration_since(q.window_start) < QUOTA_WINDOW);
+        }
+        let quota = quotas.entry(pid).or_insert(Quota {
+            window_start: now,
+            accepted: 0,
+            dropped: 0,
+        });
+        if now.duration_since(quota.window_start) >= QUOTA_WINDOW {
+            if quota.dropped > 0 {
+                info!("[{}] {} forwarded log records dropped", pid, quota.dropped);
+            }
+            quota.window_start = now;
+            quota.accepted = 0;
+            quota.dropped = 0;
+        }
+        quota.dropped += record.lost as u64;
+        if quota.accepted >= QUOTA_RECORDS {
+            quota.dropped += 1;
+            continue;
+        }
+        quota.accepted += 1;
+
+        let message = String::from_utf8_lossy(record.message);
+        log!(
+            level_of(record.priority),
+            "[{}] {}: {}",
+            pid,
+            String::from_utf8_lossy(record.tag),
+            message.trim_end_matches(['\0', '\n'])
+        );
+    }
+}
+
+pub struct Record<'a> {
+    pub priority: u8,
+    pub pid: u32,
+    pub lost: u32,
+    pub tag: &'a [u8],
+    pub message: &'a [u8],
+}
+
+// Layout: u8 priority, u32 pid, u32 lost records, then `tag\0message`.
+pub fn parse_record(buf: &[u8]) -> Option<Record<'_>> {
+    if buf.len() < HEADER_SIZE {
+        return None;
+    }
+    let payload = &buf[HEADER_SIZE..];
+    let (tag, message) = match payload.iter().position(|&b| b == 0) {
+        Some(n) => (&payload[..n], &payload[n + 1..]),
+        None => (&payload[..0], payload),
+    };
+    Some(Record {
+        priority: buf[0],
+        pid: u32::from_ne_bytes(buf[1..5].try_into().ok()?),
+        lost: u32::from_ne_bytes(buf[5..9].try_into().ok()?),
+        tag,
+        message,
+    })
+}
+
+// Android log priorities, see android/log.h
+fn level_of(priority: u8) -> Level {
+    match priority {
+        0..=2 => Level::Trace,
+        3 => Level::Debug,
+        4 => Level::Info,
+        5 => Level::Warn,
+        _ => Level::Error,
+    }
+}
@@ -1,9 +1,51 @@
+mod adjust;
+mod audit;
+mod blackbox;
+mod cli;
 mod companion;
+mod config;
 mod constants;
+mod coredump;
+mod crash;
 mod dl;
+mod dryrun;
+mod explain;
+mod fingerprint;
+mod handover;
+mod hide;
+mod history;
+mod logfwd;
+mod manifest;
+mod messages;
+mod metrics;
+mod nscheck;
+mod packages;
+mod pidfd;
+mod policy;
+mod prelisten;
+mod privop;
+mod probe;
+mod profile;
+mod props;
+mod protocheck;
+#[cfg(test)]
+mod protocol_tests;
+mod quarantine;
+mod request;
+mod ring;
 mod root_impl;
+mod scripts;
+mod sockdir;
+mod store;
+mod subsystem;
+mod tmpdir;
+mod uring;
+mod users;
 mod utils;
+mod version;
+mod writer;
 mod zygiskd;
+mod zygote;
 
 use crate::constants::ZKSU_VERSION;
 
@@ -15,12 +57,37 @@ fn init_android_logger(tag: &str) {
     );
 }
 
+// Commands run from a shell need the same working directory as the daemon
+// so that relative paths like PATH_MODULES_DIR resolve identically.
+fn enter_module_dir() {
+    let module_dir = std::env::current_exe().ok().and_then(|exe| {
+        exe.parent()
+            .and_then(|bin| bin.parent())
+            .map(|d| d.to_owned())
+    });
+    if let Some(dir) = module_dir {
+        let _ = std::env::set_current_dir(dir);
+    }
+}
+
 fn start() {
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().collect();
+    // Accepted anywhere on the command line, before or after the command
+    if let Some(index) = args.iter().position(|arg| arg == "--dry-run") {
+        args.remove(index);
+        dryrun::enable(true);
+    }
     if args.len() == 3 && args[1] == "companion" {
         let fd: i32 = args[2].parse().unwrap();
         companion::entry(fd);
         return;
+    } else if args.len() == 6 && args[1] == "namespace" {
+        config::setup();
+        if let Err(e) = utils::namespace_helper(&args[2], &args[3], &args[4], &args[5]) {
+            log::error!("Failed to prepare mount namespace: {}", e);
+            std::process::exit(1);
+        }
+        return;
     } else if args.len() == 2 && args[1] == "version" {
         println!("NeoZygisk daemon {}", ZKSU_VERSION);
         return;
@@ -28,6 +95,192 @@ fn start() {
         root_impl::setup();
         println!("root impl: {:?}", root_impl::get_impl());
         return;
+    } else if args.len() == 2 && args[1] == "--describe-commands" {
+        println!("{}", cli::describe_commands());
+        return;
+    } else if args.len() == 3 && args[1] == "completions" {
+        match cli::completions(&args[2]) {
+            Ok(script) => print!("{}", script),
+            Err(e) => {
+                eprintln!("completions: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    } else if args.len() == 2 && args[1] == "blackbox" {
+        match blackbox::dump() {
+            Ok(events) => events.iter().for_each(|e| println!("{}", e)),
+            Err(e) => {
+                eprintln!("blackbox: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    } else if (args.len() == 2 || args.len() == 3) && args[1] == "history" {
+        config::setup();
+        match history::query(args.get(2).map_or("", String::as_str)) {
+            Ok(lines) => lines.iter().for_each(|l| println!("{}", l)),
+            Err(e) => {
+                eprintln!("history: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    } else if args.len() == 2 && args[1] == "messages" {
+        enter_module_dir();
+        config::setup();
+        print!("{}", messages::dump());
+        return;
+    } else if args.len() == 2 && args[1] == "standby" {
+        if let Err(e) = handover::standby() {
+            eprintln!("standby: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    } else if args.len() == 2 && args[1] == "disable-all" {
+        if let Err(e) = zygiskd::request_disable_all() {
+            eprintln!("disable-all: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    } else if args.len() == 2 && args[1] == "rotate-secrets" {
+        if let Err(e) = zygiskd::request_rotate_secrets() {
+            eprintln!("rotate-secrets: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    } else if args.len() == 2 && args[1] == "profile" {
+        match zygiskd::request_profile() {
+            Ok(profiles) => {
+                for (arch, profile) in profiles {
+                    println!("{}:\n{}", arch, profile);
+                }
+            }
+            Err(e) => {
+                eprintln!("profile: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    } else if args.len() == 3 && args[1] == "dump-companion" {
+        if let Err(e) = coredump::main(&args[2]) {
+            eprintln!("dump-companion: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    } else if args.len() >= 2 && args[1] == "script" {
+        let command = match (args.get(3).map(String::as_str), args.len()) {
+            (Some("deny"), 5 | 6) => scripts::Command::DenyStatus,
+            (Some("setprop"), 6) => scripts::Command::SetProperty,
+            (Some("modules"), 4) => scripts::Command::ListModules,
+            (Some("history"), 4 | 5) => scripts::Command::History,
+            _ => {
+                eprintln!(
+                    "script: expected <module> followed by deny <uid|package> [user], \
+                     setprop <name> <value>, modules or history [package]"
+                );
+                std::process::exit(1);
+            }
+        };
+        let arg = |i: usize| args.get(i).map_or("", String::as_str);
+        match zygiskd::request_script(&args[2], command, [arg(4), arg(5)]) {
+            Ok(output) if output.is_empty() => {}
+            Ok(output) => println!("{}", output),
+            Err(e) => {
+                eprintln!("script: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    } else if args.len() == 2 && args[1] == "protocheck" {
+        match protocheck::main() {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("protocheck: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    } else if args.len() == 4 && args[1] == "protocheck" && args[2] == "serve" {
+        if let Err(e) = protocheck::serve(&args[3]) {
+            eprintln!("protocheck: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    } else if (2..=4).contains(&args.len()) && args[1] == "subsystem" {
+        let control = match (args.get(2).map(String::as_str), args.len()) {
+            (None, 2) | (Some("list"), 3) => subsystem::Control::List,
+            (Some("start"), 4) => subsystem::Control::Start,
+            (Some("stop"), 4) => subsystem::Control::Stop,
+            (Some("reload"), 4) => subsystem::Control::Reload,
+            _ => {
+                eprintln!("subsystem: expected list, or start, stop or reload <name>");
+                std::process::exit(1);
+            }
+        };
+        let name = args.get(3).map_or("", String::as_str);
+        match zygiskd::request_subsystem(control, name) {
+            Ok(outputs) => {
+                for (arch, output) in outputs {
+                    if control == subsystem::Control::List {
+                        println!("{}:\n{}", arch, output);
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("subsystem: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    } else if args.len() == 2 && args[1] == "quarantine" {
+        for entry in quarantine::entries() {
+            match entry {
+                quarantine::Entry::Pending { module, crashes } => {
+                    println!("pending: {} ({} crashes)", module, crashes)
+                }
+                quarantine::Entry::Quarantined { module, crashes } => {
+                    println!("quarantined: {} ({} crashes)", module, crashes)
+                }
+            }
+        }
+        return;
+    } else if args.len() == 4 && args[1] == "quarantine" && args[2] == "keep" {
+        if let Err(e) = quarantine::keep(&args[3]) {
+            eprintln!("quarantine: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    } else if (args.len() == 2 || args.len() == 3) && args[1] == "probe" {
+        enter_module_dir();
+        config::setup();
+        let result = match args.get(2).map(|uid| uid.parse::<u32>()) {
+            Some(Ok(uid)) => probe::main(Some(uid)),
+            Some(Err(_)) => Err(anyhow::anyhow!("invalid uid `{}`", args[2])),
+            None => probe::main(None),
+        };
+        if let Err(e) = result {
+            eprintln!("probe: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    } else if (3..=4).contains(&args.len()) && args[1] == "explain" {
+        enter_module_dir();
+        config::setup();
+        root_impl::setup();
+        if let Err(e) = explain::main(&args[2], args.get(3).map(String::as_str)) {
+            eprintln!("explain: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Only the monitor starts the daemon, a mistyped command must not start a
+    // second one taking over the socket
+    if args.len() > 1 {
+        eprint!("{}", cli::usage());
+        std::process::exit(1);
     }
 
     utils::switch_mount_namespace(1).expect("switch mnt ns");
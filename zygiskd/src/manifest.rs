@@ -0,0 +1,195 @@
+use crate::constants::{MANIFEST_SCHEMA_VERSION, MAX_METADATA_SIZE, ZYGISK_API_VERSION};
+use crate::version::Version;
+use anyhow::{Result, bail};
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+
+// Keys understood by root managers in module.prop; anything else is reported
+// as a warning only, since managers keep adding their own.
+const MODULE_PROP_KEYS: &[&str] = &[
+    "id",
+    "name",
+    "version",
+    "versionCode",
+    "author",
+    "description",
+    "updateJson",
+    "support",
+    "donate",
+    "banner",
+];
+
+const MANIFEST_KEYS: &[&str] = &["schema", "minApi", "propertyOverlay"];
+
+#[derive(Debug, Default)]
+pub struct ModuleProp {
+    pub id: Option<String>,
+    pub version: Option<String>,
+    pub version_code: Option<i64>,
+}
+
+/// NeoZygisk specific metadata, read from `zygisk/manifest.prop`. Its schema
+/// and minimal API are only checked while loading, refusing the module if
+/// they cannot be honored.
+#[derive(Debug)]
+pub struct Manifest {
+    /// Whether the overlays of `zygisk/props` are applied. Apps getting one
+    /// keep libzygisk.so mapped for their whole life, as the property hooks
+    /// point into it, a trace left only for modules asking for it.
+    pub property_overlay: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Metadata {
+    pub prop: Option<ModuleProp>,
+    pub manifest: Option<Manifest>,
+    /// Problems which do not prevent the module from loading.
+    pub issues: Vec<String>,
+}
+
+/// Read the metadata of the module at `dir`.
+///
+/// An error means the module declared requirements we cannot honor and must not be loaded.
+pub fn load(dir: &Path, name: &str) -> Result<Metadata> {
+    let mut metadata = Metadata::default();
+
+    match read_bounded(&dir.join("module.prop")) {
+        Ok(Some(content)) => {
+            let prop = parse_module_prop(&content, name, &mut metadata.issues);
+            metadata.prop = Some(prop);
+        }
+        Ok(None) => metadata.issues.push("module.prop is missing".to_string()),
+        Err(e) => metadata.issues.push(format!("module.prop: {}", e)),
+    }
+
+    if let Some(content) = read_bounded(&dir.join("zygisk/manifest.prop"))? {
+        let manifest = parse_manifest(&content, &mut metadata.issues)?;
+        metadata.manifest = Some(manifest);
+    }
+
+    Ok(metadata)
+}
+
+pub fn read_bounded(path: &Path) -> Result<Option<String>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let size = file.metadata()?.len();
+    if size > MAX_METADATA_SIZE {
+        bail!("size {} exceeds limit of {} bytes", size, MAX_METADATA_SIZE);
+    }
+    let mut content = String::new();
+    file.take(MAX_METADATA_SIZE).read_to_string(&mut content)?;
+    Ok(Some(content))
+}
+
+pub fn parse_entries<'a>(
+    content: &'a str,
+    file: &str,
+    known_keys: &[&str],
+    issues: &mut Vec<String>,
+) -> Vec<(&'a str, &'a str)> {
+    let mut entries: Vec<(&str, &str)> = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => (key.trim(), value.trim()),
+            _ => {
+                issues.push(format!("{}:{}: expected `key=value`", file, number + 1));
+                continue;
+            }
+        };
+        // Known keys ending with `.` accept any suffix, e.g. per package settings
+        let known = known_keys
+            .iter()
+            .any(|k| *k == key || (k.ends_with('.') && key.starts_with(k)));
+        if !known {
+            issues.push(format!("{}:{}: unknown key `{}`", file, number + 1, key));
+        }
+        if let Some(entry) = entries.iter_mut().find(|(k, _)| *k == key) {
+            issues.push(format!("{}:{}: duplicated key `{}`", file, number + 1, key));
+            entry.1 = value;
+        } else {
+            entries.push((key, value));
+        }
+    }
+    entries
+}
+
+fn parse_module_prop(content: &str, name: &str, issues: &mut Vec<String>) -> ModuleProp {
+    let mut prop = ModuleProp::default();
+    for (key, value) in parse_entries(content, "module.prop", MODULE_PROP_KEYS, issues) {
+        match key {
+            "id" => prop.id = Some(value.to_string()),
+            "version" => prop.version = Some(value.to_string()),
+            "versionCode" => match value.parse::<i64>() {
+                Ok(code) => prop.version_code = Some(code),
+                Err(_) => issues.push(format!("module.prop: invalid versionCode `{}`", value)),
+            },
+            _ => {}
+        }
+    }
+    match prop.id.as_deref() {
+        None => issues.push("module.prop: missing id".to_string()),
+        Some(id) if id != name => issues.push(format!(
+            "module.prop: id `{}` does not match directory `{}`",
+            id, name
+        )),
+        _ => {}
+    }
+    prop
+}
+
+fn parse_manifest(content: &str, issues: &mut Vec<String>) -> Result<Manifest> {
+    let mut schema = None;
+    let mut min_api = None;
+    let mut property_overlay = false;
+    for (key, value) in parse_entries(content, "manifest.prop", MANIFEST_KEYS, issues) {
+        match key {
+            "schema" => match value.parse::<u32>() {
+                Ok(v) => schema = Some(v),
+                Err(_) => bail!("manifest.prop: invalid schema `{}`", value),
+            },
+            "minApi" => match value.parse::<Version>() {
+                Ok(v) => min_api = Some(v),
+                Err(_) => bail!("manifest.prop: invalid minApi `{}`", value),
+            },
+            "propertyOverlay" => match value {
+                "true" | "1" => property_overlay = true,
+                "false" | "0" => property_overlay = false,
+                _ => issues.push(format!(
+                    "manifest.prop: invalid propertyOverlay `{}`",
+                    value
+                )),
+            },
+            _ => {}
+        }
+    }
+    let schema = match schema {
+        Some(schema) => schema,
+        None => bail!("manifest.prop: missing schema"),
+    };
+    if schema == 0 || schema > MANIFEST_SCHEMA_VERSION {
+        bail!(
+            "manifest.prop: unsupported schema {} (supported up to {})",
+            schema,
+            MANIFEST_SCHEMA_VERSION
+        );
+    }
+    if let Some(api) = &min_api {
+        if !Version::from(ZYGISK_API_VERSION).at_least(api) {
+            bail!(
+                "manifest.prop: requires Zygisk API {} but only {} is available",
+                api,
+                ZYGISK_API_VERSION
+            );
+        }
+    }
+    Ok(Manifest { property_overlay })
+}
@@ -0,0 +1,197 @@
+use crate::constants::{COMPAT_GUARDS, PATH_DATA_DIR, ROOT_ADVISORIES};
+use crate::{config, manifest, utils};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// A user facing string with its English default.
+///
+/// `code` never changes once released, so that the manager and scripts can
+/// match on it whatever the language of `text`.
+pub struct Message {
+    pub code: &'static str,
+    pub text: &'static str,
+}
+
+pub const ROOT: Message = Message {
+    code: "NZ-S001",
+    text: "Root: {}",
+};
+pub const MODULES: Message = Message {
+    code: "NZ-S002",
+    text: "Module({}):",
+};
+pub const ADVISORY: Message = Message {
+    code: "NZ-S003",
+    text: "Advisory: {}",
+};
+pub const SKIPPED_MODULES: Message = Message {
+    code: "NZ-S004",
+    text: "Skipped({}):",
+};
+pub const DISABLED_MODULE: Message = Message {
+    code: "NZ-S005",
+    text: "{} (disabled)",
+};
+pub const REMOVED_MODULE: Message = Message {
+    code: "NZ-S006",
+    text: "{} (removed at next boot)",
+};
+pub const REDUCED: Message = Message {
+    code: "NZ-S007",
+    text: "Reduced functionality: {}",
+};
+pub const QUARANTINE_PENDING: Message = Message {
+    code: "NZ-S008",
+    text: "Quarantine proposed: {} crashed apps {} times, disable or `zygiskd quarantine keep {}`",
+};
+pub const QUARANTINED: Message = Message {
+    code: "NZ-S009",
+    text: "Quarantined: {} crashed apps {} times and stays disabled from the next boot",
+};
+pub const DAEMON_CRASHED: Message = Message {
+    code: "NZ-S010",
+    text: "Restarted after a crash: {}",
+};
+pub const DEGRADED: Message = Message {
+    code: "NZ-S011",
+    text: "Started without {}, retried in the background: {}",
+};
+pub const INVALID_ROOT: Message = Message {
+    code: "NZ-E001",
+    text: "Invalid root implementation: {}",
+};
+
+const STATUS: &[&Message] = &[
+    &ROOT,
+    &MODULES,
+    &ADVISORY,
+    &SKIPPED_MODULES,
+    &DISABLED_MODULE,
+    &REMOVED_MODULE,
+    &REDUCED,
+    &QUARANTINE_PENDING,
+    &QUARANTINED,
+    &DAEMON_CRASHED,
+    &DEGRADED,
+    &INVALID_ROOT,
+];
+
+/// Every message of the daemon, including root advisories and compatibility guards.
+pub fn catalog() -> Vec<&'static Message> {
+    let advisories = ROOT_ADVISORIES.iter().map(|a| &a.issue);
+    let guards = COMPAT_GUARDS.iter().map(|g| &g.issue);
+    STATUS
+        .iter()
+        .copied()
+        .chain(advisories)
+        .chain(guards)
+        .collect()
+}
+
+static TRANSLATIONS: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+// Most specific first, e.g. `zh-CN` then `zh`
+fn locale_tags() -> Vec<String> {
+    let tag = config::get()
+        .locale
+        .clone()
+        .or_else(|| utils::get_property("persist.sys.locale").ok())
+        .or_else(|| utils::get_property("ro.product.locale").ok())
+        .filter(|tag| !tag.is_empty());
+    let Some(tag) = tag else {
+        return Vec::new();
+    };
+    let mut tags = vec![tag.clone()];
+    if let Some((language, _)) = tag.split_once(['-', '_']) {
+        tags.push(language.to_string());
+    }
+    tags
+}
+
+// Locale files are `code=text` lines, in the data directory to let users
+// override the ones shipped in the module directory
+fn load() -> HashMap<String, String> {
+    let mut translations = HashMap::new();
+    let codes: Vec<&str> = catalog().iter().map(|m| m.code).collect();
+    let dirs = [
+        PathBuf::from(PATH_DATA_DIR).join("locale"),
+        PathBuf::from("locale"),
+    ];
+    for tag in locale_tags() {
+        if tag.contains('/') || tag.starts_with('.') {
+            continue;
+        }
+        for dir in &dirs {
+            let name = format!("{}.prop", tag);
+            let content = match manifest::read_bounded(&dir.join(&name)) {
+                Ok(Some(content)) => content,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Ignoring {}: {}", dir.join(&name).display(), e);
+                    continue;
+                }
+            };
+            debug!("Loading messages from {}", dir.join(&name).display());
+            let mut issues = Vec::new();
+            for (code, text) in manifest::parse_entries(&content, &name, &codes, &mut issues) {
+                let Some(message) = catalog().into_iter().find(|m| m.code == code) else {
+                    continue;
+                };
+                // A translation missing a placeholder would drop information
+                if text.matches("{}").count() != message.text.matches("{}").count() {
+                    issues.push(format!("{}: placeholders of {} do not match", name, code));
+                    continue;
+                }
+                translations
+                    .entry(code.to_string())
+                    .or_insert_with(|| text.to_string());
+            }
+            for issue in issues {
+                warn!("{}", issue);
+            }
+        }
+    }
+    translations
+}
+
+/// `message` in the language of the device, English if it is not translated.
+pub fn text(message: &Message) -> String {
+    match TRANSLATIONS.get_or_init(load).get(message.code) {
+        Some(text) => text.clone(),
+        None => message.text.to_string(),
+    }
+}
+
+/// Localized `message` with its placeholders replaced by `args` in order.
+pub fn format(message: &Message, args: &[&dyn Display]) -> String {
+    let text = text(message);
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut parts = text.split("{}").peekable();
+    while let Some(part) = parts.next() {
+        out.push_str(part);
+        if parts.peek().is_some() {
+            if let Some(arg) = args.next() {
+                out.push_str(&arg.to_string());
+            }
+        }
+    }
+    out
+}
+
+/// Like `format`, prefixed with the raw code for the manager to recognize the message.
+pub fn coded(message: &Message, args: &[&dyn Display]) -> String {
+    format!("[{}] {}", message.code, format(message, args))
+}
+
+/// The catalog in the format of a locale file, translated where possible.
+pub fn dump() -> String {
+    let mut out = String::new();
+    for message in catalog() {
+        out.push_str(&format!("{}={}\n", message.code, text(message)));
+    }
+    out
+}
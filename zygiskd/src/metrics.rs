@@ -0,0 +1,96 @@
+use crate::config;
+use crate::constants::MountNamespace;
+use crate::hide::HideStrategy;
+use crate::subsystem::Subsystem;
+use crate::writer::{self, AsyncWriter};
+use crate::zygote::SpawnPath;
+use anyhow::{Result, anyhow};
+use log::debug;
+use std::sync::RwLock;
+use std::time::Duration;
+
+// Timing of daemon requests on the fork path, one record per request.
+static METRICS: RwLock<Option<AsyncWriter>> = RwLock::new(None);
+
+pub struct Metrics;
+
+pub static SUBSYSTEM: Metrics = Metrics;
+
+impl Subsystem for Metrics {
+    fn name(&self) -> &'static str {
+        "metrics"
+    }
+
+    fn start(&self) -> Result<()> {
+        if config::minimal() {
+            debug!("No metrics with the minimal runtime profile");
+            return Ok(());
+        }
+        let writer = writer::open_data_file("metrics.log")
+            .ok_or_else(|| anyhow!("metrics.log cannot be opened"))?;
+        *METRICS.write().unwrap() = Some(writer);
+        Ok(())
+    }
+
+    // Records already queued are still written out
+    fn stop(&self) {
+        METRICS.write().unwrap().take();
+    }
+
+    fn running(&self) -> bool {
+        METRICS.read().unwrap().is_some()
+    }
+}
+
+pub fn record_process_flags(uid: i32, path: SpawnPath, elapsed: Duration) {
+    if let Some(writer) = METRICS.read().unwrap().as_ref() {
+        let record = format!(
+            "{} process_flags uid={} path={:?} us={}\n",
+            writer::timestamp(),
+            uid,
+            path,
+            elapsed.as_micros()
+        );
+        writer.write(record.into_bytes());
+    }
+}
+
+// Time taken to prepare a cached mount namespace, recorded in measurement mode
+pub fn record_mount_namespace(
+    namespace: MountNamespace,
+    strategy: &dyn HideStrategy,
+    elapsed: Duration,
+) {
+    if let Some(writer) = METRICS.read().unwrap().as_ref() {
+        let record = format!(
+            "{} mount_namespace type={:?} strategy={} us={}\n",
+            writer::timestamp(),
+            namespace,
+            strategy.name(),
+            elapsed.as_micros()
+        );
+        writer.write(record.into_bytes());
+    }
+}
+
+// Module that an app never acknowledged past its post-specialize phase
+pub fn record_injection_incomplete(module: &str, process: &str, reason: &str) {
+    if let Some(writer) = METRICS.read().unwrap().as_ref() {
+        let record = format!(
+            "{} injection_incomplete module={} process={} reason={}\n",
+            writer::timestamp(),
+            module,
+            process,
+            reason
+        );
+        writer.write(record.into_bytes());
+    }
+}
+
+pub fn dropped() -> u64 {
+    METRICS
+        .read()
+        .unwrap()
+        .as_ref()
+        .map_or(0, |writer| writer.dropped())
+}
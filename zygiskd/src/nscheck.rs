@@ -0,0 +1,172 @@
+use crate::subsystem::Subsystem;
+use crate::{blackbox, config, pidfd, utils};
+use anyhow::Result;
+use log::{debug, info, warn};
+use procfs::process::MountInfos;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// The Clean and Module mount namespaces are copies of the namespace of zygote
+// taken once, so that mounts changed by the system later on (vendor overlays
+// remounted, APEX updated) never reach the apps they are handed to. The
+// mount table of init, which zygote shares, is compared now and then with the
+// one they were copied from, and the copies taken again once they diverge.
+
+// Mounts of the root implementation and its modules, which come and go
+// without the system changing
+const IGNORED_SOURCES: &[&str] = &["APatch", "KSU", "magisk"];
+// Changed mount points named in the log at most
+const LOGGED_CHANGES: usize = 4;
+
+struct Snapshot {
+    zygote: pidfd::Process,
+    zygote_pid: i32,
+    mounts: BTreeSet<String>,
+}
+
+static SNAPSHOT: Mutex<Option<Snapshot>> = Mutex::new(None);
+
+fn mounts() -> Result<BTreeSet<String>> {
+    let content = std::fs::read("/proc/1/mountinfo")?;
+    let mounts = MountInfos::from_buf_read(content.as_slice())?
+        .into_iter()
+        .filter(|info| {
+            !info.root.starts_with("/adb/modules")
+                && !info.mount_point.starts_with("/data/adb/modules")
+                && !info.mount_point.starts_with("/debug_ramdisk")
+                && !info
+                    .mount_source
+                    .as_deref()
+                    .is_some_and(|source| IGNORED_SOURCES.contains(&source))
+        })
+        .map(|info| {
+            format!(
+                "{} {} {}",
+                info.mount_point.display(),
+                info.fs_type,
+                info.root
+            )
+        })
+        .collect();
+    Ok(mounts)
+}
+
+/// Remember the mount table the namespaces were just copied from, zygote
+/// `pid` being what they are copied from again.
+pub fn snapshot(pid: i32) {
+    let snapshot = pidfd::Process::open(pid).and_then(|zygote| {
+        Ok(Snapshot {
+            zygote,
+            zygote_pid: pid,
+            mounts: mounts()?,
+        })
+    });
+    match snapshot {
+        Ok(snapshot) => *SNAPSHOT.lock().unwrap() = Some(snapshot),
+        Err(e) => warn!("Mount namespaces will not be checked: {}", e),
+    }
+}
+
+/// Stop checking the mount table until the next snapshot, once the namespaces
+/// copied from it were dropped.
+pub fn forget() {
+    SNAPSHOT.lock().unwrap().take();
+}
+
+// Bumped on every stop, ending the checker of the previous start
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+pub struct Checker;
+
+pub static SUBSYSTEM: Checker = Checker;
+
+impl Subsystem for Checker {
+    fn name(&self) -> &'static str {
+        "nscheck"
+    }
+
+    /// Check the mount table every `namespaceCheckInterval`.
+    fn start(&self) -> Result<()> {
+        let Some(interval) = config::get().namespace_check_interval else {
+            return Ok(());
+        };
+        let generation = GENERATION.load(Ordering::SeqCst);
+        let current = move || GENERATION.load(Ordering::SeqCst) == generation;
+        thread::Builder::new()
+            .name("nscheck".to_string())
+            .spawn(move || {
+                while current() {
+                    thread::sleep(interval);
+                    if !current() {
+                        break;
+                    }
+                    if let Err(e) = check() {
+                        warn!("Failed to check mount namespaces: {}", e);
+                    }
+                }
+            })?;
+        RUNNING.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // The snapshot is kept, checking again compares with it
+    fn stop(&self) {
+        GENERATION.fetch_add(1, Ordering::SeqCst);
+        RUNNING.store(false, Ordering::SeqCst);
+    }
+
+    fn running(&self) -> bool {
+        RUNNING.load(Ordering::SeqCst)
+    }
+}
+
+fn check() -> Result<()> {
+    let mut guard = SNAPSHOT.lock().unwrap();
+    let Some(snapshot) = guard.as_mut() else {
+        return Ok(());
+    };
+    let current = mounts()?;
+    if current == snapshot.mounts {
+        return Ok(());
+    }
+    if snapshot.zygote.wait_exit(Duration::ZERO)? {
+        // Taken again once the next zygote asks for the namespaces
+        debug!(
+            "Zygote {} is gone, mount namespaces left as they are",
+            snapshot.zygote_pid
+        );
+        *guard = None;
+        return Ok(());
+    }
+    let added: Vec<&String> = current.difference(&snapshot.mounts).collect();
+    let removed: Vec<&String> = snapshot.mounts.difference(&current).collect();
+    let changes: Vec<&str> = added
+        .iter()
+        .chain(removed.iter())
+        .take(LOGGED_CHANGES)
+        .filter_map(|mount| mount.split(' ').next())
+        .collect();
+    info!(
+        "Mount table changed ({} added, {} removed: {}), mount namespaces are stale",
+        added.len(),
+        removed.len(),
+        changes.join(", ")
+    );
+    // Not held while the helpers run, a new zygote may take a snapshot meanwhile
+    let zygote_pid = snapshot.zygote_pid;
+    drop(guard);
+    let rebuilt = utils::rebuild_mount_namespaces(zygote_pid)?;
+    if rebuilt > 0 {
+        info!("Rebuilt {} mount namespaces", rebuilt);
+        blackbox::record(&format!("{} stale mount namespaces rebuilt", rebuilt));
+    }
+    match SNAPSHOT.lock().unwrap().as_mut() {
+        Some(snapshot) if snapshot.zygote_pid == zygote_pid => snapshot.mounts = current,
+        _ => {}
+    }
+    Ok(())
+}
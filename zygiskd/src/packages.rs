@@ -0,0 +1,370 @@
+use anyhow::{Context, Result, bail};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+const PACKAGES_XML: &str = "/data/system/packages.xml";
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PackageInfo {
+    pub version_code: i64,
+    /// Hex encoded SHA-256 of the current signing certificate.
+    pub cert_digest: Option<String>,
+    pub installer: Option<String>,
+}
+
+/// Look up `package` in the package manager database, falling back to `dumpsys`
+/// without the certificate digest if the database cannot be read.
+pub fn get(package: &str) -> Result<Option<PackageInfo>> {
+    match std::fs::read(PACKAGES_XML)
+        .map_err(anyhow::Error::from)
+        .and_then(|content| find_in_packages_xml(&content, package))
+    {
+        Ok(info) => Ok(info),
+        Err(e) => {
+            log::debug!("Cannot read {}, asking dumpsys: {}", PACKAGES_XML, e);
+            from_dumpsys(package)
+        }
+    }
+}
+
+/// App id of `package`, the uid it has in user 0 and in every other user
+/// modulo `users::PER_USER_RANGE`, `None` when it is not installed.
+pub fn app_id(package: &str) -> Result<Option<u32>> {
+    Ok(app_ids()?.remove(package))
+}
+
+/// App ids of every installed package, by package name.
+pub fn app_ids() -> Result<HashMap<String, u32>> {
+    let content = std::fs::read(PACKAGES_XML)?;
+    let ids = parse(&content)?
+        .into_iter()
+        .filter_map(|tag| match tag {
+            Tag::Start(name, attrs) if name == "package" => {
+                let attr = |key: &str| attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+                // Packages sharing a uid only have `sharedUserId`
+                let id = attr("userId").or(attr("sharedUserId"))?.parse().ok()?;
+                Some((attr("name")?.clone(), id))
+            }
+            _ => None,
+        })
+        .collect();
+    Ok(ids)
+}
+
+/// Start tags of a system XML file like packages.xml, as element names and
+/// attributes, in either format.
+pub fn read_elements(path: &Path) -> Result<Vec<(String, Vec<(String, String)>)>> {
+    let elements = parse(&std::fs::read(path)?)?
+        .into_iter()
+        .filter_map(|tag| match tag {
+            Tag::Start(name, attrs) => Some((name, attrs)),
+            Tag::End => None,
+        })
+        .collect();
+    Ok(elements)
+}
+
+/// Last change of the package manager database, when it can be read.
+pub fn modified() -> Option<std::time::SystemTime> {
+    std::fs::metadata(PACKAGES_XML)
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+fn parse(content: &[u8]) -> Result<Vec<Tag>> {
+    if content.starts_with(abx::MAGIC) {
+        abx::parse(content)
+    } else {
+        text::parse(std::str::from_utf8(content)?)
+    }
+}
+
+fn find_in_packages_xml(content: &[u8], package: &str) -> Result<Option<PackageInfo>> {
+    let tags = parse(content)?;
+
+    // Certificates are stored once with their key, later packages only refer to their index
+    let mut certs: HashMap<String, String> = HashMap::new();
+    let mut stack: Vec<&str> = Vec::new();
+    let mut found: Option<PackageInfo> = None;
+    let mut cert_index: Option<String> = None;
+    let mut in_target = false;
+    for tag in &tags {
+        match tag {
+            Tag::Start(name, attrs) => {
+                let attr = |key: &str| attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+                let parent = stack.last().copied();
+                if name == "package" && parent == Some("packages") {
+                    in_target = attr("name").map(String::as_str) == Some(package);
+                    if in_target {
+                        found = Some(PackageInfo {
+                            version_code: attr("version").and_then(|v| v.parse().ok()).unwrap_or(0),
+                            cert_digest: None,
+                            installer: attr("installer").cloned(),
+                        });
+                    }
+                }
+                if name == "cert" {
+                    if let (Some(index), Some(key)) = (attr("index"), attr("key")) {
+                        certs.insert(index.clone(), key.clone());
+                    }
+                    // Only the current signatures, not `pastSigs` of a rotated key
+                    let current = parent == Some("sigs")
+                        && stack.len() >= 2
+                        && stack[stack.len() - 2] == "package";
+                    if in_target && current && cert_index.is_none() {
+                        cert_index = attr("index").cloned();
+                    }
+                }
+                stack.push(name.as_str());
+            }
+            Tag::End => {
+                if stack.pop() == Some("package") && stack.last() == Some(&"packages") {
+                    in_target = false;
+                }
+            }
+        }
+    }
+
+    if let Some(info) = found.as_mut() {
+        info.cert_digest = cert_index
+            .and_then(|index| certs.get(&index))
+            .and_then(|key| decode_hex(key))
+            .map(|der| encode_hex(&Sha256::digest(der)));
+    }
+    Ok(found)
+}
+
+fn from_dumpsys(package: &str) -> Result<Option<PackageInfo>> {
+    let output = Command::new("dumpsys")
+        .args(["package", package])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .output()
+        .context("failed to run dumpsys")?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let mut info: Option<PackageInfo> = None;
+    for field in output.split_whitespace() {
+        if let Some(code) = field.strip_prefix("versionCode=") {
+            info.get_or_insert_default().version_code = code.parse().unwrap_or(0);
+        } else if let Some(installer) = field.strip_prefix("installerPackageName=") {
+            info.get_or_insert_default().installer =
+                Some(installer.to_string()).filter(|i| i != "null");
+        }
+    }
+    Ok(info)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Element events of an XML document, text content is not needed
+enum Tag {
+    Start(String, Vec<(String, String)>),
+    End,
+}
+
+// Android Binary XML, used for packages.xml since Android 12
+mod abx {
+    use super::{Tag, encode_hex};
+    use anyhow::{Result, bail};
+
+    pub const MAGIC: &[u8] = b"ABX\0";
+
+    const START_DOCUMENT: u8 = 0;
+    const END_DOCUMENT: u8 = 1;
+    const START_TAG: u8 = 2;
+    const END_TAG: u8 = 3;
+    const ATTRIBUTE: u8 = 15;
+
+    const TYPE_NULL: u8 = 1 << 4;
+    const TYPE_STRING: u8 = 2 << 4;
+    const TYPE_STRING_INTERNED: u8 = 3 << 4;
+    const TYPE_BYTES_HEX: u8 = 4 << 4;
+    const TYPE_BYTES_BASE64: u8 = 5 << 4;
+    const TYPE_INT: u8 = 6 << 4;
+    const TYPE_INT_HEX: u8 = 7 << 4;
+    const TYPE_LONG: u8 = 8 << 4;
+    const TYPE_LONG_HEX: u8 = 9 << 4;
+    const TYPE_FLOAT: u8 = 10 << 4;
+    const TYPE_DOUBLE: u8 = 11 << 4;
+    const TYPE_BOOLEAN_TRUE: u8 = 12 << 4;
+    const TYPE_BOOLEAN_FALSE: u8 = 13 << 4;
+
+    struct Reader<'a> {
+        buf: &'a [u8],
+        pos: usize,
+        interned: Vec<String>,
+    }
+
+    impl<'a> Reader<'a> {
+        fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+            let Some(bytes) = self.buf.get(self.pos..self.pos + len) else {
+                bail!("truncated at offset {}", self.pos);
+            };
+            self.pos += len;
+            Ok(bytes)
+        }
+
+        fn u16(&mut self) -> Result<u16> {
+            Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+        }
+
+        fn string(&mut self) -> Result<String> {
+            let len = self.u16()? as usize;
+            Ok(String::from_utf8(self.take(len)?.to_vec())?)
+        }
+
+        fn interned(&mut self) -> Result<String> {
+            match self.u16()? {
+                0xffff => {
+                    let s = self.string()?;
+                    self.interned.push(s.clone());
+                    Ok(s)
+                }
+                index => match self.interned.get(index as usize) {
+                    Some(s) => Ok(s.clone()),
+                    None => bail!("invalid interned string {}", index),
+                },
+            }
+        }
+
+        // Values are rendered the way the text serializer writes them, except
+        // for hex numbers which are kept decimal
+        fn value(&mut self, kind: u8) -> Result<String> {
+            Ok(match kind {
+                TYPE_NULL => String::new(),
+                TYPE_STRING => self.string()?,
+                TYPE_STRING_INTERNED => self.interned()?,
+                TYPE_BYTES_HEX | TYPE_BYTES_BASE64 => {
+                    let len = self.u16()? as usize;
+                    encode_hex(self.take(len)?)
+                }
+                TYPE_INT | TYPE_INT_HEX => {
+                    i32::from_be_bytes(self.take(4)?.try_into()?).to_string()
+                }
+                TYPE_LONG | TYPE_LONG_HEX => {
+                    i64::from_be_bytes(self.take(8)?.try_into()?).to_string()
+                }
+                TYPE_FLOAT => f32::from_be_bytes(self.take(4)?.try_into()?).to_string(),
+                TYPE_DOUBLE => f64::from_be_bytes(self.take(8)?.try_into()?).to_string(),
+                TYPE_BOOLEAN_TRUE => "true".to_string(),
+                TYPE_BOOLEAN_FALSE => "false".to_string(),
+                _ => bail!("unknown value type {:#x}", kind),
+            })
+        }
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Vec<Tag>> {
+        let mut reader = Reader {
+            buf,
+            pos: MAGIC.len(),
+            interned: Vec::new(),
+        };
+        let mut tags = Vec::new();
+        while reader.pos < buf.len() {
+            let token = reader.take(1)?[0];
+            let (command, kind) = (token & 0x0f, token & 0xf0);
+            match command {
+                START_DOCUMENT => {}
+                END_DOCUMENT => break,
+                START_TAG => tags.push(Tag::Start(reader.interned()?, Vec::new())),
+                END_TAG => {
+                    reader.interned()?;
+                    tags.push(Tag::End);
+                }
+                ATTRIBUTE => {
+                    let name = reader.interned()?;
+                    let value = reader.value(kind)?;
+                    match tags.last_mut() {
+                        Some(Tag::Start(_, attrs)) => attrs.push((name, value)),
+                        _ => bail!("attribute `{}` outside of a tag", name),
+                    }
+                }
+                // Text, comments and the like
+                _ => {
+                    reader.value(kind)?;
+                }
+            }
+        }
+        Ok(tags)
+    }
+}
+
+// Plain XML as written by older Android versions, only as much as the
+// package manager serializer produces
+mod text {
+    use super::Tag;
+    use anyhow::{Result, bail};
+
+    pub fn parse(content: &str) -> Result<Vec<Tag>> {
+        let mut tags = Vec::new();
+        let mut rest = content;
+        while let Some(start) = rest.find('<') {
+            rest = &rest[start + 1..];
+            let Some(end) = rest.find('>') else {
+                bail!("unterminated tag");
+            };
+            let tag = &rest[..end];
+            rest = &rest[end + 1..];
+            if tag.starts_with('?') || tag.starts_with('!') {
+                continue;
+            }
+            if tag.starts_with('/') {
+                tags.push(Tag::End);
+                continue;
+            }
+            let (tag, empty) = match tag.strip_suffix('/') {
+                Some(tag) => (tag, true),
+                None => (tag, false),
+            };
+            let (name, mut attrs_str) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+            let mut attrs = Vec::new();
+            while let Some((key, value)) = attrs_str.split_once("=\"") {
+                let Some((value, next)) = value.split_once('"') else {
+                    bail!("unterminated attribute in `{}`", name);
+                };
+                attrs.push((key.trim().to_string(), unescape(value)));
+                attrs_str = next;
+            }
+            tags.push(Tag::Start(name.to_string(), attrs));
+            if empty {
+                tags.push(Tag::End);
+            }
+        }
+        Ok(tags)
+    }
+
+    fn unescape(value: &str) -> String {
+        value
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&")
+    }
+}
+
+/// Validate a package name before it is used in a lookup.
+pub fn check_name(package: &str) -> Result<()> {
+    let valid = !package.is_empty()
+        && package
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_');
+    if !valid {
+        bail!("invalid package name `{}`", package);
+    }
+    Ok(())
+}
@@ -0,0 +1,166 @@
+use anyhow::{Result, bail};
+use log::debug;
+use std::io::Error;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// Processes the daemon waits on or signals are pinned by a pidfd as soon as
+// their pid is known, so that a pid reused after they exit can never be
+// waited on or signalled in their place. Kernels older than 5.3 have no
+// pidfd, processes are then tracked by their raw pid as they used to be.
+
+// Syscall numbers shared by every architecture since Linux 5.1
+const SYS_PIDFD_SEND_SIGNAL: libc::c_long = 424;
+const SYS_PIDFD_OPEN: libc::c_long = 434;
+const P_PIDFD: libc::idtype_t = 3;
+// Interval between checks of /proc for processes tracked by their raw pid
+const FALLBACK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// What `Process::poll_with` woke up for.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Ready {
+    /// The descriptor has data or was hung up
+    Fd,
+    /// The process exited first
+    Exited,
+    TimedOut,
+}
+
+pub struct Process {
+    pid: i32,
+    pidfd: Option<OwnedFd>,
+}
+
+impl Process {
+    /// Pin process `pid`, which must be alive or an unreaped child.
+    pub fn open(pid: i32) -> Result<Process> {
+        if pid <= 0 {
+            bail!("invalid pid {}", pid);
+        }
+        if UNSUPPORTED.load(Ordering::Relaxed) {
+            return Ok(Process { pid, pidfd: None });
+        }
+        let fd = unsafe { libc::syscall(SYS_PIDFD_OPEN, pid, 0) };
+        if fd >= 0 {
+            let pidfd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+            return Ok(Process {
+                pid,
+                pidfd: Some(pidfd),
+            });
+        }
+        let error = Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::ENOSYS) => {
+                if !UNSUPPORTED.swap(true, Ordering::Relaxed) {
+                    debug!("No pidfd support, tracking processes by pid");
+                }
+                Ok(Process { pid, pidfd: None })
+            }
+            _ => bail!("pidfd_open {}: {}", pid, error),
+        }
+    }
+
+    /// Send `signal`, failing with ESRCH once the process exited.
+    pub fn signal(&self, signal: i32) -> Result<()> {
+        let sent = match &self.pidfd {
+            Some(pidfd) => unsafe {
+                let info: *const libc::siginfo_t = std::ptr::null();
+                libc::syscall(SYS_PIDFD_SEND_SIGNAL, pidfd.as_raw_fd(), signal, info, 0) == 0
+            },
+            None => unsafe { libc::kill(self.pid, signal) == 0 },
+        };
+        if !sent {
+            bail!(
+                "signal {} to {}: {}",
+                signal,
+                self.pid,
+                Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+
+    /// Wait for `fd` to be readable or the process to exit, whichever comes
+    /// first, `fd` winning ties so that a last message is not lost.
+    pub fn poll_with(&self, fd: RawFd, timeout: Option<Duration>) -> Result<Ready> {
+        let mut fds = vec![libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        if let Some(pidfd) = &self.pidfd {
+            fds.push(libc::pollfd {
+                fd: pidfd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            });
+        }
+        let timeout = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+        let ready = loop {
+            let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+            if ready >= 0 {
+                break ready;
+            }
+            let error = Error::last_os_error();
+            if error.raw_os_error() != Some(libc::EINTR) {
+                bail!("poll: {}", error);
+            }
+        };
+        Ok(match ready {
+            0 => Ready::TimedOut,
+            _ if fds[0].revents != 0 => Ready::Fd,
+            _ => Ready::Exited,
+        })
+    }
+
+    /// Wait up to `timeout` for the process to exit, returning whether it did.
+    pub fn wait_exit(&self, timeout: Duration) -> Result<bool> {
+        let Some(pidfd) = &self.pidfd else {
+            let start = Instant::now();
+            while std::path::Path::new(&format!("/proc/{}", self.pid)).exists() {
+                if start.elapsed() >= timeout {
+                    return Ok(false);
+                }
+                std::thread::sleep(FALLBACK_POLL_INTERVAL);
+            }
+            return Ok(true);
+        };
+        let mut pfd = libc::pollfd {
+            fd: pidfd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        match unsafe { libc::poll(&mut pfd, 1, timeout) } {
+            -1 => bail!("poll: {}", Error::last_os_error()),
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    /// Wait for the exit of this child of the daemon, returning its wait status.
+    pub fn reap(&self) -> Result<i32> {
+        let Some(pidfd) = &self.pidfd else {
+            let mut status = 0;
+            if unsafe { libc::waitpid(self.pid, &mut status, 0) } == -1 {
+                bail!("waitpid {}: {}", self.pid, Error::last_os_error());
+            }
+            return Ok(status);
+        };
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let id = pidfd.as_raw_fd() as libc::id_t;
+        if unsafe { libc::waitid(P_PIDFD, id, &mut info, libc::WEXITED) } == -1 {
+            bail!("waitid {}: {}", self.pid, Error::last_os_error());
+        }
+        // Rebuild the waitpid status the callers know how to decode
+        let (code, status) = unsafe { (info.si_code, info.si_status()) };
+        Ok(match code {
+            libc::CLD_EXITED => (status & 0xff) << 8,
+            libc::CLD_DUMPED => (status & 0x7f) | 0x80,
+            _ => status & 0x7f,
+        })
+    }
+}
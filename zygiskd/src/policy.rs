@@ -0,0 +1,84 @@
+use crate::constants::{MountNamespace, ProcessFlags};
+use crate::{root_impl, users};
+use log::trace;
+
+/// Compute the process flags of `uid`.
+///
+/// Every rule that fires is appended to `rules` when given, so that
+/// `zygiskd explain` can show the user why a decision was made.
+pub fn process_flags(
+    uid: i32,
+    is_first_process: bool,
+    mut rules: Option<&mut Vec<String>>,
+) -> ProcessFlags {
+    let mut fired = |rule: String| {
+        trace!("{}", rule);
+        if let Some(rules) = rules.as_deref_mut() {
+            rules.push(rule);
+        }
+    };
+
+    let mut flags = ProcessFlags::empty();
+    let app_uid = uid;
+    let uid = users::policy_uid(app_uid);
+    if uid != app_uid {
+        fired(format!(
+            "Uid {} is in a Private Space, decided as uid {}",
+            app_uid, uid
+        ));
+    }
+    if is_first_process {
+        flags |= ProcessFlags::IS_FIRST_PROCESS;
+        if root_impl::uid_is_systemui(uid) {
+            fired(format!("Uid {} is systemui", uid));
+        } else {
+            fired(format!("Uid {} is the first app process", uid));
+        }
+    } else if root_impl::uid_is_manager(uid) {
+        flags |= ProcessFlags::PROCESS_IS_MANAGER;
+        fired(format!("Uid {} is manager", uid));
+    } else {
+        if root_impl::uid_granted_root(uid) {
+            flags |= ProcessFlags::PROCESS_GRANTED_ROOT;
+        }
+        fired(format!(
+            "Uid {} granted root: {}",
+            uid,
+            flags.contains(ProcessFlags::PROCESS_GRANTED_ROOT)
+        ));
+        if root_impl::denylist_inverted() {
+            fired("Denylist is inverted by sulist mode".to_string());
+        }
+        if root_impl::uid_should_umount(uid) {
+            flags |= ProcessFlags::PROCESS_ON_DENYLIST;
+        }
+        fired(format!(
+            "Uid {} on denylist: {}",
+            uid,
+            flags.contains(ProcessFlags::PROCESS_ON_DENYLIST)
+        ));
+    }
+    match root_impl::get_impl() {
+        root_impl::RootImpl::APatch => flags |= ProcessFlags::PROCESS_ROOT_IS_APATCH,
+        root_impl::RootImpl::KernelSU => flags |= ProcessFlags::PROCESS_ROOT_IS_KSU,
+        root_impl::RootImpl::Magisk => flags |= ProcessFlags::PROCESS_ROOT_IS_MAGISK,
+        _ => panic!("wrong root impl: {:?}", root_impl::get_impl()),
+    }
+    flags
+}
+
+/// The mount namespace the injector switches an app process into, mirroring
+/// the `unshare` hook of the injector. `None` keeps the namespace of zygote.
+pub fn mount_namespace(flags: ProcessFlags) -> Option<MountNamespace> {
+    if flags.contains(ProcessFlags::IS_FIRST_PROCESS) {
+        None
+    } else if flags
+        .intersects(ProcessFlags::PROCESS_IS_MANAGER | ProcessFlags::PROCESS_GRANTED_ROOT)
+    {
+        Some(MountNamespace::Root)
+    } else if flags.contains(ProcessFlags::PROCESS_ON_DENYLIST) {
+        Some(MountNamespace::Clean)
+    } else {
+        Some(MountNamespace::Module)
+    }
+}
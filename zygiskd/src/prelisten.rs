@@ -0,0 +1,61 @@
+use anyhow::Result;
+use log::warn;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+// The daemon socket is bound as soon as the daemon starts, before modules
+// are loaded and the root implementation is probed. Zygote connecting in the
+// meantime would otherwise stall on the tiny listen backlog, so early
+// connections are accepted here and kept unanswered until the main loop is
+// ready to serve them in order.
+const MAX_PARKED: usize = 64;
+const POLL_INTERVAL_MS: i32 = 50;
+
+pub struct Parking {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Vec<UnixStream>>,
+}
+
+/// Start accepting connections of `listener` until `release` is called.
+pub fn start(listener: &UnixListener) -> Result<Parking> {
+    let listener = listener.try_clone()?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = thread::Builder::new()
+        .name("prelisten".to_string())
+        .spawn({
+            let stop = Arc::clone(&stop);
+            move || park(listener, &stop)
+        })?;
+    Ok(Parking { stop, thread })
+}
+
+impl Parking {
+    /// Stop parking and hand over the connections accepted so far, oldest first.
+    pub fn release(self) -> Vec<UnixStream> {
+        self.stop.store(true, Ordering::SeqCst);
+        self.thread.join().unwrap_or_default()
+    }
+}
+
+fn park(listener: UnixListener, stop: &AtomicBool) -> Vec<UnixStream> {
+    let mut parked = Vec::new();
+    // Past the limit, further connections wait in the backlog as they used to
+    while !stop.load(Ordering::SeqCst) && parked.len() < MAX_PARKED {
+        let mut pfd = libc::pollfd {
+            fd: listener.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut pfd, 1, POLL_INTERVAL_MS) } <= 0 {
+            continue;
+        }
+        match listener.accept() {
+            Ok((stream, _)) => parked.push(stream),
+            Err(e) => warn!("Failed to accept early connection: {}", e),
+        }
+    }
+    parked
+}
@@ -0,0 +1,146 @@
+use crate::constants::PATH_MODULES_DIR;
+use crate::{audit, dryrun, props, tmpdir, utils};
+use anyhow::{Result, bail};
+use num_enum::TryFromPrimitive;
+use rustix::fs::{Mode, OFlags, ResolveFlags};
+use rustix::io::Errno;
+use std::fs;
+use std::io::Read;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::path::{Component, Path, PathBuf};
+
+// Privileged operations the daemon runs for module companions, so that they
+// need not exec su and stack root prompts, mirroring
+// `zygisk::PrivilegedOperation` of api.hpp. Requests are only taken from the
+// companion of the module, arguments are vetted per operation and every
+// request is audited.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Operation {
+    CopyFile,
+    GetEnforce,
+    SetProperty,
+}
+
+// Files are copied in memory, modules have no business moving large ones around
+const MAX_COPY_SIZE: u64 = 16 * 1024 * 1024;
+// Properties modules may set: debugging and logging switches, which change
+// neither how the system boots nor how it runs services or enforces security,
+// and are gone at the next boot
+const ALLOWED_PROPERTY_PREFIXES: &[&str] = &["debug.", "log.tag."];
+
+/// Run `operation` with `args` on behalf of `module`, returning its output.
+pub fn run(module: &str, operation: Operation, args: [&str; 2]) -> Result<String> {
+    let result = match operation {
+        Operation::CopyFile => copy_file(module, args[0], args[1]).map(|_| String::new()),
+        Operation::GetEnforce => get_enforce(),
+        Operation::SetProperty => set_property(args[0], args[1]).map(|_| String::new()),
+    };
+    audit::record(&format!(
+        "privileged module={} {:?} args={:?} result={}",
+        module,
+        operation,
+        args,
+        match &result {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        }
+    ));
+    result
+}
+
+// Canonical directories a module may copy from and to: its module directory
+// and its scratch directory
+fn module_dirs(module: &str) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![fs::canonicalize(
+        PathBuf::from(PATH_MODULES_DIR).join(module),
+    )?];
+    if let Ok(dir) = tmpdir::module_dir(module) {
+        dirs.push(fs::canonicalize(dir)?);
+    }
+    Ok(dirs)
+}
+
+// Open `path` beneath one of the directories of `module`, without ever
+// leaving it through a symbolic link or `..`, whichever component it is in
+fn open_beneath(module: &str, path: &Path, flags: OFlags) -> Result<OwnedFd> {
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        bail!("`{}` is not a plain absolute path", path.display());
+    }
+    for dir in module_dirs(module)? {
+        let Ok(relative) = path.strip_prefix(&dir) else {
+            continue;
+        };
+        let relative = if relative.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            relative
+        };
+        let flags = flags | OFlags::NOFOLLOW | OFlags::CLOEXEC;
+        let dir_fd = rustix::fs::open(&dir, OFlags::PATH | OFlags::DIRECTORY, Mode::empty())?;
+        let resolve = ResolveFlags::BENEATH | ResolveFlags::NO_MAGICLINKS;
+        let fd = match rustix::fs::openat2(&dir_fd, relative, flags, Mode::empty(), resolve) {
+            // Kernels before 5.6, the file is checked to be beneath once open instead
+            Err(Errno::NOSYS) => {
+                let fd = rustix::fs::open(dir.join(relative), flags, Mode::empty())?;
+                let opened = fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd()))?;
+                if !opened.starts_with(&dir) {
+                    bail!("`{}` leads out of {}", path.display(), dir.display());
+                }
+                fd
+            }
+            result => result?,
+        };
+        return Ok(fd);
+    }
+    bail!(
+        "`{}` is outside the directories of the module",
+        path.display()
+    )
+}
+
+// Both files are in the module directory or its scratch directory, and the
+// copy itself is a regular file
+fn copy_file(module: &str, from: &str, to: &str) -> Result<()> {
+    let to = Path::new(to);
+    let (Some(parent), Some(name)) = (to.parent(), to.file_name()) else {
+        bail!("`{}` is not a file path", to.display());
+    };
+    let parent = open_beneath(module, parent, OFlags::RDONLY | OFlags::DIRECTORY)?;
+    let mut file = fs::File::from(open_beneath(module, Path::new(from), OFlags::RDONLY)?);
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        bail!("`{}` is not a regular file", from);
+    }
+    if metadata.len() > MAX_COPY_SIZE {
+        bail!("`{}` is larger than {} bytes", from, MAX_COPY_SIZE);
+    }
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    if dryrun::skip(|| format!("copy {} to {}", from, to.display())) {
+        return Ok(());
+    }
+    utils::write_atomic_at(parent.as_fd(), name, &content)
+}
+
+fn get_enforce() -> Result<String> {
+    match fs::read_to_string("/sys/fs/selinux/enforce")?.trim() {
+        "1" => Ok("Enforcing".to_string()),
+        "0" => Ok("Permissive".to_string()),
+        other => bail!("unexpected SELinux mode `{}`", other),
+    }
+}
+
+fn set_property(name: &str, value: &str) -> Result<()> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "._-:@".contains(c);
+    if name.is_empty() || !name.chars().all(valid) {
+        bail!("invalid property name `{}`", name);
+    }
+    if !ALLOWED_PROPERTY_PREFIXES
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+    {
+        bail!("property `{}` may not be set by modules", name);
+    }
+    props::set_blocking(name, value)
+}
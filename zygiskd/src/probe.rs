@@ -0,0 +1,120 @@
+use crate::config;
+use crate::constants::{MountNamespace, PATH_MODULES_DIR, PROBE_PATHS};
+use crate::zygiskd::{self, ModuleMarker};
+use anyhow::{Result, bail};
+use rustix::thread::{LinkNameSpaceType, move_into_link_name_space};
+use std::fs;
+use std::os::fd::AsFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+// Files of modules checked at most, some ship whole system partitions
+const MAX_MODULE_FILES: usize = 4096;
+
+// A file a module lays over `/system`, found reachable when the path shows the
+// module copy itself, as left by a bind mount nobody reverted.
+struct ModuleFile {
+    module: String,
+    target: PathBuf,
+    identity: (u64, u64),
+}
+
+fn module_files() -> Vec<ModuleFile> {
+    let mut files = Vec::new();
+    let Ok(entries) = fs::read_dir(PATH_MODULES_DIR) else {
+        return files;
+    };
+    let mut dirs: Vec<PathBuf> = entries.filter_map(|e| Some(e.ok()?.path())).collect();
+    dirs.sort();
+    for dir in dirs {
+        let name = dir
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        if ModuleMarker::of(&dir).is_some() {
+            continue;
+        }
+        walk(&name, &dir.join("system"), Path::new("/system"), &mut files);
+    }
+    files
+}
+
+fn walk(module: &str, source: &Path, target: &Path, files: &mut Vec<ModuleFile>) {
+    let Ok(entries) = fs::read_dir(source) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if files.len() >= MAX_MODULE_FILES {
+            return;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let target = target.join(entry.file_name());
+        if metadata.is_dir() {
+            walk(module, &entry.path(), &target, files);
+        } else if metadata.is_file() {
+            files.push(ModuleFile {
+                module: module.to_string(),
+                target,
+                identity: (metadata.dev(), metadata.ino()),
+            });
+        }
+    }
+}
+
+/// Report which root detection probe paths and module files are still
+/// reachable from the Clean mount namespace handed to `uid`.
+pub fn main(uid: Option<u32>) -> Result<()> {
+    let files = module_files();
+    let probes: Vec<String> = PROBE_PATHS
+        .iter()
+        .map(|path| path.to_string())
+        .chain(config::get().probe_paths.iter().cloned())
+        .collect();
+    let Some(namespace) = zygiskd::request_mount_namespace(MountNamespace::Clean, uid)? else {
+        bail!("NeoZygisk is disabled, apps keep the mount namespace of zygote");
+    };
+    // The command is single threaded, so it can join the namespace itself
+    move_into_link_name_space(namespace.as_fd(), Some(LinkNameSpaceType::Mount))?;
+
+    match uid {
+        Some(uid) => println!("Clean mount namespace of uid {}", uid),
+        None => println!("Clean mount namespace of zygote"),
+    }
+    let mut reachable = 0;
+    println!("Probe paths({}):", probes.len());
+    for probe in &probes {
+        if fs::symlink_metadata(probe).is_ok() {
+            println!("\treachable: {}", probe);
+            reachable += 1;
+        } else {
+            println!("\thidden: {}", probe);
+        }
+    }
+    let leaked: Vec<&ModuleFile> = files
+        .iter()
+        .filter(|file| {
+            fs::metadata(&file.target)
+                .is_ok_and(|metadata| (metadata.dev(), metadata.ino()) == file.identity)
+        })
+        .collect();
+    println!("Module files({}):", files.len());
+    for file in &leaked {
+        println!(
+            "\treachable: {} (module {})",
+            file.target.display(),
+            file.module
+        );
+    }
+    if files.len() >= MAX_MODULE_FILES {
+        println!("\tonly the first {} files were checked", MAX_MODULE_FILES);
+    }
+    println!(
+        "{} of {} paths reachable",
+        reachable + leaked.len(),
+        probes.len() + files.len()
+    );
+    Ok(())
+}
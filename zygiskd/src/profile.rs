@@ -0,0 +1,130 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// Contention within the daemon itself, as opposed to the injection latency
+// recorded in the metrics: time spent waiting for and holding its key locks,
+// records waiting in its queues and requests waiting for a thread. Counters
+// are kept in memory since the start of the daemon and read with
+// `zygiskd profile`.
+
+/// A lock, queue or task being timed.
+///
+/// `wait` is the time to acquire a lock, spent queued or before a thread
+/// picks a task up, `busy` the time a lock is held, a record is written out
+/// or a task runs.
+pub struct Site {
+    pub name: &'static str,
+    count: AtomicU64,
+    wait_us: AtomicU64,
+    max_wait_us: AtomicU64,
+    busy_us: AtomicU64,
+    max_busy_us: AtomicU64,
+}
+
+impl Site {
+    const fn new(name: &'static str) -> Site {
+        Site {
+            name,
+            count: AtomicU64::new(0),
+            wait_us: AtomicU64::new(0),
+            max_wait_us: AtomicU64::new(0),
+            busy_us: AtomicU64::new(0),
+            max_busy_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, wait: Duration, busy: Duration) {
+        let (wait, busy) = (wait.as_micros() as u64, busy.as_micros() as u64);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.wait_us.fetch_add(wait, Ordering::Relaxed);
+        self.max_wait_us.fetch_max(wait, Ordering::Relaxed);
+        self.busy_us.fetch_add(busy, Ordering::Relaxed);
+        self.max_busy_us.fetch_max(busy, Ordering::Relaxed);
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} count={} wait_us={} max_wait_us={} busy_us={} max_busy_us={}",
+            self.name,
+            self.count.load(Ordering::Relaxed),
+            self.wait_us.load(Ordering::Relaxed),
+            self.max_wait_us.load(Ordering::Relaxed),
+            self.busy_us.load(Ordering::Relaxed),
+            self.max_busy_us.load(Ordering::Relaxed)
+        )
+    }
+}
+
+pub static COMPANION: Site = Site::new("lock.companion");
+pub static MOUNT_NAMESPACES: Site = Site::new("lock.mount_namespaces");
+pub static BLACKBOX: Site = Site::new("lock.blackbox");
+pub static STORE: Site = Site::new("lock.store");
+pub static WRITER: Site = Site::new("lock.writer");
+pub static WRITER_QUEUE: Site = Site::new("queue.writer");
+/// Requests handled on their own thread, but for injection reports which
+/// wait on the app
+pub static REQUEST: Site = Site::new("task.request");
+pub static CACHE_NAMESPACE: Site = Site::new("task.cache_namespace");
+
+static SITES: [&Site; 8] = [
+    &COMPANION,
+    &MOUNT_NAMESPACES,
+    &BLACKBOX,
+    &STORE,
+    &WRITER,
+    &WRITER_QUEUE,
+    &REQUEST,
+    &CACHE_NAMESPACE,
+];
+
+/// A `MutexGuard` recording to its site how long it was waited for and held.
+pub struct Guard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    site: &'static Site,
+    wait: Duration,
+    acquired: Instant,
+}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for Guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for Guard<'_, T> {
+    fn drop(&mut self) {
+        self.site.record(self.wait, self.acquired.elapsed());
+    }
+}
+
+/// Lock `mutex` like `mutex.lock().unwrap()`, timed as `site`.
+pub fn lock<'a, T>(site: &'static Site, mutex: &'a Mutex<T>) -> Guard<'a, T> {
+    let start = Instant::now();
+    let guard = mutex.lock().unwrap();
+    let acquired = Instant::now();
+    Guard {
+        guard,
+        site,
+        wait: acquired - start,
+        acquired,
+    }
+}
+
+/// One line per site, as sent to `zygiskd profile`.
+pub fn dump() -> String {
+    SITES
+        .iter()
+        .map(|site| site.summary())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
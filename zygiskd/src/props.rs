@@ -0,0 +1,211 @@
+use crate::constants::{PATH_DATA_DIR, PATH_MODULES_DIR};
+use crate::subsystem::Subsystem;
+use crate::utils::{self, LateInit};
+use crate::{audit, dryrun, manifest};
+use anyhow::{Result, anyhow};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{OnceLock, RwLock};
+use std::thread;
+use std::time::Duration;
+
+const WRITE_ATTEMPTS: u32 = 3;
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+type Overlay = Vec<(String, String)>;
+
+// Per-package virtual values of read-only properties, applied by the injector
+// inside each app process instead of changing the global property area.
+//
+// Sources, in decreasing priority:
+//   PATH_DATA_DIR/props/<package>.prop           set by the user
+//   <module>/zygisk/props/<package>.prop         shipped by modules, in load order,
+//                                                with `propertyOverlay=true` in their manifest
+// Reloaded as a subsystem, `None` while stopped
+static OVERLAYS: RwLock<Option<HashMap<String, Overlay>>> = RwLock::new(None);
+static MODULE_NAMES: LateInit<Vec<String>> = LateInit::new();
+
+/// Remember the modules whose overlays are loaded by the subsystem.
+pub fn setup(module_names: &[&str]) {
+    MODULE_NAMES.init(module_names.iter().map(|name| name.to_string()).collect());
+}
+
+pub struct Overlays;
+
+pub static SUBSYSTEM: Overlays = Overlays;
+
+impl Subsystem for Overlays {
+    fn name(&self) -> &'static str {
+        "props"
+    }
+
+    fn start(&self) -> Result<()> {
+        let overlays = load(&MODULE_NAMES);
+        *OVERLAYS.write().unwrap() = Some(overlays);
+        Ok(())
+    }
+
+    // Apps started meanwhile get no overlay
+    fn stop(&self) {
+        OVERLAYS.write().unwrap().take();
+    }
+
+    fn running(&self) -> bool {
+        OVERLAYS.read().unwrap().is_some()
+    }
+}
+
+fn load(module_names: &[String]) -> HashMap<String, Overlay> {
+    let mut overlays: HashMap<String, Overlay> = HashMap::new();
+    let mut owners: HashMap<(String, String), String> = HashMap::new();
+    let mut sources = vec![("user".to_string(), Path::new(PATH_DATA_DIR).join("props"))];
+    for name in module_names {
+        let module_dir = Path::new(PATH_MODULES_DIR).join(name);
+        let dir = module_dir.join("zygisk/props");
+        let opted_in = manifest::load(&module_dir, name)
+            .is_ok_and(|metadata| metadata.manifest.is_some_and(|m| m.property_overlay));
+        if !opted_in {
+            if dir.is_dir() {
+                warn!(
+                    "Module `{}` ships overlays without propertyOverlay=true",
+                    name
+                );
+            }
+            continue;
+        }
+        sources.push((format!("module `{}`", name), dir));
+    }
+    for (source, dir) in sources {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let package = match file_name.strip_suffix(".prop") {
+                Some(package) => package.to_string(),
+                None => continue,
+            };
+            let content = match manifest::read_bounded(&entry.path()) {
+                Ok(Some(content)) => content,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Ignoring overlay {}: {}", entry.path().display(), e);
+                    continue;
+                }
+            };
+            let mut issues = Vec::new();
+            let entries = manifest::parse_entries(&content, &file_name, &[], &mut issues);
+            let overlay = overlays.entry(package.clone()).or_default();
+            for (key, value) in entries {
+                if !key.starts_with("ro.") {
+                    warn!(
+                        "{}: only ro.* properties can be virtualized, skip {}",
+                        source, key
+                    );
+                    continue;
+                }
+                let owner = (package.clone(), key.to_string());
+                if let Some(winner) = owners.get(&owner) {
+                    warn!(
+                        "Overlay conflict on {} for {}: keeping value of {}, ignoring {}",
+                        key, package, winner, source
+                    );
+                    continue;
+                }
+                owners.insert(owner, source.clone());
+                overlay.push((key.to_string(), value.to_string()));
+            }
+        }
+    }
+    overlays.retain(|_, overlay| !overlay.is_empty());
+    info!("Property overlays loaded for {} packages", overlays.len());
+    overlays
+}
+
+/// Overlay of the app process named `process`, its package being the part before `:`.
+pub fn overlay_for(process: &str) -> Overlay {
+    let package = process.split(':').next().unwrap_or(process);
+    let overlays = OVERLAYS.read().unwrap();
+    match overlays.as_ref().and_then(|overlays| overlays.get(package)) {
+        Some(overlay) => {
+            debug!(
+                "Process {} gets {} virtual properties",
+                process,
+                overlay.len()
+            );
+            overlay.clone()
+        }
+        None => Vec::new(),
+    }
+}
+
+struct PropertyWrite {
+    name: String,
+    value: String,
+    reply: Sender<Result<()>>,
+}
+
+// All property mutations of the daemon go through this single worker, so that
+// writes of different subsystems are serialized towards the property service.
+static WRITER: OnceLock<Sender<PropertyWrite>> = OnceLock::new();
+
+fn writer() -> Result<Sender<PropertyWrite>> {
+    if let Some(sender) = WRITER.get() {
+        return Ok(sender.clone());
+    }
+    let (sender, receiver) = mpsc::channel::<PropertyWrite>();
+    thread::Builder::new()
+        .name("props".to_string())
+        .spawn(move || {
+            for write in receiver {
+                let result = write_property(&write.name, &write.value);
+                let _ = write.reply.send(result);
+            }
+        })?;
+    // Another thread may have won the race, its worker is kept then
+    Ok(WRITER.get_or_init(|| sender).clone())
+}
+
+fn write_property(name: &str, value: &str) -> Result<()> {
+    if dryrun::skip(|| format!("set property {}={}", name, value)) {
+        return Ok(());
+    }
+    let mut attempt = 1;
+    loop {
+        match utils::set_property(name, value) {
+            Ok(()) => {
+                audit::record(&format!("setprop {}={} attempts={}", name, value, attempt));
+                return Ok(());
+            }
+            Err(e) if attempt < WRITE_ATTEMPTS => {
+                debug!("Retrying property write of {}: {}", name, e);
+                thread::sleep(WRITE_RETRY_DELAY * attempt);
+                attempt += 1;
+            }
+            Err(e) => {
+                warn!("Failed to set {} after {} attempts: {}", name, attempt, e);
+                audit::record(&format!("setprop {}={} failed: {}", name, value, e));
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Write a property and wait until the property service accepted it or all retries failed.
+pub fn set_blocking(name: &str, value: &str) -> Result<()> {
+    let (reply, result) = mpsc::channel();
+    writer()?
+        .send(PropertyWrite {
+            name: name.to_string(),
+            value: value.to_string(),
+            reply,
+        })
+        .map_err(|_| anyhow!("property writer is gone"))?;
+    result
+        .recv()
+        .map_err(|_| anyhow!("property writer is gone"))?
+}
@@ -0,0 +1,524 @@
+use crate::constants::{DaemonSocketAction, MountNamespace, ProcessFlags};
+use crate::request::{self, Request};
+use crate::utils::{MAX_STRING_SIZE, UnixStreamExt};
+use crate::zygote::SpawnPath;
+use crate::{adjust, privop, scripts, sockdir, subsystem};
+use anyhow::{Result, anyhow, bail};
+use passfd::FdPassingExt;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+// Conformance checks of the daemon protocol, for zygote injectors other than
+// the loader of NeoZygisk. `zygisk-protocheck` talks to the running daemon of
+// each architecture like an injector would, with valid and invalid requests;
+// with `serve <path>` it stands in for the daemon and checks the requests an
+// injector sends to it instead.
+
+// Longest wait for a reply, or for the daemon to hang up
+const TIMEOUT: Duration = Duration::from_secs(5);
+// Placeholder the daemon cannot mistake for a module, a package or a process
+const UNKNOWN: &str = "org.neozygisk.protocheck";
+
+enum Outcome {
+    Pass(String),
+    Fail(String),
+    // Left out, with what the request would change on the running system
+    Skip(&'static str),
+}
+
+fn connect(socket: &Path, action: u8) -> Result<UnixStream> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.write_u8(action)?;
+    Ok(stream)
+}
+
+// The daemon hangs up once a request is answered or refused, without a byte more
+fn expect_hang_up(mut stream: UnixStream) -> Result<()> {
+    let mut buf = [0u8; 64];
+    match stream.read(&mut buf) {
+        Ok(0) => Ok(()),
+        Ok(n) => bail!("{} unexpected bytes instead of hanging up", n),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            bail!("no hang up within {:?}", TIMEOUT)
+        }
+        // Reset by the daemon closing with our bytes unread
+        Err(e) if e.kind() == ErrorKind::ConnectionReset => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn recv_fd(stream: &UnixStream) -> Result<OwnedFd> {
+    let fd = stream.recv_fd()?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+// What a valid request of `action` would change on the running system
+fn side_effect(action: DaemonSocketAction) -> Option<&'static str> {
+    match action {
+        DaemonSocketAction::RequestCompanionSocket | DaemonSocketAction::CreateSocketPair => {
+            Some("spawns the companion of a module")
+        }
+        DaemonSocketAction::PingHeartbeat | DaemonSocketAction::SystemServerStarted => {
+            Some("notifies the monitor")
+        }
+        DaemonSocketAction::CacheMountNamespace => Some("copies the mount namespaces again"),
+        DaemonSocketAction::ZygoteRestart => Some("stops the companions"),
+        DaemonSocketAction::DisableAll => Some("turns NeoZygisk off"),
+        DaemonSocketAction::RotateSecrets => Some("renames the socket"),
+        DaemonSocketAction::ReportInjection => Some("records an injection that never happened"),
+        _ => None,
+    }
+}
+
+// Valid requests without lasting effect, each checked for the layout of its reply
+fn valid(socket: &Path, action: DaemonSocketAction) -> Result<Outcome> {
+    if let Some(effect) = side_effect(action) {
+        return Ok(Outcome::Skip(effect));
+    }
+    if action == DaemonSocketAction::GetModuleDir {
+        let mut modules = connect(socket, DaemonSocketAction::ReadModules as u8)?;
+        if modules.read_usize()? == 0 {
+            return Ok(Outcome::Pass("no module to ask for".to_string()));
+        }
+    }
+    let mut stream = connect(socket, action as u8)?;
+    let detail = match action {
+        DaemonSocketAction::GetProcessFlags => {
+            stream.write_u32(10000)?;
+            stream.write_u8(SpawnPath::Fork as u8)?;
+            let flags = stream.read_u32()?;
+            if ProcessFlags::from_bits(flags).is_none() {
+                bail!("unknown process flags {:#x}", flags);
+            }
+            format!("flags {:#x}", flags)
+        }
+        DaemonSocketAction::UpdateMountNamespace => {
+            stream.write_u8(MountNamespace::Clean as u8)?;
+            stream.write_u32(u32::MAX)?;
+            // Zygote never inherits its own namespace
+            stream.write_u8(0)?;
+            let pid = stream.read_u32()?;
+            // The daemon hangs up instead while the namespace is not cached yet
+            match stream.read_u32() {
+                Ok(0) => "namespace of zygote kept".to_string(),
+                Ok(fd) => {
+                    let generation = stream.read_usize()?;
+                    let target = fs::read_link(format!("/proc/{}/fd/{}", pid, fd))?;
+                    stream.write_usize(generation)?;
+                    if !target.to_string_lossy().starts_with("mnt:") {
+                        bail!(
+                            "fd {} of {} is {}, no mount namespace",
+                            fd,
+                            pid,
+                            target.display()
+                        );
+                    }
+                    match stream.read_u8()? {
+                        1 => format!("namespace at fd {} of {}", fd, pid),
+                        _ => format!("namespace at fd {} of {} replaced meanwhile", fd, pid),
+                    }
+                }
+                Err(_) => return Ok(Outcome::Pass("namespace not cached yet".to_string())),
+            }
+        }
+        DaemonSocketAction::ReadModules => {
+            let count = stream.read_usize()?;
+            for _ in 0..count {
+                let name = stream.read_string()?;
+                if name.is_empty() || name.contains('/') {
+                    bail!("invalid module name `{}`", name);
+                }
+                recv_fd(&stream)?;
+            }
+            format!("{} modules", count)
+        }
+        DaemonSocketAction::GetPropertyOverlay => {
+            stream.write_string(UNKNOWN)?;
+            match stream.read_usize()? {
+                0 => "no overlay".to_string(),
+                count => bail!("{} properties for an unknown process", count),
+            }
+        }
+        DaemonSocketAction::GetPackageInfo => {
+            stream.write_string("android")?;
+            if stream.read_u8()? == 1 {
+                let version_code = stream.read_string()?;
+                if version_code.parse::<i64>().is_err() {
+                    bail!("invalid version code `{}`", version_code);
+                }
+                stream.read_string()?;
+                stream.read_string()?;
+            }
+            "package info".to_string()
+        }
+        DaemonSocketAction::DumpProfile => {
+            let profile = stream.read_string()?;
+            format!("{} profile lines", profile.lines().count())
+        }
+        DaemonSocketAction::Handover => {
+            stream.write_string(&format!("/{}", UNKNOWN))?;
+            match stream.read_u8()? {
+                0 => "handover to a missing binary refused".to_string(),
+                answer => bail!("handover to a missing binary answered {}", answer),
+            }
+        }
+        DaemonSocketAction::AdjustProcess => {
+            stream.write_string(UNKNOWN)?;
+            stream.write_u32(std::process::id())?;
+            stream.write_u8(adjust::Adjustment::Nice as u8)?;
+            stream.write_string("0")?;
+            match stream.read_u8()? {
+                0 => "refused for an unknown module".to_string(),
+                answer => bail!("unknown module answered {}", answer),
+            }
+        }
+        DaemonSocketAction::RunPrivileged => {
+            stream.write_string(UNKNOWN)?;
+            stream.write_u8(privop::Operation::SetProperty as u8)?;
+            stream.write_string("")?;
+            stream.write_string("")?;
+            match stream.read_u8()? {
+                0 => format!("refused: {}", stream.read_string()?),
+                answer => bail!("unknown module answered {}", answer),
+            }
+        }
+        DaemonSocketAction::RunScript => {
+            stream.write_string("")?;
+            stream.write_string(UNKNOWN)?;
+            stream.write_u8(scripts::Command::ListModules as u8)?;
+            stream.write_string("")?;
+            stream.write_string("")?;
+            match stream.read_u8()? {
+                0 => format!("refused: {}", stream.read_string()?),
+                answer => bail!("empty token answered {}", answer),
+            }
+        }
+        DaemonSocketAction::ControlSubsystem => {
+            stream.write_u8(subsystem::Control::List as u8)?;
+            stream.write_string("")?;
+            match stream.read_u8()? {
+                1 => format!("{} subsystems", stream.read_string()?.lines().count()),
+                _ => bail!("listing refused: {}", stream.read_string()?),
+            }
+        }
+        DaemonSocketAction::GetLogSocket => match stream.read_string()? {
+            path if path.is_empty() => "log forwarding stopped".to_string(),
+            path if Path::new(&path).is_absolute() => "log socket".to_string(),
+            path => bail!("log socket at relative path `{}`", path),
+        },
+        DaemonSocketAction::GetHistory => {
+            stream.write_string("")?;
+            match stream.read_u8()? {
+                1 => format!("{} packages", stream.read_string()?.lines().count()),
+                _ => format!("no history: {}", stream.read_string()?),
+            }
+        }
+        DaemonSocketAction::GetModuleDir => {
+            stream.write_usize(0)?;
+            let dir = recv_fd(&stream)?;
+            let dir = fs::read_link(format!("/proc/self/fd/{}", dir.as_raw_fd()))?;
+            format!("module directory {}", dir.display())
+        }
+        _ => unreachable!("{:?} is left out", action),
+    };
+    expect_hang_up(stream)?;
+    Ok(Outcome::Pass(detail))
+}
+
+// Requests cut short, then with a field out of range, which the daemon must
+// refuse by hanging up without waiting for more
+fn invalid(socket: &Path, action: DaemonSocketAction) -> Result<Vec<&'static str>> {
+    let oversized = (MAX_STRING_SIZE + 1).to_ne_bytes().to_vec();
+    let (payload, bad): (bool, Option<(&'static str, Vec<u8>)>) = match action {
+        DaemonSocketAction::GetProcessFlags => {
+            let mut bytes = 10000u32.to_ne_bytes().to_vec();
+            bytes.push(u8::MAX);
+            (true, Some(("bad spawn path", bytes)))
+        }
+        // Any pid is taken, and then copied from
+        DaemonSocketAction::CacheMountNamespace => (true, None),
+        DaemonSocketAction::UpdateMountNamespace => (true, Some(("bad namespace", vec![u8::MAX]))),
+        DaemonSocketAction::ControlSubsystem => (true, Some(("bad control", vec![u8::MAX]))),
+        DaemonSocketAction::GetPropertyOverlay
+        | DaemonSocketAction::GetPackageInfo
+        | DaemonSocketAction::Handover
+        | DaemonSocketAction::AdjustProcess
+        | DaemonSocketAction::RunPrivileged
+        | DaemonSocketAction::RunScript
+        | DaemonSocketAction::GetHistory => (true, Some(("oversized string", oversized))),
+        DaemonSocketAction::ReportInjection => {
+            let mut bytes = 10000u32.to_ne_bytes().to_vec();
+            bytes.extend_from_slice(&oversized);
+            (true, Some(("oversized string", bytes)))
+        }
+        DaemonSocketAction::RequestCompanionSocket | DaemonSocketAction::GetModuleDir => (
+            true,
+            Some(("bad module index", usize::MAX.to_ne_bytes().to_vec())),
+        ),
+        DaemonSocketAction::CreateSocketPair => {
+            let mut bytes = 0usize.to_ne_bytes().to_vec();
+            bytes.push(u8::MAX);
+            (true, Some(("bad socket type", bytes)))
+        }
+        _ => (false, None),
+    };
+    let mut refused = Vec::new();
+    if payload {
+        let stream = connect(socket, action as u8)?;
+        stream.shutdown(Shutdown::Write)?;
+        expect_hang_up(stream).map_err(|e| anyhow!("truncated request: {}", e))?;
+        refused.push("truncated");
+    }
+    if let Some((what, bytes)) = bad {
+        let mut stream = connect(socket, action as u8)?;
+        stream.write_all(&bytes)?;
+        expect_hang_up(stream).map_err(|e| anyhow!("{}: {}", what, e))?;
+        refused.push(what);
+    }
+    Ok(refused)
+}
+
+// Whether the daemon still answers, after a check which might have killed it
+fn alive(socket: &Path) -> Result<()> {
+    let mut stream = connect(socket, DaemonSocketAction::DumpProfile as u8)?;
+    stream.read_string()?;
+    Ok(())
+}
+
+fn actions() -> impl Iterator<Item = DaemonSocketAction> {
+    (0..=u8::MAX).map_while(|value| DaemonSocketAction::try_from(value).ok())
+}
+
+/// Check the daemon of our architecture, printing one line per request type and
+/// returning whether it conforms.
+pub fn main() -> Result<bool> {
+    let tmp_path = std::env::var("TMP_PATH")
+        .map_err(|_| anyhow!("TMP_PATH is not set, run through zygisk-protocheck"))?;
+    let socket = sockdir::lookup(Path::new(&tmp_path), sockdir::ARCH)?;
+    alive(&socket).map_err(|e| anyhow!("no {} daemon answers: {}", sockdir::ARCH, e))?;
+    println!("{} daemon at {}", sockdir::ARCH, socket.display());
+    let mut conforms = true;
+    for action in actions() {
+        let outcome = valid(&socket, action).unwrap_or_else(|e| Outcome::Fail(e.to_string()));
+        let outcome = match (outcome, invalid(&socket, action)) {
+            (Outcome::Fail(e), _) => Outcome::Fail(e),
+            (_, Err(e)) => Outcome::Fail(e.to_string()),
+            (outcome, Ok(refused)) if refused.is_empty() => outcome,
+            (Outcome::Pass(detail), Ok(refused)) => {
+                Outcome::Pass(format!("{}; refused {}", detail, refused.join(", ")))
+            }
+            (Outcome::Skip(reason), Ok(refused)) => Outcome::Pass(format!(
+                "refused {}; valid request left out as it {}",
+                refused.join(", "),
+                reason
+            )),
+        };
+        let outcome = match alive(&socket) {
+            Ok(()) => outcome,
+            Err(e) => Outcome::Fail(format!("daemon stopped answering: {}", e)),
+        };
+        match outcome {
+            Outcome::Pass(detail) => println!("ok   {:?}: {}", action, detail),
+            Outcome::Skip(reason) => println!("skip {:?}: {}", action, reason),
+            Outcome::Fail(e) => {
+                println!("FAIL {:?}: {}", action, e);
+                conforms = false;
+            }
+        }
+    }
+    let unknown = actions().count() as u8;
+    for value in [unknown, u8::MAX] {
+        match expect_hang_up(connect(&socket, value)?).and_then(|_| alive(&socket)) {
+            Ok(()) => println!("ok   action {}: refused", value),
+            Err(e) => {
+                println!("FAIL action {}: {}", value, e);
+                conforms = false;
+            }
+        }
+    }
+    Ok(conforms)
+}
+
+// Answer `action` like a daemon without modules would, describing the request
+fn answer(stream: &mut UnixStream, action: DaemonSocketAction, first: &mut bool) -> Result<String> {
+    // Decoded as the daemon does, so that what passes here is served there
+    Ok(match request::read_fields(action, stream)? {
+        Request::PingHeartbeat | Request::ZygoteRestart | Request::SystemServerStarted => {
+            String::new()
+        }
+        Request::GetProcessFlags { uid, path } => {
+            let flags = if std::mem::take(first) {
+                ProcessFlags::IS_FIRST_PROCESS
+            } else {
+                ProcessFlags::empty()
+            };
+            stream.write_u32(flags.bits())?;
+            format!("uid {} via {:?}", uid, path)
+        }
+        Request::CacheMountNamespace { pid } => format!("pid {}", pid),
+        Request::UpdateMountNamespace {
+            namespace,
+            uid,
+            inherits_clean,
+        } => {
+            stream.write_u32(std::process::id())?;
+            // The namespace of zygote is kept, as when NeoZygisk is turned off
+            stream.write_u32(0)?;
+            format!(
+                "{:?} namespace of uid {}, inherited {}",
+                namespace, uid, inherits_clean
+            )
+        }
+        Request::ReadModules => {
+            stream.write_usize(0)?;
+            String::new()
+        }
+        Request::RequestCompanionSocket { index } | Request::GetModuleDir { index } => {
+            bail!("index {} of a daemon without modules", index)
+        }
+        Request::CreateSocketPair { index, kind } => {
+            bail!(
+                "{:?} socket pair for index {} of a daemon without modules",
+                kind,
+                index
+            )
+        }
+        Request::GetPropertyOverlay { process } => {
+            stream.write_usize(0)?;
+            format!("process {}", process)
+        }
+        Request::DisableAll => {
+            stream.write_u8(1)?;
+            String::new()
+        }
+        Request::Handover { exe } => {
+            stream.write_u8(0)?;
+            format!("to {}", exe)
+        }
+        Request::GetPackageInfo { package } => {
+            stream.write_u8(0)?;
+            format!("package {}", package)
+        }
+        Request::ReportInjection {
+            uid,
+            process,
+            modules,
+            unmount,
+            duration_us,
+        } => {
+            if !modules.is_empty() {
+                bail!(
+                    "{} modules injected by a daemon without modules",
+                    modules.len()
+                );
+            }
+            // Nothing to acknowledge without modules, the end marker alone may follow
+            stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+            match stream.read_usize() {
+                Ok(index) if index != usize::MAX => {
+                    bail!("acknowledged module {} of a daemon without modules", index)
+                }
+                _ => {}
+            }
+            format!(
+                "{} of uid {}, {:?} in {}us",
+                process, uid, unmount, duration_us
+            )
+        }
+        Request::AdjustProcess {
+            module,
+            pid,
+            adjustment,
+            value,
+        } => {
+            stream.write_u8(0)?;
+            format!("{:?} of {} to {} for {}", adjustment, pid, value, module)
+        }
+        Request::RunPrivileged {
+            module, operation, ..
+        } => {
+            stream.write_u8(0)?;
+            stream.write_string("refused by protocheck")?;
+            format!("{:?} for {}", operation, module)
+        }
+        Request::RunScript {
+            module, command, ..
+        } => {
+            stream.write_u8(0)?;
+            stream.write_string("refused by protocheck")?;
+            format!("{:?} for {}", command, module)
+        }
+        Request::ControlSubsystem { control, name } => {
+            stream.write_u8(1)?;
+            stream.write_string("")?;
+            format!("{:?} {}", control, name)
+        }
+        Request::DumpProfile => {
+            stream.write_string("")?;
+            String::new()
+        }
+        Request::RotateSecrets => {
+            stream.write_u8(0)?;
+            String::new()
+        }
+        Request::GetLogSocket => {
+            stream.write_string("")?;
+            String::new()
+        }
+        Request::GetHistory { package } => {
+            stream.write_u8(0)?;
+            stream.write_string("no history kept by protocheck")?;
+            package
+        }
+    })
+}
+
+// Bytes an injector sent past the end of its request
+fn trailing(stream: &mut UnixStream) -> Result<usize> {
+    stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let mut buf = [0u8; 64];
+    match stream.read(&mut buf) {
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(0),
+        result => Ok(result?),
+    }
+}
+
+/// Stand in for the daemon at `path`, printing whether each request received
+/// conforms, until interrupted.
+pub fn serve(path: &str) -> Result<()> {
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    println!("Answering as a daemon without modules at {}", path);
+    let mut first = true;
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let value = match stream.read_u8() {
+            Ok(value) => value,
+            Err(e) => {
+                println!("FAIL no request: {}", e);
+                continue;
+            }
+        };
+        let Ok(action) = DaemonSocketAction::try_from(value) else {
+            println!("FAIL unknown action {}", value);
+            continue;
+        };
+        let result = answer(&mut stream, action, &mut first)
+            .and_then(|detail| Ok((detail, trailing(&mut stream)?)));
+        match result {
+            Ok((detail, 0)) => println!("ok   {:?} {}", action, detail),
+            Ok((detail, extra)) => {
+                println!("FAIL {:?} {}: {} bytes past the end", action, detail, extra)
+            }
+            Err(e) => println!("FAIL {:?}: {}", action, e),
+        }
+    }
+    Ok(())
+}
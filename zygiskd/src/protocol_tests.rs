@@ -0,0 +1,556 @@
+// Property tests of the wire formats spoken by the daemon: the socket protocol
+// with zygote and the CLI, forwarded log records and the handover state.
+//
+// Decoding must either give back exactly what was encoded or fail; it must never
+// panic, which aborts the daemon, nor wait for bytes a broken peer will not send.
+
+use crate::adjust::Adjustment;
+use crate::constants::{CompanionAction, DaemonSocketAction, MountNamespace, SocketPairType};
+use crate::handover::{ModuleState, State};
+use crate::history::UnmountResult;
+use crate::logfwd;
+use crate::privop::Operation;
+use crate::request::{self, Request};
+use crate::scripts::Command;
+use crate::subsystem::Control;
+use crate::utils::{MAX_STRING_SIZE, UnixStreamExt};
+use crate::zygote::SpawnPath;
+use anyhow::Result;
+use proptest::collection::vec;
+use proptest::prelude::*;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Number of DaemonSocketAction variants
+const ACTIONS: u8 = 23;
+
+fn pair() -> (UnixStream, UnixStream) {
+    let (writer, reader) = UnixStream::pair().unwrap();
+    // A decoder waiting for bytes that never come fails the test instead of hanging it
+    reader
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    (writer, reader)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    U8(u8),
+    U32(u32),
+    Usize(usize),
+    Str(String),
+}
+
+fn field() -> impl Strategy<Value = Field> {
+    prop_oneof![
+        any::<u8>().prop_map(Field::U8),
+        any::<u32>().prop_map(Field::U32),
+        any::<usize>().prop_map(Field::Usize),
+        ".{0,256}".prop_map(Field::Str),
+    ]
+}
+
+fn write_field(stream: &mut UnixStream, field: &Field) -> Result<()> {
+    match field {
+        Field::U8(v) => stream.write_u8(*v),
+        Field::U32(v) => stream.write_u32(*v),
+        Field::Usize(v) => stream.write_usize(*v),
+        Field::Str(v) => stream.write_string(v),
+    }
+}
+
+// Read a field of the same type as `like`
+fn read_field(stream: &mut UnixStream, like: &Field) -> Result<Field> {
+    Ok(match like {
+        Field::U8(_) => Field::U8(stream.read_u8()?),
+        Field::U32(_) => Field::U32(stream.read_u32()?),
+        Field::Usize(_) => Field::Usize(stream.read_usize()?),
+        Field::Str(_) => Field::Str(stream.read_string()?),
+    })
+}
+
+fn encode(fields: &[Field]) -> Vec<u8> {
+    let (mut writer, mut reader) = pair();
+    for field in fields {
+        write_field(&mut writer, field).unwrap();
+    }
+    drop(writer);
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).unwrap();
+    bytes
+}
+
+fn spawn_path() -> impl Strategy<Value = SpawnPath> {
+    prop_oneof![
+        Just(SpawnPath::Fork),
+        Just(SpawnPath::Usap),
+        Just(SpawnPath::SystemServer)
+    ]
+}
+
+fn mount_namespace() -> impl Strategy<Value = MountNamespace> {
+    prop_oneof![
+        Just(MountNamespace::Clean),
+        Just(MountNamespace::Root),
+        Just(MountNamespace::Module)
+    ]
+}
+
+fn unmount_result() -> impl Strategy<Value = UnmountResult> {
+    prop_oneof![
+        Just(UnmountResult::Skipped),
+        Just(UnmountResult::Unmounted),
+        Just(UnmountResult::Failed),
+        Just(UnmountResult::Root),
+        Just(UnmountResult::Module)
+    ]
+}
+
+fn socket_pair_type() -> impl Strategy<Value = SocketPairType> {
+    prop_oneof![
+        Just(SocketPairType::Stream),
+        Just(SocketPairType::Datagram),
+        Just(SocketPairType::SeqPacket)
+    ]
+}
+
+fn adjustment() -> impl Strategy<Value = Adjustment> {
+    prop_oneof![
+        Just(Adjustment::Nice),
+        Just(Adjustment::OomScoreAdj),
+        Just(Adjustment::Cgroup)
+    ]
+}
+
+fn operation() -> impl Strategy<Value = Operation> {
+    prop_oneof![
+        Just(Operation::CopyFile),
+        Just(Operation::GetEnforce),
+        Just(Operation::SetProperty)
+    ]
+}
+
+fn command() -> impl Strategy<Value = Command> {
+    prop_oneof![
+        Just(Command::DenyStatus),
+        Just(Command::SetProperty),
+        Just(Command::ListModules),
+        Just(Command::History)
+    ]
+}
+
+fn control() -> impl Strategy<Value = Control> {
+    prop_oneof![
+        Just(Control::List),
+        Just(Control::Start),
+        Just(Control::Stop),
+        Just(Control::Reload)
+    ]
+}
+
+fn request() -> impl Strategy<Value = Request> {
+    prop_oneof![
+        Just(Request::PingHeartbeat),
+        (any::<u32>(), spawn_path()).prop_map(|(uid, path)| Request::GetProcessFlags { uid, path }),
+        any::<u32>().prop_map(|pid| Request::CacheMountNamespace { pid }),
+        (mount_namespace(), any::<u32>(), any::<bool>()).prop_map(
+            |(namespace, uid, inherits_clean)| Request::UpdateMountNamespace {
+                namespace,
+                uid,
+                inherits_clean
+            }
+        ),
+        Just(Request::ReadModules),
+        any::<usize>().prop_map(|index| Request::RequestCompanionSocket { index }),
+        any::<usize>().prop_map(|index| Request::GetModuleDir { index }),
+        Just(Request::ZygoteRestart),
+        Just(Request::SystemServerStarted),
+        "[a-z.:_]{0,128}".prop_map(|process| Request::GetPropertyOverlay { process }),
+        Just(Request::DisableAll),
+        "/.{0,128}".prop_map(|exe| Request::Handover { exe }),
+        "[a-z0-9._]{1,64}".prop_map(|package| Request::GetPackageInfo { package }),
+        (
+            any::<u32>(),
+            "[a-z.:_]{0,128}",
+            vec(any::<usize>(), 0..8),
+            unmount_result(),
+            any::<u32>()
+        )
+            .prop_map(|(uid, process, modules, unmount, duration_us)| {
+                Request::ReportInjection {
+                    uid,
+                    process,
+                    modules,
+                    unmount,
+                    duration_us,
+                }
+            }),
+        (
+            "[A-Za-z0-9_.-]{1,32}",
+            any::<u32>(),
+            adjustment(),
+            "[a-z0-9-]{0,20}"
+        )
+            .prop_map(|(module, pid, adjustment, value)| Request::AdjustProcess {
+                module,
+                pid,
+                adjustment,
+                value,
+            }),
+        (any::<usize>(), socket_pair_type())
+            .prop_map(|(index, kind)| Request::CreateSocketPair { index, kind }),
+        ("[A-Za-z0-9_.-]{1,32}", operation(), ".{0,128}", ".{0,128}").prop_map(
+            |(module, operation, arg0, arg1)| Request::RunPrivileged {
+                module,
+                operation,
+                args: [arg0, arg1],
+            }
+        ),
+        Just(Request::DumpProfile),
+        Just(Request::RotateSecrets),
+        (
+            "[0-9a-f]{0,32}",
+            "[A-Za-z0-9_.-]{1,32}",
+            command(),
+            ".{0,128}",
+            ".{0,128}"
+        )
+            .prop_map(|(token, module, command, arg0, arg1)| Request::RunScript {
+                token,
+                module,
+                command,
+                args: [arg0, arg1],
+            }),
+        (control(), "[a-z]{0,16}")
+            .prop_map(|(control, name)| Request::ControlSubsystem { control, name }),
+        Just(Request::GetLogSocket),
+        "([a-z][a-z0-9_.]{0,31})?".prop_map(|package| Request::GetHistory { package }),
+    ]
+}
+
+// Encoded as the injector (daemon.cpp) and the CLI write requests
+fn write_request(stream: &mut UnixStream, request: &Request) -> Result<()> {
+    let action = match request {
+        Request::PingHeartbeat => DaemonSocketAction::PingHeartbeat,
+        Request::GetProcessFlags { .. } => DaemonSocketAction::GetProcessFlags,
+        Request::CacheMountNamespace { .. } => DaemonSocketAction::CacheMountNamespace,
+        Request::UpdateMountNamespace { .. } => DaemonSocketAction::UpdateMountNamespace,
+        Request::ReadModules => DaemonSocketAction::ReadModules,
+        Request::RequestCompanionSocket { .. } => DaemonSocketAction::RequestCompanionSocket,
+        Request::GetModuleDir { .. } => DaemonSocketAction::GetModuleDir,
+        Request::ZygoteRestart => DaemonSocketAction::ZygoteRestart,
+        Request::SystemServerStarted => DaemonSocketAction::SystemServerStarted,
+        Request::GetPropertyOverlay { .. } => DaemonSocketAction::GetPropertyOverlay,
+        Request::DisableAll => DaemonSocketAction::DisableAll,
+        Request::Handover { .. } => DaemonSocketAction::Handover,
+        Request::GetPackageInfo { .. } => DaemonSocketAction::GetPackageInfo,
+        Request::ReportInjection { .. } => DaemonSocketAction::ReportInjection,
+        Request::AdjustProcess { .. } => DaemonSocketAction::AdjustProcess,
+        Request::CreateSocketPair { .. } => DaemonSocketAction::CreateSocketPair,
+        Request::RunPrivileged { .. } => DaemonSocketAction::RunPrivileged,
+        Request::DumpProfile => DaemonSocketAction::DumpProfile,
+        Request::RotateSecrets => DaemonSocketAction::RotateSecrets,
+        Request::RunScript { .. } => DaemonSocketAction::RunScript,
+        Request::ControlSubsystem { .. } => DaemonSocketAction::ControlSubsystem,
+        Request::GetLogSocket => DaemonSocketAction::GetLogSocket,
+        Request::GetHistory { .. } => DaemonSocketAction::GetHistory,
+    };
+    stream.write_u8(action as u8)?;
+    match request {
+        Request::GetProcessFlags { uid, path } => {
+            stream.write_u32(*uid)?;
+            stream.write_u8(*path as u8)
+        }
+        Request::CacheMountNamespace { pid } => stream.write_u32(*pid),
+        Request::UpdateMountNamespace {
+            namespace,
+            uid,
+            inherits_clean,
+        } => {
+            stream.write_u8(*namespace as u8)?;
+            stream.write_u32(*uid)?;
+            stream.write_u8(*inherits_clean as u8)
+        }
+        Request::RequestCompanionSocket { index } | Request::GetModuleDir { index } => {
+            stream.write_usize(*index)
+        }
+        Request::GetPropertyOverlay { process } | Request::GetPackageInfo { package: process } => {
+            stream.write_string(process)
+        }
+        Request::Handover { exe } | Request::GetHistory { package: exe } => {
+            stream.write_string(exe)
+        }
+        Request::ReportInjection {
+            uid,
+            process,
+            modules,
+            unmount,
+            duration_us,
+        } => {
+            stream.write_u32(*uid)?;
+            stream.write_string(process)?;
+            stream.write_usize(modules.len())?;
+            for module in modules {
+                stream.write_usize(*module)?;
+            }
+            stream.write_u8(*unmount as u8)?;
+            stream.write_u32(*duration_us)
+        }
+        Request::AdjustProcess {
+            module,
+            pid,
+            adjustment,
+            value,
+        } => {
+            stream.write_string(module)?;
+            stream.write_u32(*pid)?;
+            stream.write_u8(*adjustment as u8)?;
+            stream.write_string(value)
+        }
+        Request::CreateSocketPair { index, kind } => {
+            stream.write_usize(*index)?;
+            stream.write_u8(*kind as u8)
+        }
+        Request::RunPrivileged {
+            module,
+            operation,
+            args,
+        } => {
+            stream.write_string(module)?;
+            stream.write_u8(*operation as u8)?;
+            stream.write_string(&args[0])?;
+            stream.write_string(&args[1])
+        }
+        Request::RunScript {
+            token,
+            module,
+            command,
+            args,
+        } => {
+            stream.write_string(token)?;
+            stream.write_string(module)?;
+            stream.write_u8(*command as u8)?;
+            stream.write_string(&args[0])?;
+            stream.write_string(&args[1])
+        }
+        Request::ControlSubsystem { control, name } => {
+            stream.write_u8(*control as u8)?;
+            stream.write_string(name)
+        }
+        _ => Ok(()),
+    }
+}
+
+// Decoded by the daemon itself, past the action byte
+fn read_request(stream: &mut UnixStream) -> Result<Request> {
+    let action = DaemonSocketAction::try_from(stream.read_u8()?)?;
+    request::read_fields(action, stream)
+}
+
+fn same_request(a: &Request, b: &Request) -> bool {
+    format!("{:?}", a) == format!("{:?}", b)
+}
+
+fn module_state() -> impl Strategy<Value = ModuleState> {
+    (
+        "[A-Za-z0-9_.-]{1,32}",
+        0..1024i32,
+        proptest::option::of(0..1024i32),
+        proptest::option::of(1..i32::MAX),
+    )
+        .prop_map(|(name, lib_fd, companion_fd, companion_pid)| ModuleState {
+            name,
+            lib_fd,
+            companion_fd,
+            companion_pid,
+        })
+}
+
+fn handover_state() -> impl Strategy<Value = State> {
+    (
+        "[A-Za-z0-9_.-]{1,64}",
+        0..1024i32,
+        proptest::option::of("/[A-Za-z0-9_/]{1,64}"),
+        any::<bool>(),
+        any::<bool>(),
+        vec(module_state(), 0..8),
+        vec((0..3u8, "[a-z-]{0,20}", 0..1024i32), 0..8),
+    )
+        .prop_map(
+            |(version, listener_fd, tmpdir, first_process_seen, disabled, modules, namespaces)| {
+                State {
+                    version,
+                    listener_fd,
+                    tmpdir: tmpdir.map(PathBuf::from),
+                    first_process_seen,
+                    disabled,
+                    modules,
+                    namespaces,
+                }
+            },
+        )
+}
+
+proptest! {
+    #[test]
+    fn fields_round_trip(fields in vec(field(), 0..32)) {
+        let (mut writer, mut reader) = pair();
+        for field in &fields {
+            write_field(&mut writer, field).unwrap();
+        }
+        for field in &fields {
+            prop_assert_eq!(&read_field(&mut reader, field).unwrap(), field);
+        }
+    }
+
+    #[test]
+    fn truncated_fields_fail(
+        fields in vec(field(), 1..16),
+        cut in any::<prop::sample::Index>(),
+    ) {
+        let bytes = encode(&fields);
+        prop_assume!(!bytes.is_empty());
+        let (mut writer, mut reader) = pair();
+        writer.write_all(&bytes[..cut.index(bytes.len())]).unwrap();
+        drop(writer);
+        let decoded: Result<Vec<Field>> =
+            fields.iter().map(|f| read_field(&mut reader, f)).collect();
+        prop_assert!(decoded.is_err());
+    }
+
+    #[test]
+    fn oversized_strings_are_rejected(len in (MAX_STRING_SIZE + 1)..=usize::MAX) {
+        let (mut writer, mut reader) = pair();
+        writer.write_usize(len).unwrap();
+        drop(writer);
+        prop_assert!(reader.read_string().is_err());
+    }
+
+    #[test]
+    fn invalid_utf8_strings_are_rejected(mut bytes in vec(any::<u8>(), 1..64)) {
+        bytes[0] = 0xff;
+        let (mut writer, mut reader) = pair();
+        writer.write_usize(bytes.len()).unwrap();
+        writer.write_all(&bytes).unwrap();
+        prop_assert!(reader.read_string().is_err());
+    }
+
+    #[test]
+    fn requests_round_trip(requests in vec(request(), 1..16)) {
+        let (mut writer, mut reader) = pair();
+        for request in &requests {
+            write_request(&mut writer, request).unwrap();
+        }
+        for request in &requests {
+            let decoded = read_request(&mut reader).unwrap();
+            prop_assert!(same_request(&decoded, request), "{:?} != {:?}", decoded, request);
+        }
+    }
+
+    #[test]
+    fn truncated_requests_fail(request in request(), cut in any::<prop::sample::Index>()) {
+        let (mut writer, mut reader) = pair();
+        write_request(&mut writer, &request).unwrap();
+        drop(writer);
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).unwrap();
+        let (mut writer, mut reader) = pair();
+        writer.write_all(&bytes[..cut.index(bytes.len())]).unwrap();
+        drop(writer);
+        prop_assert!(read_request(&mut reader).is_err());
+    }
+
+    #[test]
+    fn oversized_module_counts_are_rejected(count in (request::MAX_MODULES + 1)..=usize::MAX) {
+        let (mut writer, mut reader) = pair();
+        writer.write_u32(10000).unwrap();
+        writer.write_string("com.example").unwrap();
+        writer.write_usize(count).unwrap();
+        // Kept open: the count alone must fail, not the end of the stream
+        let decoded = request::read_fields(DaemonSocketAction::ReportInjection, &mut reader);
+        prop_assert!(decoded.is_err());
+    }
+
+    #[test]
+    fn arbitrary_request_bytes_never_panic(bytes in vec(any::<u8>(), 0..512)) {
+        let (mut writer, mut reader) = pair();
+        writer.write_all(&bytes).unwrap();
+        drop(writer);
+        while read_request(&mut reader).is_ok() {}
+    }
+
+    #[test]
+    fn message_types_match_their_values(value in any::<u8>()) {
+        match DaemonSocketAction::try_from(value) {
+            Ok(action) => prop_assert_eq!(action as u8, value),
+            Err(_) => prop_assert!(value >= ACTIONS),
+        }
+        if let Ok(action) = CompanionAction::try_from(value) {
+            prop_assert_eq!(action as u8, value);
+        }
+        if let Ok(namespace) = MountNamespace::try_from(value) {
+            prop_assert_eq!(namespace as u8, value);
+        }
+        if let Ok(path) = SpawnPath::try_from(value) {
+            prop_assert_eq!(path as u8, value);
+        }
+        if let Ok(unmount) = UnmountResult::try_from(value) {
+            prop_assert_eq!(unmount as u8, value);
+        }
+        if let Ok(adjustment) = Adjustment::try_from(value) {
+            prop_assert_eq!(adjustment as u8, value);
+        }
+        if let Ok(kind) = SocketPairType::try_from(value) {
+            prop_assert_eq!(kind as u8, value);
+        }
+        if let Ok(operation) = Operation::try_from(value) {
+            prop_assert_eq!(operation as u8, value);
+        }
+        if let Ok(command) = Command::try_from(value) {
+            prop_assert_eq!(command as u8, value);
+        }
+        if let Ok(control) = Control::try_from(value) {
+            prop_assert_eq!(control as u8, value);
+        }
+    }
+
+    #[test]
+    fn log_records_round_trip(
+        priority in any::<u8>(),
+        pid in any::<u32>(),
+        lost in any::<u32>(),
+        tag in "[^\0]{0,32}",
+        message in vec(any::<u8>(), 0..256),
+    ) {
+        let mut buf = vec![priority];
+        buf.extend_from_slice(&pid.to_ne_bytes());
+        buf.extend_from_slice(&lost.to_ne_bytes());
+        buf.extend_from_slice(tag.as_bytes());
+        buf.push(0);
+        buf.extend_from_slice(&message);
+        let record = logfwd::parse_record(&buf).unwrap();
+        prop_assert_eq!(record.priority, priority);
+        prop_assert_eq!(record.pid, pid);
+        prop_assert_eq!(record.lost, lost);
+        prop_assert_eq!(record.tag, tag.as_bytes());
+        prop_assert_eq!(record.message, &message[..]);
+    }
+
+    #[test]
+    fn arbitrary_log_records_never_panic(bytes in vec(any::<u8>(), 0..64)) {
+        let record = logfwd::parse_record(&bytes);
+        prop_assert_eq!(record.is_some(), bytes.len() >= 9);
+    }
+
+    #[test]
+    fn handover_state_round_trips(state in handover_state()) {
+        prop_assert_eq!(State::decode(&state.encode()).unwrap(), state);
+    }
+
+    #[test]
+    fn arbitrary_handover_state_never_panics(content in "(.{0,32}(\t.{0,16}){0,4}\n){0,8}") {
+        let _ = State::decode(&content);
+    }
+}
@@ -0,0 +1,297 @@
+use crate::constants::{PATH_DATA_DIR, PATH_MODULES_DIR};
+use crate::zygiskd::ModuleMarker;
+use crate::{audit, blackbox, config, dryrun, utils};
+use anyhow::{Result, bail};
+use log::{info, warn};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Modules crashing the apps they are injected in are quarantined with the
+// `disable` marker of the root manager, so that the user undoes it like any
+// other disabled module. Their state is kept in a file per module, which the
+// `quarantine` command reads and edits while the daemon runs.
+const DIRECTORY: &str = "quarantine";
+
+/// What happens to a module crashing apps, set by the `quarantine` config key.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Mode {
+    /// Crashes are only logged and audited
+    Off,
+    /// The module is disabled once it crashed `quarantineCrashes` times
+    Immediate,
+    /// The quarantine is proposed in the status first, and only done after
+    /// `quarantineGraceCrashes` more crashes or `quarantineTimeout` without
+    /// the user either disabling the module or keeping it
+    Grace,
+}
+
+pub const MODES: &[(&str, Mode)] = &[
+    ("off", Mode::Off),
+    ("immediate", Mode::Immediate),
+    ("grace", Mode::Grace),
+];
+
+/// Quarantine state of a module, as shown in the status.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Entry {
+    Pending { module: String, crashes: u32 },
+    Quarantined { module: String, crashes: u32 },
+}
+
+#[derive(Default)]
+struct State {
+    /// Boot the crashes were counted in
+    boot: String,
+    crashes: u32,
+    /// Seconds since the epoch when the quarantine was proposed
+    pending_since: Option<u64>,
+    grace_crashes: u32,
+    quarantined: bool,
+}
+
+impl State {
+    fn encode(&self) -> String {
+        let mut out = format!("boot={}\ncrashes={}\n", self.boot, self.crashes);
+        if let Some(since) = self.pending_since {
+            out.push_str(&format!(
+                "pending={}\ngraceCrashes={}\n",
+                since, self.grace_crashes
+            ));
+        }
+        if self.quarantined {
+            out.push_str("quarantined=1\n");
+        }
+        out
+    }
+
+    fn decode(content: &str) -> State {
+        let mut state = State::default();
+        for (key, value) in content.lines().filter_map(|line| line.split_once('=')) {
+            match key {
+                "boot" => state.boot = value.to_string(),
+                "crashes" => state.crashes = value.parse().unwrap_or(0),
+                "pending" => state.pending_since = value.parse().ok(),
+                "graceCrashes" => state.grace_crashes = value.parse().unwrap_or(0),
+                "quarantined" => state.quarantined = value == "1",
+                _ => {}
+            }
+        }
+        state
+    }
+}
+
+// Per daemon; both daemons writing the same module at once only loses a crash
+static LOCK: Mutex<()> = Mutex::new(());
+
+// Tests keep their states out of the data directory of a device
+fn directory() -> PathBuf {
+    if cfg!(test) {
+        std::env::temp_dir().join(format!("neozygisk-{}-{}", DIRECTORY, std::process::id()))
+    } else {
+        PathBuf::from(PATH_DATA_DIR).join(DIRECTORY)
+    }
+}
+
+fn path(module: &str) -> PathBuf {
+    directory().join(module)
+}
+
+fn load(module: &str) -> Option<State> {
+    fs::read_to_string(path(module))
+        .ok()
+        .map(|content| State::decode(&content))
+}
+
+fn save(module: &str, state: &State) -> Result<()> {
+    if dryrun::skip(|| format!("record {} crashes of {}", state.crashes, module)) {
+        return Ok(());
+    }
+    fs::create_dir_all(directory())?;
+    utils::write_atomic(&path(module), state.encode().as_bytes())
+}
+
+fn forget(module: &str) {
+    let _ = fs::remove_file(path(module));
+}
+
+fn modules() -> Vec<String> {
+    let Ok(entries) = fs::read_dir(directory()) else {
+        return Vec::new();
+    };
+    let mut modules: Vec<String> = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| !name.starts_with('.'))
+        .collect();
+    modules.sort();
+    modules
+}
+
+fn marker(module: &str) -> Option<ModuleMarker> {
+    ModuleMarker::of(&PathBuf::from(PATH_MODULES_DIR).join(module))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+fn quarantine(module: &str, state: &mut State, reason: &str) -> Result<()> {
+    warn!("Quarantining module `{}`: {}", module, reason);
+    audit::record(&format!("quarantine module={} reason={}", module, reason));
+    blackbox::record(&format!("quarantined {}", module));
+    state.pending_since = None;
+    state.quarantined = true;
+    let marker = PathBuf::from(PATH_MODULES_DIR).join(module).join("disable");
+    if !dryrun::skip(|| format!("create {}", marker.display())) {
+        utils::write_atomic(&marker, b"")?;
+    }
+    save(module, state)
+}
+
+fn current_boot() -> String {
+    utils::boot_id().unwrap_or_default()
+}
+
+/// Forget crashes of past boots, and the quarantines the user undid by
+/// enabling the module again. Crashes counted earlier in this boot, by a
+/// daemon since restarted, are kept.
+pub fn setup() {
+    let boot = current_boot();
+    let _guard = LOCK.lock().unwrap();
+    for module in modules() {
+        match load(&module) {
+            Some(state) if state.pending_since.is_some() => {}
+            Some(state) if state.quarantined && marker(&module).is_some() => {}
+            Some(state) if !state.quarantined && !boot.is_empty() && state.boot == boot => {}
+            _ => forget(&module),
+        }
+    }
+}
+
+/// Count a crash of an app caused by `module`, returning whether its entry
+/// in the status changed.
+pub fn record_crash(module: &str, process: &str) -> bool {
+    let config = config::get();
+    if config.quarantine == Mode::Off {
+        return false;
+    }
+    let _guard = LOCK.lock().unwrap();
+    let mut state = load(module).unwrap_or_default();
+    if state.quarantined {
+        return false;
+    }
+    state.boot = current_boot();
+    state.crashes += 1;
+    let result = match state.pending_since {
+        None if state.crashes < config.quarantine_crashes => save(module, &state).map(|_| false),
+        None if config.quarantine == Mode::Immediate => {
+            let reason = format!("crashed {} times, last in {}", state.crashes, process);
+            quarantine(module, &mut state, &reason).map(|_| true)
+        }
+        None => {
+            info!(
+                "Proposing to quarantine module `{}` after {} crashes",
+                module, state.crashes
+            );
+            audit::record(&format!("quarantine pending module={}", module));
+            state.pending_since = Some(now());
+            save(module, &state).map(|_| true)
+        }
+        Some(_) => {
+            state.grace_crashes += 1;
+            if state.grace_crashes >= config.quarantine_grace_crashes {
+                let reason = format!("crashed {} more times while pending", state.grace_crashes);
+                quarantine(module, &mut state, &reason).map(|_| true)
+            } else {
+                save(module, &state).map(|_| true)
+            }
+        }
+    };
+    result.unwrap_or_else(|e| {
+        warn!("Failed to record the crash of module `{}`: {}", module, e);
+        false
+    })
+}
+
+/// Settle pending quarantines the user answered by disabling the module,
+/// and do those left unanswered for `quarantineTimeout`.
+pub fn expire() {
+    let timeout = config::get().quarantine_timeout;
+    let _guard = LOCK.lock().unwrap();
+    for module in modules() {
+        let Some(mut state) = load(&module) else {
+            continue;
+        };
+        let Some(since) = state.pending_since else {
+            continue;
+        };
+        if marker(&module).is_some() {
+            audit::record(&format!("quarantine confirmed module={}", module));
+            forget(&module);
+        } else if Duration::from_secs(now().saturating_sub(since)) >= timeout {
+            let reason = format!("no answer within {}s", timeout.as_secs());
+            if let Err(e) = quarantine(&module, &mut state, &reason) {
+                warn!("Failed to quarantine module `{}`: {}", module, e);
+            }
+        }
+    }
+}
+
+/// Modules with a pending or done quarantine.
+pub fn entries() -> Vec<Entry> {
+    modules()
+        .into_iter()
+        .filter_map(|module| {
+            let state = load(&module)?;
+            let crashes = state.crashes;
+            match (state.pending_since, state.quarantined) {
+                (_, true) => Some(Entry::Quarantined { module, crashes }),
+                (Some(_), _) => Some(Entry::Pending { module, crashes }),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Answer a pending quarantine of `module` by keeping the module enabled,
+/// counting its crashes afresh.
+pub fn keep(module: &str) -> Result<()> {
+    if module.is_empty() || module.contains('/') || module.starts_with('.') {
+        bail!("invalid module name `{}`", module);
+    }
+    let _guard = LOCK.lock().unwrap();
+    match load(module) {
+        Some(state) if state.pending_since.is_some() => {
+            if !dryrun::skip(|| format!("keep {}", module)) {
+                audit::record(&format!("quarantine dismissed module={}", module));
+                forget(module);
+            }
+            Ok(())
+        }
+        _ => bail!("no quarantine of `{}` is pending", module),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As run by `zygiskd quarantine keep`, without any of the daemon setup
+    #[test]
+    fn keep_without_daemon() {
+        let state = State {
+            crashes: 3,
+            pending_since: Some(now()),
+            ..State::default()
+        };
+        save("crashing", &state).unwrap();
+        assert!(matches!(entries().as_slice(), [Entry::Pending { .. }]));
+        keep("crashing").unwrap();
+        assert!(entries().is_empty());
+        assert!(keep("crashing").is_err());
+        let _ = fs::remove_dir_all(directory());
+    }
+}
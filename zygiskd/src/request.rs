@@ -0,0 +1,181 @@
+use crate::adjust::Adjustment;
+use crate::constants::{DaemonSocketAction, MountNamespace, SocketPairType};
+use crate::history::UnmountResult;
+use crate::privop::Operation;
+use crate::scripts::Command;
+use crate::subsystem::Control;
+use crate::utils::UnixStreamExt;
+use crate::zygote::SpawnPath;
+use anyhow::{Result, bail};
+use std::os::unix::net::UnixStream;
+
+// Requests of the daemon socket as sent by the injector (daemon.cpp) and the CLI,
+// with their fields in the order they are written. What the daemon answers, and
+// what some requests exchange afterwards, is handled where they are served.
+
+// More modules than any device loads, so that a bogus count fails at once
+pub const MAX_MODULES: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub enum Request {
+    PingHeartbeat,
+    GetProcessFlags {
+        uid: u32,
+        path: SpawnPath,
+    },
+    CacheMountNamespace {
+        pid: u32,
+    },
+    UpdateMountNamespace {
+        namespace: MountNamespace,
+        /// u32::MAX stands for zygote itself
+        uid: u32,
+        /// Forked after zygote switched to its Clean namespace
+        inherits_clean: bool,
+    },
+    ReadModules,
+    RequestCompanionSocket {
+        index: usize,
+    },
+    GetModuleDir {
+        index: usize,
+    },
+    ZygoteRestart,
+    SystemServerStarted,
+    GetPropertyOverlay {
+        process: String,
+    },
+    DisableAll,
+    Handover {
+        exe: String,
+    },
+    GetPackageInfo {
+        package: String,
+    },
+    ReportInjection {
+        uid: u32,
+        process: String,
+        modules: Vec<usize>,
+        unmount: UnmountResult,
+        duration_us: u32,
+    },
+    AdjustProcess {
+        module: String,
+        pid: u32,
+        adjustment: Adjustment,
+        value: String,
+    },
+    CreateSocketPair {
+        index: usize,
+        kind: SocketPairType,
+    },
+    RunPrivileged {
+        module: String,
+        operation: Operation,
+        args: [String; 2],
+    },
+    DumpProfile,
+    RotateSecrets,
+    RunScript {
+        token: String,
+        module: String,
+        command: Command,
+        args: [String; 2],
+    },
+    ControlSubsystem {
+        control: Control,
+        name: String,
+    },
+    GetLogSocket,
+    GetHistory {
+        /// Empty to list the packages with a history
+        package: String,
+    },
+}
+
+/// Read the fields of a request for `action`, whose byte was already read.
+pub fn read_fields(action: DaemonSocketAction, stream: &mut UnixStream) -> Result<Request> {
+    Ok(match action {
+        DaemonSocketAction::PingHeartbeat => Request::PingHeartbeat,
+        DaemonSocketAction::GetProcessFlags => Request::GetProcessFlags {
+            uid: stream.read_u32()?,
+            path: SpawnPath::try_from(stream.read_u8()?)?,
+        },
+        DaemonSocketAction::CacheMountNamespace => Request::CacheMountNamespace {
+            pid: stream.read_u32()?,
+        },
+        DaemonSocketAction::UpdateMountNamespace => Request::UpdateMountNamespace {
+            namespace: MountNamespace::try_from(stream.read_u8()?)?,
+            uid: stream.read_u32()?,
+            inherits_clean: stream.read_u8()? == 1,
+        },
+        DaemonSocketAction::ReadModules => Request::ReadModules,
+        DaemonSocketAction::RequestCompanionSocket => Request::RequestCompanionSocket {
+            index: stream.read_usize()?,
+        },
+        DaemonSocketAction::GetModuleDir => Request::GetModuleDir {
+            index: stream.read_usize()?,
+        },
+        DaemonSocketAction::ZygoteRestart => Request::ZygoteRestart,
+        DaemonSocketAction::SystemServerStarted => Request::SystemServerStarted,
+        DaemonSocketAction::GetPropertyOverlay => Request::GetPropertyOverlay {
+            process: stream.read_string()?,
+        },
+        DaemonSocketAction::DisableAll => Request::DisableAll,
+        DaemonSocketAction::Handover => Request::Handover {
+            exe: stream.read_string()?,
+        },
+        DaemonSocketAction::GetPackageInfo => Request::GetPackageInfo {
+            package: stream.read_string()?,
+        },
+        DaemonSocketAction::ReportInjection => {
+            let uid = stream.read_u32()?;
+            let process = stream.read_string()?;
+            let count = stream.read_usize()?;
+            if count > MAX_MODULES {
+                bail!("{} modules reported", count);
+            }
+            let modules = (0..count)
+                .map(|_| stream.read_usize())
+                .collect::<Result<Vec<_>>>()?;
+            Request::ReportInjection {
+                uid,
+                process,
+                modules,
+                unmount: UnmountResult::try_from(stream.read_u8()?)?,
+                duration_us: stream.read_u32()?,
+            }
+        }
+        DaemonSocketAction::AdjustProcess => Request::AdjustProcess {
+            module: stream.read_string()?,
+            pid: stream.read_u32()?,
+            adjustment: Adjustment::try_from(stream.read_u8()?)?,
+            value: stream.read_string()?,
+        },
+        DaemonSocketAction::CreateSocketPair => Request::CreateSocketPair {
+            index: stream.read_usize()?,
+            kind: SocketPairType::try_from(stream.read_u8()?)?,
+        },
+        DaemonSocketAction::RunPrivileged => Request::RunPrivileged {
+            module: stream.read_string()?,
+            operation: Operation::try_from(stream.read_u8()?)?,
+            args: [stream.read_string()?, stream.read_string()?],
+        },
+        DaemonSocketAction::DumpProfile => Request::DumpProfile,
+        DaemonSocketAction::RotateSecrets => Request::RotateSecrets,
+        DaemonSocketAction::RunScript => Request::RunScript {
+            token: stream.read_string()?,
+            module: stream.read_string()?,
+            command: Command::try_from(stream.read_u8()?)?,
+            args: [stream.read_string()?, stream.read_string()?],
+        },
+        DaemonSocketAction::ControlSubsystem => Request::ControlSubsystem {
+            control: Control::try_from(stream.read_u8()?)?,
+            name: stream.read_string()?,
+        },
+        DaemonSocketAction::GetLogSocket => Request::GetLogSocket,
+        DaemonSocketAction::GetHistory => Request::GetHistory {
+            package: stream.read_string()?,
+        },
+    })
+}
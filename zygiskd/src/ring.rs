@@ -0,0 +1,86 @@
+use crate::utils;
+use anyhow::{Result, bail};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// A file of fixed size records overwriting the oldest one once full.
+// Layout: magic, next slot (u32), then `slots` records of `slot_size` bytes.
+const HEADER_SIZE: u64 = 8;
+
+pub struct Layout {
+    pub magic: &'static [u8; 4],
+    pub slots: u32,
+    pub slot_size: usize,
+}
+
+pub struct Ring {
+    path: PathBuf,
+    layout: &'static Layout,
+}
+
+impl Ring {
+    pub fn open(path: &Path, layout: &'static Layout) -> Result<Ring> {
+        let ring = Ring {
+            path: path.to_path_buf(),
+            layout,
+        };
+        if ring.load().is_none() {
+            // A new or unreadable ring is replaced whole, never left half initialized
+            let mut empty = vec![0u8; ring.size()];
+            empty[..4].copy_from_slice(layout.magic);
+            utils::write_atomic(path, &empty)?;
+        }
+        Ok(ring)
+    }
+
+    fn size(&self) -> usize {
+        HEADER_SIZE as usize + self.layout.slots as usize * self.layout.slot_size
+    }
+
+    fn load(&self) -> Option<Vec<u8>> {
+        fs::read(&self.path)
+            .ok()
+            .filter(|content| content.len() == self.size() && &content[..4] == self.layout.magic)
+    }
+
+    /// Overwrite the oldest record with `record`, truncated to fit its slot.
+    ///
+    /// The ring is written again whole and renamed over the previous one, so
+    /// that a power loss leaves either ring, never a torn record.
+    pub fn push(&mut self, record: &str) -> Result<()> {
+        let Some(mut content) = self.load() else {
+            bail!("{} is not a record ring", self.path.display());
+        };
+        let next = u32::from_ne_bytes(content[4..8].try_into().unwrap()) % self.layout.slots;
+        let start = HEADER_SIZE as usize + next as usize * self.layout.slot_size;
+        let slot = &mut content[start..start + self.layout.slot_size];
+        let len = record.len().min(self.layout.slot_size - 1);
+        slot.fill(0);
+        slot[..len].copy_from_slice(&record.as_bytes()[..len]);
+        let next = (next + 1) % self.layout.slots;
+        content[4..8].copy_from_slice(&next.to_ne_bytes());
+        utils::write_atomic(&self.path, &content)
+    }
+}
+
+/// Records of the ring at `path`, oldest first.
+pub fn read(path: &Path, layout: &Layout) -> Result<Vec<String>> {
+    let content = fs::read(path)?;
+    if content.len() < HEADER_SIZE as usize || &content[..4] != layout.magic {
+        bail!("{} is not a record ring", path.display());
+    }
+    let next = u32::from_ne_bytes(content[4..8].try_into().unwrap()) % layout.slots;
+    let mut records = Vec::new();
+    for i in 0..layout.slots {
+        let index = ((next + i) % layout.slots) as usize;
+        let start = HEADER_SIZE as usize + index * layout.slot_size;
+        let Some(slot) = content.get(start..start + layout.slot_size) else {
+            continue;
+        };
+        let end = slot.iter().position(|&b| b == 0).unwrap_or(slot.len());
+        if end > 0 {
+            records.push(String::from_utf8_lossy(&slot[..end]).to_string());
+        }
+    }
+    Ok(records)
+}
@@ -1,12 +1,11 @@
-use std::{
-    fs::File,
-    io::{BufRead, BufReader},
-    process::{Command, Stdio},
-};
+use std::fs;
+use std::process::{Command, Stdio};
 
 use log::debug;
 
 use crate::constants::MIN_APATCH_VERSION;
+use crate::utils::LateInit;
+use crate::version;
 
 const CONFIG_FILE: &str = "/data/adb/ap/package_config";
 
@@ -25,6 +24,8 @@ struct PackageInfo {
     sctx: String,
 }
 
+static VERSION: LateInit<version::Version> = LateInit::new();
+
 pub fn get_apatch() -> Option<Version> {
     Command::new("apd")
         .arg("-V")
@@ -38,11 +39,15 @@ pub fn get_apatch() -> Option<Version> {
             if parts.len() != 2 {
                 None
             } else {
-                parts[1].parse::<i32>().ok()
+                parts[1].parse::<version::Version>().ok()
             }
         })
         .map(|version| {
-            if version >= MIN_APATCH_VERSION {
+            let supported = version.at_least(&MIN_APATCH_VERSION.into());
+            if !VERSION.initiated() {
+                VERSION.init(version);
+            }
+            if supported {
                 Version::Supported
             } else {
                 Version::TooOld
@@ -50,23 +55,17 @@ pub fn get_apatch() -> Option<Version> {
         })
 }
 
-fn parse_config_file(filename: &str) -> Result<Vec<PackageInfo>, String> {
-    let file = File::open(filename).map_err(|e| format!("Failed to open file: {}", e))?;
-    let mut reader = BufReader::new(file);
+pub fn version() -> Option<version::Version> {
+    VERSION.initiated().then(|| (*VERSION).clone())
+}
 
-    // Skip the header row
-    let mut line = String::new();
-    reader
-        .read_line(&mut line)
-        .map_err(|e| format!("Failed to read header: {}", e))?;
-    line.clear();
+fn parse_config_file(filename: &str) -> Result<Vec<PackageInfo>, String> {
+    let content = fs::read(filename).map_err(|e| format!("Failed to read file: {}", e))?;
+    let content = String::from_utf8_lossy(&content);
 
     let mut result = Vec::new();
-    while let Ok(bytes_read) = reader.read_line(&mut line) {
-        if bytes_read == 0 {
-            break; // Reached end of file
-        }
-
+    // Skip the header row
+    for line in content.lines().skip(1) {
         let mut parts = line.trim().split(',');
         match (
             parts.next(),
@@ -103,7 +102,6 @@ fn parse_config_file(filename: &str) -> Result<Vec<PackageInfo>, String> {
                 return Err(format!("Invalid line format: {}", line));
             }
         }
-        line.clear();
     }
 
     Ok(result)
@@ -1,4 +1,6 @@
 use crate::constants::{MAX_KSU_VERSION, MIN_KSU_VERSION};
+use crate::utils::LateInit;
+use crate::version;
 
 const KERNEL_SU_OPTION: u32 = 0xdeadbeefu32;
 
@@ -12,6 +14,8 @@ pub enum Version {
     Abnormal,
 }
 
+static VERSION: LateInit<version::Version> = LateInit::new();
+
 pub fn get_kernel_su() -> Option<Version> {
     let mut version = 0;
     unsafe {
@@ -23,13 +27,28 @@ pub fn get_kernel_su() -> Option<Version> {
             0,
         )
     };
-    const MAX_OLD_VERSION: i32 = MIN_KSU_VERSION - 1;
-    match version {
-        0 => None,
-        MIN_KSU_VERSION..=MAX_KSU_VERSION => Some(Version::Supported),
-        1..=MAX_OLD_VERSION => Some(Version::TooOld),
-        _ => Some(Version::Abnormal),
+    if version == 0 {
+        return None;
+    }
+    if version < 0 {
+        return Some(Version::Abnormal);
+    }
+    let version = version::Version::from(version);
+    let status = if !version.at_least(&MIN_KSU_VERSION.into()) {
+        Version::TooOld
+    } else if version.at_least(&(MAX_KSU_VERSION + 1).into()) {
+        Version::Abnormal
+    } else {
+        Version::Supported
+    };
+    if !VERSION.initiated() {
+        VERSION.init(version);
     }
+    Some(status)
+}
+
+pub fn version() -> Option<version::Version> {
+    VERSION.initiated().then(|| (*VERSION).clone())
 }
 
 pub fn uid_granted_root(uid: i32) -> bool {
@@ -1,15 +1,19 @@
+use crate::constants::MIN_MAGISK_VERSION;
+use crate::utils::LateInit;
+use crate::version;
+use log::info;
 use std::fs;
 use std::os::android::fs::MetadataExt;
-use crate::constants::MIN_MAGISK_VERSION;
 use std::process::{Command, Stdio};
-use log::info;
-use crate::utils::LateInit;
 
 const MAGISK_OFFICIAL: &str = "com.topjohnwu.magisk";
 const MAGISK_THIRD_PARTIES: &[(&str, &str)] = &[
     ("alpha", "io.github.vvb2060.magisk"),
     ("kitsune", "io.github.huskydg.magisk"),
+    ("delta", "io.github.huskydg.magisk"),
 ];
+// Variants offering the sulist mode, which turns the denylist into an allowlist
+const MAGISK_SULIST_VARIANTS: &[&str] = &["kitsune", "delta"];
 
 pub enum Version {
     Supported,
@@ -17,6 +21,8 @@ pub enum Version {
 }
 
 static VARIANT: LateInit<&str> = LateInit::new();
+static SULIST: LateInit<bool> = LateInit::new();
+static VERSION: LateInit<version::Version> = LateInit::new();
 
 pub fn get_magisk() -> Option<Version> {
     if !VARIANT.initiated() {
@@ -28,11 +34,17 @@ pub fn get_magisk() -> Option<Version> {
             .and_then(|child| child.wait_with_output().ok())
             .and_then(|output| String::from_utf8(output.stdout).ok())
             .map(|version| {
-                let third_party = MAGISK_THIRD_PARTIES.iter().find_map(|v| {
-                    version.contains(v.0).then_some(v.1)
-                });
+                let third_party = MAGISK_THIRD_PARTIES
+                    .iter()
+                    .find_map(|v| version.contains(v.0).then_some(v.1));
                 VARIANT.init(third_party.unwrap_or(MAGISK_OFFICIAL));
                 info!("Magisk variant: {}", *VARIANT);
+                let sulist =
+                    MAGISK_SULIST_VARIANTS.iter().any(|v| version.contains(v)) && sulist_enforced();
+                if sulist {
+                    info!("Magisk sulist mode enforced");
+                }
+                SULIST.init(sulist);
             });
     }
     Command::new("magisk")
@@ -42,9 +54,13 @@ pub fn get_magisk() -> Option<Version> {
         .ok()
         .and_then(|child| child.wait_with_output().ok())
         .and_then(|output| String::from_utf8(output.stdout).ok())
-        .and_then(|output| output.trim().parse::<i32>().ok())
+        .and_then(|output| output.parse::<version::Version>().ok())
         .map(|version| {
-            if version >= MIN_MAGISK_VERSION {
+            let supported = version.at_least(&MIN_MAGISK_VERSION.into());
+            if !VERSION.initiated() {
+                VERSION.init(version);
+            }
+            if supported {
                 Version::Supported
             } else {
                 Version::TooOld
@@ -52,6 +68,27 @@ pub fn get_magisk() -> Option<Version> {
         })
 }
 
+fn sulist_enforced() -> bool {
+    Command::new("magisk")
+        .arg("--sqlite")
+        .arg("select value from settings where key=\"sulist\" limit 1")
+        .stdout(Stdio::piped())
+        .spawn()
+        .ok()
+        .and_then(|child| child.wait_with_output().ok())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim() == "value=1")
+        == Some(true)
+}
+
+pub fn version() -> Option<version::Version> {
+    VERSION.initiated().then(|| (*VERSION).clone())
+}
+
+pub fn is_sulist() -> bool {
+    SULIST.initiated() && *SULIST
+}
+
 pub fn uid_granted_root(uid: i32) -> bool {
     Command::new("magisk")
         .arg("--sqlite")
@@ -68,6 +105,11 @@ pub fn uid_granted_root(uid: i32) -> bool {
 }
 
 pub fn uid_should_umount(uid: i32) -> bool {
+    // In sulist mode only the listed packages may see root
+    uid_on_denylist(uid) != is_sulist()
+}
+
+fn uid_on_denylist(uid: i32) -> bool {
     let output = Command::new("pm")
         .args(["list", "packages", "--uid", &uid.to_string()])
         .stdout(Stdio::piped())
@@ -104,7 +146,9 @@ pub fn uid_should_umount(uid: i32) -> bool {
 pub fn uid_is_manager(uid: i32) -> bool {
     let output = Command::new("magisk")
         .arg("--sqlite")
-        .arg(format!("select value from strings where key=\"requester\" limit 1"))
+        .arg(format!(
+            "select value from strings where key=\"requester\" limit 1"
+        ))
         .stdout(Stdio::piped())
         .spawn()
         .ok()
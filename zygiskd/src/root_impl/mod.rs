@@ -1,8 +1,20 @@
+use crate::constants::{ROOT_ADVISORIES, RootAdvisory, RootPart};
+use crate::utils;
+use crate::version::Version;
+
+#[cfg(feature = "apatch")]
 mod apatch;
+#[cfg(feature = "kernelsu")]
 mod kernelsu;
+#[cfg(feature = "magisk")]
 mod magisk;
 
-#[derive(Debug)]
+#[cfg(not(any(feature = "apatch", feature = "kernelsu", feature = "magisk")))]
+compile_error!("at least one root implementation feature must be enabled");
+
+// Variants of backends compiled out are kept, so that callers need no feature gates
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum RootImpl {
     None,
     TooOld,
@@ -15,30 +27,51 @@ pub enum RootImpl {
 
 static mut ROOT_IMPL: RootImpl = RootImpl::None;
 
+// Outcome of probing a single root implementation
+#[allow(dead_code)]
+enum Detection {
+    Supported(RootImpl),
+    TooOld,
+    Abnormal,
+}
+
+// Backends compiled into this daemon, selected by cargo features
+const BACKENDS: &[fn() -> Option<Detection>] = &[
+    #[cfg(feature = "apatch")]
+    || {
+        apatch::get_apatch().map(|version| match version {
+            apatch::Version::Supported => Detection::Supported(RootImpl::APatch),
+            apatch::Version::TooOld => Detection::TooOld,
+        })
+    },
+    #[cfg(feature = "kernelsu")]
+    || {
+        kernelsu::get_kernel_su().map(|version| match version {
+            kernelsu::Version::Supported => Detection::Supported(RootImpl::KernelSU),
+            kernelsu::Version::TooOld => Detection::TooOld,
+            kernelsu::Version::Abnormal => Detection::Abnormal,
+        })
+    },
+    #[cfg(feature = "magisk")]
+    || {
+        magisk::get_magisk().map(|version| match version {
+            magisk::Version::Supported => Detection::Supported(RootImpl::Magisk),
+            magisk::Version::TooOld => Detection::TooOld,
+        })
+    },
+];
+
 pub fn setup() {
-    let apatch_version = apatch::get_apatch();
-    let ksu_version = kernelsu::get_kernel_su();
-    let magisk_version = magisk::get_magisk();
-
-    let impl_ = match (apatch_version, ksu_version, magisk_version) {
-        (None, None, None) => RootImpl::None,
-        (None, Some(_), Some(_)) => RootImpl::Multiple,
-        (Some(_), None, Some(_)) => RootImpl::Multiple,
-        (Some(_), Some(_), None) => RootImpl::Multiple,
-        (Some(_), Some(_), Some(_)) => RootImpl::Multiple,
-        (Some(apatch_version), None, None) => match apatch_version {
-            apatch::Version::Supported => RootImpl::APatch,
-            apatch::Version::TooOld => RootImpl::TooOld,
-        },
-        (None, Some(ksu_version), None) => match ksu_version {
-            kernelsu::Version::Supported => RootImpl::KernelSU,
-            kernelsu::Version::TooOld => RootImpl::TooOld,
-            kernelsu::Version::Abnormal => RootImpl::Abnormal,
-        },
-        (None, None, Some(magisk_version)) => match magisk_version {
-            magisk::Version::Supported => RootImpl::Magisk,
-            magisk::Version::TooOld => RootImpl::TooOld,
+    let mut detections: Vec<Detection> = BACKENDS.iter().filter_map(|detect| detect()).collect();
+
+    let impl_ = match detections.len() {
+        0 => RootImpl::None,
+        1 => match detections.remove(0) {
+            Detection::Supported(impl_) => impl_,
+            Detection::TooOld => RootImpl::TooOld,
+            Detection::Abnormal => RootImpl::Abnormal,
         },
+        _ => RootImpl::Multiple,
     };
     unsafe {
         ROOT_IMPL = impl_;
@@ -49,10 +82,52 @@ pub fn get_impl() -> &'static RootImpl {
     unsafe { &*(&raw const ROOT_IMPL) }
 }
 
+/// Version reported by the detected root implementation.
+pub fn version() -> Option<Version> {
+    match get_impl() {
+        #[cfg(feature = "apatch")]
+        RootImpl::APatch => apatch::version(),
+        #[cfg(feature = "kernelsu")]
+        RootImpl::KernelSU => kernelsu::version(),
+        #[cfg(feature = "magisk")]
+        RootImpl::Magisk => magisk::version(),
+        _ => None,
+    }
+}
+
+/// Known issues of the detected root implementation on this device.
+pub fn advisories() -> Vec<&'static RootAdvisory> {
+    let sdk = utils::get_property("ro.build.version.sdk")
+        .ok()
+        .and_then(|sdk| sdk.parse::<u32>().ok())
+        .unwrap_or(0);
+    ROOT_ADVISORIES
+        .iter()
+        .filter(|a| a.root == *get_impl())
+        .filter(|a| {
+            // Unknown versions are not reported
+            let version = match a.part {
+                RootPart::Core => version(),
+                RootPart::Ksud => ksud_version(),
+            };
+            version.is_some_and(|version| !version.at_least(&a.fixed_in.into()))
+        })
+        .filter(|a| (a.min_sdk..=a.max_sdk).contains(&sdk))
+        .collect()
+}
+
+// Set by ksud for the scripts of modules, which the daemon is started from
+fn ksud_version() -> Option<Version> {
+    std::env::var("KSU_VER_CODE").ok()?.parse().ok()
+}
+
 pub fn uid_granted_root(uid: i32) -> bool {
     match get_impl() {
+        #[cfg(feature = "apatch")]
         RootImpl::APatch => apatch::uid_granted_root(uid),
+        #[cfg(feature = "kernelsu")]
         RootImpl::KernelSU => kernelsu::uid_granted_root(uid),
+        #[cfg(feature = "magisk")]
         RootImpl::Magisk => magisk::uid_granted_root(uid),
         _ => panic!("uid_granted_root: unknown root impl {:?}", get_impl()),
     }
@@ -60,17 +135,32 @@ pub fn uid_granted_root(uid: i32) -> bool {
 
 pub fn uid_should_umount(uid: i32) -> bool {
     match get_impl() {
+        #[cfg(feature = "apatch")]
         RootImpl::APatch => apatch::uid_should_umount(uid),
+        #[cfg(feature = "kernelsu")]
         RootImpl::KernelSU => kernelsu::uid_should_umount(uid),
+        #[cfg(feature = "magisk")]
         RootImpl::Magisk => magisk::uid_should_umount(uid),
         _ => panic!("uid_should_umount: unknown root impl {:?}", get_impl()),
     }
 }
 
+// Whether the list of uid_should_umount is an allowlist rather than a denylist
+pub fn denylist_inverted() -> bool {
+    match get_impl() {
+        #[cfg(feature = "magisk")]
+        RootImpl::Magisk => magisk::is_sulist(),
+        _ => false,
+    }
+}
+
 pub fn uid_is_manager(uid: i32) -> bool {
     match get_impl() {
+        #[cfg(feature = "apatch")]
         RootImpl::APatch => apatch::uid_is_manager(uid),
+        #[cfg(feature = "kernelsu")]
         RootImpl::KernelSU => kernelsu::uid_is_manager(uid),
+        #[cfg(feature = "magisk")]
         RootImpl::Magisk => magisk::uid_is_manager(uid),
         _ => panic!("uid_is_manager: unknown root impl {:?}", get_impl()),
     }
@@ -0,0 +1,145 @@
+use crate::constants::PATH_MODULES_DIR;
+use crate::privop::{self, Operation};
+use crate::zygiskd::ModuleMarker;
+use crate::{audit, history, packages, root_impl, sockdir, users, utils};
+use anyhow::{Result, anyhow, bail};
+use num_enum::TryFromPrimitive;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Modules without native code reach the daemon from their scripts through
+// `zygisk-script`, which runs `zygiskd script`. Requests carry a token
+// generated by each daemon and kept next to the socket names, readable by
+// root only, so that finding the socket is not enough to be served.
+const TOKEN_CONTEXT: &str = "u:object_r:zygisk_file:s0";
+
+/// Requests a script may make, mirrored by the `zygiskd script` commands.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Command {
+    /// Whether an app gets the modules unmounted, by uid or by package name
+    /// and user, user 0 by default
+    DenyStatus,
+    /// Set a property, vetted like the privileged operation of companions
+    SetProperty,
+    /// The Zygisk modules loaded by the daemon
+    ListModules,
+    /// The injection history of a package, or the packages with one
+    History,
+}
+
+static CURRENT: Mutex<String> = Mutex::new(String::new());
+
+fn token_path(tmp_path: &Path, arch: &str) -> PathBuf {
+    sockdir::directory(tmp_path).join(format!("{}.token", arch))
+}
+
+/// Generate the token of this daemon, replacing any previous one.
+pub fn rotate_token(tmp_path: &Path) -> Result<()> {
+    let token = utils::random_hex(16)?;
+    let path = token_path(tmp_path, sockdir::ARCH);
+    utils::write_atomic(&path, token.as_bytes())?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    utils::chcon(&path.to_string_lossy(), TOKEN_CONTEXT)?;
+    *CURRENT.lock().unwrap() = token;
+    Ok(())
+}
+
+/// Token of the daemon for `arch`, as read by `zygiskd script`.
+pub fn read_token(tmp_path: &Path, arch: &str) -> Result<String> {
+    Ok(fs::read_to_string(token_path(tmp_path, arch))?
+        .trim()
+        .to_string())
+}
+
+// Compared in constant time, the token being the only secret of the request
+fn check_token(token: &str) -> bool {
+    let current = CURRENT.lock().unwrap();
+    if current.is_empty() || current.len() != token.len() {
+        return false;
+    }
+    let diff = current
+        .bytes()
+        .zip(token.bytes())
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+    diff == 0
+}
+
+// Scripts act for an enabled module, installed like any other
+fn check_module(module: &str) -> Result<()> {
+    if module.is_empty() || module.contains('/') || module.starts_with('.') {
+        bail!("invalid module name `{}`", module);
+    }
+    let dir = PathBuf::from(PATH_MODULES_DIR).join(module);
+    if !dir.is_dir() {
+        bail!("no module `{}` is installed", module);
+    }
+    if let Some(marker) = ModuleMarker::of(&dir) {
+        bail!("module `{}` is {:?}", module, marker);
+    }
+    Ok(())
+}
+
+fn deny_status(app: &str, user: &str) -> Result<String> {
+    let uid = match app.parse::<u32>() {
+        Ok(uid) => uid,
+        Err(_) => {
+            packages::check_name(app)?;
+            let user = match user {
+                "" => 0,
+                user => user
+                    .parse::<u32>()
+                    .map_err(|_| anyhow!("invalid user `{}`", user))?,
+            };
+            let Some(app_id) = packages::app_id(app)? else {
+                bail!("no package `{}` is installed", app);
+            };
+            user * users::PER_USER_RANGE + app_id
+        }
+    };
+    let policy_uid = users::policy_uid(uid as i32);
+    Ok(format!(
+        "uid={}\numount={}\nroot={}",
+        uid,
+        root_impl::uid_should_umount(policy_uid) as u8,
+        root_impl::uid_granted_root(policy_uid) as u8
+    ))
+}
+
+/// Run `command` with `args` for the script of `module` holding `token`,
+/// `modules` being the Zygisk modules loaded by the daemon.
+pub fn run(
+    token: &str,
+    module: &str,
+    command: Command,
+    args: [&str; 2],
+    modules: &[&str],
+) -> Result<String> {
+    if !check_token(token) {
+        audit::record(&format!(
+            "script module={} {:?} result=bad token",
+            module, command
+        ));
+        bail!("invalid token");
+    }
+    check_module(module)?;
+    match command {
+        Command::DenyStatus => {
+            let result = deny_status(args[0], args[1]);
+            audit::record(&format!(
+                "script module={} {:?} app={} result={}",
+                module,
+                command,
+                args[0],
+                if result.is_ok() { "ok" } else { "failed" }
+            ));
+            result
+        }
+        // Audited by the privileged operation itself
+        Command::SetProperty => privop::run(module, Operation::SetProperty, args),
+        Command::ListModules => Ok(modules.join("\n")),
+        Command::History => history::read(args[0]).map(|lines| lines.join("\n")),
+    }
+}
@@ -0,0 +1,126 @@
+use crate::{lp_select, utils};
+use anyhow::{Result, bail};
+use log::{debug, info, warn};
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt, symlink};
+use std::path::{Path, PathBuf};
+
+// Daemon sockets get random names once per boot, so that apps can neither
+// probe them at a known path nor squat it before the daemon binds. Each
+// daemon publishes the name of its socket in `TMP_PATH/sockets/<arch>`, a
+// directory root and zygote can search but nobody else can list. The name is
+// rotated on request, in case it leaked. The log forwarding socket of each
+// daemon gets a random name in the same directory, which is not published:
+// clients ask the daemon for it.
+const DIRECTORY: &str = "sockets";
+const DIRECTORY_CONTEXT: &str = "u:object_r:zygisk_file:s0";
+pub const ARCHES: [&str; 2] = ["cp32", "cp64"];
+/// Entry of the daemon of our architecture
+pub const ARCH: &str = lp_select!(ARCHES[0], ARCHES[1]);
+
+/// Directory of the socket names, also holding the tokens of `zygiskd script`.
+pub fn directory(tmp_path: &Path) -> PathBuf {
+    tmp_path.join(DIRECTORY)
+}
+
+/// Path to bind the socket published as `entry` to, keeping the name
+/// published earlier in the boot by a previous daemon.
+pub fn publish(tmp_path: &Path, entry: &str) -> Result<PathBuf> {
+    let dir = directory(tmp_path);
+    match lookup(tmp_path, entry) {
+        Ok(path) if path.parent() == Some(dir.as_path()) => return Ok(path),
+        _ => {}
+    }
+    let path = unpublished(tmp_path)?;
+    write_entry(
+        &dir,
+        entry,
+        &path.file_name().unwrap_or_default().to_string_lossy(),
+    )?;
+    debug!("Socket name for {} published for this boot", entry);
+    Ok(path)
+}
+
+/// New random path in the directory, which only the daemon knows of.
+pub fn unpublished(tmp_path: &Path) -> Result<PathBuf> {
+    let dir = directory(tmp_path);
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o711)
+        .create(&dir)?;
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o711))?;
+    utils::chcon(&dir.to_string_lossy(), DIRECTORY_CONTEXT)?;
+    Ok(dir.join(format!("{}.sock", utils::random_hex(8)?)))
+}
+
+fn write_entry(dir: &Path, entry: &str, name: &str) -> Result<()> {
+    let entry = dir.join(entry);
+    utils::write_atomic(&entry, name.as_bytes())?;
+    fs::set_permissions(&entry, fs::Permissions::from_mode(0o600))?;
+    utils::chcon(&entry.to_string_lossy(), DIRECTORY_CONTEXT)
+}
+
+/// Move the socket published as `entry` to a new random name, returning the
+/// path it had and what `bind` made of the new one.
+///
+/// The new name is only published once `bind` succeeded, so that clients
+/// looking it up never find it missing; the old path is left to the caller
+/// to remove once its pending connections are served.
+pub fn rotate<T>(
+    tmp_path: &Path,
+    entry: &str,
+    bind: impl FnOnce(&Path) -> Result<T>,
+) -> Result<(PathBuf, T)> {
+    let dir = directory(tmp_path);
+    let old = lookup(tmp_path, entry)?;
+    let name = format!("{}.sock", utils::random_hex(8)?);
+    let bound = bind(&dir.join(&name))?;
+    write_entry(&dir, entry, &name)?;
+    debug!("Socket for {} renamed", entry);
+    Ok((old, bound))
+}
+
+/// Path of the socket published as `entry`, like the daemon for an arch.
+///
+/// Daemons older than the directory only ever listened at `TMP_PATH/<entry>.sock`,
+/// which is still tried when nothing is published.
+pub fn lookup(tmp_path: &Path, entry: &str) -> Result<PathBuf> {
+    let path = directory(tmp_path).join(entry);
+    match fs::read_to_string(&path) {
+        Ok(name) => {
+            let name = name.trim();
+            if name.is_empty() || name.contains('/') || name.starts_with('.') {
+                bail!("invalid socket name `{}` in {}", name, path.display());
+            }
+            Ok(directory(tmp_path).join(name))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("No socket published for {}, trying the legacy path", entry);
+            Ok(tmp_path.join(format!("{}.sock", entry)))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Link the legacy path to the socket of this daemon, for modules and tools
+/// still connecting to it.
+///
+/// This brings back the predictable path, so it is only done when asked by
+/// the `legacySocketPath` config key.
+pub fn link_legacy(tmp_path: &Path) {
+    let legacy = tmp_path.join(format!("{}.sock", ARCH));
+    let socket = match lookup(tmp_path, ARCH) {
+        Ok(socket) if socket != legacy => socket,
+        Ok(_) => return,
+        Err(e) => {
+            warn!("Cannot link {}: {}", legacy.display(), e);
+            return;
+        }
+    };
+    let _ = fs::remove_file(&legacy);
+    let target = Path::new(DIRECTORY).join(socket.file_name().unwrap_or_default());
+    match symlink(&target, &legacy) {
+        Ok(()) => info!("Legacy socket path {} enabled", legacy.display()),
+        Err(e) => warn!("Failed to link {}: {}", legacy.display(), e),
+    }
+}
@@ -0,0 +1,140 @@
+use crate::constants::PATH_DATA_DIR;
+use crate::ring::{self, Layout, Ring};
+use crate::{config, profile};
+use anyhow::Result;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+/// Records kept per key, like the injections of every package.
+pub struct Table {
+    pub name: &'static str,
+    /// Records past `layout.slots` push out the oldest, longer ones are truncated
+    pub layout: Layout,
+}
+
+/// Where the daemon keeps what it records across requests.
+///
+/// Keys are validated by the callers, as they become file names for the
+/// flat file backend.
+pub trait Store: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Append `record` to the records of `key`.
+    fn push(&self, table: &'static Table, key: &str, record: &str) -> Result<()>;
+
+    /// Records of `key`, oldest first, `None` if nothing was recorded.
+    fn records(&self, table: &'static Table, key: &str) -> Result<Option<Vec<String>>>;
+
+    /// Keys with records, sorted.
+    fn keys(&self, table: &'static Table) -> Result<Vec<String>>;
+}
+
+/// A ring file per key in a directory of the data directory per table, the
+/// only backend commands run from a shell read directly instead of asking
+/// the daemons.
+pub struct FileStore {
+    lock: Mutex<()>,
+}
+
+impl FileStore {
+    fn dir(table: &Table) -> PathBuf {
+        PathBuf::from(PATH_DATA_DIR).join(table.name)
+    }
+}
+
+impl Store for FileStore {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn push(&self, table: &'static Table, key: &str, record: &str) -> Result<()> {
+        let dir = FileStore::dir(table);
+        let _guard = profile::lock(&profile::STORE, &self.lock);
+        fs::create_dir_all(&dir)?;
+        Ring::open(&dir.join(key), &table.layout)?.push(record)
+    }
+
+    fn records(&self, table: &'static Table, key: &str) -> Result<Option<Vec<String>>> {
+        let path = FileStore::dir(table).join(key);
+        if !path.exists() {
+            return Ok(None);
+        }
+        ring::read(&path, &table.layout).map(Some)
+    }
+
+    fn keys(&self, table: &'static Table) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = match fs::read_dir(FileStore::dir(table)) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+/// Records lost with the daemon, for devices short on storage or where
+/// writes to the data partition are unwanted.
+#[derive(Default)]
+pub struct MemoryStore {
+    tables: Mutex<HashMap<(&'static str, String), VecDeque<String>>>,
+}
+
+impl Store for MemoryStore {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn push(&self, table: &'static Table, key: &str, record: &str) -> Result<()> {
+        let mut end = record.len().min(table.layout.slot_size - 1);
+        while !record.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut tables = profile::lock(&profile::STORE, &self.tables);
+        let records = tables.entry((table.name, key.to_string())).or_default();
+        if records.len() == table.layout.slots as usize {
+            records.pop_front();
+        }
+        records.push_back(record[..end].to_string());
+        Ok(())
+    }
+
+    fn records(&self, table: &'static Table, key: &str) -> Result<Option<Vec<String>>> {
+        let tables = profile::lock(&profile::STORE, &self.tables);
+        let records = tables.get(&(table.name, key.to_string()));
+        Ok(records.map(|records| records.iter().cloned().collect()))
+    }
+
+    fn keys(&self, table: &'static Table) -> Result<Vec<String>> {
+        let tables = profile::lock(&profile::STORE, &self.tables);
+        let mut keys: Vec<String> = tables
+            .keys()
+            .filter(|(name, _)| *name == table.name)
+            .map(|(_, key)| key.clone())
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+pub const BACKENDS: &[&str] = &["file", "memory"];
+
+static STORE: OnceLock<Box<dyn Store>> = OnceLock::new();
+
+/// The backend chosen by the `store` config key.
+pub fn get() -> &'static dyn Store {
+    STORE
+        .get_or_init(|| match config::get().store.as_str() {
+            // Records of a dry run are not kept past the daemon
+            _ if config::get().dry_run => Box::new(MemoryStore::default()),
+            "memory" => Box::new(MemoryStore::default()),
+            _ => Box::new(FileStore {
+                lock: Mutex::new(()),
+            }),
+        })
+        .as_ref()
+}
@@ -0,0 +1,189 @@
+use crate::audit;
+use anyhow::{Result, bail};
+use log::{info, warn};
+use num_enum::TryFromPrimitive;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Features of the daemon which can be started, stopped and reloaded on their
+// own with `zygiskd subsystem`, while the core (sockets, modules and cached
+// mount namespaces) keeps serving zygote for the whole life of the process.
+// Zygote waits for the core until every subsystem is started, so subsystems
+// are only given until the startup deadline: those failing or still starting
+// by then are degraded and started again in the background.
+
+// Before a degraded subsystem which failed to start is tried again
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A feature registered with the daemon core.
+pub trait Subsystem: Sync {
+    fn name(&self) -> &'static str;
+    /// Start, or leave stopped when turned off by the configuration
+    fn start(&self) -> Result<()>;
+    fn stop(&self);
+    /// Pick up what changed on disk since it started
+    fn reload(&self) -> Result<()> {
+        self.stop();
+        self.start()
+    }
+    fn running(&self) -> bool;
+}
+
+/// Requests of `zygiskd subsystem`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Control {
+    List,
+    Start,
+    Stop,
+    Reload,
+}
+
+struct Entry {
+    subsystem: &'static dyn Subsystem,
+    // Why it is not started yet, while started in the background
+    degraded: Mutex<Option<String>>,
+    // Held while started in the background, so that requests never start it at the same time
+    busy: Mutex<()>,
+}
+
+// Also serializes the requests
+static REGISTRY: Mutex<Vec<Arc<Entry>>> = Mutex::new(Vec::new());
+static DEADLINE: OnceLock<Instant> = OnceLock::new();
+
+/// Give subsystems registered from now on until `deadline` to start, rather
+/// than waiting for each of them.
+pub fn set_deadline(deadline: Instant) {
+    let _ = DEADLINE.set(deadline);
+}
+
+/// Register and start `subsystem`, waiting for it until the startup deadline at
+/// most. A failure to start is only logged, and the start tried again later.
+pub fn register(subsystem: &'static dyn Subsystem) {
+    let entry = Arc::new(Entry {
+        subsystem,
+        degraded: Mutex::new(Some("still starting".to_string())),
+        busy: Mutex::new(()),
+    });
+    REGISTRY.lock().unwrap().push(Arc::clone(&entry));
+    let (sender, receiver) = mpsc::channel();
+    let spawned = thread::Builder::new()
+        .name(format!("start-{}", subsystem.name()))
+        .spawn({
+            let entry = Arc::clone(&entry);
+            move || start_in_background(&entry, sender)
+        });
+    if let Err(e) = spawned {
+        warn!("Subsystem {} unavailable: {}", subsystem.name(), e);
+        return;
+    }
+    let timeout = DEADLINE.get().map_or(Duration::MAX, |deadline| {
+        deadline.saturating_duration_since(Instant::now())
+    });
+    match receiver.recv_timeout(timeout) {
+        // Disconnected when stopped by a request before starting
+        Ok(Ok(())) | Err(RecvTimeoutError::Disconnected) => {}
+        Ok(Err(e)) => warn!(
+            "Subsystem {} unavailable, retrying in {:?}: {}",
+            subsystem.name(),
+            RETRY_INTERVAL,
+            e
+        ),
+        Err(RecvTimeoutError::Timeout) => warn!(
+            "Subsystem {} not started by the startup deadline, left starting in the background",
+            subsystem.name()
+        ),
+    }
+}
+
+// Start `entry` until it succeeds, sending the outcome of the first attempt
+fn start_in_background(entry: &Entry, first: mpsc::Sender<Result<()>>) {
+    for attempt in 0.. {
+        let result = {
+            let _busy = entry.busy.lock().unwrap();
+            let mut degraded = entry.degraded.lock().unwrap();
+            // Stopped or started meanwhile by a request
+            if degraded.is_none() || entry.subsystem.running() {
+                degraded.take();
+                return;
+            }
+            drop(degraded);
+            let result = entry.subsystem.start();
+            *entry.degraded.lock().unwrap() = result.as_ref().err().map(|e| e.to_string());
+            result
+        };
+        if attempt > 0 && result.is_ok() {
+            info!(
+                "Subsystem {} started at attempt {}",
+                entry.subsystem.name(),
+                attempt + 1
+            );
+        }
+        let failed = result.is_err();
+        if attempt == 0 {
+            let _ = first.send(result);
+        }
+        if !failed {
+            return;
+        }
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// Subsystems left starting in the background, with why they are not started yet.
+pub fn degraded() -> Vec<(&'static str, String)> {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|entry| {
+            Some((
+                entry.subsystem.name(),
+                entry.degraded.lock().unwrap().clone()?,
+            ))
+        })
+        .collect()
+}
+
+/// Apply `control` to the subsystem `name`, returning what to print.
+pub fn control(control: Control, name: &str) -> Result<String> {
+    let registry = REGISTRY.lock().unwrap();
+    if control == Control::List {
+        let lines: Vec<String> = registry
+            .iter()
+            .map(|entry| {
+                let state = match entry.degraded.lock().unwrap().as_deref() {
+                    Some(reason) => format!("degraded ({})", reason),
+                    None if entry.subsystem.running() => "running".to_string(),
+                    None => "stopped".to_string(),
+                };
+                format!("{} {}", entry.subsystem.name(), state)
+            })
+            .collect();
+        return Ok(lines.join("\n"));
+    }
+    let Some(entry) = registry.iter().find(|entry| entry.subsystem.name() == name) else {
+        bail!("unknown subsystem `{}`", name);
+    };
+    let Ok(_busy) = entry.busy.try_lock() else {
+        bail!("{} is still starting in the background", name);
+    };
+    let subsystem = entry.subsystem;
+    // Stopping a degraded subsystem ends its retries
+    let degraded = entry.degraded.lock().unwrap().take().is_some();
+    match control {
+        Control::Start if subsystem.running() => bail!("{} is already running", name),
+        Control::Stop if !subsystem.running() && !degraded => bail!("{} is not running", name),
+        Control::Stop => subsystem.stop(),
+        Control::Start => subsystem.start()?,
+        _ => subsystem.reload()?,
+    }
+    info!("Subsystem {}: {:?}", name, control);
+    audit::record(&format!("subsystem {} {:?}", name, control));
+    if control != Control::Stop && !subsystem.running() {
+        bail!("{} is turned off by the configuration", name);
+    }
+    Ok(String::new())
+}
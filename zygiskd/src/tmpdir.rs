@@ -0,0 +1,120 @@
+use crate::config;
+use crate::utils::{self, LateInit};
+use anyhow::{Result, bail};
+use log::{debug, info, warn};
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+const TMPDIR_CONTEXT: &str = "u:object_r:zygisk_file:s0";
+// Below the scratch root, the boot id and the directory name chosen for it
+const BOOT_RECORD: &str = ".boot";
+
+// Scratch space whose path can not be predicted by apps: a random directory
+// created once per boot, below which every subsystem and module gets its own
+// subdirectory. Daemons restarted within the boot find it again through the
+// boot record, and the first daemon of a boot removes the previous one.
+static BOOT_DIR: LateInit<PathBuf> = LateInit::new();
+
+pub fn setup() -> Result<()> {
+    let root = match &config::get().tmp_root {
+        Some(root) => root.clone(),
+        None => PathBuf::from(std::env::var("TMP_PATH")?),
+    };
+    let boot = utils::boot_id()?;
+    let record = root.join(BOOT_RECORD);
+    let previous = fs::read_to_string(&record).unwrap_or_default();
+    let mut lines = previous.lines();
+    let (previous_boot, previous_name) = (lines.next(), lines.next().filter(|n| valid_name(n)));
+    let name = match (previous_boot == Some(boot.as_str()), previous_name) {
+        (true, Some(name)) => name.to_string(),
+        (_, previous_name) => {
+            if let Some(name) = previous_name {
+                remove_stale(&root.join(name));
+            }
+            let name = utils::random_hex(8)?;
+            fs::create_dir_all(&root)?;
+            utils::write_atomic(&record, format!("{}\n{}\n", boot, name).as_bytes())?;
+            // The name is what keeps the directory unpredictable
+            fs::set_permissions(&record, fs::Permissions::from_mode(0o600))?;
+            name
+        }
+    };
+    let dir = root.join(name);
+    create_private_dir(&dir)?;
+    info!("Scratch directory: {}", dir.display());
+    BOOT_DIR.init(dir);
+    Ok(())
+}
+
+// Only names the daemon could have generated, never a path out of the root
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn remove_stale(dir: &Path) {
+    if !dir.is_dir() {
+        return;
+    }
+    match fs::remove_dir_all(dir) {
+        Ok(()) => debug!(
+            "Removed scratch directory {} of a previous boot",
+            dir.display()
+        ),
+        Err(e) => warn!(
+            "Failed to remove stale scratch directory {}: {}",
+            dir.display(),
+            e
+        ),
+    }
+}
+
+/// Keep using the scratch directory of a previous daemon.
+pub fn adopt(dir: PathBuf) -> Result<()> {
+    if !dir.is_dir() {
+        bail!("{} is gone", dir.display());
+    }
+    info!("Scratch directory: {} (inherited)", dir.display());
+    BOOT_DIR.init(dir);
+    Ok(())
+}
+
+pub fn boot_dir() -> Option<&'static Path> {
+    BOOT_DIR.initiated().then(|| BOOT_DIR.as_path())
+}
+
+/// Scratch directory for a daemon subsystem such as `trace` or `staging`.
+pub fn subsystem_dir(name: &str) -> Result<PathBuf> {
+    scoped_dir("sys", name)
+}
+
+/// Scratch directory handed to the companion of module `name`.
+pub fn module_dir(name: &str) -> Result<PathBuf> {
+    scoped_dir("mod", name)
+}
+
+fn scoped_dir(scope: &str, name: &str) -> Result<PathBuf> {
+    if !BOOT_DIR.initiated() {
+        bail!("scratch directory is not available");
+    }
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        bail!("invalid scratch directory name `{}`", name);
+    }
+    let dir = BOOT_DIR.join(scope).join(name);
+    if !dir.is_dir() {
+        create_private_dir(&dir)?;
+        debug!("Created scratch directory {}", dir.display());
+    }
+    Ok(dir)
+}
+
+fn create_private_dir(dir: &Path) -> Result<()> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+    // DirBuilder is subject to umask and leaves existing parents untouched
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    utils::chcon(&dir.to_string_lossy(), TMPDIR_CONTEXT)?;
+    Ok(())
+}
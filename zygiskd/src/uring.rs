@@ -0,0 +1,211 @@
+use io_uring::{IoUring, opcode, types};
+use log::debug;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+// Batches of small files (the threads of a process, tombstone heads) are read
+// through a ring, so that a round costs a single `io_uring_enter` instead of
+// one `read` per chunk and file. Single files are not worth setting a ring up
+// for and are read directly, as are all files on kernels or policies
+// restricting io_uring (ENOSYS, EPERM from `kernel.io_uring_disabled`,
+// seccomp or SELinux).
+//
+// Every thread owns its ring, so that reads never wait on each other. A child
+// forked with a ring inherits the queues of its parent: the ring is dropped
+// and set up again when its owner is not the current process.
+const ENTRIES: u32 = 32;
+const CHUNK: usize = 16 * 1024;
+
+enum Ring {
+    Unset,
+    Ready { owner: u32, ring: IoUring },
+    // Unavailable, or abandoned with entries in flight
+    Broken { owner: u32 },
+}
+
+thread_local! {
+    static RING: RefCell<Ring> = const { RefCell::new(Ring::Unset) };
+}
+
+/// Content of every file of `paths`, in order.
+pub fn read_files<P: AsRef<Path>>(paths: &[P]) -> Vec<io::Result<Vec<u8>>> {
+    read_batch(paths, usize::MAX)
+}
+
+/// At most the first `limit` bytes of every file of `paths`, in order.
+pub fn read_heads<P: AsRef<Path>>(paths: &[P], limit: usize) -> Vec<io::Result<Vec<u8>>> {
+    read_batch(paths, limit)
+}
+
+fn read_direct(path: &Path, limit: usize) -> io::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    File::open(path)?
+        .take(limit as u64)
+        .read_to_end(&mut content)?;
+    Ok(content)
+}
+
+// Files are opened beforehand in the calling thread, so that paths like
+// `/proc/self` resolve as they would for a blocking read
+fn read_batch<P: AsRef<Path>>(paths: &[P], limit: usize) -> Vec<io::Result<Vec<u8>>> {
+    let paths: Vec<&Path> = paths.iter().map(|p| p.as_ref()).collect();
+    if paths.len() < 2 {
+        return paths.iter().map(|path| read_direct(path, limit)).collect();
+    }
+    RING.with_borrow_mut(|ring| {
+        let owner = std::process::id();
+        let stale = match ring {
+            Ring::Unset => true,
+            Ring::Ready { owner: o, .. } | Ring::Broken { owner: o } => *o != owner,
+        };
+        if stale {
+            *ring = match IoUring::new(ENTRIES) {
+                Ok(ring) => Ring::Ready { owner, ring },
+                Err(e) => {
+                    debug!("io_uring unavailable, using blocking reads: {}", e);
+                    Ring::Broken { owner }
+                }
+            };
+        }
+        match ring {
+            Ring::Ready { ring: uring, .. } => {
+                let (results, broken) = read_with(uring, &paths, limit);
+                if broken {
+                    *ring = Ring::Broken { owner };
+                }
+                results
+            }
+            _ => paths.iter().map(|path| read_direct(path, limit)).collect(),
+        }
+    })
+}
+
+// Contents of `paths`, and whether the ring must not be used again
+fn read_with(
+    ring: &mut IoUring,
+    paths: &[&Path],
+    limit: usize,
+) -> (Vec<io::Result<Vec<u8>>>, bool) {
+    let mut results: Vec<io::Result<Vec<u8>>> = paths.iter().map(|_| Ok(Vec::new())).collect();
+    let mut pending: Vec<(usize, File)> = Vec::new();
+    for (index, path) in paths.iter().enumerate() {
+        match File::open(path) {
+            Ok(file) => pending.push((index, file)),
+            Err(e) => results[index] = Err(e),
+        }
+    }
+
+    while !pending.is_empty() {
+        let batch = pending.len().min(ENTRIES as usize);
+        let completions = match read_round(ring, &pending[..batch], &mut results, limit) {
+            Ok(completions) => completions,
+            Err(e) => {
+                // The ring itself failed, finish the remaining files without it
+                debug!("io_uring read failed, falling back: {}", e);
+                for (index, _) in pending.drain(..) {
+                    results[index] = read_direct(paths[index], limit);
+                }
+                return (results, true);
+            }
+        };
+        let mut done = Vec::new();
+        for (slot, result) in completions.into_iter().enumerate() {
+            let index = pending[slot].0;
+            let full = results[index].as_ref().is_ok_and(|buf| buf.len() >= limit);
+            match result {
+                Ok(0) => done.push(slot),
+                Ok(_) if full => done.push(slot),
+                Ok(_) => {}
+                // Some files do not support the ring (EINVAL, EOPNOTSUPP) or
+                // are denied to it, read them the usual way
+                Err(_) => {
+                    results[index] = read_direct(paths[index], limit);
+                    done.push(slot);
+                }
+            }
+        }
+        for slot in done.into_iter().rev() {
+            pending.remove(slot);
+        }
+    }
+    (results, false)
+}
+
+// Read the next chunk of each file of `batch`, returning the amount read per file.
+fn read_round(
+    ring: &mut IoUring,
+    batch: &[(usize, File)],
+    results: &mut [io::Result<Vec<u8>>],
+    limit: usize,
+) -> io::Result<Vec<io::Result<usize>>> {
+    for (slot, (index, file)) in batch.iter().enumerate() {
+        let Ok(buf) = &mut results[*index] else {
+            unreachable!("pending files have a buffer");
+        };
+        let chunk = CHUNK.min(limit - buf.len());
+        buf.reserve(chunk);
+        let entry = opcode::Read::new(
+            types::Fd(file.as_raw_fd()),
+            unsafe { buf.as_mut_ptr().add(buf.len()) },
+            chunk as u32,
+        )
+        .offset(buf.len() as u64)
+        .build()
+        .user_data(slot as u64);
+        // Safety: the buffer is neither moved nor dropped until its
+        // completion is reaped below
+        if unsafe { ring.submission().push(&entry) }.is_err() {
+            abandon(batch, results);
+            return Err(io::Error::other("submission queue full"));
+        }
+    }
+
+    let mut completions: Vec<Option<io::Result<usize>>> = batch.iter().map(|_| None).collect();
+    let mut reaped = 0;
+    while reaped < batch.len() {
+        match ring.submit_and_wait(batch.len() - reaped) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                abandon(batch, results);
+                return Err(e);
+            }
+        }
+        for cqe in ring.completion() {
+            let slot = cqe.user_data() as usize;
+            completions[slot] = Some(if cqe.result() < 0 {
+                Err(io::Error::from_raw_os_error(-cqe.result()))
+            } else {
+                Ok(cqe.result() as usize)
+            });
+            reaped += 1;
+        }
+    }
+
+    Ok(batch
+        .iter()
+        .zip(completions)
+        .map(|((index, _), completion)| {
+            let completion = completion.unwrap();
+            if let (Ok(read), Ok(buf)) = (&completion, &mut results[*index]) {
+                // Safety: the kernel initialized `read` bytes past the length
+                unsafe { buf.set_len(buf.len() + read) };
+            }
+            completion
+        })
+        .collect())
+}
+
+// Entries may still be queued or in flight: leak their buffers rather than
+// letting the kernel write into freed memory. The caller stops using the ring
+// since its late completions would be mixed with the next reads.
+fn abandon(batch: &[(usize, File)], results: &mut [io::Result<Vec<u8>>]) {
+    for (index, _) in batch {
+        if let Ok(buf) = &mut results[*index] {
+            std::mem::forget(std::mem::take(buf));
+        }
+    }
+}
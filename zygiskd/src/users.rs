@@ -0,0 +1,166 @@
+use crate::config::{self, PrivateSpace};
+use crate::packages;
+use anyhow::{Result, bail};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io::{Error, ErrorKind, Read};
+use std::os::fd::FromRawFd;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+
+// Android 15 keeps the apps of the Private Space in a profile of their own,
+// a user whose id is allocated like any other. Root implementations only know
+// the copies installed for the parent user, so apps of the Private Space are
+// decided as their copy in the parent user unless the config says otherwise.
+
+/// Uids given to each user, as `UserHandle.PER_USER_RANGE`.
+pub const PER_USER_RANGE: u32 = 100000;
+const PRIVATE_PROFILE_TYPE: &str = "android.os.usertype.profile.PRIVATE";
+const USERS_DIR: &str = "/data/system/users";
+
+// Parent of every user, `None` for users which are no Private Space
+static PARENTS: Mutex<Option<HashMap<u32, Option<u32>>>> = Mutex::new(None);
+
+pub fn user_id(uid: u32) -> u32 {
+    uid / PER_USER_RANGE
+}
+
+/// The uid shared by the copies of an app in every user.
+pub fn app_id(uid: u32) -> u32 {
+    uid % PER_USER_RANGE
+}
+
+// Every user has its record in `/data/system/users/<id>.xml`, whose root
+// element reads like
+//   <user id="11" type="android.os.usertype.profile.PRIVATE" profileGroupId="0" ...>
+fn read_user(path: &Path) -> Option<(u32, Option<u32>)> {
+    let elements = packages::read_elements(path).ok()?;
+    let (_, attrs) = elements.into_iter().find(|(name, _)| name == "user")?;
+    let attr = |key: &str| {
+        attrs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    };
+    let id = attr("id")?.parse().ok()?;
+    let parent = match attr("type") {
+        Some(PRIVATE_PROFILE_TYPE) => attr("profileGroupId").and_then(|id| id.parse().ok()),
+        _ => None,
+    };
+    Some((id, parent))
+}
+
+fn scan_users() -> HashMap<u32, Option<u32>> {
+    let Ok(entries) = fs::read_dir(USERS_DIR) else {
+        return HashMap::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.strip_suffix(".xml")
+                .is_some_and(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+        })
+        .filter_map(|entry| read_user(&entry.path()))
+        .collect()
+}
+
+fn update(users: HashMap<u32, Option<u32>>) {
+    let mut parents = PARENTS.lock().unwrap();
+    let parents = parents.get_or_insert_with(HashMap::new);
+    for (&id, &parent) in &users {
+        if let (Some(parent), false) = (parent, parents.contains_key(&id)) {
+            info!("User {} is the Private Space of user {}", id, parent);
+        }
+    }
+    *parents = users;
+}
+
+/// Read the users, and read them again whenever one is added, so that
+/// lookups while apps are forked never have to.
+pub fn setup() {
+    update(scan_users());
+    let watch = || -> Result<()> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            bail!("inotify_init1: {}", Error::last_os_error());
+        }
+        let mut inotify = unsafe { fs::File::from_raw_fd(fd) };
+        let dir = CString::new(USERS_DIR)?;
+        // Records are written to a temporary file and renamed in place
+        let mask = libc::IN_CREATE | libc::IN_MOVED_TO | libc::IN_CLOSE_WRITE;
+        if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+            bail!(
+                "inotify_add_watch {}: {}",
+                USERS_DIR,
+                Error::last_os_error()
+            );
+        }
+        thread::Builder::new()
+            .name("users".to_string())
+            .spawn(move || {
+                let mut events = [0u8; 4096];
+                loop {
+                    match inotify.read(&mut events) {
+                        Ok(0) => return,
+                        Ok(_) => update(scan_users()),
+                        Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                        Err(e) => {
+                            warn!("Stopped watching {}: {}", USERS_DIR, e);
+                            return;
+                        }
+                    }
+                }
+            })?;
+        Ok(())
+    };
+    if let Err(e) = watch() {
+        warn!("Users added later are not looked up: {}", e);
+    }
+}
+
+/// Parent user of `user` when it is a Private Space.
+pub fn private_space_parent(user: u32) -> Option<u32> {
+    if user == 0 {
+        return None;
+    }
+    let mut parents = PARENTS.lock().unwrap();
+    // Read on first use by commands, the daemon reads them at setup
+    let parent = parents
+        .get_or_insert_with(scan_users)
+        .get(&user)
+        .copied()
+        .flatten();
+    debug!("User {} has parent {:?}", user, parent);
+    parent
+}
+
+// Overrides name packages, found by their data directory in the parent user
+fn override_for(parent: u32, uid: u32) -> Option<PrivateSpace> {
+    config::get()
+        .private_space_overrides
+        .iter()
+        .find(|(package, _)| {
+            let data_dir = format!("/data/user_de/{}/{}", parent, package);
+            rustix::fs::stat(data_dir.as_str()).is_ok_and(|s| app_id(s.st_uid) == app_id(uid))
+        })
+        .map(|(_, policy)| *policy)
+}
+
+/// The uid whose deny and umount policies apply to `uid`: its copy in the
+/// parent user for apps of a Private Space, `uid` itself otherwise.
+pub fn policy_uid(uid: i32) -> i32 {
+    let uid = uid as u32;
+    let Some(parent) = private_space_parent(user_id(uid)) else {
+        return uid as i32;
+    };
+    let policy = override_for(parent, uid).unwrap_or(config::get().private_space);
+    match policy {
+        PrivateSpace::Parent => (parent * PER_USER_RANGE + app_id(uid)) as i32,
+        PrivateSpace::Own => uid as i32,
+    }
+}
@@ -1,18 +1,26 @@
-use anyhow::{Result, bail};
-use log::{debug, error, trace};
-use procfs::process::Process;
+use anyhow::{Result, anyhow, bail};
+use log::{debug, info, trace, warn};
+use procfs::FromBufRead;
+use procfs::process::MountInfos;
+use rustix::fs::{AtFlags, Mode, OFlags};
 use rustix::net::{
     AddressFamily, SendFlags, SocketAddrUnix, SocketType, bind_unix, connect_unix, listen,
     sendto_unix, socket,
 };
 use rustix::path::Arg;
 use rustix::thread::gettid;
-use std::ffi::{CStr, CString, c_char, c_void};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CStr, CString, OsStr, c_char, c_void};
 use std::io::Error;
-use std::os::fd::{AsFd, AsRawFd};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, IntoRawFd};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::net::UnixListener;
-use std::process::Command;
-use std::sync::OnceLock;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
 use std::{
     fs,
     io::{Read, Write},
@@ -20,7 +28,8 @@ use std::{
 };
 
 use crate::constants::MountNamespace;
-use crate::root_impl;
+use crate::hide::{self, HideStrategy};
+use crate::{config, metrics, pidfd, profile, root_impl};
 
 #[cfg(target_pointer_width = "64")]
 #[macro_export]
@@ -79,7 +88,59 @@ impl<T> std::ops::Deref for LateInit<T> {
     }
 }
 
-pub fn set_socket_create_context(context: &str) -> Result<()> {
+/// Context of sockets zygote and apps can keep using, even after specialization.
+pub const ZYGOTE_CONTEXT: &str = "u:r:zygote:s0";
+/// Context of the socket pairs brokered for modules, which apps are allowed to use.
+pub const MODULE_SOCKET_CONTEXT: &str = "u:object_r:zygisk_module_socket:s0";
+
+thread_local! {
+    // What this thread last wrote to its sockcreate attribute, `None` if it
+    // never did and creates sockets with its own context
+    static SOCKCREATE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Sockets created by this thread get `context` while the guard lives; the
+/// previous context of the thread is restored when it drops.
+///
+/// The attribute is per thread, so guards never affect sockets created by
+/// other threads, and setting the context already in place costs nothing.
+pub struct ScopedSockCreateContext {
+    previous: Option<String>,
+}
+
+impl ScopedSockCreateContext {
+    pub fn new(context: &str) -> Result<ScopedSockCreateContext> {
+        let previous = SOCKCREATE.with_borrow(Clone::clone);
+        // Writing our own context is the same as never writing one
+        let context = Some(context).filter(|c| *c != get_current_attr().unwrap_or(""));
+        if previous.as_deref() != context {
+            set_socket_create_context(context.unwrap_or(""))?;
+            SOCKCREATE.set(context.map(str::to_string));
+        }
+        Ok(ScopedSockCreateContext { previous })
+    }
+
+    /// Sockets get the context of the daemon itself, whatever the thread set before.
+    pub fn own() -> Result<ScopedSockCreateContext> {
+        ScopedSockCreateContext::new(get_current_attr()?)
+    }
+}
+
+impl Drop for ScopedSockCreateContext {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        if SOCKCREATE.with_borrow(|current| *current == previous) {
+            return;
+        }
+        match set_socket_create_context(previous.as_deref().unwrap_or("")) {
+            Ok(()) => SOCKCREATE.set(previous),
+            Err(e) => warn!("Failed to restore the socket creation context: {}", e),
+        }
+    }
+}
+
+// An empty context sets back the default, the context of the thread itself
+fn set_socket_create_context(context: &str) -> Result<()> {
     let path = "/proc/thread-self/attr/sockcreate";
     match fs::write(path, context) {
         Ok(_) => Ok(()),
@@ -94,9 +155,18 @@ pub fn set_socket_create_context(context: &str) -> Result<()> {
     }
 }
 
-pub fn get_current_attr() -> Result<String> {
+/// SELinux context of the daemon, read once since it never transitions.
+pub fn get_current_attr() -> Result<&'static str> {
+    static CURRENT: OnceLock<String> = OnceLock::new();
+    if let Some(current) = CURRENT.get() {
+        return Ok(current);
+    }
     let s = fs::read("/proc/self/attr/current")?;
-    Ok(s.to_string_lossy().to_string())
+    let current = s
+        .to_string_lossy()
+        .trim_end_matches(['\0', '\n'])
+        .to_string();
+    Ok(CURRENT.get_or_init(|| current))
 }
 
 pub fn chcon(path: &str, context: &str) -> Result<()> {
@@ -104,6 +174,71 @@ pub fn chcon(path: &str, context: &str) -> Result<()> {
     Ok(())
 }
 
+/// Replace the content of `path` with `bytes`, so that a crash or power loss
+/// leaves either the old or the new content, never part of both.
+///
+/// The data goes to a temporary file in the same directory, synced before it
+/// is renamed over `path`; the directory is synced last for the rename itself
+/// to be durable.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file path", path.display()))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let dir = fs::File::open(dir)?;
+    write_atomic_at(dir.as_fd(), name, bytes)
+}
+
+/// Like `write_atomic`, for the file `name` of the directory open as `dir`, so
+/// that the directory cannot be swapped for another meanwhile.
+pub fn write_atomic_at(dir: BorrowedFd, name: &OsStr, bytes: &[u8]) -> Result<()> {
+    // Unique per thread, so that concurrent writers never share a temporary file
+    let tmp = format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        gettid().as_raw_nonzero()
+    );
+    let existing = rustix::fs::statat(dir, name, AtFlags::SYMLINK_NOFOLLOW)
+        .ok()
+        .map(|stat| Mode::from_raw_mode(stat.st_mode as _));
+    let write = || -> Result<()> {
+        let flags = OFlags::WRONLY | OFlags::CREATE | OFlags::TRUNC | OFlags::NOFOLLOW;
+        let mode = Mode::from_raw_mode(0o666);
+        let fd = rustix::fs::openat(dir, tmp.as_str(), flags | OFlags::CLOEXEC, mode)?;
+        if let Some(mode) = existing {
+            rustix::fs::fchmod(&fd, mode)?;
+        }
+        let mut file = fs::File::from(fd);
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        rustix::fs::renameat(dir, tmp.as_str(), dir, name)?;
+        rustix::fs::fsync(dir)?;
+        Ok(())
+    };
+    let result = write();
+    if result.is_err() {
+        let _ = rustix::fs::unlinkat(dir, tmp.as_str(), AtFlags::empty());
+    }
+    result
+}
+
+pub fn random_hex(bytes: usize) -> Result<String> {
+    let mut buf = vec![0u8; bytes];
+    fs::File::open("/dev/urandom")?.read_exact(&mut buf)?;
+    Ok(buf.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Id the kernel draws at each boot, telling daemons of the same boot apart
+/// from those of the previous ones.
+pub fn boot_id() -> Result<String> {
+    Ok(fs::read_to_string("/proc/sys/kernel/random/boot_id")?
+        .trim()
+        .to_string())
+}
+
 pub fn get_property(name: &str) -> Result<String> {
     let name = CString::new(name)?;
     let mut buf = vec![0u8; 92];
@@ -114,6 +249,33 @@ pub fn get_property(name: &str) -> Result<String> {
     Ok(prop.to_string_lossy().to_string())
 }
 
+pub fn set_property(name: &str, value: &str) -> Result<()> {
+    let c_name = CString::new(name)?;
+    let c_value = CString::new(value)?;
+    if unsafe { __system_property_set(c_name.as_ptr(), c_value.as_ptr()) } != 0 {
+        bail!("property service rejected {}", name);
+    }
+    Ok(())
+}
+
+// Block until the system property `name` is set to `expected`.
+pub fn wait_property(name: &str, expected: &str) -> Result<()> {
+    let c_name = CString::new(name)?;
+    let mut serial = 0;
+    while get_property(name)? != expected {
+        let info = unsafe { __system_property_find(c_name.as_ptr()) };
+        if info.is_null() {
+            // Property not created yet, nothing to wait on
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            continue;
+        }
+        unsafe {
+            __system_property_wait(info, serial, &mut serial, std::ptr::null());
+        }
+    }
+    Ok(())
+}
+
 pub fn switch_mount_namespace(pid: i32) -> Result<()> {
     let cwd = std::env::current_dir()?;
     let mnt = fs::File::open(format!("/proc/{}/ns/mnt", pid))?;
@@ -122,10 +284,49 @@ pub fn switch_mount_namespace(pid: i32) -> Result<()> {
     Ok(())
 }
 
-// save mount namespaces for all application process
-static CLEAN_MNT_NS_FD: LateInit<i32> = LateInit::new();
-static ROOT_MNT_NS_FD: LateInit<i32> = LateInit::new();
-static MODULE_MNT_NS_FD: LateInit<i32> = LateInit::new();
+// save mount namespaces for all application process, per hide strategy except for Root
+type NamespaceKey = (MountNamespace, &'static str);
+
+struct Namespaces {
+    fds: Vec<(NamespaceKey, i32)>,
+    // Replaced by the last rebuild, only closed at the next one so that apps
+    // told about them just before can still open them
+    retired: Vec<i32>,
+    // Bumped whenever the fds of a previous generation are replaced or closed
+    generation: usize,
+    // Fds handed out before this generation may have been closed since
+    oldest_open: usize,
+    // Hide strategy and generation of the Clean namespace zygote switched to
+    zygote: Option<(&'static str, usize)>,
+}
+
+static MNT_NS: Mutex<Namespaces> = Mutex::new(Namespaces {
+    fds: Vec::new(),
+    retired: Vec::new(),
+    generation: 0,
+    oldest_open: 0,
+    zygote: None,
+});
+
+// Longest wait for a helper to prepare a namespace
+const NAMESPACE_HELPER_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn namespace_key(namespace_type: MountNamespace, strategy: &dyn HideStrategy) -> NamespaceKey {
+    match namespace_type {
+        MountNamespace::Root => (namespace_type, ""),
+        _ => (namespace_type, strategy.name()),
+    }
+}
+
+fn cached_mount_namespace(key: &NamespaceKey) -> Option<(i32, usize)> {
+    let namespaces = profile::lock(&profile::MOUNT_NAMESPACES, &MNT_NS);
+    let fd = namespaces
+        .fds
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, fd)| *fd)?;
+    Some((fd, namespaces.generation))
+}
 
 // Use `man 7 namespaces` to read the Linux manual about namespaces.
 // In the section `The /proc/pid/ns/ directory`, it is explained that:
@@ -134,159 +335,337 @@ static MODULE_MNT_NS_FD: LateInit<i32> = LateInit::new();
 // namespace of the process specified by pid. As long as this file descriptor
 // remains open, the namespace will remain alive, even if all processes in the
 // namespace terminate.
-pub fn save_mount_namespace(pid: i32, namespace_type: MountNamespace) -> Result<i32> {
-    // We shall use CLEAN_MNT_NS_FD and ROOT_MNT_NS_FD to keep the namespace file handle.
-    let is_initialized = match namespace_type {
-        MountNamespace::Clean => CLEAN_MNT_NS_FD.initiated(),
-        MountNamespace::Root => ROOT_MNT_NS_FD.initiated(),
-        MountNamespace::Module => MODULE_MNT_NS_FD.initiated(),
-    };
+//
+// Returns the fd along with the generation it was handed out in.
+pub fn save_mount_namespace(
+    pid: i32,
+    namespace_type: MountNamespace,
+    strategy: &'static dyn HideStrategy,
+) -> Result<(i32, usize)> {
+    // We shall use MNT_NS to keep the namespace file handle.
+    let key = namespace_key(namespace_type, strategy);
+    let is_initialized = cached_mount_namespace(&key).is_some();
 
     if !is_initialized {
         if pid == -1 {
             bail!(
-                "Caching not finished [Clean, Root, Module]: [{}, {}, {}]",
-                CLEAN_MNT_NS_FD.initiated(),
-                ROOT_MNT_NS_FD.initiated(),
-                MODULE_MNT_NS_FD.initiated()
+                "Caching not finished for {:?} with {}",
+                namespace_type,
+                strategy.name()
             );
         }
-        // Use a pipe to keep the forked child process open
-        // till the namespace is read.
+        let ns_file = build_mount_namespace(pid, namespace_type, strategy)?;
+        profile::lock(&profile::MOUNT_NAMESPACES, &MNT_NS)
+            .fds
+            .push((key, ns_file.as_raw_fd()));
+        trace!(
+            "{:?} mount namespace cached as fd {}",
+            key,
+            ns_file.as_raw_fd()
+        );
+        std::mem::forget(ns_file);
+    }
+    Ok(cached_mount_namespace(&key).unwrap_or((0, 0)))
+}
 
-        let mut pipes = [0; 2];
-        unsafe {
-            libc::pipe(pipes.as_mut_ptr());
+/// Whether fds handed out in `generation` still refer to the namespaces they
+/// were handed out for, checked once a client has opened one.
+pub fn mount_namespace_valid(generation: usize) -> bool {
+    let namespaces = profile::lock(&profile::MOUNT_NAMESPACES, &MNT_NS);
+    (namespaces.oldest_open..=namespaces.generation).contains(&generation)
+}
+
+/// Record that zygote switched to the Clean namespace of `strategy` handed out in
+/// `generation`, which the apps it forks from now on inherit a copy of.
+pub fn zygote_switched_mount_namespace(strategy: &'static dyn HideStrategy, generation: usize) {
+    profile::lock(&profile::MOUNT_NAMESPACES, &MNT_NS).zygote = Some((strategy.name(), generation));
+}
+
+/// Whether the namespace an app inherited from zygote is still the Clean namespace of
+/// `strategy`, which it can then keep instead of switching to an identical one.
+pub fn zygote_mount_namespace_current(strategy: &dyn HideStrategy) -> bool {
+    let namespaces = profile::lock(&profile::MOUNT_NAMESPACES, &MNT_NS);
+    namespaces.zygote == Some((strategy.name(), namespaces.generation))
+}
+
+// Copy the mount namespace of `pid` in a helper process, prepared as `namespace_type`.
+//
+// The helper is a fresh exec of the daemon binary rather than a fork: the
+// daemon has threads, and a forked child could deadlock on a lock one of them
+// held, as the allocator or the logger. It keeps running, and so the namespace
+// alive, until its stdin is closed.
+fn build_mount_namespace(
+    pid: i32,
+    namespace_type: MountNamespace,
+    strategy: &'static dyn HideStrategy,
+) -> Result<fs::File> {
+    let start = std::time::Instant::now();
+    let mut child = Command::new("/proc/self/exe")
+        .arg0(lp_select!("zygiskd32", "zygiskd64"))
+        .arg("namespace")
+        .arg(pid.to_string())
+        .arg((namespace_type as u8).to_string())
+        .arg(strategy.name())
+        .arg(mount_source()?)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdout = child.stdout.take().unwrap();
+    trace!("waiting {} to cache mount namespace", child.id());
+    let helper = pidfd::Process::open(child.id() as i32)?;
+    let ready = helper.poll_with(stdout.as_raw_fd(), Some(NAMESPACE_HELPER_TIMEOUT))?;
+    let mut status = [1u8];
+    if ready != pidfd::Ready::Fd || stdout.read_exact(&mut status).is_err() || status[0] != 0 {
+        let _ = child.kill();
+        let _ = child.wait();
+        match ready {
+            pidfd::Ready::TimedOut => bail!(
+                "{} did not cache {:?} mount namespace within {:?}",
+                child.id(),
+                namespace_type,
+                NAMESPACE_HELPER_TIMEOUT
+            ),
+            _ => bail!(
+                "{} failed to cache {:?} mount namespace",
+                child.id(),
+                namespace_type
+            ),
         }
-        let (reader, writer) = (pipes[0], pipes[1]);
-        match unsafe { libc::fork() } {
-            0 => {
-                // Child process
-                switch_mount_namespace(pid)?;
-                if namespace_type != MountNamespace::Root {
-                    unsafe {
-                        libc::unshare(libc::CLONE_NEWNS);
-                    }
-                    revert_unmount(namespace_type == MountNamespace::Module)?;
-                }
-                let mut mypid = 0;
-                while mypid != unsafe { libc::getpid() } {
-                    write_int(writer, 0)?;
-                    std::thread::sleep(std::time::Duration::from_millis(50));
-                    mypid = read_int(reader)?;
-                }
-                std::process::exit(0);
+    }
+    trace!("{} finished caching mount namespace", child.id());
+    profile::CACHE_NAMESPACE.record(Duration::ZERO, start.elapsed());
+    if config::get().measure_unmount {
+        metrics::record_mount_namespace(namespace_type, strategy, start.elapsed());
+    }
+    let ns_file = fs::File::open(format!("/proc/{}/ns/mnt", child.id()));
+    // Hanging up lets the helper exit
+    drop(child.stdin.take());
+    child.wait()?;
+    Ok(ns_file?)
+}
+
+/// Entry of the helper process of `build_mount_namespace`.
+pub fn namespace_helper(
+    pid: &str,
+    namespace_type: &str,
+    strategy: &str,
+    mount_source: &str,
+) -> Result<()> {
+    let pid: i32 = pid.parse()?;
+    let namespace_type = MountNamespace::try_from(namespace_type.parse::<u8>()?)?;
+    let strategy =
+        hide::find(strategy).ok_or_else(|| anyhow!("invalid hide strategy `{}`", strategy))?;
+    switch_mount_namespace(pid)?;
+    if namespace_type != MountNamespace::Root {
+        if unsafe { libc::unshare(libc::CLONE_NEWNS) } == -1 {
+            bail!(Error::last_os_error());
+        }
+        let modules_only = namespace_type == MountNamespace::Module;
+        revert_unmount_at_low_priority(modules_only, strategy, mount_source)?;
+    }
+    let mut stdout = std::io::stdout();
+    stdout.write_all(&[0])?;
+    stdout.flush()?;
+    // Keep the namespace alive till the daemon has opened it
+    let _ = std::io::stdin().read(&mut [0]);
+    Ok(())
+}
+
+/// Copy again the cached Clean and Module mount namespaces from the namespace
+/// of `pid`, returning how many were replaced.
+///
+/// They are built before the cache is locked, apps asking meanwhile get the
+/// previous ones.
+pub fn rebuild_mount_namespaces(pid: i32) -> Result<usize> {
+    let mut rebuilt = Vec::new();
+    for strategy in hide::in_use() {
+        for namespace_type in [MountNamespace::Clean, MountNamespace::Module] {
+            let key = namespace_key(namespace_type, strategy);
+            if cached_mount_namespace(&key).is_some() {
+                rebuilt.push((key, build_mount_namespace(pid, namespace_type, strategy)?));
             }
-            child if child > 0 => {
-                // Parent process
-                trace!("waiting {child} to cache mount namespace");
-                if read_int(reader)? == 0 {
-                    trace!("{child} finished caching mount namespace");
-                }
-                let ns_path = format!("/proc/{}/ns/mnt", child);
-                let ns_file = fs::OpenOptions::new().read(true).open(&ns_path)?;
-                write_int(writer, child)?;
-                unsafe {
-                    if libc::close(reader) == -1
-                        || libc::close(writer) == -1
-                        || libc::waitpid(child, std::ptr::null_mut(), 0) == -1
-                    {
-                        bail!(Error::last_os_error());
-                    }
-                };
-                match namespace_type {
-                    MountNamespace::Clean => {
-                        CLEAN_MNT_NS_FD.init(ns_file.as_raw_fd());
-                        trace!("CLEAN_MNT_NS_FD updated to {}", *CLEAN_MNT_NS_FD);
-                    }
-                    MountNamespace::Root => {
-                        ROOT_MNT_NS_FD.init(ns_file.as_raw_fd());
-                        trace!("ROOT_MNT_NS_FD updated to {}", *ROOT_MNT_NS_FD);
-                    }
-                    MountNamespace::Module => {
-                        MODULE_MNT_NS_FD.init(ns_file.as_raw_fd());
-                        trace!("MODULE_MNT_NS_FD updated to {}", *MODULE_MNT_NS_FD);
-                    }
-                };
-                std::mem::forget(ns_file);
+        }
+    }
+    let mut namespaces = profile::lock(&profile::MOUNT_NAMESPACES, &MNT_NS);
+    let namespaces = &mut *namespaces;
+    for fd in namespaces.retired.drain(..) {
+        unsafe { libc::close(fd) };
+    }
+    // Fds of the previous generation were just closed, the current ones are retired
+    namespaces.oldest_open = namespaces.generation;
+    namespaces.generation += 1;
+    let count = rebuilt.len();
+    for (key, file) in rebuilt {
+        match namespaces.fds.iter_mut().find(|(k, _)| *k == key) {
+            Some(entry) => {
+                namespaces.retired.push(entry.1);
+                entry.1 = file.into_raw_fd();
+                trace!("{:?} mount namespace rebuilt as fd {}", key, entry.1);
             }
-            _ => bail!(Error::last_os_error()),
+            // Dropped meanwhile, as when NeoZygisk is turned off
+            None => drop(file),
         }
     }
-    match namespace_type {
-        MountNamespace::Clean if CLEAN_MNT_NS_FD.initiated() => Ok(*CLEAN_MNT_NS_FD),
-        MountNamespace::Root if ROOT_MNT_NS_FD.initiated() => Ok(*ROOT_MNT_NS_FD),
-        MountNamespace::Module if MODULE_MNT_NS_FD.initiated() => Ok(*MODULE_MNT_NS_FD),
-        _ => Ok(0),
+    Ok(count)
+}
+
+/// Cached mount namespaces as (type, hide strategy, fd), for handing them over.
+pub fn cached_mount_namespaces() -> Vec<(MountNamespace, &'static str, i32)> {
+    let namespaces = profile::lock(&profile::MOUNT_NAMESPACES, &MNT_NS);
+    namespaces
+        .fds
+        .iter()
+        .map(|((t, s), fd)| (*t, *s, *fd))
+        .collect()
+}
+
+/// Adopt a mount namespace cached by a previous daemon.
+pub fn restore_mount_namespace(
+    namespace_type: MountNamespace,
+    strategy: &'static dyn HideStrategy,
+    fd: i32,
+) {
+    let key = namespace_key(namespace_type, strategy);
+    trace!("{:?} mount namespace restored as fd {}", key, fd);
+    profile::lock(&profile::MOUNT_NAMESPACES, &MNT_NS)
+        .fds
+        .push((key, fd));
+}
+
+/// Close all cached mount namespaces, so that they can be freed by the kernel.
+///
+/// Fds are closed under the lock they are handed out with, and bumping the
+/// generation tells clients which opened one meanwhile that it may have been reused.
+pub fn drop_mount_namespaces() {
+    let mut namespaces = profile::lock(&profile::MOUNT_NAMESPACES, &MNT_NS);
+    let namespaces = &mut *namespaces;
+    for (key, fd) in namespaces.fds.drain(..) {
+        unsafe { libc::close(fd) };
+        trace!("{:?} mount namespace dropped", key);
+    }
+    for fd in namespaces.retired.drain(..) {
+        unsafe { libc::close(fd) };
     }
+    namespaces.generation += 1;
+    namespaces.oldest_open = namespaces.generation;
 }
 
-fn revert_unmount(modules_only: bool) -> Result<()> {
-    let mount_infos = Process::myself().unwrap().mountinfo().unwrap();
-    let mut targets: Vec<String> = Vec::new();
-    let mount_source = match root_impl::get_impl() {
-        root_impl::RootImpl::APatch => "APatch",
-        root_impl::RootImpl::KernelSU => "KSU",
-        root_impl::RootImpl::Magisk => "magisk",
-        _ => panic!("wrong root impl: {:?}", root_impl::get_impl()),
-    };
+// Hide mount points at the lowest priority, so that building namespaces while apps
+// are launching does not compete with them for the CPU. Only the namespace helper
+// does this, it has nothing else to do meanwhile.
+fn revert_unmount_at_low_priority(
+    modules_only: bool,
+    strategy: &dyn HideStrategy,
+    mount_source: &str,
+) -> Result<()> {
+    let param = libc::sched_param { sched_priority: 0 };
+    if unsafe { libc::sched_setscheduler(0, libc::SCHED_IDLE, &param) } == -1 {
+        debug!(
+            "Failed to lower unmount priority: {}",
+            Error::last_os_error()
+        );
+    }
+    let start = std::time::Instant::now();
+    let result = revert_unmount(modules_only, strategy, mount_source);
+    if config::get().measure_unmount {
+        info!(
+            "Hid mount points with {} in {}us using {} umount2 calls",
+            strategy.name(),
+            start.elapsed().as_micros(),
+            hide::detach_calls()
+        );
+    }
+    result
+}
+
+// Source of the mounts of the root solution, as seen in mountinfo
+fn mount_source() -> Result<&'static str> {
+    match root_impl::get_impl() {
+        root_impl::RootImpl::APatch => Ok("APatch"),
+        root_impl::RootImpl::KernelSU => Ok("KSU"),
+        root_impl::RootImpl::Magisk => Ok("magisk"),
+        _ => bail!("wrong root impl: {:?}", root_impl::get_impl()),
+    }
+}
+
+// Unmount traces of the root solution in the current mount namespace.
+fn revert_unmount(
+    modules_only: bool,
+    strategy: &dyn HideStrategy,
+    mount_source: &str,
+) -> Result<()> {
+    debug!("Hiding mount points with {}", strategy.name());
+    strategy.hide(&|| unmount_targets(modules_only, mount_source))
+}
+
+// Mount points to hide, innermost first.
+//
+// Only failing to read the mount table is fatal; a mount entry we cannot
+// handle is logged and skipped so that the rest of the pass still happens.
+//
+// When batching, mounts stacked inside another target are left out: lazily
+// detaching their ancestor takes the whole subtree along in a single call.
+fn unmount_targets(modules_only: bool, mount_source: &str) -> Result<Vec<CString>> {
+    let mount_infos = fs::read("/proc/self/mountinfo")
+        .map_err(anyhow::Error::from)
+        .and_then(|content| Ok(MountInfos::from_buf_read(content.as_slice())?))
+        .map_err(|e| anyhow::anyhow!("failed to read mountinfo: {}", e))?;
+    let parents: HashMap<i32, i32> = mount_infos.iter().map(|i| (i.mnt_id, i.pid)).collect();
+    let mut matched = Vec::new();
     for info in mount_infos {
-        let path = info.mount_point.to_str().unwrap().to_string();
+        let path = info.mount_point.as_os_str();
         let should_unmount: bool = if modules_only {
-            path.starts_with("/debug_ramdisk") && mount_source != "magisk"
+            path.as_bytes().starts_with(b"/debug_ramdisk") && mount_source != "magisk"
         } else {
             info.root.starts_with("/adb/modules")
-                || path.starts_with("/data/adb/modules")
-                || info.mount_source == Some(mount_source.to_string())
+                || path.as_bytes().starts_with(b"/data/adb/modules")
+                || info.mount_source.as_deref() == Some(mount_source)
         };
         if should_unmount {
-            targets.push(path);
-        }
-    }
-    targets.reverse();
-    for path in targets {
-        unsafe {
-            if libc::umount2(CString::new(path.clone())?.as_ptr(), libc::MNT_DETACH) == -1 {
-                error!("failed to to unmount {}", path);
-                bail!(Error::last_os_error());
-            } else {
-                debug!("Unmounted {}", path);
-            }
+            matched.push(info);
         }
     }
-    Ok(())
-}
 
-fn write_int(fd: libc::c_int, value: i32) -> Result<()> {
-    unsafe {
-        if libc::write(
-            fd,
-            &value as *const _ as *const c_void,
-            std::mem::size_of::<i32>(),
-        ) == -1
-        {
-            bail!(Error::last_os_error());
+    let ids: HashSet<i32> = matched.iter().map(|info| info.mnt_id).collect();
+    let batch = config::get().unmount_batch;
+    let covered = |mut id: i32| {
+        // Bounded walk, the mount table may change while being read
+        for _ in 0..parents.len() {
+            match parents.get(&id) {
+                Some(&parent) if parent != id => {
+                    if ids.contains(&parent) {
+                        return true;
+                    }
+                    id = parent;
+                }
+                _ => break,
+            }
         }
+        false
     };
-    Ok(())
-}
-
-fn read_int(fd: libc::c_int) -> Result<i32> {
-    let mut buf = [0u8; 4];
-    unsafe {
-        if libc::read(
-            fd,
-            buf.as_mut_ptr() as *mut c_void,
-            std::mem::size_of::<i32>(),
-        ) == -1
-        {
-            bail!(Error::last_os_error());
+    let mut targets: Vec<CString> = Vec::new();
+    for info in &matched {
+        if batch && covered(info.mnt_id) {
+            continue;
         }
-    };
-    let value = i32::from_le_bytes(buf);
-    Ok(value)
+        let path = info.mount_point.as_os_str();
+        match CString::new(path.as_bytes()) {
+            Ok(path) => targets.push(path),
+            Err(_) => warn!("Skip unmounting invalid path {:?}", path),
+        }
+    }
+    trace!(
+        "{} of {} mount points to detach",
+        targets.len(),
+        matched.len()
+    );
+    targets.reverse();
+    Ok(targets)
 }
 
+// Longest string accepted from a peer, far above any process name or path
+pub const MAX_STRING_SIZE: usize = 64 * 1024;
+
 pub trait UnixStreamExt {
     fn read_u8(&mut self) -> Result<u8>;
     fn read_u32(&mut self) -> Result<u32>;
@@ -319,6 +698,13 @@ impl UnixStreamExt for UnixStream {
 
     fn read_string(&mut self) -> Result<String> {
         let len = self.read_usize()?;
+        if len > MAX_STRING_SIZE {
+            bail!(
+                "string of {} bytes exceeds limit of {}",
+                len,
+                MAX_STRING_SIZE
+            );
+        }
         let mut buf = vec![0u8; len];
         self.read_exact(&mut buf)?;
         Ok(String::from_utf8(buf)?)
@@ -357,16 +743,31 @@ pub fn unix_listener_from_path(path: &str) -> Result<UnixListener> {
 }
 
 pub fn unix_datagram_sendto(path: &str, buf: &[u8]) -> Result<()> {
-    // FIXME: shall we set create context every time?
-    set_socket_create_context(get_current_attr()?.as_str())?;
+    let socket = {
+        let _context = ScopedSockCreateContext::own()?;
+        socket(AddressFamily::UNIX, SocketType::DGRAM, None)?
+    };
     let addr = SocketAddrUnix::new(path.as_bytes())?;
-    let socket = socket(AddressFamily::UNIX, SocketType::DGRAM, None)?;
     connect_unix(&socket, &addr)?;
     sendto_unix(socket, buf, SendFlags::empty(), &addr)?;
-    set_socket_create_context("u:r:zygote:s0")?;
     Ok(())
 }
 
+/// Uid of the process at the other end of `stream`, as it connected.
+pub fn peer_uid(stream: &UnixStream) -> Result<u32> {
+    Ok(rustix::net::sockopt::get_socket_peercred(stream)?
+        .uid
+        .as_raw())
+}
+
+/// Pid of the process at the other end of `stream`, as it connected.
+pub fn peer_pid(stream: &UnixStream) -> Result<i32> {
+    Ok(rustix::net::sockopt::get_socket_peercred(stream)?
+        .pid
+        .as_raw_nonzero()
+        .get())
+}
+
 pub fn check_unix_socket(stream: &UnixStream, block: bool) -> bool {
     unsafe {
         let mut pfd = libc::pollfd {
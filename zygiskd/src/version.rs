@@ -0,0 +1,121 @@
+use anyhow::{Result, bail};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// A version as reported by root managers or declared by modules, like
+/// `27005`, `v1.2.3` or `11039-beta`.
+///
+/// Parsing keeps the dotted numeric core and whatever follows it as the
+/// channel tag, so that `-beta`, `+debug` or ` (kitsune)` suffixes no longer
+/// make a version unreadable.
+#[derive(Debug, Clone, Eq)]
+pub struct Version {
+    core: Vec<u64>,
+    channel: Option<String>,
+}
+
+impl Version {
+    /// Whether the numeric core is at least the one of `min`, ignoring channels.
+    ///
+    /// Managers tag builds of a given version code with their channel, so a
+    /// `27005-beta` satisfies a requirement of `27005`.
+    pub fn at_least(&self, min: &Version) -> bool {
+        self.cmp_core(min) != Ordering::Less
+    }
+
+    // Missing components count as zero, so that `1.2` equals `1.2.0`
+    fn cmp_core(&self, other: &Version) -> Ordering {
+        let len = self.core.len().max(other.core.len());
+        let part = |core: &[u64], i: usize| core.get(i).copied().unwrap_or(0);
+        (0..len)
+            .map(|i| part(&self.core, i).cmp(&part(&other.core, i)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Version> {
+        let s = s.trim();
+        let s = s.strip_prefix(['v', 'V']).unwrap_or(s);
+        let end = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let (core, rest) = s.split_at(end);
+        let core: Vec<u64> = match core
+            .trim_end_matches('.')
+            .split('.')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+        {
+            Ok(core) => core,
+            Err(_) => bail!("`{}` does not start with a version number", s),
+        };
+        let channel = rest.trim_start_matches(['-', '+', '_', ' ', '(']);
+        let channel = channel.trim_end_matches(')');
+        Ok(Version {
+            core,
+            channel: (!channel.is_empty()).then(|| channel.to_string()),
+        })
+    }
+}
+
+impl From<i64> for Version {
+    fn from(code: i64) -> Version {
+        Version {
+            core: vec![code.max(0) as u64],
+            channel: None,
+        }
+    }
+}
+
+impl From<i32> for Version {
+    fn from(code: i32) -> Version {
+        Version::from(code as i64)
+    }
+}
+
+impl From<u32> for Version {
+    fn from(code: u32) -> Version {
+        Version::from(code as i64)
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Version) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Version) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Like semver, a tagged version sorts before the plain release of the same core
+impl Ord for Version {
+    fn cmp(&self, other: &Version) -> Ordering {
+        self.cmp_core(other)
+            .then_with(|| match (&self.channel, &other.channel) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+                (Some(a), Some(b)) => a.cmp(b),
+            })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let core: Vec<String> = self.core.iter().map(u64::to_string).collect();
+        write!(f, "{}", core.join("."))?;
+        if let Some(channel) = &self.channel {
+            write!(f, "-{}", channel)?;
+        }
+        Ok(())
+    }
+}
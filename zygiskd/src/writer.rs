@@ -0,0 +1,260 @@
+use crate::constants::PATH_DATA_DIR;
+use crate::{config, profile};
+use anyhow::Result;
+use log::{debug, warn};
+use std::collections::VecDeque;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FsyncPolicy {
+    Never,
+    Always,
+    Periodic(Duration),
+}
+
+struct Shared {
+    // Records with the time they were queued at
+    queue: Mutex<VecDeque<(Instant, Vec<u8>)>>,
+    ready: Condvar,
+    capacity: usize,
+    dropped: AtomicU64,
+    // Set once the writer is dropped, the thread exiting when the queue is empty
+    closed: AtomicBool,
+}
+
+/// Append-only file writer whose `write` never blocks on I/O.
+///
+/// Records are queued for a background thread; once `capacity` records are
+/// pending, the oldest ones are dropped and counted instead. Past `rotate_at`
+/// bytes, the file is renamed to `<path>.1`, replacing the previous one, and
+/// started afresh, so that at most twice as much stays on disk.
+pub struct AsyncWriter {
+    path: PathBuf,
+    shared: Arc<Shared>,
+}
+
+impl AsyncWriter {
+    pub fn spawn(path: &Path, capacity: usize, fsync: FsyncPolicy, rotate_at: u64) -> Result<Self> {
+        let file = open_append(path)?;
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(VecDeque::with_capacity(capacity)),
+            ready: Condvar::new(),
+            capacity,
+            dropped: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        });
+        let worker = Arc::clone(&shared);
+        let output = Output {
+            size: file.metadata()?.len(),
+            file,
+            path: path.to_owned(),
+            rotate_at,
+        };
+        thread::Builder::new()
+            .name("writer".to_string())
+            .spawn(move || write_loop(output, &worker, fsync))?;
+        Ok(AsyncWriter {
+            path: path.to_owned(),
+            shared,
+        })
+    }
+
+    pub fn write(&self, record: Vec<u8>) {
+        let mut queue = profile::lock(&profile::WRITER, &self.shared.queue);
+        if queue.len() >= self.shared.capacity {
+            queue.pop_front();
+            let dropped = self.shared.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!(
+                    "{} dropped {} records under pressure",
+                    self.path.display(),
+                    dropped
+                );
+            }
+        }
+        queue.push_back((Instant::now(), record));
+        self.shared.ready.notify_one();
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for AsyncWriter {
+    fn drop(&mut self) {
+        let _queue = self.shared.queue.lock().unwrap();
+        self.shared.closed.store(true, Ordering::SeqCst);
+        self.shared.ready.notify_one();
+    }
+}
+
+// Records queued at most per file with the minimal runtime profile
+const MINIMAL_CAPACITY: usize = 32;
+// Size of a data file rotated to `<name>.1`
+const ROTATE_AT: u64 = 1024 * 1024;
+
+/// Open `name` under the daemon data directory with the configured policy.
+pub fn open_data_file(name: &str) -> Option<AsyncWriter> {
+    let config = config::get();
+    let path = Path::new(PATH_DATA_DIR).join(name);
+    let capacity = if config::minimal() {
+        config.writer_capacity.min(MINIMAL_CAPACITY)
+    } else {
+        config.writer_capacity
+    };
+    match fs::create_dir_all(PATH_DATA_DIR)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| AsyncWriter::spawn(&path, capacity, config.fsync, ROTATE_AT))
+    {
+        Ok(writer) => Some(writer),
+        Err(e) => {
+            warn!("Failed to open {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Append `record` to `name` under the daemon data directory right away, for
+/// commands run without an `AsyncWriter`.
+pub fn append_data_file(name: &str, record: &[u8]) {
+    let path = Path::new(PATH_DATA_DIR).join(name);
+    let full = fs::metadata(&path).is_ok_and(|m| m.len() + record.len() as u64 > ROTATE_AT);
+    if full {
+        let _ = fs::rename(&path, rotated(&path));
+    }
+    let written = open_append(&path).and_then(|mut file| file.write_all(record));
+    if let Err(e) = written {
+        debug!("Failed to write {}: {}", path.display(), e);
+    }
+}
+
+/// Seconds since epoch with millisecond precision, used to prefix records.
+pub fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{}.{:03}", now.as_secs(), now.subsec_millis())
+}
+
+fn open_append(path: &Path) -> std::io::Result<fs::File> {
+    fs::OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+struct Output {
+    file: fs::File,
+    path: PathBuf,
+    size: u64,
+    rotate_at: u64,
+}
+
+impl Output {
+    fn write(&mut self, record: &[u8]) -> std::io::Result<()> {
+        // A record larger than the limit still goes to a file of its own
+        if self.size > 0 && self.size + record.len() as u64 > self.rotate_at {
+            if let Err(e) = self.rotate() {
+                debug!("Failed to rotate {}: {}", self.path.display(), e);
+            }
+        }
+        self.file.write_all(record)?;
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    // Records are not lost to a failed rotation, they keep going to the
+    // current file
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let _ = self.file.sync_data();
+        fs::rename(&self.path, rotated(&self.path))?;
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn write_loop(mut output: Output, shared: &Shared, fsync: FsyncPolicy) {
+    let name = output.path.display().to_string();
+    let mut last_sync = Instant::now();
+    let mut unsynced = false;
+    loop {
+        let records: Vec<(Instant, Vec<u8>)> = {
+            let mut queue = shared.queue.lock().unwrap();
+            while queue.is_empty() {
+                if shared.closed.load(Ordering::SeqCst) {
+                    if unsynced && fsync != FsyncPolicy::Never {
+                        let _ = output.file.sync_data();
+                    }
+                    return;
+                }
+                match fsync {
+                    // Wake up eventually to sync what was written so far
+                    FsyncPolicy::Periodic(interval) if unsynced => {
+                        let (guard, timeout) = shared.ready.wait_timeout(queue, interval).unwrap();
+                        queue = guard;
+                        if timeout.timed_out() {
+                            break;
+                        }
+                    }
+                    _ => queue = shared.ready.wait(queue).unwrap(),
+                }
+            }
+            queue.drain(..).collect()
+        };
+        for (queued, record) in &records {
+            let start = Instant::now();
+            if let Err(e) = output.write(record) {
+                debug!("Failed to write {}: {}", name, e);
+            }
+            profile::WRITER_QUEUE.record(start - *queued, start.elapsed());
+        }
+        unsynced |= !records.is_empty();
+        let should_sync = match fsync {
+            FsyncPolicy::Never => false,
+            FsyncPolicy::Always => unsynced,
+            FsyncPolicy::Periodic(interval) => unsynced && last_sync.elapsed() >= interval,
+        };
+        if should_sync {
+            if let Err(e) = output.file.sync_data() {
+                debug!("Failed to sync {}: {}", name, e);
+            }
+            last_sync = Instant::now();
+            unsynced = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_past_the_limit() {
+        let dir = std::env::temp_dir().join(format!("neozygisk-writer-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.log");
+        let mut output = Output {
+            file: open_append(&path).unwrap(),
+            path: path.clone(),
+            size: 0,
+            rotate_at: 8,
+        };
+        for record in [b"1234\n", b"5678\n", b"abcd\n"] {
+            output.write(record).unwrap();
+        }
+        assert_eq!(fs::read(&path).unwrap(), b"abcd\n");
+        assert_eq!(fs::read(rotated(&path)).unwrap(), b"5678\n");
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -1,133 +1,978 @@
-use crate::constants::{DaemonSocketAction, MountNamespace, ProcessFlags};
+use crate::constants::{
+    CompanionAction, DaemonSocketAction, MountNamespace, Reduction, SocketPairType,
+};
+use crate::request::{self, Request};
+use crate::subsystem::Subsystem;
 use crate::utils::{LateInit, UnixStreamExt, check_unix_socket, save_mount_namespace};
-use crate::{constants, lp_select, root_impl, utils};
-use anyhow::{Result, bail};
+use crate::zygote::SpawnPath;
+use crate::{
+    adjust, audit, blackbox, config, constants, crash, dryrun, fingerprint, handover, hide,
+    history, logfwd, lp_select, manifest, messages, metrics, nscheck, packages, pidfd, policy,
+    prelisten, privop, profile, props, quarantine, root_impl, scripts, sockdir, store, subsystem,
+    tmpdir, uring, users, utils, zygote,
+};
+use anyhow::{Result, anyhow, bail};
 use log::{debug, error, info, trace, warn};
 use passfd::FdPassingExt;
 use rustix::fs::{FdFlags, fcntl_setfd};
+use rustix::net::{AddressFamily, SocketFlags, SocketType, socketpair};
+use std::collections::VecDeque;
 use std::fs;
-use std::io::Error;
+use std::io::{Error, ErrorKind, Read, Write};
 use std::ops::Deref;
-use std::os::fd::{AsFd, OwnedFd, RawFd};
+use std::os::fd::{AsFd, FromRawFd, OwnedFd, RawFd};
 use std::os::unix::process::CommandExt;
 use std::os::unix::{
     net::{UnixListener, UnixStream},
     prelude::AsRawFd,
 };
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, exit};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-struct Module {
-    name: String,
+pub struct Module {
+    pub name: String,
+    metadata: manifest::Metadata,
     lib_fd: OwnedFd,
     companion: Mutex<Option<UnixStream>>,
+    // Process of the companion, valid while `companion` is set
+    companion_pid: AtomicI32,
+    // Always locked after `companion`
+    companion_failure: Mutex<Option<CompanionFailure>>,
+    delayed_work_scheduled: AtomicBool,
+    // The library names `zygisk_companion_boot_entry`, so its companion is
+    // started with the daemon rather than on the first request of an app
+    boot_entry: bool,
+    // Injections into apps that never acknowledged finishing with this module
+    incomplete_injections: AtomicUsize,
+    // Apps injected with the module, which its companion may adjust
+    targets: adjust::Targets,
+}
+
+// Why the last spawn of a companion gave none, so that every request for it
+// does not fork again
+#[derive(Debug, Clone, Copy)]
+enum CompanionFailure {
+    // The module exports no companion entry, which only a reboot changes
+    NoEntry,
+    // Spawned again once this instant is reached
+    Failed(Instant),
+}
+
+struct SpawnedCompanion {
+    stream: UnixStream,
+    pid: i32,
+    // Set if the companion asked to run delayed work after boot
+    boot_delay: Option<Duration>,
 }
 
 struct Context {
     modules: Vec<Module>,
+    arch: &'static str,
+    // Set once NeoZygisk is turned off, until the next reboot
+    disabled: AtomicBool,
 }
 
+const DISABLE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const QUARANTINE_POLL_INTERVAL: Duration = Duration::from_secs(60);
+const DEGRADED_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const MEMORY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+const LOAD_WORKERS: usize = 4;
+// Time given to an app to run the post-specialize phase of its modules
+const INJECTION_ACK_DEADLINE: Duration = Duration::from_secs(10);
+// Ends the acknowledgments of an injection
+const INJECTION_ACK_END: usize = usize::MAX;
+// Where debuggerd writes the tombstones of native crashes
+const TOMBSTONE_DIR: &str = "/data/tombstones";
+// Time given to debuggerd to write the tombstone of an app whose connection closed
+const TOMBSTONE_WAIT: Duration = Duration::from_secs(5);
+// Time given to a companion spawned on first request to load its module
+const COMPANION_WARMUP_TIMEOUT: Duration = Duration::from_secs(10);
+// Before a companion which failed to spawn is tried again
+const COMPANION_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
 static TMP_PATH: LateInit<String> = LateInit::new();
 static CONTROLLER_SOCKET: LateInit<String> = LateInit::new();
-static PATH_CP_NAME: LateInit<String> = LateInit::new();
 static IS_FIRST_PROCESS: LateInit<bool> = LateInit::new();
 
 pub fn main() -> Result<()> {
+    let started = Instant::now();
     info!("Welcome to NeoZygisk ({}) !", constants::ZKSU_VERSION);
+    crash::setup();
 
     TMP_PATH.init(std::env::var("TMP_PATH")?);
     CONTROLLER_SOCKET.init(format!("{}/init_monitor", TMP_PATH.deref()));
-    PATH_CP_NAME.init(format!(
-        "{}/{}",
-        TMP_PATH.deref(),
-        lp_select!("/cp32.sock", "/cp64.sock")
-    ));
 
+    let inherited = handover::take().unwrap_or_else(|e| {
+        warn!("Ignoring broken handover, starting afresh: {}", e);
+        None
+    });
+    let mut listener = match &inherited {
+        // Connections queued while exec-ing are still pending on the inherited listener
+        Some(state) => UnixListener::from(handover::own(state.listener_fd)),
+        None => create_daemon_socket()?,
+    };
+    let parking = prelisten::start(&listener)?;
     let arch = get_arch()?;
     debug!("Daemon architecture: {arch}");
-    let modules = load_modules(arch)?;
+    zygote::setup();
+    config::setup();
+    if let Some(deadline) = config::get().startup_deadline {
+        subsystem::set_deadline(started + deadline);
+    }
+    if config::get().dry_run {
+        dryrun::enable(false);
+        warn!("Dry run: changes to the system are only logged");
+    }
+    debug!("Store: {}", store::get().name());
+    if config::get().legacy_socket_path {
+        sockdir::link_legacy(Path::new(TMP_PATH.deref()));
+    }
+    if let Err(e) = scripts::rotate_token(Path::new(TMP_PATH.deref())) {
+        warn!("Script requests unavailable: {}", e);
+    }
+    blackbox::setup();
+    blackbox::record(&format!(
+        "daemon {} started, root {:?}",
+        constants::ZKSU_VERSION,
+        root_impl::get_impl()
+    ));
+    let environment = fingerprint::setup();
+    blackbox::record(&format!("environment {}", environment));
+    for guard in fingerprint::guards() {
+        blackbox::record(&format!(
+            "reduced {:?} by {}",
+            guard.reduction, guard.issue.code
+        ));
+    }
+    let inherited_tmpdir = inherited.as_ref().and_then(|state| state.tmpdir.clone());
+    let scratch = match inherited_tmpdir {
+        Some(dir) => tmpdir::adopt(dir),
+        None => tmpdir::setup(),
+    };
+    if let Err(e) = scratch {
+        warn!("Scratch directories unavailable: {}", e);
+    }
+    audit::setup();
+    subsystem::register(&metrics::SUBSYSTEM);
+    quarantine::setup();
+    users::setup();
+    let modules = match &inherited {
+        Some(state) => restore_state(state),
+        None => load_modules(arch)?,
+    };
+    props::setup(&modules.iter().map(|m| m.name.as_str()).collect::<Vec<_>>());
+    subsystem::register(&props::SUBSYSTEM);
+    subsystem::register(&nscheck::SUBSYSTEM);
+    subsystem::register(&logfwd::SUBSYSTEM);
 
-    {
-        let mut msg = Vec::<u8>::new();
-        let info = match root_impl::get_impl() {
-            root_impl::RootImpl::APatch
-            | root_impl::RootImpl::KernelSU
-            | root_impl::RootImpl::Magisk => {
-                msg.extend_from_slice(&constants::DAEMON_SET_INFO.to_le_bytes());
-                let module_names: Vec<_> = modules.iter().map(|m| m.name.as_str()).collect();
-                if module_names.len() > 0 {
-                    format!(
-                        "\t\tRoot: {:?}\n\t\tModule({}):\n\t\t\t{}",
-                        root_impl::get_impl(),
-                        modules.len(),
-                        module_names.join("\n\t\t\t")
-                    )
-                } else {
-                    format!("\t\tRoot: {:?}", root_impl::get_impl())
-                }
-            }
-            _ => {
-                msg.extend_from_slice(&constants::DAEMON_SET_ERROR_INFO.to_le_bytes());
-                format!(
-                    "\t\tInvalid root implementation: {:?}",
-                    root_impl::get_impl()
-                )
-            }
-        };
-        msg.extend_from_slice(&(info.len() as u32 + 1).to_le_bytes());
-        msg.extend_from_slice(info.as_bytes());
-        msg.extend_from_slice(&[0u8]);
-        utils::unix_datagram_sendto(&CONTROLLER_SOCKET, msg.as_slice())
-            .expect("failed to send info");
+    for advisory in root_impl::advisories() {
+        warn!("Root implementation advisory: {}", advisory.issue.text);
+    }
+    send_status(&modules, arch).expect("failed to send info");
+    if config::minimal() {
+        watch_memory_budget();
     }
 
-    let context = Context { modules };
+    let context = Context {
+        modules,
+        arch,
+        disabled: AtomicBool::new(inherited.as_ref().is_some_and(|state| state.disabled)),
+    };
     let context = Arc::new(context);
-    let listener = create_daemon_socket()?;
-    for stream in listener.incoming() {
-        let mut stream = stream?;
+    watch_disable_flag(&context);
+    watch_quarantines(&context);
+    watch_degraded(&context);
+    watch_injection_acks(&context)?;
+    start_boot_companions(&context);
+    let mut pending = VecDeque::from(parking.release());
+    if !pending.is_empty() {
+        debug!(
+            "Serving {} connections parked during startup",
+            pending.len()
+        );
+        blackbox::record(&format!("{} early connections parked", pending.len()));
+    }
+    loop {
+        let mut stream = match pending.pop_front() {
+            Some(stream) => stream,
+            None => listener.accept()?.0,
+        };
         let context = Arc::clone(&context);
-        let action = stream.read_u8()?;
-        let action = DaemonSocketAction::try_from(action)?;
+        // A client closing early or speaking another version must not stop the daemon
+        let action = match stream
+            .read_u8()
+            .and_then(|a| Ok(DaemonSocketAction::try_from(a)?))
+        {
+            Ok(action) => action,
+            Err(e) => {
+                warn!("Ignoring bad daemon request: {}", e);
+                continue;
+            }
+        };
         trace!("New daemon action {:?}", action);
+        let _op = crash::set_op(action);
         match action {
             DaemonSocketAction::CacheMountNamespace => {
-                let pid = stream.read_u32()? as i32;
-                save_mount_namespace(pid, MountNamespace::Clean)?;
-                save_mount_namespace(pid, MountNamespace::Root)?;
-                save_mount_namespace(pid, MountNamespace::Module)?;
+                let Ok(Request::CacheMountNamespace { pid }) =
+                    request::read_fields(action, &mut stream)
+                else {
+                    warn!("Ignoring truncated {:?} request", action);
+                    continue;
+                };
+                let pid = pid as i32;
+                blackbox::record(&format!("caching mount namespaces from {pid}"));
+                let strategies = hide::in_use();
+                save_mount_namespace(pid, MountNamespace::Root, strategies[0])?;
+                for strategy in strategies {
+                    save_mount_namespace(pid, MountNamespace::Clean, strategy)?;
+                    save_mount_namespace(pid, MountNamespace::Module, strategy)?;
+                }
+                nscheck::snapshot(pid);
             }
             DaemonSocketAction::PingHeartbeat => {
+                blackbox::record("zygote injected");
                 let value = constants::ZYGOTE_INJECTED;
                 utils::unix_datagram_sendto(&CONTROLLER_SOCKET, &value.to_le_bytes())?;
             }
             DaemonSocketAction::ZygoteRestart => {
-                info!("Zygote restarted, clean up companions");
+                blackbox::record("zygote restarted");
+                info!(
+                    "Zygote restarted ({}), clean up companions",
+                    zygote::summary()
+                );
+                debug!(
+                    "Records dropped so far: audit {}, metrics {}",
+                    audit::dropped(),
+                    metrics::dropped()
+                );
                 for module in &context.modules {
-                    let mut companion = module.companion.lock().unwrap();
+                    let mut companion = profile::lock(&profile::COMPANION, &module.companion);
                     companion.take();
+                    // Tried again by the new zygote, which may remedy transient failures;
+                    // a missing entry stays missing until the next reboot
+                    let mut failure = module.companion_failure.lock().unwrap();
+                    if matches!(*failure, Some(CompanionFailure::Failed(_))) {
+                        failure.take();
+                    }
+                }
+            }
+            DaemonSocketAction::Handover => {
+                let Ok(Request::Handover { exe }) = request::read_fields(action, &mut stream)
+                else {
+                    warn!("Ignoring truncated {:?} request", action);
+                    continue;
+                };
+                // The daemon runs whatever it hands over to as root
+                let target = match utils::peer_uid(&stream) {
+                    Ok(0) => handover::verify(&exe),
+                    Ok(uid) => Err(anyhow!("requested by uid {}", uid)),
+                    Err(e) => Err(e),
+                };
+                let (exe, version) = match target {
+                    Ok(target) => target,
+                    Err(e) => {
+                        warn!("Refusing handover to {}: {}", exe, e);
+                        let _ = stream.write_u8(0);
+                        continue;
+                    }
+                };
+                info!("Handover to {} ({}) requested", version, exe.display());
+                let _ = stream.write_u8(1);
+                drop(stream);
+                blackbox::record(&format!("handing over to {version}"));
+                let state = handover_state(&context, &listener);
+                if let Err(e) = handover::exec(&exe, &state) {
+                    error!("Handover failed, keep serving: {}", e);
+                }
+            }
+            // Replies are dropped when the client is already gone
+            DaemonSocketAction::DisableAll => {
+                if !from_root(&stream, action) {
+                    let _ = stream.write_u8(0);
+                    continue;
+                }
+                disable_all(&context);
+                let _ = stream.write_u8(1);
+            }
+            DaemonSocketAction::DumpProfile => {
+                let _ = stream.write_string(&profile::dump());
+            }
+            DaemonSocketAction::RotateSecrets if !from_root(&stream, action) => {
+                let _ = stream.write_u8(0);
+            }
+            DaemonSocketAction::RotateSecrets => {
+                match rotate_secrets(&context, &mut listener, &mut pending) {
+                    Ok(()) => {
+                        let _ = stream.write_u8(1);
+                    }
+                    Err(e) => {
+                        warn!("Failed to rotate the daemon secrets: {}", e);
+                        let _ = stream.write_u8(0);
+                    }
                 }
             }
             DaemonSocketAction::SystemServerStarted => {
+                zygote::record_spawn(SpawnPath::SystemServer, 1000);
+                blackbox::record("system_server started");
                 let value = constants::SYSTEM_SERVER_STARTED;
                 utils::unix_datagram_sendto(&CONTROLLER_SOCKET, &value.to_le_bytes())?;
             }
             _ => {
+                let accepted = Instant::now();
                 thread::spawn(move || {
+                    let started = Instant::now();
+                    let _op = crash::set_op(action);
                     if let Err(e) = handle_daemon_action(action, stream, &context) {
                         warn!("Error handling daemon action: {}\n{}", e, e.backtrace());
                     }
+                    profile::REQUEST.record(started - accepted, started.elapsed());
                 });
             }
         }
     }
+}
 
+// Controls changing the daemon for every app are only taken from root
+fn from_root(stream: &UnixStream, action: DaemonSocketAction) -> bool {
+    match utils::peer_uid(stream) {
+        Ok(0) => true,
+        Ok(uid) => {
+            warn!("Refusing {:?} from uid {}", action, uid);
+            false
+        }
+        Err(e) => {
+            warn!("Refusing {:?}: {}", action, e);
+            false
+        }
+    }
+}
+
+// Regenerate the script token and serve from sockets of new names, so that
+// whoever learnt the old ones can no longer reach the daemon. Connections
+// already queued on the old socket are still served; clients connecting later
+// look the new name up, and companions are told to.
+fn rotate_secrets(
+    context: &Context,
+    listener: &mut UnixListener,
+    pending: &mut VecDeque<UnixStream>,
+) -> Result<()> {
+    let tmp_path = Path::new(TMP_PATH.deref());
+    // Rotated first, nothing may fail once the daemon socket is renamed
+    scripts::rotate_token(tmp_path)?;
+    logfwd::rotate(tmp_path)?;
+    let (old_path, new) = sockdir::rotate(tmp_path, sockdir::ARCH, bind_daemon_socket)?;
+    let old = std::mem::replace(listener, new);
+    // Unlinked first, so that no connection lands on the old socket while draining it
+    let _ = fs::remove_file(&old_path);
+    match old.set_nonblocking(true) {
+        Ok(()) => {
+            while let Ok((stream, _)) = old.accept() {
+                if stream.set_nonblocking(false).is_ok() {
+                    pending.push_back(stream);
+                }
+            }
+        }
+        Err(e) => warn!("Connections pending on the old socket are dropped: {}", e),
+    }
+    if config::get().legacy_socket_path {
+        sockdir::link_legacy(tmp_path);
+    }
+    for module in &context.modules {
+        let mut companion = profile::lock(&profile::COMPANION, &module.companion);
+        let told = match companion.as_mut() {
+            Some(sock) => sock.write_u8(CompanionAction::SecretsRotated as u8).is_ok(),
+            None => true,
+        };
+        // Spawned again on the next request
+        if !told {
+            companion.take();
+        }
+    }
+    audit::record("rotate-secrets");
+    blackbox::record("daemon secrets rotated");
     Ok(())
 }
 
-fn get_arch() -> Result<&'static str> {
+// Tear down all module state so that turning NeoZygisk off takes effect without a reboot:
+// the monitor stops injecting zygote, while processes forked by an already injected zygote
+// get neither modules nor companions.
+fn disable_all(context: &Context) {
+    if dryrun::skip(|| "disable all modules and stop the monitor".to_string()) {
+        return;
+    }
+    if context.disabled.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    info!("NeoZygisk disabled, tearing down module state");
+    blackbox::record("disabled by user");
+    let value = constants::MONITOR_STOP;
+    if let Err(e) = utils::unix_datagram_sendto(&CONTROLLER_SOCKET, &value.to_le_bytes()) {
+        warn!("Failed to stop the monitor: {}", e);
+    }
+    for module in &context.modules {
+        // Companions exit once their end of the stream is closed
+        profile::lock(&profile::COMPANION, &module.companion).take();
+    }
+    utils::drop_mount_namespaces();
+    // Nothing is left for the checker to rebuild, nor for a restart of it
+    nscheck::forget();
+    // Also ends its retries when degraded, so it is not told to stop only when running
+    let _ = subsystem::control(subsystem::Control::Stop, nscheck::SUBSYSTEM.name());
+}
+
+// Status shown by the monitor in the module description, sent again whenever
+// it changes while the daemon runs
+fn send_status(modules: &[Module], arch: &str) -> Result<()> {
+    let mut msg = Vec::<u8>::new();
+    let info = match root_impl::get_impl() {
+        root_impl::RootImpl::APatch
+        | root_impl::RootImpl::KernelSU
+        | root_impl::RootImpl::Magisk => {
+            msg.extend_from_slice(&constants::DAEMON_SET_INFO.to_le_bytes());
+            let module_names: Vec<_> = modules.iter().map(describe_module).collect();
+            let root = format!("{:?}", root_impl::get_impl());
+            let mut info = format!("\t\t{}", messages::coded(&messages::ROOT, &[&root]));
+            for advisory in root_impl::advisories() {
+                let issue = messages::text(&advisory.issue);
+                info.push_str(&format!(
+                    "\n\t\t[{}] {}",
+                    advisory.issue.code,
+                    messages::format(&messages::ADVISORY, &[&issue])
+                ));
+            }
+            for guard in fingerprint::guards() {
+                let issue = messages::text(&guard.issue);
+                info.push_str(&format!(
+                    "\n\t\t[{}] {}",
+                    guard.issue.code,
+                    messages::format(&messages::REDUCED, &[&issue])
+                ));
+            }
+            if module_names.len() > 0 {
+                info.push_str(&format!(
+                    "\n\t\t{}\n\t\t\t{}",
+                    messages::coded(&messages::MODULES, &[&modules.len()]),
+                    module_names.join("\n\t\t\t")
+                ));
+            }
+            // Shown like the root manager will treat them at the next boot
+            let flagged: Vec<_> = flagged_modules(arch)
+                .into_iter()
+                .map(|(name, marker)| marker.describe(&name))
+                .collect();
+            if !flagged.is_empty() {
+                info.push_str(&format!(
+                    "\n\t\t{}\n\t\t\t{}",
+                    messages::coded(&messages::SKIPPED_MODULES, &[&flagged.len()]),
+                    flagged.join("\n\t\t\t")
+                ));
+            }
+            if let Some(crash) = crash::previous() {
+                info.push_str(&format!(
+                    "\n\t\t{}",
+                    messages::coded(&messages::DAEMON_CRASHED, &[&crash])
+                ));
+            }
+            for (name, reason) in subsystem::degraded() {
+                info.push_str(&format!(
+                    "\n\t\t{}",
+                    messages::coded(&messages::DEGRADED, &[&name, &reason])
+                ));
+            }
+            for entry in quarantine::entries() {
+                let (message, module, crashes) = match &entry {
+                    quarantine::Entry::Pending { module, crashes } => {
+                        (&messages::QUARANTINE_PENDING, module, crashes)
+                    }
+                    quarantine::Entry::Quarantined { module, crashes } => {
+                        (&messages::QUARANTINED, module, crashes)
+                    }
+                };
+                info.push_str(&format!(
+                    "\n\t\t{}",
+                    messages::coded(message, &[module, crashes, module])
+                ));
+            }
+            info
+        }
+        _ => {
+            msg.extend_from_slice(&constants::DAEMON_SET_ERROR_INFO.to_le_bytes());
+            let root = format!("{:?}", root_impl::get_impl());
+            format!("\t\t{}", messages::coded(&messages::INVALID_ROOT, &[&root]))
+        }
+    };
+    msg.extend_from_slice(&(info.len() as u32 + 1).to_le_bytes());
+    msg.extend_from_slice(info.as_bytes());
+    msg.extend_from_slice(&[0u8]);
+    utils::unix_datagram_sendto(&CONTROLLER_SOCKET, msg.as_slice())
+}
+
+// Memory of our own, file mappings such as module libraries aside
+fn anonymous_memory_kib() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("RssAnon:"))?;
+    line.split_whitespace().nth(1)?.parse::<u64>().ok()
+}
+
+// Resident memory against the target of the minimal runtime profile, for as long as
+// the daemon runs: caches and queues fill up with use, not at startup
+fn watch_memory_budget() {
+    thread::spawn(|| {
+        let mut over = false;
+        loop {
+            match anonymous_memory_kib() {
+                Some(kib) if kib > constants::MINIMAL_MEMORY_BUDGET_KIB => {
+                    if !over {
+                        warn!(
+                            "Resident memory {} KiB over the target of {} KiB",
+                            kib,
+                            constants::MINIMAL_MEMORY_BUDGET_KIB
+                        );
+                    }
+                    over = true;
+                }
+                Some(kib) => {
+                    if over {
+                        info!("Resident memory back to {} KiB", kib);
+                    }
+                    over = false;
+                }
+                None => {
+                    debug!("Resident memory unknown");
+                    return;
+                }
+            }
+            thread::sleep(MEMORY_POLL_INTERVAL);
+        }
+    });
+}
+
+fn watch_quarantines(context: &Arc<Context>) {
+    if config::get().quarantine != quarantine::Mode::Grace {
+        return;
+    }
+    let context = Arc::clone(context);
+    thread::spawn(move || {
+        let mut entries = quarantine::entries();
+        loop {
+            thread::sleep(QUARANTINE_POLL_INTERVAL);
+            quarantine::expire();
+            // Also catches answers given with `zygiskd quarantine keep`
+            let current = quarantine::entries();
+            if current != entries {
+                if let Err(e) = send_status(&context.modules, context.arch) {
+                    warn!("Failed to update the status: {}", e);
+                }
+                entries = current;
+            }
+        }
+    });
+}
+
+// Until every subsystem left starting in the background is started
+fn watch_degraded(context: &Arc<Context>) {
+    let mut degraded = subsystem::degraded();
+    if degraded.is_empty() {
+        return;
+    }
+    let context = Arc::clone(context);
+    thread::spawn(move || {
+        while !degraded.is_empty() {
+            thread::sleep(DEGRADED_POLL_INTERVAL);
+            let current = subsystem::degraded();
+            if current != degraded {
+                if let Err(e) = send_status(&context.modules, context.arch) {
+                    warn!("Failed to update the status: {}", e);
+                }
+                degraded = current;
+            }
+        }
+    });
+}
+
+// Root managers disable a module by creating `disable` in its directory, which is our cwd.
+fn watch_disable_flag(context: &Arc<Context>) {
+    let context = Arc::clone(context);
+    thread::spawn(move || {
+        while !context.disabled.load(Ordering::SeqCst) {
+            if Path::new("disable").exists() {
+                disable_all(&context);
+                break;
+            }
+            thread::sleep(DISABLE_POLL_INTERVAL);
+        }
+    });
+}
+
+// Send `action` and the fields written by `fields`, in the order
+// `request::read_fields` decodes them
+fn write_request(
+    stream: &mut UnixStream,
+    action: DaemonSocketAction,
+    fields: impl FnOnce(&mut UnixStream) -> Result<()>,
+) -> Result<()> {
+    stream.write_u8(action as u8)?;
+    fields(stream)
+}
+
+// A status byte, then the output of the request or why it was refused
+fn read_reply(stream: &mut UnixStream) -> Result<std::result::Result<String, String>> {
+    Ok(match stream.read_u8()? {
+        1 => Ok(stream.read_string()?),
+        _ => Err(stream.read_string()?),
+    })
+}
+
+// Streams to every running daemon by architecture, for commands of zygisk-ctl
+fn connect_daemons() -> Result<Vec<(&'static str, UnixStream)>> {
+    let tmp_path = std::env::var("TMP_PATH")
+        .map_err(|_| anyhow::anyhow!("TMP_PATH is not set, run through zygisk-ctl"))?;
+    let streams: Vec<_> = sockdir::ARCHES
+        .iter()
+        .filter_map(|arch| {
+            let socket = sockdir::lookup(Path::new(&tmp_path), arch).ok()?;
+            Some((*arch, UnixStream::connect(&socket).ok()?))
+        })
+        .collect();
+    if streams.is_empty() {
+        bail!("no daemon is running");
+    }
+    Ok(streams)
+}
+
+/// Ask every running daemon to disable NeoZygisk, as done by `zygiskd disable-all`.
+pub fn request_disable_all() -> Result<()> {
+    for (arch, mut stream) in connect_daemons()? {
+        if dryrun::skip(|| format!("ask the {} daemon to disable NeoZygisk", arch)) {
+            continue;
+        }
+        write_request(&mut stream, DaemonSocketAction::DisableAll, |_| Ok(()))?;
+        if stream.read_u8()? == 0 {
+            bail!(
+                "the {} daemon refused, only root may disable NeoZygisk",
+                arch
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Move the socket of every running daemon to a new name, as done by
+/// `zygiskd rotate-secrets`.
+pub fn request_rotate_secrets() -> Result<()> {
+    for (arch, mut stream) in connect_daemons()? {
+        if dryrun::skip(|| format!("rename the socket of the {} daemon", arch)) {
+            continue;
+        }
+        write_request(&mut stream, DaemonSocketAction::RotateSecrets, |_| Ok(()))?;
+        if stream.read_u8()? == 0 {
+            bail!(
+                "the {} daemon failed to rotate its secrets, see its log",
+                arch
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Apply `control` to subsystem `name` of every running daemon, as done by
+/// `zygiskd subsystem`, returning what each of them printed.
+pub fn request_subsystem(
+    control: subsystem::Control,
+    name: &str,
+) -> Result<Vec<(&'static str, String)>> {
+    let mut outputs = Vec::new();
+    for (arch, mut stream) in connect_daemons()? {
+        write_request(
+            &mut stream,
+            DaemonSocketAction::ControlSubsystem,
+            |stream| {
+                stream.write_u8(control as u8)?;
+                stream.write_string(name)
+            },
+        )?;
+        match read_reply(&mut stream)? {
+            Ok(output) => outputs.push((arch, output)),
+            Err(reason) => bail!("the {} daemon refused: {}", arch, reason),
+        }
+    }
+    Ok(outputs)
+}
+
+/// Lock and queue timings of every running daemon, as printed by `zygiskd profile`.
+pub fn request_profile() -> Result<Vec<(&'static str, String)>> {
+    let mut profiles = Vec::new();
+    for (arch, mut stream) in connect_daemons()? {
+        write_request(&mut stream, DaemonSocketAction::DumpProfile, |_| Ok(()))?;
+        profiles.push((arch, stream.read_string()?));
+    }
+    Ok(profiles)
+}
+
+/// Records of `package` kept by every running daemon, or the packages they
+/// have records of for an empty `package`, as printed by `zygiskd history`.
+pub fn request_history(package: &str) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    let mut error = None;
+    for (_, mut stream) in connect_daemons()? {
+        write_request(&mut stream, DaemonSocketAction::GetHistory, |stream| {
+            stream.write_string(package)
+        })?;
+        match read_reply(&mut stream)? {
+            Ok(records) => lines.extend(records.lines().map(str::to_string)),
+            Err(reason) => error = Some(reason),
+        }
+    }
+    if let (true, Some(error)) = (lines.is_empty(), error) {
+        bail!("{}", error);
+    }
+    // Records start with their timestamp, packages are listed by both daemons
+    lines.sort();
+    lines.dedup();
+    Ok(lines)
+}
+
+// Connect to the daemon of our architecture from a companion process
+// Looked up once per process, and again once the daemon says it was renamed
+static DAEMON_SOCKET: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+fn connect_daemon() -> Result<UnixStream> {
+    let lookup = || -> Result<PathBuf> {
+        let tmp_path = std::env::var("TMP_PATH")?;
+        sockdir::lookup(Path::new(&tmp_path), sockdir::ARCH)
+    };
+    let cached = DAEMON_SOCKET.lock().unwrap().clone();
+    let connected = cached.and_then(|path| Some((UnixStream::connect(&path).ok()?, path)));
+    // Looked up again as well when renamed without this process being told
+    let (stream, path) = match connected {
+        Some(connected) => connected,
+        None => {
+            let path = lookup()?;
+            (UnixStream::connect(&path)?, path)
+        }
+    };
+    *DAEMON_SOCKET.lock().unwrap() = Some(path);
+    Ok(stream)
+}
+
+/// Look the daemon socket up again on the next request.
+pub fn forget_daemon_socket() {
+    DAEMON_SOCKET.lock().unwrap().take();
+}
+
+// Send a request to the daemon of our architecture, see `write_request`
+fn send_request(
+    action: DaemonSocketAction,
+    fields: impl FnOnce(&mut UnixStream) -> Result<()>,
+) -> Result<UnixStream> {
+    let mut stream = connect_daemon()?;
+    write_request(&mut stream, action, fields)?;
+    Ok(stream)
+}
+
+/// Ask the daemon about `package`, on behalf of a companion.
+pub fn request_package_info(package: &str) -> Result<Option<packages::PackageInfo>> {
+    let mut stream = send_request(DaemonSocketAction::GetPackageInfo, |stream| {
+        stream.write_string(package)
+    })?;
+    if stream.read_u8()? == 0 {
+        return Ok(None);
+    }
+    let version_code = stream.read_string()?.parse()?;
+    let cert_digest = Some(stream.read_string()?).filter(|d| !d.is_empty());
+    let installer = Some(stream.read_string()?).filter(|i| !i.is_empty());
+    Ok(Some(packages::PackageInfo {
+        version_code,
+        cert_digest,
+        installer,
+    }))
+}
+
+/// Ask the daemon to adjust the app process `pid`, on behalf of the companion of `module`.
+pub fn request_adjust_process(
+    module: &str,
+    pid: i32,
+    adjustment: adjust::Adjustment,
+    value: &str,
+) -> Result<()> {
+    let mut stream = send_request(DaemonSocketAction::AdjustProcess, |stream| {
+        stream.write_string(module)?;
+        stream.write_u32(pid as u32)?;
+        stream.write_u8(adjustment as u8)?;
+        stream.write_string(value)
+    })?;
+    match stream.read_u8()? {
+        1 => Ok(()),
+        _ => bail!("rejected by the daemon"),
+    }
+}
+
+/// Ask the daemon to run a privileged `operation`, on behalf of the companion of `module`.
+pub fn request_privileged(
+    module: &str,
+    operation: privop::Operation,
+    args: [&str; 2],
+) -> Result<String> {
+    let mut stream = send_request(DaemonSocketAction::RunPrivileged, |stream| {
+        stream.write_string(module)?;
+        stream.write_u8(operation as u8)?;
+        stream.write_string(args[0])?;
+        stream.write_string(args[1])
+    })?;
+    match read_reply(&mut stream)? {
+        Ok(output) => Ok(output),
+        Err(reason) => bail!("rejected by the daemon: {}", reason),
+    }
+}
+
+/// Run `command` for the script of `module` with the daemon of our
+/// architecture, as done by `zygiskd script`.
+pub fn request_script(module: &str, command: scripts::Command, args: [&str; 2]) -> Result<String> {
+    let tmp_path = std::env::var("TMP_PATH")
+        .map_err(|_| anyhow::anyhow!("TMP_PATH is not set, run through zygisk-script"))?;
+    let token = scripts::read_token(Path::new(&tmp_path), sockdir::ARCH)?;
+    let mut stream = send_request(DaemonSocketAction::RunScript, |stream| {
+        stream.write_string(&token)?;
+        stream.write_string(module)?;
+        stream.write_u8(command as u8)?;
+        stream.write_string(args[0])?;
+        stream.write_string(args[1])
+    })?;
+    match read_reply(&mut stream)? {
+        Ok(output) => Ok(output),
+        Err(reason) => bail!("rejected by the daemon: {}", reason),
+    }
+}
+
+/// The mount namespace the daemon hands out for `uid`, or for zygote itself
+/// with `None`; `None` is returned while apps keep the namespace of zygote.
+pub fn request_mount_namespace(
+    namespace: MountNamespace,
+    uid: Option<u32>,
+) -> Result<Option<fs::File>> {
+    // Retried while rebuilds replace the namespace being opened
+    for _ in 0..3 {
+        let mut stream = send_request(DaemonSocketAction::UpdateMountNamespace, |stream| {
+            stream.write_u8(namespace as u8)?;
+            stream.write_u32(uid.unwrap_or(u32::MAX))?;
+            // Not asked from an app forked by an unmounted zygote
+            stream.write_u8(0)
+        })?;
+        let pid = stream.read_u32()?;
+        // The daemon hangs up when the namespace is not cached yet
+        let fd = stream
+            .read_u32()
+            .map_err(|_| anyhow!("{:?} mount namespace not cached by the daemon", namespace))?;
+        if fd == 0 {
+            return Ok(None);
+        }
+        let generation = stream.read_usize()?;
+        let file = fs::File::open(format!("/proc/{}/fd/{}", pid, fd))?;
+        stream.write_usize(generation)?;
+        if stream.read_u8()? == 1 {
+            return Ok(Some(file));
+        }
+    }
+    bail!("{:?} mount namespace kept being replaced", namespace)
+}
+
+fn handover_state(context: &Context, listener: &UnixListener) -> handover::State {
+    let modules = context
+        .modules
+        .iter()
+        .map(|module| handover::ModuleState {
+            name: module.name.clone(),
+            lib_fd: module.lib_fd.as_raw_fd(),
+            companion_fd: profile::lock(&profile::COMPANION, &module.companion)
+                .as_ref()
+                .map(|c| c.as_raw_fd()),
+            companion_pid: Some(module.companion_pid.load(Ordering::SeqCst)).filter(|p| *p > 0),
+        })
+        .collect();
+    let namespaces = utils::cached_mount_namespaces()
+        .into_iter()
+        .map(|(namespace_type, strategy, fd)| (namespace_type as u8, strategy.to_string(), fd))
+        .collect();
+    handover::State {
+        version: constants::ZKSU_VERSION.to_string(),
+        listener_fd: listener.as_raw_fd(),
+        tmpdir: tmpdir::boot_dir().map(|dir| dir.to_owned()),
+        first_process_seen: IS_FIRST_PROCESS.initiated(),
+        disabled: context.disabled.load(Ordering::SeqCst),
+        modules,
+        namespaces,
+    }
+}
+
+// Modules are restored in their original order, since zygote refers to them by index.
+fn restore_state(state: &handover::State) -> Vec<Module> {
+    info!("Taking over from daemon {}", state.version);
+    blackbox::record(&format!("took over from {}", state.version));
+    if state.first_process_seen {
+        IS_FIRST_PROCESS.init(false);
+    }
+    for (namespace_type, strategy, fd) in &state.namespaces {
+        let namespace_type = MountNamespace::try_from(*namespace_type);
+        let strategy = if strategy.is_empty() {
+            Some(hide::STRATEGIES[0])
+        } else {
+            hide::find(strategy)
+        };
+        match (namespace_type, strategy) {
+            (Ok(namespace_type), Some(strategy)) => {
+                utils::restore_mount_namespace(namespace_type, strategy, *fd)
+            }
+            _ => drop(handover::own(*fd)),
+        }
+    }
+    state
+        .modules
+        .iter()
+        .map(|module| {
+            let dir = Path::new(constants::PATH_MODULES_DIR).join(&module.name);
+            let metadata = manifest::load(&dir, &module.name).unwrap_or_default();
+            let companion = module
+                .companion_fd
+                .map(|fd| UnixStream::from(handover::own(fd)));
+            Module {
+                name: module.name.clone(),
+                metadata,
+                lib_fd: handover::own(module.lib_fd),
+                companion: Mutex::new(companion),
+                companion_pid: AtomicI32::new(module.companion_pid.unwrap_or(0)),
+                companion_failure: Mutex::new(None),
+                delayed_work_scheduled: AtomicBool::new(false),
+                // Started by the daemon handing over, already
+                boot_entry: false,
+                incomplete_injections: AtomicUsize::new(0),
+                // Apps injected before the handover are not known
+                targets: adjust::Targets::default(),
+            }
+        })
+        .collect()
+}
+
+fn describe_module(module: &Module) -> String {
+    let mut description = module.name.clone();
+    if let Some(version) = module
+        .metadata
+        .prop
+        .as_ref()
+        .and_then(|p| p.version.as_ref())
+    {
+        description += &format!(" ({})", version);
+    }
+    if !module.metadata.issues.is_empty() {
+        description += &format!(" ⚠ {}", module.metadata.issues.join("; "));
+    }
+    description
+}
+
+pub fn get_arch() -> Result<&'static str> {
     let system_arch = utils::get_property("ro.product.cpu.abi")?;
     if system_arch.contains("arm") {
         return Ok(lp_select!("armeabi-v7a", "arm64-v8a"));
@@ -138,50 +983,231 @@ fn get_arch() -> Result<&'static str> {
     bail!("Unsupported system architecture: {}", system_arch);
 }
 
-fn load_modules(arch: &str) -> Result<Vec<Module>> {
-    let mut modules = Vec::new();
+// Outcome of loading a single module, reported once every module is done
+enum LoadOutcome {
+    Loaded(Module),
+    Refused(String),
+    Failed(String),
+}
+
+/// Marker file left by the root manager in a module directory, acted upon at the next boot.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ModuleMarker {
+    /// `disable`: the module stays installed but is not loaded
+    Disabled,
+    /// `remove`: the module is deleted at the next boot, and not loaded until then
+    Removed,
+}
+
+impl ModuleMarker {
+    // A module marked both ways is removed, which is what happens next
+    pub fn of(dir: &Path) -> Option<ModuleMarker> {
+        if dir.join("remove").exists() {
+            Some(ModuleMarker::Removed)
+        } else if dir.join("disable").exists() {
+            Some(ModuleMarker::Disabled)
+        } else {
+            None
+        }
+    }
+
+    pub fn describe(self, name: &str) -> String {
+        let message = match self {
+            ModuleMarker::Disabled => &messages::DISABLED_MODULE,
+            ModuleMarker::Removed => &messages::REMOVED_MODULE,
+        };
+        messages::format(message, &[&name])
+    }
+}
+
+// Zygisk modules of the modules directory: name, directory, library and
+// the marker keeping them from being loaded
+fn scan_modules(arch: &str) -> Result<Vec<(String, PathBuf, PathBuf, Option<ModuleMarker>)>> {
     let dir = match fs::read_dir(constants::PATH_MODULES_DIR) {
         Ok(dir) => dir,
         Err(e) => {
             warn!("Failed reading modules directory: {}", e);
-            return Ok(modules);
+            return Ok(Vec::new());
         }
     };
+    let mut modules = Vec::new();
     for entry in dir.into_iter() {
         let entry = entry?;
-        let name = entry.file_name().into_string().unwrap();
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(name) => {
+                warn!("Skip module with invalid name {:?}", name);
+                continue;
+            }
+        };
         let so_path = entry.path().join(format!("zygisk/{arch}.so"));
-        let disabled = entry.path().join("disable");
-        if !so_path.exists() || disabled.exists() {
+        if !so_path.exists() {
             continue;
         }
-        info!("Loading module `{name}`...");
-        let lib_fd = match create_library_fd(&so_path) {
-            Ok(fd) => fd,
-            Err(e) => {
+        let marker = ModuleMarker::of(&entry.path());
+        modules.push((name, entry.path(), so_path, marker));
+    }
+    Ok(modules)
+}
+
+/// Zygisk modules not loaded because of a marker of the root manager.
+pub fn flagged_modules(arch: &str) -> Vec<(String, ModuleMarker)> {
+    let modules = scan_modules(arch).unwrap_or_default();
+    modules
+        .into_iter()
+        .filter_map(|(name, _, _, marker)| Some((name, marker?)))
+        .collect()
+}
+
+pub fn load_modules(arch: &str) -> Result<Vec<Module>> {
+    if fingerprint::reduced(Reduction::NoModules) {
+        info!("Not loading modules on this environment");
+        return Ok(Vec::new());
+    }
+    let start = Instant::now();
+    let mut modules = Vec::new();
+    let mut candidates = Vec::new();
+    for (name, dir, so_path, marker) in scan_modules(arch)? {
+        match marker {
+            Some(marker) => debug!("Skip module: {}", marker.describe(&name)),
+            None => candidates.push((name, dir, so_path)),
+        }
+    }
+
+    // Parsing metadata and copying libraries out of /data dominate startup
+    // with many modules, spread them over a few threads
+    let max_workers = if config::minimal() { 1 } else { LOAD_WORKERS };
+    let workers = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(max_workers)
+        .min(candidates.len());
+    let next = AtomicUsize::new(0);
+    let outcomes: Vec<Mutex<Option<LoadOutcome>>> =
+        candidates.iter().map(|_| Mutex::new(None)).collect();
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some((name, dir, so_path)) = candidates.get(index) else {
+                        break;
+                    };
+                    *outcomes[index].lock().unwrap() = Some(load_module(name, dir, so_path));
+                }
+            });
+        }
+    });
+
+    // Reported in directory order, whichever finished first
+    let (mut refused, mut failed) = (0, 0);
+    for ((name, _, _), outcome) in candidates.iter().zip(outcomes) {
+        match outcome
+            .into_inner()
+            .unwrap()
+            .expect("every module is processed")
+        {
+            LoadOutcome::Loaded(module) => {
+                for issue in &module.metadata.issues {
+                    warn!("Module `{name}` metadata: {issue}");
+                }
+                blackbox::record(&format!("loading module {name}"));
+                match module.metadata.prop.as_ref() {
+                    Some(prop) => info!(
+                        "Loading module `{name}` (version {}, code {})...",
+                        prop.version.as_deref().unwrap_or("unknown"),
+                        prop.version_code.unwrap_or(0)
+                    ),
+                    None => info!("Loading module `{name}`..."),
+                }
+                modules.push(module);
+            }
+            LoadOutcome::Refused(e) => {
+                warn!("Refusing to load module `{name}`: {e}");
+                blackbox::record(&format!("refused module {name}"));
+                refused += 1;
+            }
+            LoadOutcome::Failed(e) => {
                 warn!("Failed to create memfd for `{name}`: {e}");
-                continue;
+                failed += 1;
             }
-        };
-        let companion = Mutex::new(None);
-        let module = Module {
-            name,
-            lib_fd,
-            companion,
-        };
-        modules.push(module);
+        }
     }
+    info!(
+        "Loaded {} modules ({} refused, {} failed) in {}ms with {} threads",
+        modules.len(),
+        refused,
+        failed,
+        start.elapsed().as_millis(),
+        workers
+    );
 
     Ok(modules)
 }
 
-fn create_library_fd(so_path: &PathBuf) -> Result<OwnedFd> {
-    let opts = memfd::MemfdOptions::default().allow_sealing(true);
-    let memfd = opts.create("jit-cache-zygisk")?;
-    let file = fs::File::open(so_path)?;
-    let mut reader = std::io::BufReader::new(file);
+fn load_module(name: &str, dir: &Path, so_path: &Path) -> LoadOutcome {
+    let metadata = match manifest::load(dir, name) {
+        Ok(metadata) => metadata,
+        Err(e) => return LoadOutcome::Refused(e.to_string()),
+    };
+    let lib_fd = match create_library_fd(so_path) {
+        Ok(fd) => fd,
+        Err(e) => return LoadOutcome::Failed(e.to_string()),
+    };
+    LoadOutcome::Loaded(Module {
+        name: name.to_string(),
+        metadata,
+        lib_fd,
+        companion: Mutex::new(None),
+        companion_pid: AtomicI32::new(0),
+        companion_failure: Mutex::new(None),
+        delayed_work_scheduled: AtomicBool::new(false),
+        boot_entry: names_boot_entry(so_path),
+        incomplete_injections: AtomicUsize::new(0),
+        targets: adjust::Targets::default(),
+    })
+}
+
+// The daemon never maps module code, so it looks for the symbol name in the
+// string table of the library. A stray match only starts a companion early,
+// which then reports that it has no boot entry after all.
+fn names_boot_entry(so_path: &Path) -> bool {
+    const NAME: &[u8] = b"\0zygisk_companion_boot_entry\0";
+    fs::read(so_path).is_ok_and(|bytes| bytes.windows(NAME.len()).any(|w| w == NAME))
+}
+
+// Kernels with vm.memfd_noexec seal memfds not created executable, which
+// the linker could then not map; older kernels reject the flag
+const MFD_EXEC: libc::c_uint = 0x0010;
+
+fn create_library_memfd() -> Result<memfd::Memfd> {
+    let name = c"jit-cache-zygisk";
+    let flags = libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING;
+    let create = |flags: libc::c_uint| unsafe {
+        libc::syscall(libc::SYS_memfd_create, name.as_ptr(), flags)
+    };
+    let mut fd = create(flags | MFD_EXEC);
+    if fd < 0 && Error::last_os_error().raw_os_error() == Some(libc::EINVAL) {
+        fd = create(flags);
+    }
+    if fd < 0 {
+        return Err(Error::last_os_error().into());
+    }
+    let file = unsafe { fs::File::from_raw_fd(fd as RawFd) };
+    memfd::Memfd::try_from_file(file).map_err(|_| anyhow!("memfd_create returned no memfd"))
+}
+
+// Every injected process maps the library from this one sealed memfd, so
+// that its code is backed by the same pages in all of them
+fn create_library_fd(so_path: &Path) -> Result<OwnedFd> {
+    let memfd = create_library_memfd()?;
+    let mut file = fs::File::open(so_path)?;
     let mut writer = memfd.as_file();
-    std::io::copy(&mut reader, &mut writer)?;
+    let size = std::io::copy(&mut file, &mut writer)?;
+    debug!(
+        "Library {} shared from a {} bytes memfd",
+        so_path.display(),
+        size
+    );
 
     let mut seals = memfd::SealsHashSet::new();
     seals.insert(memfd::FileSeal::SealShrink);
@@ -194,17 +1220,27 @@ fn create_library_fd(so_path: &PathBuf) -> Result<OwnedFd> {
 }
 
 fn create_daemon_socket() -> Result<UnixListener> {
-    utils::set_socket_create_context("u:r:zygote:s0")?;
-    let listener = utils::unix_listener_from_path(&PATH_CP_NAME)?;
+    let path = sockdir::publish(Path::new(TMP_PATH.deref()), sockdir::ARCH)?;
+    bind_daemon_socket(&path)
+}
+
+fn bind_daemon_socket(path: &Path) -> Result<UnixListener> {
+    // Connections accepted later keep the context of the listener
+    let _context = utils::ScopedSockCreateContext::new(utils::ZYGOTE_CONTEXT)?;
+    let listener = utils::unix_listener_from_path(&path.to_string_lossy())?;
     Ok(listener)
 }
 
-fn spawn_companion(name: &str, lib_fd: RawFd) -> Result<Option<UnixStream>> {
+fn spawn_companion(name: &str, lib_fd: RawFd) -> Result<Option<SpawnedCompanion>> {
     let (mut daemon, companion) = UnixStream::pair()?;
 
     // FIXME: avoid getting self path from arg0
     let process = std::env::args().next().unwrap();
     let nice_name = process.split('/').last().unwrap();
+    let scratch_dir = tmpdir::module_dir(name);
+    if let Err(e) = &scratch_dir {
+        debug!("No scratch directory for `{}`: {}", name, e);
+    }
 
     unsafe {
         let pid = libc::fork();
@@ -212,14 +1248,34 @@ fn spawn_companion(name: &str, lib_fd: RawFd) -> Result<Option<UnixStream>> {
             bail!(Error::last_os_error());
         } else if pid > 0 {
             drop(companion);
-            let mut status: libc::c_int = 0;
-            libc::waitpid(pid, &mut status, 0);
+            let status = pidfd::Process::open(pid)?.reap()?;
             if libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 {
+                // A companion stuck loading its module is abandoned, it exits on hang up
+                daemon.set_read_timeout(Some(COMPANION_WARMUP_TIMEOUT))?;
+                // Written by the intermediate child before exiting
+                let companion_pid = daemon.read_u32()? as i32;
                 daemon.write_string(name)?;
                 daemon.send_fd(lib_fd)?;
-                return match daemon.read_u8()? {
+                let warmed_up = |e: anyhow::Error| match e.downcast_ref::<Error>().map(|e| e.kind())
+                {
+                    Some(ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                        anyhow!("not ready within {:?}", COMPANION_WARMUP_TIMEOUT)
+                    }
+                    _ => e,
+                };
+                return match daemon.read_u8().map_err(warmed_up)? {
                     0 => Ok(None),
-                    1 => Ok(Some(daemon)),
+                    1 => {
+                        let has_boot_entry = daemon.read_u8().map_err(warmed_up)? != 0;
+                        let boot_delay = daemon.read_u32().map_err(warmed_up)?;
+                        daemon.set_read_timeout(None)?;
+                        Ok(Some(SpawnedCompanion {
+                            stream: daemon,
+                            pid: companion_pid,
+                            boot_delay: has_boot_entry
+                                .then(|| Duration::from_secs(boot_delay as u64)),
+                        }))
+                    }
                     _ => bail!("Invalid companion response"),
                 };
             } else {
@@ -231,102 +1287,217 @@ fn spawn_companion(name: &str, lib_fd: RawFd) -> Result<Option<UnixStream>> {
         }
     }
 
-    Command::new(&process)
+    let mut command = Command::new(&process);
+    command
         .arg0(format!("{}-{}", nice_name, name))
         .arg("companion")
-        .arg(format!("{}", companion.as_raw_fd()))
-        .spawn()?;
-    exit(0)
+        .arg(format!("{}", companion.as_raw_fd()));
+    if let Ok(dir) = scratch_dir {
+        command.env("ZYGISK_TMPDIR", dir);
+    }
+    // Never return into the daemon loop from the forked child
+    match command.spawn() {
+        Ok(child) => {
+            let mut companion = companion;
+            let written = companion.write_u32(child.id());
+            exit(written.is_err() as i32)
+        }
+        Err(_) => exit(1),
+    }
+}
+
+// The companion of module `index`, spawned on the first request for it and
+// again if it is not running, unless its last spawn failed
+fn companion_of(context: &Arc<Context>, index: usize) -> profile::Guard<'_, Option<UnixStream>> {
+    let module = &context.modules[index];
+    let mut companion = profile::lock(&profile::COMPANION, &module.companion);
+    if let Some(sock) = companion.as_ref() {
+        if !check_unix_socket(sock, false) {
+            error!("Poll companion for module `{}` crashed", module.name);
+            companion.take();
+        }
+    }
+    if companion.is_some() {
+        return companion;
+    }
+    let mut failure = module.companion_failure.lock().unwrap();
+    let cached = match *failure {
+        Some(CompanionFailure::NoEntry) => true,
+        Some(CompanionFailure::Failed(retry_at)) => Instant::now() < retry_at,
+        None => false,
+    };
+    if cached {
+        trace!(
+            "Companion of `{}` not spawned again: {:?}",
+            module.name, *failure
+        );
+        return companion;
+    }
+    match spawn_companion(&module.name, module.lib_fd.as_raw_fd()) {
+        Ok(Some(c)) => {
+            trace!("Spawned companion for `{}`", module.name);
+            if let Some(delay) = c.boot_delay {
+                schedule_delayed_work(context, index, delay);
+            }
+            *companion = Some(c.stream);
+            module.companion_pid.store(c.pid, Ordering::SeqCst);
+            *failure = None;
+        }
+        Ok(None) => {
+            warn!("Companion not spawned for `{}`", module.name);
+            *failure = Some(CompanionFailure::NoEntry);
+        }
+        Err(e) => {
+            warn!(
+                "Failed to spawn companion for `{}`, retrying in {:?}: {}",
+                module.name, COMPANION_RETRY_INTERVAL, e
+            );
+            *failure = Some(CompanionFailure::Failed(
+                Instant::now() + COMPANION_RETRY_INTERVAL,
+            ));
+        }
+    };
+    companion
+}
+
+// Requests made on behalf of a module are only taken from its companion, the
+// process the daemon runs its code in with root
+fn check_companion(module: &Module, stream: &UnixStream) -> Result<()> {
+    let peer = utils::peer_pid(stream)?;
+    let companion = profile::lock(&profile::COMPANION, &module.companion);
+    let alive = companion
+        .as_ref()
+        .is_some_and(|sock| check_unix_socket(sock, false));
+    if !alive || module.companion_pid.load(Ordering::SeqCst) != peer {
+        bail!("process {} is not the companion of `{}`", peer, module.name);
+    }
+    Ok(())
+}
+
+// Without a boot entry started here, boot work would wait for an app to
+// request the companion of the module, which may never happen
+fn start_boot_companions(context: &Arc<Context>) {
+    if !context.modules.iter().any(|module| module.boot_entry) {
+        return;
+    }
+    let context = Arc::clone(context);
+    thread::spawn(move || {
+        for (index, module) in context.modules.iter().enumerate() {
+            if module.boot_entry {
+                debug!("Starting companion of `{}` for its boot work", module.name);
+                drop(companion_of(&context, index));
+            }
+        }
+    });
+}
+
+fn schedule_delayed_work(context: &Arc<Context>, index: usize, delay: Duration) {
+    let module = &context.modules[index];
+    if module.delayed_work_scheduled.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    debug!(
+        "Scheduled delayed work for `{}` {}s after boot",
+        module.name,
+        delay.as_secs()
+    );
+    let context = Arc::clone(context);
+    thread::spawn(move || {
+        let module = &context.modules[index];
+        if let Err(e) = utils::wait_property("sys.boot_completed", "1") {
+            warn!("Failed to wait for boot completion: {}", e);
+            return;
+        }
+        thread::sleep(delay);
+        let mut companion = profile::lock(&profile::COMPANION, &module.companion);
+        match companion.as_mut() {
+            Some(sock) => {
+                trace!("Pinging companion of `{}` for delayed work", module.name);
+                if let Err(e) = sock.write_u8(CompanionAction::RunDelayedWork as u8) {
+                    warn!("Failed to ping companion of `{}`: {}", module.name, e);
+                }
+            }
+            None => warn!(
+                "Companion of `{}` is gone, drop its delayed work",
+                module.name
+            ),
+        }
+    });
 }
 
 fn handle_daemon_action(
     action: DaemonSocketAction,
     mut stream: UnixStream,
-    context: &Context,
+    context: &Arc<Context>,
 ) -> Result<()> {
-    match action {
-        DaemonSocketAction::GetProcessFlags => {
-            let uid = stream.read_u32()? as i32;
-            let mut flags = ProcessFlags::empty();
-            if !IS_FIRST_PROCESS.initiated() {
-                flags |= ProcessFlags::IS_FIRST_PROCESS;
-                if root_impl::uid_is_systemui(uid) {
-                    trace!("Uid {} is systemui", uid,);
-                } else {
-                    trace!("Uid {} is the first app process", uid,);
-                }
+    match request::read_fields(action, &mut stream)? {
+        Request::GetProcessFlags { uid, path } => {
+            let uid = uid as i32;
+            let start = Instant::now();
+            zygote::record_spawn(path, uid);
+            let is_first_process = !IS_FIRST_PROCESS.initiated();
+            if is_first_process {
                 IS_FIRST_PROCESS.init(false);
-            } else if root_impl::uid_is_manager(uid) {
-                flags |= ProcessFlags::PROCESS_IS_MANAGER;
-                trace!("Uid {} is manager", uid,);
-            } else {
-                if root_impl::uid_granted_root(uid) {
-                    flags |= ProcessFlags::PROCESS_GRANTED_ROOT;
-                }
-                if root_impl::uid_should_umount(uid) {
-                    flags |= ProcessFlags::PROCESS_ON_DENYLIST;
-                }
             }
-            match root_impl::get_impl() {
-                root_impl::RootImpl::APatch => flags |= ProcessFlags::PROCESS_ROOT_IS_APATCH,
-                root_impl::RootImpl::KernelSU => flags |= ProcessFlags::PROCESS_ROOT_IS_KSU,
-                root_impl::RootImpl::Magisk => flags |= ProcessFlags::PROCESS_ROOT_IS_MAGISK,
-                _ => panic!("wrong root impl: {:?}", root_impl::get_impl()),
-            }
-            trace!(
-                "Uid {} granted root: {}",
-                uid,
-                flags.contains(ProcessFlags::PROCESS_GRANTED_ROOT)
-            );
-            trace!(
-                "Uid {} on denylist: {}",
-                uid,
-                flags.contains(ProcessFlags::PROCESS_ON_DENYLIST)
-            );
+            let flags = policy::process_flags(uid, is_first_process, None);
             stream.write_u32(flags.bits())?;
+            audit::record_process(uid, path, flags);
+            metrics::record_process_flags(uid, path, start.elapsed());
         }
-        DaemonSocketAction::UpdateMountNamespace => {
-            let namespace_type = stream.read_u8()?;
-            let namespace_type = MountNamespace::try_from(namespace_type)?;
+        Request::UpdateMountNamespace {
+            namespace: namespace_type,
+            uid,
+            inherits_clean,
+        } => {
+            let uid = Some(uid).filter(|uid| *uid != u32::MAX);
+            let strategy = hide::for_uid(uid);
             stream.write_u32(unsafe { libc::getpid() } as u32)?;
-            let fd = save_mount_namespace(-1, namespace_type)?;
+            let reuse = namespace_type == MountNamespace::Clean
+                && inherits_clean
+                && utils::zygote_mount_namespace_current(strategy);
+            if context.disabled.load(Ordering::SeqCst) || reuse {
+                // Keep the namespace inherited from zygote
+                stream.write_u32(0)?;
+                return Ok(());
+            }
+            let (fd, generation) = save_mount_namespace(-1, namespace_type, strategy)?;
             stream.write_u32(fd as u32)?;
+            // Echoed back once the client has opened the fd, which a rebuild may
+            // have closed and reused meanwhile
+            stream.write_usize(generation)?;
+            let opened = stream.read_usize()?;
+            let valid = utils::mount_namespace_valid(opened);
+            stream.write_u8(valid as u8)?;
+            if valid && uid.is_none() && namespace_type == MountNamespace::Clean {
+                utils::zygote_switched_mount_namespace(strategy, opened);
+            }
         }
-        DaemonSocketAction::ReadModules => {
+        Request::ReadModules => {
+            if context.disabled.load(Ordering::SeqCst) {
+                stream.write_usize(0)?;
+                return Ok(());
+            }
             stream.write_usize(context.modules.len())?;
             for module in context.modules.iter() {
                 stream.write_string(&module.name)?;
                 stream.send_fd(module.lib_fd.as_raw_fd())?;
             }
         }
-        DaemonSocketAction::RequestCompanionSocket => {
-            let index = stream.read_usize()?;
-            let module = &context.modules[index];
-            let mut companion = module.companion.lock().unwrap();
-            if let Some(sock) = companion.as_ref() {
-                if !check_unix_socket(sock, false) {
-                    error!("Poll companion for module `{}` crashed", module.name);
-                    companion.take();
-                }
+        Request::RequestCompanionSocket { index } => {
+            let Some(module) = context.modules.get(index) else {
+                bail!("invalid module index {}", index);
+            };
+            if context.disabled.load(Ordering::SeqCst) {
+                stream.write_u8(0)?;
+                return Ok(());
             }
-            if companion.is_none() {
-                match spawn_companion(&module.name, module.lib_fd.as_raw_fd()) {
-                    Ok(c) => {
-                        if c.is_some() {
-                            trace!("Spawned companion for `{}`", module.name);
-                        } else {
-                            warn!("Companion not spawned for `{}`", module.name);
-                        }
-                        *companion = c;
-                    }
-                    Err(e) => {
-                        warn!("Failed to spawn companion for `{}`: {}", module.name, e);
-                    }
-                };
-            }
-            match companion.as_ref() {
+            let mut companion = companion_of(context, index);
+            match companion.as_mut() {
                 Some(sock) => {
-                    if let Err(e) = sock.send_fd(stream.as_raw_fd()) {
+                    if let Err(e) = sock
+                        .write_u8(CompanionAction::HandleRequest as u8)
+                        .and_then(|_| Ok(sock.send_fd(stream.as_raw_fd())?))
+                    {
                         error!(
                             "Failed to send companion fd socket of module `{}`: {}",
                             module.name, e
@@ -340,9 +1511,212 @@ fn handle_daemon_action(
                 }
             }
         }
-        DaemonSocketAction::GetModuleDir => {
-            let index = stream.read_usize()?;
-            let module = &context.modules[index];
+        Request::CreateSocketPair { index, kind } => {
+            let Some(module) = context.modules.get(index) else {
+                bail!("invalid module index {}", index);
+            };
+            if context.disabled.load(Ordering::SeqCst) {
+                stream.write_u8(0)?;
+                return Ok(());
+            }
+            let socket_type = match kind {
+                SocketPairType::Stream => SocketType::STREAM,
+                SocketPairType::Datagram => SocketType::DGRAM,
+                SocketPairType::SeqPacket => SocketType::SEQPACKET,
+            };
+            // Of a type of their own which apps are allowed to use, unlike the
+            // sockets of the daemon
+            let (app, companion_end) = {
+                let _context = utils::ScopedSockCreateContext::new(utils::MODULE_SOCKET_CONTEXT)?;
+                socketpair(AddressFamily::UNIX, socket_type, SocketFlags::CLOEXEC, None)?
+            };
+            let mut companion = companion_of(context, index);
+            let Some(sock) = companion.as_mut() else {
+                stream.write_u8(0)?;
+                return Ok(());
+            };
+            // The companion acknowledges on its end, as for companion sockets
+            if let Err(e) = sock
+                .write_u8(CompanionAction::HandleRequest as u8)
+                .and_then(|_| Ok(sock.send_fd(companion_end.as_raw_fd())?))
+            {
+                error!(
+                    "Failed to send socket pair of module `{}`: {}",
+                    module.name, e
+                );
+                stream.write_u8(0)?;
+                return Ok(());
+            }
+            drop(companion);
+            debug!("Brokered {:?} socket pair for `{}`", kind, module.name);
+            stream.write_u8(1)?;
+            stream.send_fd(app.as_raw_fd())?;
+        }
+        Request::GetPropertyOverlay { process } => {
+            let overlay = props::overlay_for(&process);
+            stream.write_usize(overlay.len())?;
+            for (name, value) in &overlay {
+                stream.write_string(name)?;
+                stream.write_string(value)?;
+            }
+        }
+        Request::GetPackageInfo { package } => {
+            packages::check_name(&package)?;
+            match packages::get(&package)? {
+                Some(info) => {
+                    stream.write_u8(1)?;
+                    stream.write_string(&info.version_code.to_string())?;
+                    stream.write_string(info.cert_digest.as_deref().unwrap_or(""))?;
+                    stream.write_string(info.installer.as_deref().unwrap_or(""))?;
+                }
+                None => stream.write_u8(0)?,
+            }
+        }
+        Request::AdjustProcess {
+            module,
+            pid,
+            adjustment,
+            value,
+        } => {
+            let pid = pid as i32;
+            let Some(owner) = context.modules.iter().find(|m| m.name == module) else {
+                stream.write_u8(0)?;
+                bail!("adjustment requested for unknown module `{}`", module);
+            };
+            if let Err(e) = check_companion(owner, &stream) {
+                stream.write_u8(0)?;
+                bail!("adjustment refused for `{}`: {}", module, e);
+            }
+            match adjust::apply(&module, &owner.targets, pid, adjustment, &value) {
+                Ok(_) => stream.write_u8(1)?,
+                Err(e) => {
+                    stream.write_u8(0)?;
+                    warn!(
+                        "Refused {:?} of {} for `{}`: {}",
+                        adjustment, pid, module, e
+                    );
+                }
+            }
+        }
+        Request::RunPrivileged {
+            module,
+            operation,
+            args,
+        } => {
+            let Some(owner) = context.modules.iter().find(|m| m.name == module) else {
+                stream.write_u8(0)?;
+                stream.write_string("unknown module")?;
+                bail!(
+                    "privileged operation requested for unknown module `{}`",
+                    module
+                );
+            };
+            if let Err(e) = check_companion(owner, &stream) {
+                stream.write_u8(0)?;
+                stream.write_string("not the companion of the module")?;
+                bail!("privileged operation refused for `{}`: {}", module, e);
+            }
+            match privop::run(&module, operation, [&args[0], &args[1]]) {
+                Ok(output) => {
+                    stream.write_u8(1)?;
+                    stream.write_string(&output)?;
+                }
+                Err(e) => {
+                    warn!("Refused {:?} for `{}`: {}", operation, module, e);
+                    stream.write_u8(0)?;
+                    stream.write_string(&e.to_string())?;
+                }
+            }
+        }
+        Request::RunScript {
+            token,
+            module,
+            command,
+            args,
+        } => {
+            let modules: Vec<&str> = if context.disabled.load(Ordering::SeqCst) {
+                Vec::new()
+            } else {
+                context.modules.iter().map(|m| m.name.as_str()).collect()
+            };
+            match scripts::run(&token, &module, command, [&args[0], &args[1]], &modules) {
+                Ok(output) => {
+                    stream.write_u8(1)?;
+                    stream.write_string(&output)?;
+                }
+                Err(e) => {
+                    warn!(
+                        "Refused {:?} for the script of `{}`: {}",
+                        command, module, e
+                    );
+                    stream.write_u8(0)?;
+                    stream.write_string(&e.to_string())?;
+                }
+            }
+        }
+        Request::ControlSubsystem { control, name } => {
+            if !from_root(&stream, action) {
+                stream.write_u8(0)?;
+                stream.write_string("only root may control subsystems")?;
+                return Ok(());
+            }
+            match subsystem::control(control, &name) {
+                Ok(output) => {
+                    stream.write_u8(1)?;
+                    stream.write_string(&output)?;
+                }
+                Err(e) => {
+                    stream.write_u8(0)?;
+                    stream.write_string(&e.to_string())?;
+                }
+            }
+        }
+        Request::ReportInjection {
+            uid,
+            process,
+            modules: indexes,
+            unmount,
+            duration_us,
+        } => {
+            if indexes.iter().any(|&i| i >= context.modules.len()) {
+                bail!("invalid module index");
+            }
+            let duration = Duration::from_micros(duration_us as u64);
+            history::record(&history::Injection {
+                process: &process,
+                uid,
+                modules: indexes
+                    .iter()
+                    .map(|&i| context.modules[i].name.as_str())
+                    .collect(),
+                unmount,
+                duration,
+            });
+            let pid = utils::peer_pid(&stream)?;
+            for &index in &indexes {
+                context.modules[index].targets.add(pid);
+            }
+            await_injection_ack(stream, process, pid, indexes)?;
+        }
+        Request::GetHistory { package } => match history::read(&package) {
+            Ok(lines) => {
+                stream.write_u8(1)?;
+                stream.write_string(&lines.join("\n"))?;
+            }
+            Err(e) => {
+                stream.write_u8(0)?;
+                stream.write_string(&e.to_string())?;
+            }
+        },
+        Request::GetLogSocket => {
+            // Empty while log forwarding is stopped
+            let path = logfwd::socket_path().unwrap_or_default();
+            stream.write_string(&path.to_string_lossy())?;
+        }
+        Request::GetModuleDir { index } => {
+            let Some(module) = context.modules.get(index) else {
+                bail!("invalid module index {}", index);
+            };
             let dir = format!("{}/{}", constants::PATH_MODULES_DIR, module.name);
             let dir = fs::File::open(dir)?;
             stream.send_fd(dir.as_raw_fd())?;
@@ -351,3 +1725,227 @@ fn handle_daemon_action(
     }
     Ok(())
 }
+
+// Once reported, the injector keeps the connection open and writes the index
+// of every module done with its post-specialize phase, then
+// `INJECTION_ACK_END`. Modules missing from the acknowledgments crashed the
+// app, hung it or lost their hooks before the app ever ran.
+//
+// Connections of every injection are watched by a single thread rather than
+// one each, as apps may take up to `INJECTION_ACK_DEADLINE` to answer.
+struct PendingAck {
+    stream: UnixStream,
+    process: String,
+    // The app, which made the connection after zygote forked it
+    pid: i32,
+    reported: SystemTime,
+    pending: Vec<usize>,
+    deadline: Instant,
+    // Bytes of an index not fully received yet
+    partial: Vec<u8>,
+}
+
+enum AckState {
+    Waiting,
+    Complete,
+    Incomplete(&'static str),
+}
+
+// Handed to the watcher, which is woken up by a byte on ACK_WAKER
+static NEW_ACKS: Mutex<Vec<PendingAck>> = Mutex::new(Vec::new());
+static ACK_WAKER: LateInit<UnixStream> = LateInit::new();
+
+fn await_injection_ack(
+    stream: UnixStream,
+    process: String,
+    pid: i32,
+    pending: Vec<usize>,
+) -> Result<()> {
+    if pending.is_empty() {
+        return Ok(());
+    }
+    stream.set_nonblocking(true)?;
+    NEW_ACKS.lock().unwrap().push(PendingAck {
+        stream,
+        process,
+        pid,
+        reported: SystemTime::now(),
+        pending,
+        deadline: Instant::now() + INJECTION_ACK_DEADLINE,
+        partial: Vec::new(),
+    });
+    // Already woken up when the buffer is full
+    let _ = (&*ACK_WAKER).write(&[0]);
+    Ok(())
+}
+
+fn watch_injection_acks(context: &Arc<Context>) -> Result<()> {
+    let (waker, wakeup) = UnixStream::pair()?;
+    waker.set_nonblocking(true)?;
+    wakeup.set_nonblocking(true)?;
+    ACK_WAKER.init(waker);
+    let context = Arc::clone(context);
+    thread::Builder::new()
+        .name("acks".to_string())
+        .spawn(move || {
+            let mut watched: Vec<PendingAck> = Vec::new();
+            loop {
+                watched.append(&mut NEW_ACKS.lock().unwrap());
+                let now = Instant::now();
+                let timeout = watched
+                    .iter()
+                    .map(|ack| ack.deadline.saturating_duration_since(now))
+                    .min()
+                    .map_or(-1, |left| left.as_millis().min(i32::MAX as u128) as i32);
+                let mut fds: Vec<libc::pollfd> = std::iter::once(wakeup.as_raw_fd())
+                    .chain(watched.iter().map(|ack| ack.stream.as_raw_fd()))
+                    .map(|fd| libc::pollfd {
+                        fd,
+                        events: libc::POLLIN,
+                        revents: 0,
+                    })
+                    .collect();
+                let ready =
+                    unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+                if ready == -1 {
+                    let error = Error::last_os_error();
+                    if error.kind() != ErrorKind::Interrupted {
+                        warn!("Stopped watching injection acknowledgments: {}", error);
+                        return;
+                    }
+                    continue;
+                }
+                if fds[0].revents != 0 {
+                    while matches!((&wakeup).read(&mut [0; 64]), Ok(n) if n > 0) {}
+                }
+                let now = Instant::now();
+                let mut waiting = Vec::with_capacity(watched.len());
+                for (mut ack, fd) in watched.drain(..).zip(&fds[1..]) {
+                    let state = match fd.revents {
+                        0 => AckState::Waiting,
+                        _ => read_acks(&mut ack),
+                    };
+                    match state {
+                        AckState::Waiting if now < ack.deadline => waiting.push(ack),
+                        AckState::Waiting => injection_incomplete(&context, &ack, "timeout"),
+                        AckState::Complete => {}
+                        AckState::Incomplete(reason) => {
+                            injection_incomplete(&context, &ack, reason)
+                        }
+                    }
+                }
+                watched = waiting;
+            }
+        })?;
+    Ok(())
+}
+
+// Take in the acknowledgments received so far
+fn read_acks(ack: &mut PendingAck) -> AckState {
+    let mut buf = [0u8; 64];
+    loop {
+        match ack.stream.read(&mut buf) {
+            Ok(0) => return AckState::Incomplete("closed"),
+            Ok(n) => ack.partial.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(_) => return AckState::Incomplete("closed"),
+        }
+    }
+    let size = std::mem::size_of::<usize>();
+    while ack.partial.len() >= size {
+        let index = usize::from_ne_bytes(ack.partial[..size].try_into().unwrap());
+        ack.partial.drain(..size);
+        match index {
+            INJECTION_ACK_END if ack.pending.is_empty() => return AckState::Complete,
+            INJECTION_ACK_END => return AckState::Incomplete("skipped"),
+            index => ack.pending.retain(|&i| i != index),
+        }
+    }
+    AckState::Waiting
+}
+
+fn injection_incomplete(context: &Arc<Context>, ack: &PendingAck, reason: &str) {
+    let process = ack.process.as_str();
+    let first_missing = ack.pending.iter().min().copied();
+    for &index in &ack.pending {
+        let module = &context.modules[index];
+        let count = module.incomplete_injections.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(
+            "Injection of module {} into {} incomplete ({}), {} so far",
+            module.name, process, reason, count
+        );
+        audit::record(&format!(
+            "injection incomplete module={} process={} reason={}",
+            module.name, process, reason
+        ));
+        metrics::record_injection_incomplete(&module.name, process, reason);
+    }
+    // Post phases run in order, so the first module missing died with the app
+    // and the others never got their turn. Apps also close the connection
+    // when killed or exiting on their own, which is not counted as a crash.
+    let Some(index) = first_missing.filter(|_| reason == "closed") else {
+        return;
+    };
+    let context = Arc::clone(context);
+    let process = ack.process.clone();
+    let (pid, reported) = (ack.pid, ack.reported);
+    let checked = thread::Builder::new()
+        .name("tombstone".to_string())
+        .spawn(move || {
+            if !wait_tombstone(pid, reported) {
+                debug!(
+                    "{} closed without a tombstone, not counted as a crash",
+                    process
+                );
+                return;
+            }
+            if !quarantine::record_crash(&context.modules[index].name, &process) {
+                return;
+            }
+            if let Err(e) = send_status(&context.modules, context.arch) {
+                warn!("Failed to update the status: {}", e);
+            }
+        });
+    if let Err(e) = checked {
+        warn!("Failed to check how {} ended: {}", ack.process, e);
+    }
+}
+
+// Whether a tombstone of `pid` written since `since` shows up in time
+fn wait_tombstone(pid: i32, since: SystemTime) -> bool {
+    let header = format!("pid: {}, ", pid);
+    let deadline = Instant::now() + TOMBSTONE_WAIT;
+    loop {
+        if tombstone_since(&header, since) {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+fn tombstone_since(header: &str, since: SystemTime) -> bool {
+    let Ok(entries) = fs::read_dir(TOMBSTONE_DIR) else {
+        return false;
+    };
+    let recent: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            // Protobuf copies come along the text tombstones since Android 12
+            let text = entry.path().extension().is_none_or(|ext| ext != "pb");
+            text && entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified >= since)
+        })
+        .map(|entry| entry.path())
+        .collect();
+    // The pid is on the first lines, after the build fingerprint
+    uring::read_heads(&recent, 4096)
+        .into_iter()
+        .flatten()
+        .any(|head| String::from_utf8_lossy(&head).contains(header))
+}
@@ -0,0 +1,74 @@
+use crate::lp_select;
+use crate::utils::{self, LateInit};
+use log::{info, trace};
+use num_enum::TryFromPrimitive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Android starts one or two zygotes depending on `ro.zygote`:
+//   zygote32 / zygote64: a single primary zygote
+//   zygote64_32: primary zygote64, secondary zygote (32-bit)
+//   zygote32_64: primary zygote (32-bit), secondary zygote64
+// Each daemon serves exactly one of them, selected by its own bitness.
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ZygoteRole {
+    /// `zygote`, which also forks system_server
+    Primary,
+    /// `zygote_secondary`, serving apps of the other ABI
+    Secondary,
+}
+
+/// How a specialized process came out of its zygote.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum SpawnPath {
+    /// nativeForkAndSpecialize: forked and specialized in one go
+    Fork,
+    /// nativeSpecializeAppProcess: taken from the USAP pool, forked long before specializing
+    Usap,
+    /// nativeForkSystemServer
+    SystemServer,
+}
+
+struct Topology {
+    role: ZygoteRole,
+    spawned: [AtomicUsize; 3],
+}
+
+static TOPOLOGY: LateInit<Topology> = LateInit::new();
+
+pub fn setup() {
+    let config = utils::get_property("ro.zygote").unwrap_or_default();
+    let role = match (config.as_str(), lp_select!(false, true)) {
+        ("zygote32_64", true) | ("zygote64_32", false) => ZygoteRole::Secondary,
+        _ => ZygoteRole::Primary,
+    };
+    info!("Zygote topology: {} ({:?})", config, role);
+    TOPOLOGY.init(Topology {
+        role,
+        spawned: Default::default(),
+    });
+}
+
+pub fn role() -> ZygoteRole {
+    TOPOLOGY.role
+}
+
+pub fn record_spawn(path: SpawnPath, uid: i32) {
+    let count = TOPOLOGY.spawned[path as usize].fetch_add(1, Ordering::Relaxed) + 1;
+    trace!("Uid {} spawned via {:?} ({} so far)", uid, path, count);
+}
+
+pub fn spawn_count(path: SpawnPath) -> usize {
+    TOPOLOGY.spawned[path as usize].load(Ordering::Relaxed)
+}
+
+pub fn summary() -> String {
+    format!(
+        "{:?}, fork: {}, usap: {}, system_server: {}",
+        role(),
+        spawn_count(SpawnPath::Fork),
+        spawn_count(SpawnPath::Usap),
+        spawn_count(SpawnPath::SystemServer)
+    )
+}
@@ -0,0 +1,473 @@
+//! End-to-end tests of the daemon, driven by a client speaking the protocol
+//! the way the loader does from zygote.
+//!
+//! They need a daemon to talk to, so they are ignored by default:
+//!
+//! - `NEOZYGISK_TMP_PATH=<work directory>` attaches to a running daemon, for
+//!   instance on a device from a root shell. Only requests without side
+//!   effects on the running system are sent then.
+//! - `NEOZYGISK_SPAWN=1` starts the daemon built along the tests in new user,
+//!   mount and pid namespaces, with an empty module directory and this
+//!   harness standing in for the monitor.
+//!
+//! `cargo test --test fake_zygote -- --ignored`
+
+use passfd::FdPassingExt;
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::{env, fs, thread};
+
+// Mirror `DaemonSocketAction` of constants.rs and `SocketAction` of daemon.hpp
+const PING_HEARTBEAT: u8 = 0;
+const GET_PROCESS_FLAGS: u8 = 1;
+const CACHE_MOUNT_NAMESPACE: u8 = 2;
+const UPDATE_MOUNT_NAMESPACE: u8 = 3;
+const READ_MODULES: u8 = 4;
+const REQUEST_COMPANION_SOCKET: u8 = 5;
+const GET_MODULE_DIR: u8 = 6;
+const GET_PROPERTY_OVERLAY: u8 = 9;
+const HANDOVER: u8 = 11;
+const DUMP_PROFILE: u8 = 17;
+const ROTATE_SECRETS: u8 = 18;
+const RUN_SCRIPT: u8 = 19;
+const CONTROL_SUBSYSTEM: u8 = 20;
+const GET_LOG_SOCKET: u8 = 21;
+const ACTIONS: u8 = 23;
+
+// `SpawnPath::Fork` and `MountNamespace::Clean`
+const SPAWN_FORK: u8 = 0;
+const NAMESPACE_CLEAN: u8 = 0;
+const ZYGOTE_INJECTED: i32 = if cfg!(target_pointer_width = "64") {
+    4
+} else {
+    5
+};
+const IS_FIRST_PROCESS: u32 = 1 << 31;
+const ARCH: &str = if cfg!(target_pointer_width = "64") {
+    "cp64"
+} else {
+    "cp32"
+};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Tests run in parallel, each with its own daemon
+static SPAWNED: AtomicUsize = AtomicUsize::new(0);
+
+struct Daemon {
+    tmp_path: PathBuf,
+    // Set when spawned by the harness, with the monitor socket standing in for the ptracer
+    spawned: Option<(Child, UnixDatagram)>,
+}
+
+impl Daemon {
+    fn get() -> Daemon {
+        if let Ok(tmp_path) = env::var("NEOZYGISK_TMP_PATH") {
+            return Daemon {
+                tmp_path: PathBuf::from(tmp_path),
+                spawned: None,
+            };
+        }
+        assert!(
+            env::var_os("NEOZYGISK_SPAWN").is_some(),
+            "set NEOZYGISK_TMP_PATH or NEOZYGISK_SPAWN to choose a daemon"
+        );
+        Daemon::spawn()
+    }
+
+    fn spawn() -> Daemon {
+        let id = SPAWNED.fetch_add(1, Ordering::Relaxed);
+        let root = env::temp_dir().join(format!("fake-zygote-{}-{}", std::process::id(), id));
+        let _ = fs::remove_dir_all(&root);
+        let tmp_path = root.join("work");
+        // The daemon runs from its module directory, next to the other modules
+        let module_dir = root.join("modules").join("zygisksu");
+        fs::create_dir_all(&tmp_path).unwrap();
+        fs::create_dir_all(&module_dir).unwrap();
+        let monitor = UnixDatagram::bind(tmp_path.join("init_monitor")).unwrap();
+        monitor.set_read_timeout(Some(REPLY_TIMEOUT)).unwrap();
+
+        // As pid 1 of its namespace, the daemon switching to the mount namespace of init
+        // stays in its own
+        let child = Command::new("unshare")
+            .args(["--user", "--map-root-user", "--mount", "--pid", "--fork"])
+            .args(["--mount-proc", "--kill-child", "--"])
+            .arg(env!("CARGO_BIN_EXE_zygiskd"))
+            .env("TMP_PATH", &tmp_path)
+            .current_dir(&module_dir)
+            .stdin(Stdio::null())
+            .spawn()
+            .expect("cannot start the daemon in user namespaces");
+        let daemon = Daemon {
+            tmp_path,
+            spawned: Some((child, monitor)),
+        };
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        while daemon.connect().is_err() {
+            assert!(
+                Instant::now() < deadline,
+                "the daemon did not start listening"
+            );
+            thread::sleep(Duration::from_millis(100));
+        }
+        daemon
+    }
+
+    // Resolve the socket like the loader, through the name published for this boot
+    fn socket_path(&self) -> PathBuf {
+        let dir = self.tmp_path.join("sockets");
+        match fs::read_to_string(dir.join(ARCH)) {
+            Ok(name) => dir.join(name.trim()),
+            Err(_) => self.tmp_path.join(format!("{}.sock", ARCH)),
+        }
+    }
+
+    fn connect(&self) -> std::io::Result<Zygote> {
+        let stream = UnixStream::connect(self.socket_path())?;
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        Ok(Zygote { stream })
+    }
+
+    fn request(&self, action: u8) -> Zygote {
+        let mut zygote = self.connect().expect("cannot connect to the daemon");
+        zygote.write_u8(action);
+        zygote
+    }
+
+    fn is_spawned(&self) -> bool {
+        self.spawned.is_some()
+    }
+
+    // Whether the daemon kept serving after a request
+    fn assert_alive(&self) {
+        let mut zygote = self.request(GET_PROCESS_FLAGS);
+        zygote.write_u32(10000);
+        zygote.write_u8(SPAWN_FORK);
+        zygote.read_u32();
+        if let Some((child, _)) = &self.spawned {
+            let pid = child.id() as i32;
+            assert_eq!(unsafe { libc::kill(pid, 0) }, 0, "the daemon exited");
+        }
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        if let Some((child, _)) = &mut self.spawned {
+            let _ = child.kill();
+            let _ = child.wait();
+            let _ = fs::remove_dir_all(self.tmp_path.parent().unwrap());
+        }
+    }
+}
+
+/// One connection to the daemon, as opened by the loader for every request.
+struct Zygote {
+    stream: UnixStream,
+}
+
+impl Zygote {
+    fn write_u8(&mut self, value: u8) {
+        self.stream.write_all(&[value]).unwrap();
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.stream.write_all(&value.to_ne_bytes()).unwrap();
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.stream.write_all(&value.to_ne_bytes()).unwrap();
+    }
+
+    fn write_string(&mut self, value: &str) {
+        self.write_usize(value.len());
+        self.stream.write_all(value.as_bytes()).unwrap();
+    }
+
+    fn read_exact<const N: usize>(&mut self) -> [u8; N] {
+        let mut buf = [0u8; N];
+        self.stream
+            .read_exact(&mut buf)
+            .expect("no reply from the daemon");
+        buf
+    }
+
+    fn read_u8(&mut self) -> u8 {
+        self.read_exact::<1>()[0]
+    }
+
+    fn read_u32(&mut self) -> u32 {
+        u32::from_ne_bytes(self.read_exact())
+    }
+
+    fn read_usize(&mut self) -> usize {
+        usize::from_ne_bytes(self.read_exact())
+    }
+
+    fn read_string(&mut self) -> String {
+        let len = self.read_usize();
+        let mut buf = vec![0u8; len];
+        self.stream.read_exact(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    fn recv_fd(&mut self) -> OwnedFd {
+        let fd = self.stream.recv_fd().expect("no fd from the daemon");
+        unsafe { OwnedFd::from_raw_fd(fd) }
+    }
+
+    // Whether the daemon closed the connection without answering
+    fn closed(&mut self) -> bool {
+        let mut buf = [0u8; 1];
+        match self.stream.read(&mut buf) {
+            Ok(read) => read == 0,
+            Err(e) => e.kind() == ErrorKind::ConnectionReset,
+        }
+    }
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn heartbeat_reaches_the_monitor() {
+    let daemon = Daemon::get();
+    if !daemon.is_spawned() {
+        // Would tell the monitor of the device that zygote was injected again
+        return;
+    }
+    drop(daemon.request(PING_HEARTBEAT));
+    let (_, monitor) = daemon.spawned.as_ref().unwrap();
+    let mut buf = [0u8; 4];
+    monitor
+        .recv(&mut buf)
+        .expect("the monitor was not notified");
+    assert_eq!(i32::from_le_bytes(buf), ZYGOTE_INJECTED);
+    daemon.assert_alive();
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn process_flags() {
+    let daemon = Daemon::get();
+    let mut flags = Vec::new();
+    for _ in 0..2 {
+        let mut zygote = daemon.request(GET_PROCESS_FLAGS);
+        zygote.write_u32(10000);
+        zygote.write_u8(SPAWN_FORK);
+        flags.push(zygote.read_u32());
+    }
+    // Only the first process ever spawned is flagged as such
+    assert_eq!(flags[1] & IS_FIRST_PROCESS, 0);
+    if daemon.is_spawned() {
+        assert_ne!(flags[0] & IS_FIRST_PROCESS, 0);
+    }
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn modules_come_with_their_library() {
+    let daemon = Daemon::get();
+    let mut zygote = daemon.request(READ_MODULES);
+    let count = zygote.read_usize();
+    if daemon.is_spawned() {
+        assert_eq!(count, 0, "the spawned daemon has no module");
+    }
+    for _ in 0..count {
+        let name = zygote.read_string();
+        assert!(!name.is_empty() && !name.contains('/'));
+        let lib = zygote.recv_fd();
+        assert!(lib.as_raw_fd() >= 0);
+    }
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn clean_mount_namespace() {
+    let daemon = Daemon::get();
+    let mut zygote = daemon.request(UPDATE_MOUNT_NAMESPACE);
+    zygote.write_u8(NAMESPACE_CLEAN);
+    zygote.write_u32(10000);
+    // Forked before zygote switched to the Clean namespace, as USAPs may be
+    zygote.write_u8(0);
+    let pid = zygote.read_u32();
+    let fd = zygote.read_u32();
+    // The loader opens /proc/<pid>/fd/<fd>, 0 meaning to keep the namespace of zygote,
+    // and echoes the generation back to learn whether the fd was reused meanwhile
+    if fd != 0 {
+        let generation = zygote.read_usize();
+        let target = fs::read_link(format!("/proc/{}/fd/{}", pid, fd)).unwrap();
+        assert!(target.to_string_lossy().starts_with("mnt:"));
+        // Nothing rebuilds the namespaces meanwhile
+        zygote.write_usize(generation);
+        assert_eq!(zygote.read_u8(), 1);
+    }
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn property_overlay_of_unknown_process() {
+    let daemon = Daemon::get();
+    let mut zygote = daemon.request(GET_PROPERTY_OVERLAY);
+    zygote.write_string("org.neozygisk.fake");
+    let count = zygote.read_usize();
+    for _ in 0..count {
+        assert!(!zygote.read_string().is_empty());
+        zygote.read_string();
+    }
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn profile_lists_request_timings() {
+    let daemon = Daemon::get();
+    let mut zygote = daemon.request(DUMP_PROFILE);
+    let profile = zygote.read_string();
+    let request = profile
+        .lines()
+        .find(|line| line.starts_with("task.request "))
+        .expect("no task.request site");
+    assert!(request.contains(" count="));
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn rotated_socket_is_found_again() {
+    let daemon = Daemon::get();
+    if !daemon.is_spawned() {
+        return;
+    }
+    let old = daemon.socket_path();
+    let mut zygote = daemon.request(ROTATE_SECRETS);
+    assert_eq!(zygote.read_u8(), 1);
+    assert_ne!(daemon.socket_path(), old);
+    assert!(UnixStream::connect(&old).is_err());
+    daemon.assert_alive();
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn log_socket_is_handed_out() {
+    let daemon = Daemon::get();
+    let mut zygote = daemon.request(GET_LOG_SOCKET);
+    let path = zygote.read_string();
+    // Empty while log forwarding is stopped
+    if path.is_empty() {
+        return;
+    }
+    let path = Path::new(&path);
+    assert!(path.is_absolute());
+    assert!(path.exists());
+    daemon.assert_alive();
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn script_without_token_is_refused() {
+    let daemon = Daemon::get();
+    let mut zygote = daemon.request(RUN_SCRIPT);
+    zygote.write_string("0123456789abcdef0123456789abcdef");
+    zygote.write_string("zygisksu");
+    // `scripts::Command::ListModules`
+    zygote.write_u8(2);
+    zygote.write_string("");
+    zygote.write_string("");
+    assert_eq!(zygote.read_u8(), 0);
+    assert_eq!(zygote.read_string(), "invalid token");
+    daemon.assert_alive();
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn subsystems_are_listed() {
+    let daemon = Daemon::get();
+    // `subsystem::Control::List`
+    let mut zygote = daemon.request(CONTROL_SUBSYSTEM);
+    zygote.write_u8(0);
+    zygote.write_string("");
+    assert_eq!(zygote.read_u8(), 1);
+    let list = zygote.read_string();
+    assert!(list.lines().any(|line| line.starts_with("props ")));
+    daemon.assert_alive();
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn unknown_action_is_dropped() {
+    let daemon = Daemon::get();
+    for action in [ACTIONS, u8::MAX] {
+        let mut zygote = daemon.request(action);
+        assert!(zygote.closed());
+    }
+    daemon.assert_alive();
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn connection_closed_before_any_request() {
+    let daemon = Daemon::get();
+    drop(daemon.connect().unwrap());
+    daemon.assert_alive();
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn truncated_request() {
+    let daemon = Daemon::get();
+    let mut zygote = daemon.request(GET_PROCESS_FLAGS);
+    zygote.write_u8(0);
+    zygote.stream.shutdown(std::net::Shutdown::Write).unwrap();
+    assert!(zygote.closed());
+    daemon.assert_alive();
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn truncated_request_served_inline() {
+    let daemon = Daemon::get();
+    // Served by the accepting thread itself, whose errors used to stop the daemon
+    for action in [CACHE_MOUNT_NAMESPACE, HANDOVER] {
+        let mut zygote = daemon.request(action);
+        zygote.stream.shutdown(std::net::Shutdown::Write).unwrap();
+        assert!(zygote.closed());
+    }
+    daemon.assert_alive();
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn invalid_module_index() {
+    let daemon = Daemon::get();
+    for action in [REQUEST_COMPANION_SOCKET, GET_MODULE_DIR] {
+        let mut zygote = daemon.request(action);
+        zygote.write_usize(usize::MAX);
+        assert!(zygote.closed());
+    }
+    daemon.assert_alive();
+}
+
+#[test]
+#[ignore = "needs a daemon, see the module documentation"]
+fn concurrent_clients() {
+    let daemon = Daemon::get();
+    let path: &Path = &daemon.socket_path();
+    thread::scope(|scope| {
+        for uid in 10000..10016 {
+            scope.spawn(move || {
+                let mut zygote = Zygote {
+                    stream: UnixStream::connect(path).unwrap(),
+                };
+                zygote.stream.set_read_timeout(Some(REPLY_TIMEOUT)).unwrap();
+                zygote.write_u8(GET_PROCESS_FLAGS);
+                zygote.write_u32(uid);
+                zygote.write_u8(SPAWN_FORK);
+                zygote.read_u32();
+            });
+        }
+    });
+    daemon.assert_alive();
+}